@@ -0,0 +1,121 @@
+//! Client of C3A administrator's API.
+//!
+//! Requests and responses are MessagePack, as for all C3A methods. Every request is wrapped into `AdminRequest`
+//! and signed by the admin's key in `C3A-Sign` header.
+
+use c3a_common::{AdminRequest, BootstrapAdminRequest, Keypair, base64_encode};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::AdminError;
+
+async fn send(request: reqwest::RequestBuilder) -> Result<Vec<u8>, AdminError> {
+  let response = request.send().await?;
+  let status = response.status();
+  let body = response.bytes().await?;
+  if !status.is_success() {
+    return Err(AdminError::Api {
+      status,
+      message: String::from_utf8_lossy(&body).into_owned(),
+    });
+  }
+  Ok(body.to_vec())
+}
+
+/// Gets a public method which doesn't need authentication.
+pub(crate) async fn get<T: DeserializeOwned>(url: &str, path: &str) -> Result<T, AdminError> {
+  let request = reqwest::Client::new().get(format!("{url}{path}"));
+  Ok(rmp_serde::from_slice(&send(request).await?)?)
+}
+
+pub(crate) struct AdminClient {
+  http: reqwest::Client,
+  url: String,
+  admin: String,
+  keypair: Keypair,
+}
+
+impl AdminClient {
+  pub(crate) fn new(url: String, admin: String, keypair: Keypair) -> Self {
+    Self {
+      http: reqwest::Client::new(),
+      url,
+      admin,
+      keypair,
+    }
+  }
+
+  fn request<T>(&self, path: &str, body: T) -> AdminRequest<T> {
+    AdminRequest {
+      admin: self.admin.clone(),
+      path: path.to_owned(),
+      issued_at: chrono::Utc::now(),
+      nonce: c3a_common::generate::<32>().to_vec(),
+      body,
+    }
+  }
+
+  fn sign<T: Serialize>(&self, request: &AdminRequest<T>) -> Result<String, AdminError> {
+    let signature = c3a_common::sign(request, &self.keypair).map_err(|e| AdminError::Sign(e.to_string()))?;
+    Ok(base64_encode(&signature))
+  }
+
+  async fn send_signed<T: Serialize>(&self, request: AdminRequest<T>) -> Result<Vec<u8>, AdminError> {
+    let signature = self.sign(&request)?;
+    let request = self
+      .http
+      .post(format!("{}{}", self.url, request.path))
+      .header(reqwest::header::CONTENT_TYPE, "application/msgpack")
+      .header(c3a_common::SIGN_HEADER, signature)
+      .body(rmp_serde::to_vec(&request)?);
+    send(request).await
+  }
+
+  async fn post_raw(&self, path: &str, body: impl Serialize) -> Result<Vec<u8>, AdminError> {
+    self.send_signed(self.request(path, body)).await
+  }
+
+  pub(crate) async fn post<T: DeserializeOwned>(&self, path: &str, body: impl Serialize) -> Result<T, AdminError> {
+    Ok(rmp_serde::from_slice(&self.post_raw(path, body).await?)?)
+  }
+
+  /// Posts to the method which responds with `200 OK` only.
+  pub(crate) async fn post_ok(&self, path: &str, body: impl Serialize) -> Result<(), AdminError> {
+    self.post_raw(path, body).await.map(|_| ())
+  }
+
+  /// Registers the admin as the first one, proving knowledge of `private_adm_key` without sending it.
+  pub(crate) async fn bootstrap(&self, private_adm_key: &str) -> Result<(), AdminError> {
+    let body = BootstrapAdminRequest {
+      public: self.keypair.public.to_vec(),
+      key_proof: vec![],
+    };
+    let mut request = self.request("/admin/bootstrap", body);
+    request.body.key_proof = c3a_common::bootstrap_key_proof(private_adm_key.as_bytes(), &request)?;
+    self.send_signed(request).await.map(|_| ())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use c3a_common::{AdminRequest, ListAppsRequest, base64_decode, verify};
+
+  #[test]
+  fn test_requests_are_signed() {
+    let keypair = c3a_common::generate_dilithium_keypair();
+    let client = super::AdminClient::new(String::from("http://localhost"), String::from("admin"), keypair);
+
+    let request = client.request("/admin/apps", ListAppsRequest {});
+    let other = client.request("/admin/apps", ListAppsRequest {});
+    let signature = client.sign(&request).unwrap();
+    assert_eq!(request.admin, "admin");
+    assert_ne!(request.nonce, other.nonce);
+    let signature = base64_decode(&signature).unwrap();
+    assert!(verify(&request, &signature, &keypair.public).unwrap());
+
+    let moved = AdminRequest {
+      path: String::from("/admin/backup"),
+      ..request
+    };
+    assert!(!verify(&moved, &signature, &keypair.public).unwrap());
+  }
+}
@@ -0,0 +1,101 @@
+//! Credentials of C3A administrator.
+//!
+//! Values are taken from the environment; missing ones are read from the config file, which has the same
+//! `KEY=value` format as `.env` of the worker. The file is set by `--config` (or `C3A_ADMIN_CONFIG`), otherwise
+//! `~/.config/c3a/admin.env` is read if it exists.
+
+use c3a_common::Keypair;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::AdminError;
+
+/// Base URL of C3A instance, e.g. `https://c3a.example.com`.
+const URL_VAR: &str = "C3A_URL";
+/// Name of the admin registered at the instance.
+const ADMIN_NAME_VAR: &str = "C3A_ADMIN_NAME";
+/// File made by `c3a-admin keygen`.
+const ADMIN_KEY_FILE_VAR: &str = "C3A_ADMIN_KEY_FILE";
+/// The same key as `C3A_PRIVATE_ADM_KEY` of the worker; needed only to register the first admin.
+const PRIVATE_ADM_KEY_VAR: &str = "C3A_PRIVATE_ADM_KEY";
+
+/// Dilithium5 keypair of the admin.
+#[derive(Deserialize, Serialize)]
+struct AdminKeyFile {
+  public: Vec<u8>,
+  secret: Vec<u8>,
+}
+
+fn default_path() -> Option<PathBuf> {
+  std::env::var_os("HOME")
+    .map(|home| PathBuf::from(home).join(".config/c3a/admin.env"))
+    .filter(|path| path.exists())
+}
+
+/// Reads the config file into the environment; variables set already aren't overridden.
+pub(crate) fn load(path: Option<&Path>) -> Result<(), AdminError> {
+  let path = if let Some(path) = path.map(Path::to_path_buf).or_else(default_path) {
+    path
+  } else {
+    return Ok(());
+  };
+  dotenv::from_path(&path).map_err(|e| AdminError::Config(format!("Can't read `{}`: {e}", path.display())))
+}
+
+fn var(name: &str) -> Result<String, AdminError> {
+  std::env::var(name).map_err(|_| AdminError::Config(format!("There is no `{name}` set!")))
+}
+
+pub(crate) fn url() -> Result<String, AdminError> {
+  Ok(var(URL_VAR)?.trim_end_matches('/').to_owned())
+}
+
+pub(crate) fn admin_name() -> Result<String, AdminError> {
+  var(ADMIN_NAME_VAR)
+}
+
+pub(crate) fn private_adm_key() -> Result<String, AdminError> {
+  var(PRIVATE_ADM_KEY_VAR)
+}
+
+pub(crate) fn load_keypair() -> Result<Keypair, AdminError> {
+  read_keypair(Path::new(&var(ADMIN_KEY_FILE_VAR)?))
+}
+
+fn read_keypair(path: &Path) -> Result<Keypair, AdminError> {
+  let key_file = rmp_serde::from_slice::<AdminKeyFile>(&std::fs::read(path)?)?;
+  c3a_common::restore_from(&key_file.public, &key_file.secret)
+    .map_err(|_| AdminError::Config(format!("`{}` doesn't contain a Dilithium5 keypair!", path.display())))
+}
+
+/// Writes a new keypair to the file, readable only by the owner; the file shouldn't exist.
+pub(crate) fn write_keypair(path: &Path, keypair: &Keypair) -> Result<(), AdminError> {
+  use std::io::Write;
+
+  let key_file = AdminKeyFile {
+    public: keypair.public.to_vec(),
+    secret: keypair.expose_secret().to_vec(),
+  };
+  let mut options = std::fs::OpenOptions::new();
+  options.write(true).create_new(true);
+  #[cfg(unix)]
+  std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+  options.open(path)?.write_all(&rmp_serde::to_vec(&key_file)?)?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  #[test]
+  fn test_keypair_file() {
+    let path = std::env::temp_dir().join(format!("c3a-admin-test-{}.key", std::process::id()));
+    let keypair = c3a_common::generate_dilithium_keypair();
+    super::write_keypair(&path, &keypair).unwrap();
+    // Existing files aren't overwritten.
+    assert!(super::write_keypair(&path, &c3a_common::generate_dilithium_keypair()).is_err());
+
+    let restored = super::read_keypair(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert!(restored.unwrap() == keypair);
+  }
+}
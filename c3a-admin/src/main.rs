@@ -0,0 +1,452 @@
+//! Command-line tool of C3A instance administrator.
+//!
+//! Credentials are read from the environment or the config file, see `config`.
+
+#![deny(warnings, clippy::todo, clippy::unimplemented)]
+
+use c3a_common::{
+  AddAdminRequest, AdminRole, BackupRequest, BanSubject, C3AKeySet, GenerateInvitationRequest,
+  GenerateInvitationResponse, LiftBanRequest, ListAdminsRequest, ListAdminsResponse, ListAppsRequest, ListAppsResponse,
+  ListBansRequest, ListBansResponse, ListInvitationsRequest, ListInvitationsResponse, MailQueueStatusRequest,
+  MailQueueStatusResponse, RemoveAdminRequest, RemoveDeadLetterRequest, RequeueDeadLetterRequest,
+  RevokeInvitationRequest, RotatePepperRequest, RotatePepperResponse, RotateSigningKeyRequest,
+  RotateSigningKeyResponse, base64_decode, base64_encode,
+};
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+mod client;
+mod config;
+mod tokens;
+
+use crate::client::AdminClient;
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum AdminError {
+  #[error("Config error: {0}")]
+  Config(String),
+  #[error("Request error: {0}")]
+  Request(#[from] reqwest::Error),
+  #[error("C3A responded with {status}: {message}")]
+  Api {
+    status: reqwest::StatusCode,
+    message: String,
+  },
+  #[error("Serialize error: {0}")]
+  Serialize(#[from] rmp_serde::encode::Error),
+  #[error("Deserialize error: {0}")]
+  Deserialize(#[from] rmp_serde::decode::Error),
+  #[error("Sign error: {0}")]
+  Sign(String),
+  #[error("Invalid token: {0}")]
+  Token(String),
+  #[error("IO error: {0}")]
+  Io(#[from] std::io::Error),
+}
+
+#[derive(Parser)]
+#[command(name = "c3a-admin", about = "Administration of C3A instance")]
+struct Cli {
+  /// File with `C3A_URL`, `C3A_ADMIN_NAME` and `C3A_ADMIN_KEY_FILE`; `~/.config/c3a/admin.env` by default.
+  #[arg(long, env = "C3A_ADMIN_CONFIG")]
+  config: Option<PathBuf>,
+  #[command(subcommand)]
+  command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+  /// Writes a new admin's keypair to the file and prints the public key.
+  Keygen { output: PathBuf },
+  /// Admins of the instance.
+  #[command(subcommand)]
+  Admins(AdminsCommand),
+  /// Invitations for app registration.
+  #[command(subcommand)]
+  Invitations(InvitationsCommand),
+  /// Registered apps.
+  #[command(subcommand)]
+  Apps(AppsCommand),
+  /// Users and IP addresses banned by apps' fail-to-ban options.
+  #[command(subcommand)]
+  Bans(BansCommand),
+  /// Signing keys and peppers.
+  #[command(subcommand)]
+  Keys(KeysCommand),
+  /// Outgoing mail queue.
+  #[command(subcommand)]
+  Mail(MailCommand),
+  /// Saves an encrypted archive of all records, see `c3a-worker restore`.
+  Backup { output: PathBuf },
+  /// Prints the header and the payload of MPAAT.
+  DecodeToken {
+    token: String,
+    /// Also checks the signature with the keys published by the instance.
+    #[arg(long)]
+    verify: bool,
+  },
+}
+
+#[derive(Subcommand)]
+enum InvitationsCommand {
+  /// Prints a new invitation code.
+  Generate {
+    /// Name or domain of the only app which may be registered with the invite.
+    #[arg(long = "for")]
+    intended_for: Option<String>,
+    /// Days before the invite expires; 7 by default.
+    #[arg(long)]
+    days: Option<u16>,
+  },
+  /// Prints all invitations, including used and expired ones.
+  List,
+  Revoke {
+    id: String,
+  },
+}
+
+#[derive(Subcommand)]
+enum AdminsCommand {
+  /// Registers yourself as the first admin with `C3A_PRIVATE_ADM_KEY`.
+  Bootstrap,
+  List,
+  Add {
+    name: String,
+    role: Role,
+    /// Public key printed by `c3a-admin keygen`.
+    public: String,
+  },
+  Remove {
+    name: String,
+  },
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum Role {
+  Viewer,
+  Operator,
+  Owner,
+}
+
+impl From<Role> for AdminRole {
+  fn from(role: Role) -> Self {
+    match role {
+      Role::Viewer => AdminRole::Viewer,
+      Role::Operator => AdminRole::Operator,
+      Role::Owner => AdminRole::Owner,
+    }
+  }
+}
+
+#[derive(Subcommand)]
+enum AppsCommand {
+  List,
+}
+
+#[derive(Subcommand)]
+enum BansCommand {
+  /// Prints active bans.
+  List {
+    /// Prints bans of this app only.
+    #[arg(long)]
+    app: Option<String>,
+  },
+  /// Lifts the ban of the user's identifier or of the IP address in the app.
+  Lift {
+    app: String,
+    #[arg(long, conflicts_with = "ip", required_unless_present = "ip")]
+    login: Option<String>,
+    #[arg(long)]
+    ip: Option<String>,
+  },
+}
+
+#[derive(Subcommand)]
+enum MailCommand {
+  /// Prints the queue's counters and dead letters.
+  Status,
+  /// Sends the dead letter again.
+  Requeue { id: String },
+  /// Removes the dead letter.
+  Remove { id: String },
+}
+
+#[derive(Subcommand)]
+enum KeysCommand {
+  /// Generates a new signing key; previous keys stay valid for verification for a while.
+  RotateSigningKey {
+    /// RFC 3339 moment since which the new key signs tokens; right now if not set.
+    #[arg(long)]
+    activates_at: Option<chrono::DateTime<chrono::Utc>>,
+  },
+  /// Generates a new pepper for users' secrets.
+  RotatePepper,
+}
+
+fn admin_client() -> Result<AdminClient, AdminError> {
+  Ok(AdminClient::new(
+    config::url()?,
+    config::admin_name()?,
+    config::load_keypair()?,
+  ))
+}
+
+fn run_keygen(output: PathBuf) -> Result<(), AdminError> {
+  let keypair = c3a_common::generate_dilithium_keypair();
+  config::write_keypair(&output, &keypair)?;
+  println!("{}", base64_encode(&keypair.public));
+  Ok(())
+}
+
+async fn run_admins(command: AdminsCommand) -> Result<(), AdminError> {
+  let client = admin_client()?;
+  match command {
+    AdminsCommand::Bootstrap => client.bootstrap(&config::private_adm_key()?).await?,
+    AdminsCommand::List => {
+      let response = client
+        .post::<ListAdminsResponse>("/admin/admins", ListAdminsRequest {})
+        .await?;
+      for admin in response.admins {
+        let added_by = admin.added_by.unwrap_or_default();
+        println!(
+          "{}\t{:?}\t{}\t{}",
+          admin.name,
+          admin.role,
+          admin.added_at.to_rfc3339(),
+          added_by
+        );
+      }
+    }
+    AdminsCommand::Add { name, role, public } => {
+      let public = base64_decode(&public).map_err(|e| AdminError::Config(format!("Invalid public key: {e}")))?;
+      let request = AddAdminRequest {
+        name,
+        role: role.into(),
+        public,
+      };
+      client.post_ok("/admin/admins/add", request).await?;
+    }
+    AdminsCommand::Remove { name } => {
+      client
+        .post_ok("/admin/admins/remove", RemoveAdminRequest { name })
+        .await?;
+    }
+  }
+  Ok(())
+}
+
+async fn run_invitations(command: InvitationsCommand) -> Result<(), AdminError> {
+  let client = admin_client()?;
+  match command {
+    InvitationsCommand::Generate { intended_for, days } => {
+      let request = GenerateInvitationRequest {
+        intended_for,
+        lifetime_days: days,
+      };
+      let response = client
+        .post::<GenerateInvitationResponse>("/apps/generate-invitation", request)
+        .await?;
+      println!("{}", response.invite);
+      println!("Id: {}, expires at {}", response.id, response.expires_at.to_rfc3339());
+    }
+    InvitationsCommand::List => {
+      let response = client
+        .post::<ListInvitationsResponse>("/admin/invitations", ListInvitationsRequest {})
+        .await?;
+      for info in response.invitations {
+        let used = info
+          .used
+          .map(|used| format!("used by `{}` at {}", used.app_name, used.used_at.to_rfc3339()))
+          .unwrap_or_default();
+        println!(
+          "{}\t{}\t{}\t{}\t{}\t{}",
+          info.id,
+          info.created_by,
+          info.created_at.to_rfc3339(),
+          info.expires_at.to_rfc3339(),
+          info.intended_for.unwrap_or_default(),
+          used
+        );
+      }
+    }
+    InvitationsCommand::Revoke { id } => {
+      client
+        .post_ok("/admin/invitations/revoke", RevokeInvitationRequest { id })
+        .await?;
+    }
+  }
+  Ok(())
+}
+
+async fn run_apps(command: AppsCommand) -> Result<(), AdminError> {
+  let client = admin_client()?;
+  match command {
+    AppsCommand::List => {
+      let response = client
+        .post::<ListAppsResponse>("/admin/apps", ListAppsRequest {})
+        .await?;
+      for app in response.apps {
+        println!("{}\t{}", app.app_name, app.domain);
+      }
+    }
+  }
+  Ok(())
+}
+
+async fn run_bans(command: BansCommand) -> Result<(), AdminError> {
+  let client = admin_client()?;
+  match command {
+    BansCommand::List { app } => {
+      let response = client
+        .post::<ListBansResponse>("/admin/bans", ListBansRequest { app_name: app })
+        .await?;
+      for ban in response.bans {
+        let subject = match ban.subject {
+          BanSubject::Login(identifier) => format!("login:{identifier}"),
+          BanSubject::Ip(ip) => format!("ip:{ip}"),
+        };
+        println!(
+          "{}\t{}\t{}\t{}",
+          ban.app_name,
+          subject,
+          ban.banned_at.to_rfc3339(),
+          ban.banned_until.to_rfc3339()
+        );
+      }
+    }
+    BansCommand::Lift { app, login, ip } => {
+      let subject = match (login, ip) {
+        (Some(identifier), _) => BanSubject::Login(identifier),
+        (None, Some(ip)) => BanSubject::Ip(ip),
+        (None, None) => {
+          return Err(AdminError::Config(String::from(
+            "Set `--login` or `--ip` to lift the ban.",
+          )));
+        }
+      };
+      client
+        .post_ok("/admin/bans/lift", LiftBanRequest { app_name: app, subject })
+        .await?;
+    }
+  }
+  Ok(())
+}
+
+async fn run_keys(command: KeysCommand) -> Result<(), AdminError> {
+  let client = admin_client()?;
+  match command {
+    KeysCommand::RotateSigningKey { activates_at } => {
+      let response = client
+        .post::<RotateSigningKeyResponse>("/admin/signing-keys/rotate", RotateSigningKeyRequest { activates_at })
+        .await?;
+      println!("New key: {}", response.kid);
+      for key in response.keys {
+        let expires_at = key.expires_at.map(|dt| dt.to_rfc3339()).unwrap_or_default();
+        println!("{}\t{}\t{}", key.kid, key.activates_at.to_rfc3339(), expires_at);
+      }
+    }
+    KeysCommand::RotatePepper => {
+      let response = client
+        .post::<RotatePepperResponse>("/admin/peppers/rotate", RotatePepperRequest {})
+        .await?;
+      println!(
+        "Current pepper: {}, accepted: {:?}",
+        response.current_version, response.known_versions
+      );
+    }
+  }
+  Ok(())
+}
+
+async fn run_mail(command: MailCommand) -> Result<(), AdminError> {
+  let client = admin_client()?;
+  match command {
+    MailCommand::Status => {
+      let response = client
+        .post::<MailQueueStatusResponse>("/admin/mail-queue", MailQueueStatusRequest {})
+        .await?;
+      let oldest = response
+        .oldest_pending_created_at
+        .map(|dt| dt.to_rfc3339())
+        .unwrap_or_default();
+      println!(
+        "Pending: {}, due: {}, retrying: {}, oldest: {}",
+        response.pending, response.due, response.retrying, oldest
+      );
+      for mail in response.dead_letters {
+        println!(
+          "{}\t{}\t{}\t{}\t{}\t{}",
+          mail.id,
+          mail.app_name,
+          mail.recipients.join(","),
+          mail.attempts,
+          mail.created_at.to_rfc3339(),
+          mail.last_error.unwrap_or_default()
+        );
+      }
+    }
+    MailCommand::Requeue { id } => {
+      client
+        .post_ok("/admin/mail-queue/requeue", RequeueDeadLetterRequest { id })
+        .await?;
+    }
+    MailCommand::Remove { id } => {
+      client
+        .post_ok("/admin/mail-queue/remove", RemoveDeadLetterRequest { id })
+        .await?;
+    }
+  }
+  Ok(())
+}
+
+async fn run_backup(output: PathBuf) -> Result<(), AdminError> {
+  let client = admin_client()?;
+  let archive = client.post::<Vec<u8>>("/admin/backup", BackupRequest {}).await?;
+  std::fs::write(&output, &archive)?;
+  println!("{} bytes are written to `{}`", archive.len(), output.display());
+  Ok(())
+}
+
+async fn run_decode_token(token: String, verify: bool) -> Result<(), AdminError> {
+  let decoded = tokens::decode(&token)?;
+  println!("Header: {}", decoded.header);
+  match decoded.payload {
+    Some(payload) => println!("Payload: {payload}"),
+    None => println!("Payload: encrypted, {} bytes", decoded.payload_length),
+  }
+
+  if verify {
+    let key_set = client::get::<C3AKeySet>(&config::url()?, "/.well-known/c3a-keys").await?;
+    let keys = key_set
+      .keys
+      .into_iter()
+      .map(|published| published.key)
+      .collect::<Vec<_>>();
+    tokens::verify(&token, &keys)?;
+    println!("Signature is valid.");
+  }
+  Ok(())
+}
+
+async fn run(cli: Cli) -> Result<(), AdminError> {
+  config::load(cli.config.as_deref())?;
+  match cli.command {
+    Command::Keygen { output } => run_keygen(output),
+    Command::Admins(command) => run_admins(command).await,
+    Command::Invitations(command) => run_invitations(command).await,
+    Command::Apps(command) => run_apps(command).await,
+    Command::Bans(command) => run_bans(command).await,
+    Command::Keys(command) => run_keys(command).await,
+    Command::Mail(command) => run_mail(command).await,
+    Command::Backup { output } => run_backup(output).await,
+    Command::DecodeToken { token, verify } => run_decode_token(token, verify).await,
+  }
+}
+
+#[tokio::main]
+async fn main() {
+  if let Err(e) = run(Cli::parse()).await {
+    eprintln!("{e}");
+    std::process::exit(1);
+  }
+}
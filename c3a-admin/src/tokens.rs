@@ -0,0 +1,108 @@
+//! Offline decoding of MPAAT tokens.
+//!
+//! MPAAT is `payload.signature.header`: the payload is URL-safe base64, the others are standard base64, all of
+//! MessagePack. Encrypted payloads can't be read without the app's key, so only their length is shown.
+
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use c3a_common::{VerificationKey, base64_decode, mpaat_extract_common_fields_with_keys};
+
+use crate::AdminError;
+
+pub(crate) struct DecodedToken {
+  pub(crate) header: serde_json::Value,
+  /// `None` if the payload is encrypted.
+  pub(crate) payload: Option<serde_json::Value>,
+  pub(crate) payload_length: usize,
+}
+
+fn invalid(e: impl std::fmt::Display) -> AdminError {
+  AdminError::Token(e.to_string())
+}
+
+pub(crate) fn decode(token: &str) -> Result<DecodedToken, AdminError> {
+  let parts = token.trim().split('.').collect::<Vec<_>>();
+  if parts.len() != 3 {
+    return Err(invalid("MPAAT should have 3 parts"));
+  }
+
+  let payload = base64_decode(parts[0]).map_err(invalid)?;
+  let header = STANDARD.decode(parts[2]).map_err(invalid)?;
+  Ok(DecodedToken {
+    header: rmp_serde::from_slice(&header).map_err(invalid)?,
+    // Ciphertext may happen to be valid MessagePack, but not a map with `exp`.
+    payload: rmp_serde::from_slice::<serde_json::Value>(&payload)
+      .ok()
+      .filter(|payload| payload.get("exp").is_some()),
+    payload_length: payload.len(),
+  })
+}
+
+/// Checks the signature and that the signing key isn't retired; the expiration isn't checked.
+pub(crate) fn verify(token: &str, keys: &[VerificationKey]) -> Result<(), AdminError> {
+  mpaat_extract_common_fields_with_keys::<serde_json::Value>(token.trim(), keys, chrono::Utc::now())
+    .map(|_| ())
+    .map_err(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+  use c3a_common::{VerificationKey, deploy_mpaat_with_kid, generate_dilithium_keypair};
+  use serde::Serialize;
+
+  #[derive(Serialize)]
+  struct Container {
+    user_id: String,
+  }
+
+  #[derive(Serialize)]
+  struct CommonFields {
+    app_name: String,
+  }
+
+  fn deploy(server_enc: Option<&[u8]>) -> (String, VerificationKey) {
+    let keypair = generate_dilithium_keypair();
+    let token = deploy_mpaat_with_kid(
+      Container {
+        user_id: String::from("alice"),
+      },
+      Some(CommonFields {
+        app_name: String::from("app"),
+      }),
+      chrono::Utc::now() + chrono::TimeDelta::hours(1),
+      &[1, 2, 3],
+      server_enc,
+      &keypair,
+      Some("kid-1"),
+    )
+    .unwrap();
+    let key = VerificationKey {
+      kid: String::from("kid-1"),
+      public: keypair.public.to_vec(),
+      activates_at: chrono::Utc::now(),
+      expires_at: None,
+    };
+    (token, key)
+  }
+
+  #[test]
+  fn test_decode_token() {
+    let (token, key) = deploy(None);
+    let decoded = super::decode(&token).unwrap();
+    assert_eq!(decoded.header["kid"], "kid-1");
+    assert_eq!(decoded.header["app_name"], "app");
+    assert_eq!(decoded.payload.unwrap()["user_id"], "alice");
+    super::verify(&token, &[key]).unwrap();
+
+    let (_, other_key) = deploy(None);
+    assert!(super::verify(&token, &[other_key]).is_err());
+    assert!(super::decode("a.b").is_err());
+  }
+
+  #[test]
+  fn test_encrypted_payload_is_not_decoded() {
+    let (token, _) = deploy(Some(&[7; 32]));
+    let decoded = super::decode(&token).unwrap();
+    assert!(decoded.payload.is_none());
+    assert!(decoded.payload_length > 0);
+  }
+}
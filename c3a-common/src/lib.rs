@@ -7,7 +7,7 @@ mod utils;
 pub use utils::*;
 
 #[cfg(feature = "pqc-utils")]
-pub use pqc_dilithium::Keypair;
+pub use pqc_dilithium::{Keypair, PUBLICKEYBYTES};
 
 pub const SIGN_HEADER: &str = "C3A-Sign";
 pub const PREREGISTER_HEADER: &str = "C3A-Registration-State";
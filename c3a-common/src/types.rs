@@ -23,6 +23,9 @@ pub struct MPAAT<U, T> {
 #[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Clone)]
 pub struct MPAATHeader<U> {
   pub sdpub: Vec<u8>,
+  /// Identifier of C3A signing key, see `VerificationKey`.
+  #[serde(default)]
+  pub kid: Option<String>,
   pub nonce: Vec<u8>,
   #[serde(flatten)]
   pub common_public_fields: Option<U>,
@@ -41,6 +44,34 @@ pub struct MPAATSignature {
   pub sig: Vec<u8>,
 }
 
+/// Public Dilithium5 key of C3A instance.
+///
+/// C3A rotates its signing keys: the new key signs tokens since `activates_at`, and the previous one
+/// stays valid for verification until `expires_at`, so already issued tokens are accepted until they expire.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Clone, Debug)]
+#[cfg_attr(
+  any(feature = "app-server-types", feature = "c3a-worker-types"),
+  derive(salvo::oapi::ToSchema)
+)]
+pub struct VerificationKey {
+  pub kid: String,
+  pub public: Vec<u8>,
+  pub activates_at: chrono::DateTime<chrono::Utc>,
+  pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl VerificationKey {
+  /// Whether tokens signed by the key are accepted at the given moment.
+  pub fn is_valid_at(&self, dt: chrono::DateTime<chrono::Utc>) -> bool {
+    dt >= self.activates_at && !self.is_retired_at(dt)
+  }
+
+  /// Whether the key is retired at the given moment; upcoming keys aren't.
+  pub fn is_retired_at(&self, dt: chrono::DateTime<chrono::Utc>) -> bool {
+    self.expires_at.is_some_and(|expires_at| dt >= expires_at)
+  }
+}
+
 /// Light MessagePack-based Application Authority Token
 #[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Clone)]
 pub struct LightMPAAT<U, T> {
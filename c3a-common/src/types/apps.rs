@@ -2,14 +2,410 @@ use chrono::Duration;
 #[cfg(any(feature = "app-server-types", feature = "c3a-worker-types"))]
 use salvo::oapi::ToSchema;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::net::IpAddr;
 
-use crate::types::users::{IdenticationRequirement, TokenEncryptionType, UserAuthenticationRequirement};
+#[cfg(any(feature = "app-server-types", feature = "c3a-worker-types"))]
+use crate::types::VerificationKey;
+#[cfg(any(feature = "app-server-types", feature = "c3a-worker-types"))]
+use crate::types::users::{HashParams, PasswordPolicyViolation};
+use crate::types::users::{
+  IdenticationRequirement, PasswordPolicy, TokenEncryptionType, UserAuthenticationRequirement,
+};
+
+/// Request of C3A administrator.
+///
+/// The whole request is signed by the admin's Dilithium5 key; the signature is sent in `C3A-Sign` header.
+/// The request is accepted once, only by the method at `path` and only for a few minutes after `issued_at`.
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Clone)]
+pub struct AdminRequest<T> {
+  /// Name of the registered admin.
+  pub admin: String,
+  pub path: String,
+  pub issued_at: chrono::DateTime<chrono::Utc>,
+  /// Random bytes, unique for every request.
+  pub nonce: Vec<u8>,
+  pub body: T,
+}
+
+/// Role of C3A administrator; each role is allowed everything the previous ones are.
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminRole {
+  /// Reads invitations, apps, admins and the state of the instance.
+  Viewer,
+  /// Manages invitations, keys and records, makes backups and seals the instance.
+  Operator,
+  /// Manages admins and restores backups.
+  Owner,
+}
 
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct AdminInfo {
+  pub name: String,
+  pub role: AdminRole,
+  /// Dilithium5 public key.
+  pub public: Vec<u8>,
+  pub added_at: chrono::DateTime<chrono::Utc>,
+  /// `None` for the first admin.
+  pub added_by: Option<String>,
+}
+
+/// Registers the first admin, who becomes an owner; refused once any admin is registered.
+///
+/// Should be signed by the new admin's key, as `AdminRequest` by `name`.
 #[cfg(feature = "c3a-worker-types")]
 #[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone)]
+pub struct BootstrapAdminRequest {
+  pub public: Vec<u8>,
+  /// Proof of knowledge of the instance's `C3A_PRIVATE_ADM_KEY`, see `c3a_common::bootstrap_key_proof`.
+  pub key_proof: Vec<u8>,
+}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone)]
+pub struct AddAdminRequest {
+  pub name: String,
+  pub role: AdminRole,
+  pub public: Vec<u8>,
+}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone)]
+pub struct RemoveAdminRequest {
+  pub name: String,
+}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone)]
+pub struct ListAdminsRequest {}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct ListAdminsResponse {
+  pub admins: Vec<AdminInfo>,
+}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Default)]
 pub struct GenerateInvitationRequest {
-  pub private_admin_key_begin: [u8; 24],
+  /// Name or domain of the only app which may be registered with the invite.
+  pub intended_for: Option<String>,
+  /// Days before the invite expires; 7 by default.
+  pub lifetime_days: Option<u16>,
+}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct GenerateInvitationResponse {
+  pub id: String,
+  /// Invitation code to be passed to the app's author, e.g. `7ZK3M-...`; it's shown only once.
+  pub invite: String,
+  pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone)]
+pub struct MailQueueStatusRequest {}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct MailQueueStatusResponse {
+  /// Messages waiting to be sent, including retried ones.
+  pub pending: usize,
+  /// Pending messages which should be sent right now.
+  pub due: usize,
+  /// Pending messages that failed at least once.
+  pub retrying: usize,
+  pub oldest_pending_created_at: Option<chrono::DateTime<chrono::Utc>>,
+  pub dead_letters: Vec<DeadLetterInfo>,
+}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone)]
+pub struct RequeueDeadLetterRequest {
+  /// Identifier from `MailQueueStatusResponse`.
+  pub id: String,
+}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone)]
+pub struct RemoveDeadLetterRequest {
+  /// Identifier from `MailQueueStatusResponse`.
+  pub id: String,
+}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone)]
+pub struct RotatePepperRequest {}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct RotatePepperResponse {
+  /// Version of the pepper used for all new hashes.
+  pub current_version: u32,
+  /// All versions still accepted for existing hashes.
+  pub known_versions: Vec<u32>,
+}
+
+/// One of Shamir shares of C3A master key.
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone)]
+pub struct UnsealRequest {
+  pub share: Vec<u8>,
+}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct UnsealStatusResponse {
+  pub sealed: bool,
+  /// Shares submitted since the instance was sealed.
+  pub submitted: usize,
+  /// Shares required to unseal; `0` if the instance is unsealed with the master key.
+  pub threshold: u8,
+}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone)]
+pub struct SealRequest {}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone)]
+pub struct MigrateRecordsRequest {}
+
+/// Numbers of records upgraded to the current schema version.
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct MigrateRecordsResponse {
+  pub apps: usize,
+  pub users: usize,
+  pub invitations: usize,
+}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone)]
+pub struct CacheStatsRequest {}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct CacheCounters {
+  pub hits: u64,
+  pub misses: u64,
+}
+
+/// Hits and misses of C3A instance's in-memory cache since the start.
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct CacheStatsResponse {
+  pub signing_keys: CacheCounters,
+  /// Restored signing keys.
+  pub signers: CacheCounters,
+  pub secret_key: CacheCounters,
+  pub apps: CacheCounters,
+}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone)]
+pub struct BackupRequest {}
+
+/// Archive made by `/admin/backup`; the instance is sealed after the restore.
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone)]
+pub struct RestoreRequest {
+  pub archive: Vec<u8>,
+}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct RestoreResponse {
+  pub records: usize,
+  /// Whether the instance waits for shares of the master key at `/unseal`.
+  pub sealed: bool,
+}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone)]
+pub struct ListInvitationsRequest {}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct InvitationUsage {
+  pub app_name: String,
+  pub used_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Invitation without its code; the identifier is a hash of the code, so codes themselves aren't exposed.
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct InvitationInfo {
+  pub id: String,
+  /// Name of the admin who generated the invite.
+  pub created_by: String,
+  pub created_at: chrono::DateTime<chrono::Utc>,
+  pub expires_at: chrono::DateTime<chrono::Utc>,
+  pub intended_for: Option<String>,
+  /// The app registered with the invite, if it's used.
+  pub used: Option<InvitationUsage>,
+}
+
+/// Invitations by creation time, including used and expired ones.
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct ListInvitationsResponse {
+  pub invitations: Vec<InvitationInfo>,
+}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone)]
+pub struct RevokeInvitationRequest {
+  /// Identifier from `ListInvitationsResponse`.
+  pub id: String,
+}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone)]
+pub struct ListAppsRequest {}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct AppSummary {
+  pub app_name: String,
+  pub domain: String,
+  pub author_dpub: Vec<u8>,
+}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct ListAppsResponse {
+  pub apps: Vec<AppSummary>,
+}
+
+/// Who is banned in the app by `Fail2BanOptions`.
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub enum BanSubject {
+  /// User's identifier.
+  Login(String),
+  /// Client's IP address.
+  Ip(String),
+}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct BanInfo {
+  pub app_name: String,
+  pub subject: BanSubject,
+  pub banned_at: chrono::DateTime<chrono::Utc>,
+  pub banned_until: chrono::DateTime<chrono::Utc>,
+}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone)]
+pub struct ListBansRequest {
+  /// Bans of all apps if not set.
+  pub app_name: Option<String>,
+}
+
+/// Active bans by the time they're imposed.
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct ListBansResponse {
+  pub bans: Vec<BanInfo>,
+}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone)]
+pub struct LiftBanRequest {
+  pub app_name: String,
+  pub subject: BanSubject,
+}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone)]
+pub struct RotateSigningKeyRequest {
+  /// Moment since which the new key signs tokens; right now if not set.
+  pub activates_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct RotateSigningKeyResponse {
+  pub kid: String,
+  /// All keys accepted for verification, including the new one.
+  pub keys: Vec<VerificationKey>,
+}
+
+/// C3A verification keys, signed by the active key with `C3A-Sign` header.
+#[cfg(any(feature = "app-server-types", feature = "c3a-worker-types"))]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct SigningKeysAnnouncement {
+  pub issued_at: chrono::DateTime<chrono::Utc>,
+  /// Key which signs new tokens and this announcement.
+  pub active_kid: String,
+  pub keys: Vec<VerificationKey>,
+}
+
+/// C3A key set published at `/.well-known/c3a-keys`.
+///
+/// Every key except the first one is endorsed by the key which was the latest before it, so a client which
+/// has pinned one key can follow all later rotations (see `c3a_common::follow_key_chain`). Retired keys are
+/// dropped from the set, so the pinned key has to be refreshed before it retires.
+#[cfg(any(feature = "app-server-types", feature = "c3a-worker-types"))]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct C3AKeySet {
+  pub keys: Vec<PublishedKey>,
+}
+
+#[cfg(any(feature = "app-server-types", feature = "c3a-worker-types"))]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct PublishedKey {
+  pub key: VerificationKey,
+  pub endorsement: Option<KeyEndorsement>,
+}
+
+/// Signature of `EndorsedKey` by the previous key.
+#[cfg(any(feature = "app-server-types", feature = "c3a-worker-types"))]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct KeyEndorsement {
+  pub previous_kid: String,
+  pub sig: Vec<u8>,
+}
+
+/// Data signed by the previous key on rotation.
+#[cfg(any(feature = "app-server-types", feature = "c3a-worker-types"))]
+#[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct EndorsedKey {
+  pub kid: String,
+  pub public: Vec<u8>,
+  pub activates_at: chrono::DateTime<chrono::Utc>,
+  pub previous_kid: String,
+}
+
+#[cfg(any(feature = "app-server-types", feature = "c3a-worker-types"))]
+impl EndorsedKey {
+  pub fn new(key: &VerificationKey, previous_kid: &str) -> Self {
+    Self {
+      kid: key.kid.to_owned(),
+      public: key.public.to_owned(),
+      activates_at: key.activates_at,
+      previous_kid: previous_kid.to_owned(),
+    }
+  }
+}
+
+/// Message that has not been sent after all allowed attempts.
+#[cfg(feature = "c3a-worker-types")]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct DeadLetterInfo {
+  pub id: String,
+  pub app_name: String,
+  pub recipients: Vec<String>,
+  pub attempts: u32,
+  pub last_error: Option<String>,
+  pub created_at: chrono::DateTime<chrono::Utc>,
 }
 
 #[cfg(any(feature = "app-server-types", feature = "c3a-worker-types"))]
@@ -26,7 +422,7 @@ pub struct SignUpOpts {
   /// Authentication flow will be the same, user may just enter another
   /// predefined data such as second variant' passwords to activate honeypot.
   pub allow_honeypots: bool,
-  /// Ban (permanently or temporarily) on unsuccessful attempts.
+  /// Ban temporarily on unsuccessful attempts.
   pub enable_fail_to_ban: Option<Fail2BanOptions>,
   /// Allows the user to view and save a 256-symbol recovery key once.
   ///
@@ -88,6 +484,14 @@ pub enum AuthenticationRequirement {
     min_size: usize,
     should_contain_different_case: bool,
     should_contain_symbols: bool,
+    #[serde(default)]
+    max_size: Option<usize>,
+    /// Minimal zxcvbn-style strength score, from 0 (too guessable) to 4 (very unguessable).
+    #[serde(default)]
+    min_strength_score: Option<u8>,
+    /// Reject passwords found in the breached-password list loaded by C3A instance.
+    #[serde(default)]
+    reject_breached: bool,
   },
 
   /// Standard TOTP 2FA.
@@ -154,9 +558,32 @@ pub enum AuthenticationRequirement {
 }
 
 impl AuthenticationRequirement {
+  pub fn password_policy(&self) -> Option<PasswordPolicy> {
+    match self {
+      &Self::Password {
+        min_size,
+        should_contain_different_case,
+        should_contain_symbols,
+        max_size,
+        min_strength_score,
+        reject_breached,
+      } => Some(PasswordPolicy {
+        min_size,
+        max_size,
+        should_contain_different_case,
+        should_contain_symbols,
+        min_strength_score,
+        reject_breached,
+      }),
+      _ => None,
+    }
+  }
+
   pub fn generate_user_data(&self) -> UserAuthenticationRequirement {
     match self {
-      Self::Password { .. } => UserAuthenticationRequirement::Password,
+      Self::Password { .. } => UserAuthenticationRequirement::Password {
+        policy: self.password_policy().unwrap(),
+      },
       Self::TOTPCode { .. } => UserAuthenticationRequirement::TOTPCode,
       Self::Question => UserAuthenticationRequirement::Question,
       Self::EmailConfirmation => UserAuthenticationRequirement::EmailConfirmation,
@@ -207,6 +634,7 @@ pub struct Fail2BanOptions {
   pub max_allowed_unsuccessful_attempts: usize,
   pub ban_login_expiration: Duration,
   pub ban_ip: bool,
+  /// `ban_login_expiration` is used if not set.
   pub ban_ip_expiration: Option<Duration>,
 }
 
@@ -215,7 +643,11 @@ pub struct Fail2BanOptions {
 #[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone)]
 pub struct RegisterAppAuthConfigurationRequest {
   pub config: AppAuthConfiguration,
-  pub invite: Vec<u8>,
+  /// Invitation code from C3A instance admin.
+  pub invite: String,
+  /// X25519 public key to receive the application's token encryption key (see `open_token_key`).
+  #[serde(default)]
+  pub key_exchange_public: Option<Vec<u8>>,
 }
 
 #[cfg(any(feature = "app-server-types", feature = "c3a-worker-types"))]
@@ -223,6 +655,49 @@ pub struct RegisterAppAuthConfigurationRequest {
 pub struct RegisterAppAuthConfigurationResponse {
   pub author_dpub: Vec<u8>,
   pub c3a_dpub: Vec<u8>,
+  /// Token encryption key sealed for `key_exchange_public`, if it was provided.
+  #[serde(default)]
+  pub sealed_token_key: Option<SealedTokenKey>,
+}
+
+/// Payload of the user's access token issued by C3A, see `deploy_mpaat`.
+///
+/// The token is bound to the application's author key and its payload is encrypted when the application has
+/// chosen `TokenEncryptionType::ChaCha20Poly1305`.
+#[cfg(any(feature = "app-server-types", feature = "c3a-worker-types"))]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct UserAccessClaims {
+  pub app_name: String,
+  pub identifier: String,
+  pub tags: Vec<AppTag>,
+}
+
+#[cfg(any(feature = "app-server-types", feature = "c3a-worker-types"))]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct RegisterUserResponse {
+  /// Access token, if it's requested with `TokenUsageType::ResponseBody`; otherwise it's set as
+  /// `C3A-Access` cookie.
+  pub access_token: Option<String>,
+  pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Body of `400` response to the registration whose password violates the app's policy.
+#[cfg(any(feature = "app-server-types", feature = "c3a-worker-types"))]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct PasswordPolicyViolationsResponse {
+  pub violations: Vec<PasswordPolicyViolation>,
+}
+
+/// Symmetric key encrypted for the holder of X25519 secret key.
+///
+/// The wrapping key is `HKDF-SHA256(salt = ephemeral_public || recipient_public, IKM = X25519 shared secret,
+/// info = "c3a::token-key-delivery" || 0x00 || app_name)`; the key itself is encrypted with ChaCha20-Poly1305.
+#[cfg_attr(any(feature = "app-server-types", feature = "c3a-worker-types"), derive(ToSchema))]
+#[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct SealedTokenKey {
+  pub ephemeral_public: Vec<u8>,
+  pub nonce: Vec<u8>,
+  pub ciphertext: Vec<u8>,
 }
 
 #[cfg(any(feature = "app-server-types", feature = "c3a-worker-types"))]
@@ -238,6 +713,61 @@ pub struct AppAuthConfiguration {
   /// and `mode5` feature enabled, if you want to dynamically change
   /// the list of tags.
   pub author_dpub: Vec<u8>,
+
+  /// Emails sent on behalf of the application. C3A defaults are used when not set.
+  #[serde(default)]
+  pub email_templates: Option<EmailTemplates>,
+
+  /// Argon2 costs for the application's users' secrets. C3A instance's costs are used when not set.
+  #[serde(default)]
+  pub hash_params: Option<HashParams>,
+}
+
+/// Localized email templates of the application.
+///
+/// Subjects and bodies may contain these placeholders:
+/// 1. `{{code}}` - confirmation or recovery code (empty for login alerts).
+/// 2. `{{app_name}}` - with `app_name`.
+/// 3. `{{identifier}}` - with user's identifier.
+/// 4. `{{expires_in_minutes}}` - with code lifetime in minutes.
+#[cfg(any(feature = "app-server-types", feature = "c3a-worker-types"))]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct EmailTemplates {
+  /// Sender mailbox, e.g. `My App <no-reply@my-app.com>`.
+  ///
+  /// The sender's domain must be the app's `domain`, its subdomain or a domain allowed by C3A instance;
+  /// otherwise the app's registration or edit is refused.
+  pub sender: Option<String>,
+  /// Locale to use when the user's locale is unknown or has no templates.
+  pub default_locale: String,
+  /// Templates by locale (e.g. `en`, `ru`, `pt-BR`).
+  pub locales: BTreeMap<String, LocalizedEmailTemplates>,
+}
+
+/// Templates for every mail kind. C3A default template is used for the kind when it's not set.
+///
+/// Only `confirmation` is sent for now; other templates are stored, and will be used once the worker gets
+/// password reset, sign-in alerts and account recovery.
+#[cfg(any(feature = "app-server-types", feature = "c3a-worker-types"))]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug, Default)]
+pub struct LocalizedEmailTemplates {
+  /// Sign-up confirmation code.
+  pub confirmation: Option<EmailTemplate>,
+  /// Reserved, not sent yet.
+  pub password_reset: Option<EmailTemplate>,
+  /// Reserved, not sent yet.
+  pub login_alert: Option<EmailTemplate>,
+  /// Reserved, not sent yet.
+  pub recovery: Option<EmailTemplate>,
+}
+
+#[cfg(any(feature = "app-server-types", feature = "c3a-worker-types"))]
+#[derive(Deserialize, Serialize, ToSchema, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct EmailTemplate {
+  pub subject: String,
+  pub text: String,
+  /// Optional HTML alternative; placeholders' values are HTML-escaped here.
+  pub html: Option<String>,
 }
 
 #[cfg(any(feature = "app-server-types", feature = "c3a-worker-types"))]
@@ -266,4 +796,6 @@ pub struct EditAppAuthConfigurationRequest {
   pub allowed_tags: Option<Vec<AppTag>>,
   pub allow_sign_up: Option<SignUpOpts>,
   pub client_based_auth_opts: Option<ClientBasedAuthorizationOpts>,
+  pub email_templates: Option<EmailTemplates>,
+  pub hash_params: Option<HashParams>,
 }
@@ -13,7 +13,38 @@ pub use crate::types::users::ids::*;
 pub enum AuthenticationData {
   TOTP { alg: String, generated_secret: String },
   U2F { challenge: u2f::protocol::Challenge },
-  Email { salt: String, hash: Vec<u8> },
+  Email {
+    salt: String,
+    hash: Vec<u8>,
+    #[serde(default)]
+    params: HashParams,
+    #[serde(default)]
+    pepper_version: u32,
+  },
+}
+
+/// Argon2id cost parameters the hash was computed with.
+///
+/// Hashes stored before the parameters were recorded use Argon2 defaults.
+#[cfg_attr(any(feature = "app-server-types", feature = "c3a-worker-types"), derive(ToSchema))]
+#[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct HashParams {
+  /// Memory cost in KiB.
+  pub m_cost: u32,
+  /// Number of iterations.
+  pub t_cost: u32,
+  /// Degree of parallelism.
+  pub p_cost: u32,
+}
+
+impl Default for HashParams {
+  fn default() -> Self {
+    Self {
+      m_cost: 19 * 1024,
+      t_cost: 2,
+      p_cost: 1,
+    }
+  }
 }
 
 #[cfg_attr(any(feature = "app-server-types", feature = "c3a-worker-types"), derive(ToSchema))]
@@ -28,7 +59,9 @@ pub struct RegistrationRequirementsResponse {
 #[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Clone)]
 #[serde(rename_all = "snake_case", tag = "type")]
 pub enum UserAuthenticationRequirement {
-  Password,
+  Password {
+    policy: PasswordPolicy,
+  },
   TOTPCode,
   Question,
   EmailConfirmation,
@@ -39,6 +72,78 @@ pub enum UserAuthenticationRequirement {
   Other { description: String },
 }
 
+/// Password rules of the application.
+///
+/// Length, letter case and symbols rules can be checked on the client side with `PasswordPolicy::check`;
+/// strength score and breached-password list are checked only by C3A.
+#[cfg_attr(any(feature = "app-server-types", feature = "c3a-worker-types"), derive(ToSchema))]
+#[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct PasswordPolicy {
+  pub min_size: usize,
+  pub max_size: Option<usize>,
+  pub should_contain_different_case: bool,
+  pub should_contain_symbols: bool,
+  /// Minimal zxcvbn-style strength score, from 0 (too guessable) to 4 (very unguessable).
+  pub min_strength_score: Option<u8>,
+  pub reject_breached: bool,
+}
+
+impl PasswordPolicy {
+  /// Longest password accepted by C3A whatever `max_size` is.
+  pub const MAX_LENGTH: usize = 256;
+
+  /// `max_size`, capped by `PasswordPolicy::MAX_LENGTH`.
+  pub fn effective_max_size(&self) -> usize {
+    self.max_size.map_or(Self::MAX_LENGTH, |max_size| max_size.min(Self::MAX_LENGTH))
+  }
+
+  /// Checks length, letter case and symbols rules.
+  pub fn check(&self, password: &str) -> Vec<PasswordPolicyViolation> {
+    let mut violations = vec![];
+    let length = password.chars().count();
+
+    if length < self.min_size {
+      violations.push(PasswordPolicyViolation::TooShort { min_size: self.min_size });
+    }
+    let max_size = self.effective_max_size();
+    if length > max_size {
+      violations.push(PasswordPolicyViolation::TooLong { max_size });
+    }
+    if self.should_contain_different_case
+      && !(password.chars().any(|c| c.is_lowercase()) && password.chars().any(|c| c.is_uppercase()))
+    {
+      violations.push(PasswordPolicyViolation::NoDifferentCase);
+    }
+    if self.should_contain_symbols && !password.chars().any(|c| !c.is_alphanumeric()) {
+      violations.push(PasswordPolicyViolation::NoSymbols);
+    }
+
+    violations
+  }
+}
+
+#[cfg_attr(any(feature = "app-server-types", feature = "c3a-worker-types"), derive(ToSchema))]
+#[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Clone, Debug, thiserror::Error)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum PasswordPolicyViolation {
+  #[error("Password should contain at least {min_size} characters")]
+  TooShort { min_size: usize },
+  #[error("Password should contain at most {max_size} characters")]
+  TooLong { max_size: usize },
+  #[error("Password should contain both lowercase and uppercase letters")]
+  NoDifferentCase,
+  #[error("Password should contain symbols")]
+  NoSymbols,
+  #[error("Password is too weak")]
+  TooWeak {
+    score: u8,
+    min_score: u8,
+    suggestions: Vec<String>,
+  },
+  #[error("Password is found in the list of breached passwords")]
+  Breached,
+}
+
 #[cfg_attr(any(feature = "app-server-types", feature = "c3a-worker-types"), derive(ToSchema))]
 #[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Clone)]
 pub struct RegisterUserRequest {
@@ -48,6 +153,15 @@ pub struct RegisterUserRequest {
   pub token_request_type: TokenUsageType,
 }
 
+/// Checks the user's password or answer to a question, e.g. on the application's sign-in form.
+#[cfg_attr(any(feature = "app-server-types", feature = "c3a-worker-types"), derive(ToSchema))]
+#[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Clone)]
+pub struct VerifyUserSecretRequest {
+  pub app_name: String,
+  pub login: String,
+  pub secret: AuthenticationStepRequest,
+}
+
 #[cfg_attr(any(feature = "app-server-types", feature = "c3a-worker-types"), derive(ToSchema))]
 #[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Clone)]
 #[serde(rename_all = "snake_case", tag = "type")]
@@ -61,6 +175,14 @@ pub enum TokenUsageType {
 #[serde(rename_all = "snake_case", tag = "type")]
 pub enum TokenEncryptionType {
   None,
+  /// Token payloads are encrypted with the application's 32-byte key derived by C3A instance:
+  ///
+  /// ```text
+  /// HKDF-SHA256(salt = "c3a-worker::hkdf::v1", IKM = main secret,
+  ///             info = "c3a-worker::token-encryption" || 0x00 || app_name)
+  /// ```
+  ///
+  /// Only this key is shared with the application server, never the main secret.
   ChaCha20Poly1305,
 }
 
@@ -97,6 +219,11 @@ pub enum AuthenticationStep {
   Password {
     salt: Vec<u8>,
     hash: Vec<u8>,
+    #[serde(default)]
+    params: HashParams,
+    /// Version of C3A pepper the hash was computed with.
+    #[serde(default)]
+    pepper_version: u32,
   },
   TOTPCode {
     secret: String,
@@ -105,6 +232,10 @@ pub enum AuthenticationStep {
     question: String,
     salt: Vec<u8>,
     hash: Vec<u8>,
+    #[serde(default)]
+    params: HashParams,
+    #[serde(default)]
+    pepper_version: u32,
   },
   EmailConfirmation,
   Proxy,
@@ -1,6 +1,9 @@
 use crate::{
   Email, EmailError, IdenticationRequirement,
-  types::{LightMPAATHeader, LightMPAATPayload, LightMPAATSignature, MPAATHeader, MPAATPayload, MPAATSignature},
+  types::{
+    LightMPAATHeader, LightMPAATPayload, LightMPAATSignature, MPAATHeader, MPAATPayload, MPAATSignature,
+    VerificationKey,
+  },
 };
 use thiserror::Error;
 
@@ -19,6 +22,8 @@ pub fn restore_from(public: &[u8], secret: &[u8]) -> Result<pqc_dilithium::Keypa
 pub enum SignError {
   #[error("Serialize error")]
   Serialize(#[from] rmp_serde::encode::Error),
+  #[error("Signer error: {0}")]
+  Signer(String),
 }
 
 #[cfg(feature = "pqc-utils")]
@@ -145,6 +150,22 @@ pub fn generate<const SZ: usize>() -> [u8; SZ] {
   arr
 }
 
+/// Proves knowledge of `C3A_PRIVATE_ADM_KEY` without sending it: HMAC-SHA256 under the key over MessagePack of
+/// the bootstrap request with an empty `key_proof`. The proof is bound to the request's nonce, so it's accepted once.
+#[cfg(all(feature = "crypt-utils", feature = "c3a-worker-types"))]
+pub fn bootstrap_key_proof(
+  private_adm_key: &[u8],
+  request: &crate::AdminRequest<crate::BootstrapAdminRequest>,
+) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+  use hmac::{Hmac, Mac};
+
+  let mut unproven = request.clone();
+  unproven.body.key_proof = vec![];
+  let mut mac = Hmac::<sha2::Sha256>::new_from_slice(private_adm_key).expect("HMAC accepts keys of any length");
+  mac.update(&rmp_serde::to_vec(&unproven)?);
+  Ok(mac.finalize().into_bytes().to_vec())
+}
+
 #[derive(Error, Debug)]
 pub enum EncryptError {
   #[error("Serialize error")]
@@ -163,6 +184,9 @@ pub fn encrypt_chacha20poly1305(
     consts::U32,
   };
 
+  if key.len() != 32 {
+    return Err(EncryptError::Encrypt);
+  }
   let serialized = rmp_serde::to_vec(message).map_err(EncryptError::Serialize)?;
   let key = GenericArray::<u8, U32>::from_slice(key);
   let cipher = ChaCha20Poly1305::new(key);
@@ -192,6 +216,10 @@ pub fn decrypt_chacha20poly1305<T: serde::de::DeserializeOwned>(
     consts::{U12, U32},
   };
 
+  // `from_slice` panics on wrong lengths, and both values may come from the token.
+  if key.len() != 32 || nonce.len() != 12 {
+    return Err(DecryptError::Decrypt);
+  }
   let key = GenericArray::<u8, U32>::from_slice(key);
   let cipher = ChaCha20Poly1305::new(key);
   let nonce = GenericArray::<u8, U12>::from_slice(nonce);
@@ -200,6 +228,127 @@ pub fn decrypt_chacha20poly1305<T: serde::de::DeserializeOwned>(
   Ok(deserialized)
 }
 
+#[cfg(feature = "crypt-utils")]
+const TOKEN_KEY_DELIVERY_INFO: &[u8] = b"c3a::token-key-delivery";
+
+#[cfg(feature = "crypt-utils")]
+#[derive(Error, Debug)]
+pub enum KeyDeliveryError {
+  #[error("Invalid X25519 public key")]
+  InvalidPublicKey,
+  #[error("Encrypt error")]
+  Encrypt,
+  #[error("Decrypt error")]
+  Decrypt,
+}
+
+/// Generates X25519 keypair to receive the token encryption key; returns secret and public keys.
+#[cfg(feature = "crypt-utils")]
+pub fn generate_x25519_keypair() -> ([u8; 32], [u8; 32]) {
+  let secret = x25519_dalek::StaticSecret::from(generate::<32>());
+  let public = x25519_dalek::PublicKey::from(&secret);
+  (secret.to_bytes(), public.to_bytes())
+}
+
+#[cfg(feature = "crypt-utils")]
+fn token_key_wrapping_key(
+  shared: &x25519_dalek::SharedSecret,
+  ephemeral_public: &[u8; 32],
+  recipient_public: &[u8; 32],
+  app_name: &str,
+) -> Result<[u8; 32], KeyDeliveryError> {
+  if !shared.was_contributory() {
+    return Err(KeyDeliveryError::InvalidPublicKey);
+  }
+
+  let mut salt = ephemeral_public.to_vec();
+  salt.extend_from_slice(recipient_public);
+
+  let mut key = [0u8; 32];
+  hkdf::Hkdf::<sha2::Sha256>::new(Some(&salt), shared.as_bytes())
+    .expand_multi_info(&[TOKEN_KEY_DELIVERY_INFO, &[0], app_name.as_bytes()], &mut key)
+    .map_err(|_| KeyDeliveryError::Encrypt)?;
+  Ok(key)
+}
+
+/// Encrypts the application's token encryption key for the holder of `recipient_public` X25519 key.
+#[cfg(feature = "crypt-utils")]
+pub fn seal_token_key(
+  token_key: &[u8],
+  recipient_public: &[u8],
+  app_name: &str,
+) -> Result<crate::SealedTokenKey, KeyDeliveryError> {
+  use chacha20poly1305::{
+    ChaCha20Poly1305,
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+  };
+
+  let recipient_public: [u8; 32] = recipient_public
+    .try_into()
+    .map_err(|_| KeyDeliveryError::InvalidPublicKey)?;
+  let ephemeral = x25519_dalek::StaticSecret::from(generate::<32>());
+  let ephemeral_public = x25519_dalek::PublicKey::from(&ephemeral).to_bytes();
+  let shared = ephemeral.diffie_hellman(&x25519_dalek::PublicKey::from(recipient_public));
+
+  let key = token_key_wrapping_key(&shared, &ephemeral_public, &recipient_public, app_name)?;
+  let cipher = ChaCha20Poly1305::new(&key.into());
+  let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+  let ciphertext = cipher
+    .encrypt(
+      &nonce,
+      Payload {
+        msg: token_key,
+        aad: app_name.as_bytes(),
+      },
+    )
+    .map_err(|_| KeyDeliveryError::Encrypt)?;
+
+  Ok(crate::SealedTokenKey {
+    ephemeral_public: ephemeral_public.to_vec(),
+    nonce: nonce.to_vec(),
+    ciphertext,
+  })
+}
+
+/// Decrypts the token encryption key received from C3A instance on the application registration.
+#[cfg(feature = "crypt-utils")]
+pub fn open_token_key(
+  sealed: &crate::SealedTokenKey,
+  recipient_secret: &[u8; 32],
+  app_name: &str,
+) -> Result<Vec<u8>, KeyDeliveryError> {
+  use chacha20poly1305::{
+    ChaCha20Poly1305,
+    aead::{Aead, KeyInit, Payload, generic_array::GenericArray},
+    consts::U12,
+  };
+
+  let ephemeral_public: [u8; 32] = sealed
+    .ephemeral_public
+    .as_slice()
+    .try_into()
+    .map_err(|_| KeyDeliveryError::InvalidPublicKey)?;
+  if sealed.nonce.len() != 12 {
+    return Err(KeyDeliveryError::Decrypt);
+  }
+
+  let secret = x25519_dalek::StaticSecret::from(*recipient_secret);
+  let recipient_public = x25519_dalek::PublicKey::from(&secret).to_bytes();
+  let shared = secret.diffie_hellman(&x25519_dalek::PublicKey::from(ephemeral_public));
+
+  let key = token_key_wrapping_key(&shared, &ephemeral_public, &recipient_public, app_name)?;
+  let cipher = ChaCha20Poly1305::new(&key.into());
+  cipher
+    .decrypt(
+      GenericArray::<u8, U12>::from_slice(&sealed.nonce),
+      Payload {
+        msg: &sealed.ciphertext,
+        aad: app_name.as_bytes(),
+      },
+    )
+    .map_err(|_| KeyDeliveryError::Decrypt)
+}
+
 #[cfg(feature = "pqc-utils")]
 #[derive(Error, Debug)]
 pub enum DeployError {
@@ -219,6 +368,48 @@ pub fn deploy_mpaat<U: serde::Serialize, T: serde::Serialize>(
   client_public: &[u8],
   server_enc: Option<&[u8]>,
   server_keys: &pqc_dilithium::Keypair,
+) -> Result<String, DeployError> {
+  deploy_mpaat_with_kid(payload, common_fields, exp, client_public, server_enc, server_keys, None)
+}
+
+/// Deploys MPAAT with the identifier of the signing key in the header (see `VerificationKey`).
+#[cfg(feature = "pqc-utils")]
+pub fn deploy_mpaat_with_kid<U: serde::Serialize, T: serde::Serialize>(
+  payload: T,
+  common_fields: Option<U>,
+  exp: chrono::DateTime<chrono::Utc>,
+  client_public: &[u8],
+  server_enc: Option<&[u8]>,
+  server_keys: &pqc_dilithium::Keypair,
+  kid: Option<&str>,
+) -> Result<String, DeployError> {
+  deploy_mpaat_with_signer(
+    payload,
+    common_fields,
+    exp,
+    client_public,
+    server_enc,
+    &server_keys.public,
+    kid,
+    |data| Ok(server_keys.sign(data).to_vec()),
+  )
+}
+
+/// Deploys MPAAT signed by `sign`, e.g. a signer restored once and reused for many tokens.
+///
+/// `sign` gets the header followed by the transferred payload and should return Dilithium5 signature made by
+/// the key `server_public`.
+#[cfg(feature = "pqc-utils")]
+#[allow(clippy::too_many_arguments)]
+pub fn deploy_mpaat_with_signer<U: serde::Serialize, T: serde::Serialize>(
+  payload: T,
+  common_fields: Option<U>,
+  exp: chrono::DateTime<chrono::Utc>,
+  client_public: &[u8],
+  server_enc: Option<&[u8]>,
+  server_public: &[u8],
+  kid: Option<&str>,
+  sign: impl FnOnce(&[u8]) -> Result<Vec<u8>, SignError>,
 ) -> Result<String, DeployError> {
   use base64::{
     Engine as _,
@@ -236,20 +427,23 @@ pub fn deploy_mpaat<U: serde::Serialize, T: serde::Serialize>(
     (rmp_serde::to_vec(&payload).map_err(EncryptError::Serialize)?, vec![])
   };
 
-  let enc_payload = URL_SAFE.encode(&enc_payload);
-
   let header = MPAATHeader {
-    sdpub: server_keys.public.to_vec(),
+    sdpub: server_public.to_vec(),
+    kid: kid.map(str::to_owned),
     nonce,
     common_public_fields: common_fields,
   };
+  let header = rmp_serde::to_vec(&header).map_err(DeployError::Serialize)?;
 
-  let sig = MPAATSignature {
-    sig: sign_mpaat(&header, &payload, server_keys).map_err(DeployError::Sign)?,
-  };
+  // The signature covers the payload exactly as it is transferred (i.e. the ciphertext
+  // when encryption is enabled), so it can be verified before any decryption happens.
+  let mut data = header.clone();
+  data.extend_from_slice(&enc_payload);
+  let sig = MPAATSignature { sig: sign(&data)? };
   let sig = STANDARD.encode(rmp_serde::to_vec(&sig).map_err(DeployError::Serialize)?);
 
-  let header = STANDARD.encode(rmp_serde::to_vec(&header).map_err(DeployError::Serialize)?);
+  let enc_payload = URL_SAFE.encode(&enc_payload);
+  let header = STANDARD.encode(&header);
 
   Ok(format!("{}.{}.{}", enc_payload, sig, header))
 }
@@ -306,14 +500,20 @@ pub enum ExtractError {
   InvalidSignature,
   #[error("Invalid server public key error")]
   InvalidServerPublicKey,
+  #[error("Retired server key error")]
+  RetiredServerKey,
+  #[error("Inactive server key error")]
+  InactiveServerKey,
   #[error("Expired error")]
   Expired,
 }
 
-pub fn mpaat_extract_common_fields<U: serde::de::DeserializeOwned>(
+/// Decodes MPAAT and verifies its signature with the key chosen by `server_public`.
+#[cfg(feature = "pqc-utils")]
+fn mpaat_open<U: serde::de::DeserializeOwned>(
   token: &str,
-  server_keys: &pqc_dilithium::Keypair,
-) -> Result<Option<U>, ExtractError> {
+  server_public: impl FnOnce(&MPAATHeader<U>) -> Result<Vec<u8>, ExtractError>,
+) -> Result<(MPAATHeader<U>, Vec<u8>), ExtractError> {
   use base64::{
     Engine as _,
     engine::general_purpose::{STANDARD, URL_SAFE},
@@ -328,62 +528,55 @@ pub fn mpaat_extract_common_fields<U: serde::de::DeserializeOwned>(
   let payload = parts.first().ok_or(ExtractError::InvalidToken)?;
   let payload = URL_SAFE.decode(payload).map_err(ExtractError::Decode)?;
 
-  let header = parts.get(2).ok_or(ExtractError::InvalidToken)?;
-  let header = STANDARD.decode(header).map_err(ExtractError::Decode)?;
+  let raw_header = parts.get(2).ok_or(ExtractError::InvalidToken)?;
+  let raw_header = STANDARD.decode(raw_header).map_err(ExtractError::Decode)?;
+  let header = rmp_serde::from_slice::<MPAATHeader<U>>(&raw_header).map_err(ExtractError::Deserialize)?;
 
-  if !verify_token(&header, &payload, &sig.sig, &server_keys.public).map_err(ExtractError::Verify)? {
+  let server_public = server_public(&header)?;
+  if !verify_token(&raw_header, &payload, &sig.sig, &server_public).map_err(ExtractError::Verify)? {
     return Err(ExtractError::InvalidSignature);
   }
-
-  let header = rmp_serde::from_slice::<MPAATHeader<U>>(&header).map_err(ExtractError::Deserialize)?;
-  if header.sdpub != server_keys.public {
+  if header.sdpub != server_public {
     return Err(ExtractError::InvalidServerPublicKey);
   }
 
-  Ok(header.common_public_fields)
+  Ok((header, payload))
 }
 
+/// Finds the key which has signed the token by its `kid` (or by the public key for tokens without `kid`)
+/// and checks that the key isn't retired.
 #[cfg(feature = "pqc-utils")]
-pub fn mpaat_extract_payload<T, U>(
-  token: &str,
-  server_enc: Option<&[u8]>,
-  server_keys: &pqc_dilithium::Keypair,
+fn find_verification_key<U>(
+  header: &MPAATHeader<U>,
+  keys: &[VerificationKey],
   current_dt: chrono::DateTime<chrono::Utc>,
-) -> Result<T, ExtractError>
-where
-  T: serde::de::DeserializeOwned,
-  U: serde::de::DeserializeOwned,
-{
-  use base64::{
-    Engine as _,
-    engine::general_purpose::{STANDARD, URL_SAFE},
-  };
-
-  let parts = token.split('.').collect::<Vec<_>>();
-
-  let sig = parts.get(1).ok_or(ExtractError::InvalidToken)?;
-  let sig = STANDARD.decode(sig).map_err(ExtractError::Decode)?;
-  let sig = rmp_serde::from_slice::<MPAATSignature>(&sig).map_err(ExtractError::Deserialize)?;
-
-  let payload = parts.first().ok_or(ExtractError::InvalidToken)?;
-  let payload = URL_SAFE.decode(payload).map_err(ExtractError::Decode)?;
-
-  let header = parts.get(2).ok_or(ExtractError::InvalidToken)?;
-  let header = STANDARD.decode(header).map_err(ExtractError::Decode)?;
-
-  if !verify_token(&header, &payload, &sig.sig, &server_keys.public).map_err(ExtractError::Verify)? {
-    return Err(ExtractError::InvalidSignature);
+) -> Result<Vec<u8>, ExtractError> {
+  let key = match &header.kid {
+    Some(kid) => keys.iter().find(|key| key.kid.eq(kid)),
+    None => keys.iter().find(|key| key.public == header.sdpub),
   }
+  .ok_or(ExtractError::InvalidServerPublicKey)?;
 
-  let header = rmp_serde::from_slice::<MPAATHeader<U>>(&header).map_err(ExtractError::Deserialize)?;
-  if header.sdpub != server_keys.public {
-    return Err(ExtractError::InvalidServerPublicKey);
+  if key.is_retired_at(current_dt) {
+    return Err(ExtractError::RetiredServerKey);
   }
+  if !key.is_valid_at(current_dt) {
+    return Err(ExtractError::InactiveServerKey);
+  }
+  Ok(key.public.clone())
+}
 
+#[cfg(feature = "pqc-utils")]
+fn mpaat_read_payload<T: serde::de::DeserializeOwned, U>(
+  header: &MPAATHeader<U>,
+  payload: &[u8],
+  server_enc: Option<&[u8]>,
+  current_dt: chrono::DateTime<chrono::Utc>,
+) -> Result<T, ExtractError> {
   let payload = if let Some(server_enc) = server_enc {
-    decrypt_chacha20poly1305::<MPAATPayload<T>>(&payload, &header.nonce, server_enc).map_err(ExtractError::Decrypt)?
+    decrypt_chacha20poly1305::<MPAATPayload<T>>(payload, server_enc, &header.nonce).map_err(ExtractError::Decrypt)?
   } else {
-    rmp_serde::from_slice::<MPAATPayload<T>>(&payload).map_err(ExtractError::Deserialize)?
+    rmp_serde::from_slice::<MPAATPayload<T>>(payload).map_err(ExtractError::Deserialize)?
   };
 
   if current_dt >= payload.exp {
@@ -393,6 +586,57 @@ where
   Ok(payload.container)
 }
 
+#[cfg(feature = "pqc-utils")]
+pub fn mpaat_extract_common_fields<U: serde::de::DeserializeOwned>(
+  token: &str,
+  server_keys: &pqc_dilithium::Keypair,
+) -> Result<Option<U>, ExtractError> {
+  let (header, _) = mpaat_open::<U>(token, |_| Ok(server_keys.public.to_vec()))?;
+  Ok(header.common_public_fields)
+}
+
+/// Same as `mpaat_extract_common_fields`, but accepts tokens signed by any of C3A verification keys.
+#[cfg(feature = "pqc-utils")]
+pub fn mpaat_extract_common_fields_with_keys<U: serde::de::DeserializeOwned>(
+  token: &str,
+  keys: &[VerificationKey],
+  current_dt: chrono::DateTime<chrono::Utc>,
+) -> Result<Option<U>, ExtractError> {
+  let (header, _) = mpaat_open::<U>(token, |header| find_verification_key(header, keys, current_dt))?;
+  Ok(header.common_public_fields)
+}
+
+#[cfg(feature = "pqc-utils")]
+pub fn mpaat_extract_payload<T, U>(
+  token: &str,
+  server_enc: Option<&[u8]>,
+  server_keys: &pqc_dilithium::Keypair,
+  current_dt: chrono::DateTime<chrono::Utc>,
+) -> Result<T, ExtractError>
+where
+  T: serde::de::DeserializeOwned,
+  U: serde::de::DeserializeOwned,
+{
+  let (header, payload) = mpaat_open::<U>(token, |_| Ok(server_keys.public.to_vec()))?;
+  mpaat_read_payload(&header, &payload, server_enc, current_dt)
+}
+
+/// Same as `mpaat_extract_payload`, but accepts tokens signed by any of C3A verification keys.
+#[cfg(feature = "pqc-utils")]
+pub fn mpaat_extract_payload_with_keys<T, U>(
+  token: &str,
+  server_enc: Option<&[u8]>,
+  keys: &[VerificationKey],
+  current_dt: chrono::DateTime<chrono::Utc>,
+) -> Result<T, ExtractError>
+where
+  T: serde::de::DeserializeOwned,
+  U: serde::de::DeserializeOwned,
+{
+  let (header, payload) = mpaat_open::<U>(token, |header| find_verification_key(header, keys, current_dt))?;
+  mpaat_read_payload(&header, &payload, server_enc, current_dt)
+}
+
 pub fn lmpaat_extract_common_fields<U: serde::de::DeserializeOwned>(
   token: &str,
   server_keys: &pqc_dilithium::Keypair,
@@ -460,3 +704,285 @@ where
 
   Ok(payload.container)
 }
+
+#[cfg(all(feature = "pqc-utils", any(feature = "app-server-types", feature = "c3a-worker-types")))]
+#[derive(Error, Debug)]
+pub enum KeyChainError {
+  /// The pinned key has retired and was pruned, or never belonged to the instance.
+  #[error("Pinned key is not published")]
+  UnknownPinnedKey,
+  #[error("Verify error")]
+  Verify(#[from] VerifyError),
+  #[error("Invalid endorsement of key `{0}`")]
+  InvalidEndorsement(String),
+}
+
+/// Follows C3A key rotations starting from the pinned public key.
+///
+/// Returns the pinned key and all keys endorsed by already trusted ones; keys without valid endorsement
+/// chain to the pinned key are not returned.
+#[cfg(all(feature = "pqc-utils", any(feature = "app-server-types", feature = "c3a-worker-types")))]
+pub fn follow_key_chain(
+  pinned_public: &[u8],
+  key_set: &crate::C3AKeySet,
+) -> Result<Vec<VerificationKey>, KeyChainError> {
+  let pinned = key_set
+    .keys
+    .iter()
+    .find(|published| published.key.public == pinned_public)
+    .ok_or(KeyChainError::UnknownPinnedKey)?;
+  let mut trusted = vec![pinned.key.clone()];
+
+  loop {
+    let mut endorsed = None;
+    for published in &key_set.keys {
+      if trusted.iter().any(|key| key.kid == published.key.kid) {
+        continue;
+      }
+      let Some(endorsement) = &published.endorsement else {
+        continue;
+      };
+      let Some(previous) = trusted.iter().find(|key| key.kid == endorsement.previous_kid) else {
+        continue;
+      };
+
+      let data = crate::EndorsedKey::new(&published.key, &previous.kid);
+      if !verify(&data, &endorsement.sig, &previous.public)? {
+        return Err(KeyChainError::InvalidEndorsement(published.key.kid.to_owned()));
+      }
+      endorsed = Some(published.key.clone());
+      break;
+    }
+
+    match endorsed {
+      Some(key) => trusted.push(key),
+      None => return Ok(trusted),
+    }
+  }
+}
+
+#[cfg(all(test, feature = "pqc-utils", feature = "crypt-utils"))]
+mod tests {
+  use super::{
+    ExtractError, KeyDeliveryError, decrypt_chacha20poly1305, deploy_mpaat, deploy_mpaat_with_kid,
+    encrypt_chacha20poly1305, generate, generate_dilithium_keypair, generate_x25519_keypair,
+    mpaat_extract_common_fields, mpaat_extract_common_fields_with_keys, mpaat_extract_payload,
+    mpaat_extract_payload_with_keys, open_token_key, seal_token_key,
+  };
+  use crate::VerificationKey;
+  use serde::{Deserialize, Serialize};
+
+  #[derive(Deserialize, Serialize, PartialEq, Debug)]
+  struct Payload {
+    user_id: String,
+    tags: Vec<String>,
+  }
+
+  #[derive(Deserialize, Serialize, PartialEq, Debug)]
+  struct Common {
+    app_name: String,
+  }
+
+  fn payload() -> Payload {
+    Payload {
+      user_id: String::from("user-1"),
+      tags: vec![String::from("user:read")],
+    }
+  }
+
+  fn deploy(server_enc: Option<&[u8]>, keypair: &pqc_dilithium::Keypair) -> String {
+    deploy_mpaat(
+      payload(),
+      Some(Common {
+        app_name: String::from("test-app"),
+      }),
+      chrono::Utc::now() + chrono::TimeDelta::minutes(5),
+      &generate_dilithium_keypair().public,
+      server_enc,
+      keypair,
+    )
+    .unwrap()
+  }
+
+  #[test]
+  fn test_mpaat_round_trip() {
+    let keypair = generate_dilithium_keypair();
+    let token = deploy(None, &keypair);
+
+    let extracted = mpaat_extract_payload::<Payload, Common>(&token, None, &keypair, chrono::Utc::now()).unwrap();
+    assert_eq!(extracted, payload());
+    assert_eq!(
+      mpaat_extract_common_fields::<Common>(&token, &keypair).unwrap().unwrap().app_name,
+      "test-app"
+    );
+  }
+
+  #[test]
+  fn test_encrypted_mpaat_round_trip() {
+    let keypair = generate_dilithium_keypair();
+    let key = generate::<32>();
+    let token = deploy(Some(&key), &keypair);
+
+    // The payload is not readable without the key.
+    assert!(mpaat_extract_payload::<Payload, Common>(&token, None, &keypair, chrono::Utc::now()).is_err());
+    assert!(matches!(
+      mpaat_extract_payload::<Payload, Common>(&token, Some(&generate::<32>()), &keypair, chrono::Utc::now()),
+      Err(ExtractError::Decrypt(_))
+    ));
+
+    let extracted = mpaat_extract_payload::<Payload, Common>(&token, Some(&key), &keypair, chrono::Utc::now()).unwrap();
+    assert_eq!(extracted, payload());
+    // Common fields stay public.
+    assert!(mpaat_extract_common_fields::<Common>(&token, &keypair).unwrap().is_some());
+  }
+
+  #[test]
+  fn test_encrypted_mpaat_is_checked() {
+    let keypair = generate_dilithium_keypair();
+    let key = generate::<32>();
+    let token = deploy(Some(&key), &keypair);
+
+    let expired = chrono::Utc::now() + chrono::TimeDelta::minutes(10);
+    assert!(matches!(
+      mpaat_extract_payload::<Payload, Common>(&token, Some(&key), &keypair, expired),
+      Err(ExtractError::Expired)
+    ));
+
+    let other = generate_dilithium_keypair();
+    assert!(matches!(
+      mpaat_extract_payload::<Payload, Common>(&token, Some(&key), &other, chrono::Utc::now()),
+      Err(ExtractError::InvalidSignature)
+    ));
+
+    let mut parts = token.split('.').map(str::to_owned).collect::<Vec<_>>();
+    let mut ciphertext = base64::Engine::decode(&base64::engine::general_purpose::URL_SAFE, &parts[0]).unwrap();
+    ciphertext[0] ^= 1;
+    parts[0] = base64::Engine::encode(&base64::engine::general_purpose::URL_SAFE, ciphertext);
+    assert!(matches!(
+      mpaat_extract_payload::<Payload, Common>(&parts.join("."), Some(&key), &keypair, chrono::Utc::now()),
+      Err(ExtractError::InvalidSignature)
+    ));
+
+    // Keys and nonces of wrong length are refused instead of panicking.
+    assert!(matches!(
+      mpaat_extract_payload::<Payload, Common>(&token, Some(&key[..16]), &keypair, chrono::Utc::now()),
+      Err(ExtractError::Decrypt(_))
+    ));
+    let (ciphertext, nonce) = encrypt_chacha20poly1305(&payload(), &key).unwrap();
+    assert!(decrypt_chacha20poly1305::<Payload>(&ciphertext, &key, &nonce[..8]).is_err());
+    assert!(encrypt_chacha20poly1305(&payload(), &key[..16]).is_err());
+  }
+
+  #[test]
+  fn test_mpaat_with_rotated_keys() {
+    let now = chrono::Utc::now();
+    let old = generate_dilithium_keypair();
+    let new = generate_dilithium_keypair();
+    let keys = vec![
+      VerificationKey {
+        kid: String::from("old"),
+        public: old.public.to_vec(),
+        activates_at: now - chrono::TimeDelta::days(2),
+        expires_at: Some(now + chrono::TimeDelta::hours(1)),
+      },
+      VerificationKey {
+        kid: String::from("new"),
+        public: new.public.to_vec(),
+        activates_at: now - chrono::TimeDelta::days(1),
+        expires_at: None,
+      },
+    ];
+    let deploy_with_kid = |keypair: &pqc_dilithium::Keypair, kid: Option<&str>| {
+      deploy_mpaat_with_kid(
+        payload(),
+        Some(Common {
+          app_name: String::from("test-app"),
+        }),
+        now + chrono::TimeDelta::minutes(5),
+        &generate_dilithium_keypair().public,
+        None,
+        keypair,
+        kid,
+      )
+      .unwrap()
+    };
+
+    // Tokens of both keys are accepted; tokens without `kid` are matched by the public key.
+    for token in [
+      deploy_with_kid(&old, Some("old")),
+      deploy_with_kid(&new, Some("new")),
+      deploy(None, &old),
+    ] {
+      assert_eq!(
+        mpaat_extract_payload_with_keys::<Payload, Common>(&token, None, &keys, now).unwrap(),
+        payload()
+      );
+      assert!(mpaat_extract_common_fields_with_keys::<Common>(&token, &keys, now).is_ok());
+    }
+
+    // The old key is retired after its expiration.
+    let token = deploy_with_kid(&old, Some("old"));
+    assert!(matches!(
+      mpaat_extract_common_fields_with_keys::<Common>(&token, &keys, now + chrono::TimeDelta::hours(2)),
+      Err(ExtractError::RetiredServerKey)
+    ));
+
+    // Tokens of the upcoming key are refused until its activation.
+    let upcoming = VerificationKey {
+      kid: String::from("upcoming"),
+      public: new.public.to_vec(),
+      activates_at: now + chrono::TimeDelta::hours(1),
+      expires_at: None,
+    };
+    let token = deploy_with_kid(&new, Some("upcoming"));
+    assert!(matches!(
+      mpaat_extract_payload_with_keys::<Payload, Common>(&token, None, std::slice::from_ref(&upcoming), now),
+      Err(ExtractError::InactiveServerKey)
+    ));
+    assert!(
+      mpaat_extract_payload_with_keys::<Payload, Common>(
+        &token,
+        None,
+        &[upcoming],
+        now + chrono::TimeDelta::hours(2)
+      )
+      .is_ok()
+    );
+
+    // `kid` can't point to another key.
+    let token = deploy_with_kid(&old, Some("new"));
+    assert!(matches!(
+      mpaat_extract_payload_with_keys::<Payload, Common>(&token, None, &keys, now),
+      Err(ExtractError::InvalidSignature)
+    ));
+    let token = deploy_with_kid(&generate_dilithium_keypair(), None);
+    assert!(matches!(
+      mpaat_extract_payload_with_keys::<Payload, Common>(&token, None, &keys, now),
+      Err(ExtractError::InvalidServerPublicKey)
+    ));
+  }
+
+  #[test]
+  fn test_token_key_delivery() {
+    let (secret, public) = generate_x25519_keypair();
+    let token_key = generate::<32>();
+
+    let sealed = seal_token_key(&token_key, &public, "test-app").unwrap();
+    assert_eq!(open_token_key(&sealed, &secret, "test-app").unwrap(), token_key);
+
+    // The key is bound to the recipient and the application.
+    let (other_secret, _) = generate_x25519_keypair();
+    assert!(matches!(
+      open_token_key(&sealed, &other_secret, "test-app"),
+      Err(KeyDeliveryError::Decrypt)
+    ));
+    assert!(matches!(
+      open_token_key(&sealed, &secret, "other-app"),
+      Err(KeyDeliveryError::Decrypt)
+    ));
+    assert!(matches!(
+      seal_token_key(&token_key, &[0u8; 32], "test-app"),
+      Err(KeyDeliveryError::InvalidPublicKey)
+    ));
+  }
+}
@@ -0,0 +1,646 @@
+//! C3A instance administrator API.
+
+use c3a_common::{
+  AddAdminRequest, AdminRequest, AdminRole, AppAuthConfiguration, AppSummary, BackupRequest, BootstrapAdminRequest,
+  CacheStatsRequest, CacheStatsResponse, LiftBanRequest, ListAdminsRequest, ListAdminsResponse, ListAppsRequest,
+  ListAppsResponse, ListBansRequest, ListBansResponse, ListInvitationsRequest, ListInvitationsResponse,
+  MailQueueStatusRequest, MailQueueStatusResponse, MigrateRecordsRequest, MigrateRecordsResponse, RemoveAdminRequest,
+  RemoveDeadLetterRequest, RequeueDeadLetterRequest, RestoreRequest, RestoreResponse, RevokeInvitationRequest,
+  RotatePepperRequest, RotatePepperResponse, RotateSigningKeyRequest, RotateSigningKeyResponse, SealRequest, UserData,
+};
+use cc_server_kit::prelude::*;
+
+use crate::Setup;
+use crate::core::admins::{Admins, add_admin, bootstrap_admin, check_admin, remove_admin};
+use crate::core::audit::{self, AuditAction};
+use crate::core::bans;
+use crate::core::invitations::{self, Invitations};
+use crate::core::peppers::{Peppers, rotate_pepper};
+use crate::core::signing_keys::rotate_signing_key;
+use crate::kv::backup::load_backup_key;
+use crate::kv::sealing::load_master_key;
+use crate::kv::unseal::{UnsealMode, extract_unsealer};
+use crate::kv::{KvDb, extract_db};
+use crate::mailer::queue::extract_mail_queue;
+
+/// Returns the state of the outgoing mail queue, including dead letters.
+///
+/// This method is available for C3A administrators with `viewer` role.
+/// You should send `AdminRequest<MailQueueStatusRequest>` as MessagePack inside request body.
+#[handler]
+async fn mail_queue_status(req: &mut Request, depot: &mut Depot) -> MResult<MsgPack<MailQueueStatusResponse>> {
+  let request = req.parse_msgpack::<AdminRequest<MailQueueStatusRequest>>().await?;
+  let kv = extract_db(depot)?;
+  let queue = extract_mail_queue(depot)?;
+  check_admin(req, &kv, &request, AdminRole::Viewer).await?;
+
+  msgpack!(queue.status().await?)
+}
+
+/// Moves the dead letter back to the outbox to be sent again.
+///
+/// This method is available for C3A administrators with `operator` role.
+#[handler]
+async fn requeue_dead_letter(req: &mut Request, depot: &mut Depot) -> MResult<OK> {
+  let request = req.parse_msgpack::<AdminRequest<RequeueDeadLetterRequest>>().await?;
+  let kv = extract_db(depot)?;
+  let queue = extract_mail_queue(depot)?;
+  check_admin(req, &kv, &request, AdminRole::Operator).await?;
+
+  queue.requeue_dead_letter(&request.body.id).await?;
+  ok!()
+}
+
+/// Removes the dead letter.
+///
+/// This method is available for C3A administrators with `operator` role.
+#[handler]
+async fn remove_dead_letter(req: &mut Request, depot: &mut Depot) -> MResult<OK> {
+  let request = req.parse_msgpack::<AdminRequest<RemoveDeadLetterRequest>>().await?;
+  let kv = extract_db(depot)?;
+  let queue = extract_mail_queue(depot)?;
+  check_admin(req, &kv, &request, AdminRole::Operator).await?;
+
+  queue.remove_dead_letter(&request.body.id).await?;
+  ok!()
+}
+
+/// Generates a new pepper for users' secrets.
+///
+/// New secrets are hashed with the new pepper; previous peppers are kept, so stored hashes stay verifiable
+/// and are re-peppered on the users' next successful checks. This method is available for C3A administrators with `operator` role.
+#[handler]
+async fn rotate_pepper_handler(req: &mut Request, depot: &mut Depot) -> MResult<MsgPack<RotatePepperResponse>> {
+  let request = req.parse_msgpack::<AdminRequest<RotatePepperRequest>>().await?;
+  let kv = extract_db(depot)?;
+  let setup = depot.obtain::<Setup>()?;
+  let admin = check_admin(req, &kv, &request, AdminRole::Operator).await?;
+
+  let version = rotate_pepper(&kv).await?;
+  audit::record(&kv, Some(admin.name.as_str()), AuditAction::RotatePepper { version }).await?;
+  let peppers = Peppers::load(&kv, setup).await?;
+  let (current_version, _) = peppers.current()?;
+
+  msgpack!(RotatePepperResponse {
+    current_version,
+    known_versions: peppers.versions(),
+  })
+}
+
+/// Generates a new signing key, active since `activates_at` or right now.
+///
+/// Previous keys stay valid for verification during `signing_keys.retire_after_hours` after the activation,
+/// so issued tokens are accepted until they expire. This method is available for C3A administrators with
+/// `operator` role.
+#[handler]
+async fn rotate_signing_key_handler(
+  req: &mut Request,
+  depot: &mut Depot,
+) -> MResult<MsgPack<RotateSigningKeyResponse>> {
+  let request = req.parse_msgpack::<AdminRequest<RotateSigningKeyRequest>>().await?;
+  let kv = extract_db(depot)?;
+  let setup = depot.obtain::<Setup>()?;
+  let admin = check_admin(req, &kv, &request, AdminRole::Operator).await?;
+
+  let now = chrono::Utc::now();
+  let activates_at = request.body.activates_at.unwrap_or(now);
+  if activates_at < now {
+    return Err(
+      ErrorResponse::from("The new key can't be activated in the past.")
+        .with_400_pub()
+        .build(),
+    );
+  }
+
+  let (kid, keys) = rotate_signing_key(&kv, activates_at, &setup.signing_keys).await?;
+  audit::record(
+    &kv,
+    Some(admin.name.as_str()),
+    AuditAction::RotateSigningKey { kid: kid.clone() },
+  )
+  .await?;
+  msgpack!(RotateSigningKeyResponse { kid, keys })
+}
+
+/// Seals the storage: the KEK and submitted shares are wiped from memory.
+///
+/// Until it's unsealed again, the instance serves only health checks and `/unseal`. This method is available
+/// for C3A administrators with `operator` role.
+#[handler]
+async fn seal_handler(req: &mut Request, depot: &mut Depot) -> MResult<OK> {
+  let request = req.parse_msgpack::<AdminRequest<SealRequest>>().await?;
+  let kv = extract_db(depot)?;
+  let unsealer = extract_unsealer(depot)?;
+  let admin = check_admin(req, &kv, &request, AdminRole::Operator).await?;
+
+  unsealer.seal(&kv).await;
+  audit::record(&kv, Some(admin.name.as_str()), AuditAction::Seal).await?;
+  ok!()
+}
+
+/// Upgrades all stored apps', users' and invitations' records to the current schema version.
+///
+/// Records are also upgraded one by one on read, so calling this method is needed only before a worker of
+/// the previous version is retired. This method is available for C3A administrators with `operator` role.
+#[handler]
+async fn migrate_records_handler(req: &mut Request, depot: &mut Depot) -> MResult<MsgPack<MigrateRecordsResponse>> {
+  let request = req.parse_msgpack::<AdminRequest<MigrateRecordsRequest>>().await?;
+  let kv = extract_db(depot)?;
+  check_admin(req, &kv, &request, AdminRole::Operator).await?;
+
+  msgpack!(MigrateRecordsResponse {
+    apps: kv.migrate_records::<AppAuthConfiguration>().await?,
+    users: kv.migrate_records::<UserData>().await?,
+    invitations: kv.migrate_records::<Invitations>().await?,
+  })
+}
+
+/// Returns hits and misses of the in-memory cache of keys and apps' configurations.
+///
+/// This method is available for C3A administrators with `viewer` role.
+#[handler]
+async fn cache_stats(req: &mut Request, depot: &mut Depot) -> MResult<MsgPack<CacheStatsResponse>> {
+  let request = req.parse_msgpack::<AdminRequest<CacheStatsRequest>>().await?;
+  let kv = extract_db(depot)?;
+  check_admin(req, &kv, &request, AdminRole::Viewer).await?;
+
+  msgpack!(kv.cache_stats())
+}
+
+/// Returns an encrypted archive of all records, see `crate::kv::backup`.
+///
+/// The archive holds all users' password hashes and sealed records, so this method is available only for C3A
+/// administrators with `owner` role.
+#[handler]
+async fn backup_handler(req: &mut Request, depot: &mut Depot) -> MResult<MsgPack<Vec<u8>>> {
+  let request = req.parse_msgpack::<AdminRequest<BackupRequest>>().await?;
+  let kv = extract_db(depot)?;
+  let setup = depot.obtain::<Setup>()?;
+  let admin = check_admin(req, &kv, &request, AdminRole::Owner).await?;
+
+  let archive = kv.export_backup(&load_backup_key(setup)?).await?;
+  audit::record(&kv, Some(admin.name.as_str()), AuditAction::Backup).await?;
+  msgpack!(archive)
+}
+
+/// Replaces all records with the ones of the archive made by `/admin/backup`, except admins and nonces of their
+/// requests.
+///
+/// The archive is fully checked before anything is replaced. With `unseal: { type: master_key }` the archive
+/// should be sealed with the instance's master key, and the instance is unsealed right after the restore;
+/// otherwise it waits for shares of the master key at `/unseal`. This method is available for C3A
+/// administrators with `owner` role.
+#[handler]
+async fn restore_handler(req: &mut Request, depot: &mut Depot) -> MResult<MsgPack<RestoreResponse>> {
+  let request = req.parse_msgpack::<AdminRequest<RestoreRequest>>().await?;
+  let kv = extract_db(depot)?;
+  let unsealer = extract_unsealer(depot)?;
+  let setup = depot.obtain::<Setup>()?;
+  let admin = check_admin(req, &kv, &request, AdminRole::Owner).await?;
+
+  let master_key = match setup.unseal {
+    UnsealMode::MasterKey => Some(load_master_key(setup)?),
+    UnsealMode::Shamir { .. } => None,
+  };
+  let records = kv
+    .restore_backup(
+      &load_backup_key(setup)?,
+      &request.body.archive,
+      master_key.as_ref().map(|key| key.as_slice()),
+    )
+    .await?;
+  audit::record(&kv, Some(admin.name.as_str()), AuditAction::Restore { records }).await?;
+  unsealer.seal(&kv).await;
+  if let Some(master_key) = &master_key {
+    kv.unseal(master_key).await?;
+    kv.initial_setup().await?;
+  }
+
+  msgpack!(RestoreResponse {
+    records,
+    sealed: kv.is_sealed(),
+  })
+}
+
+/// Returns all invitations, including used and expired ones.
+///
+/// This method is available for C3A administrators with `viewer` role.
+#[handler]
+async fn list_invitations(req: &mut Request, depot: &mut Depot) -> MResult<MsgPack<ListInvitationsResponse>> {
+  let request = req.parse_msgpack::<AdminRequest<ListInvitationsRequest>>().await?;
+  let kv = extract_db(depot)?;
+  check_admin(req, &kv, &request, AdminRole::Viewer).await?;
+
+  msgpack!(ListInvitationsResponse {
+    invitations: invitations::list(&kv).await?,
+  })
+}
+
+/// Revokes the unused invitation by its identifier.
+///
+/// This method is available for C3A administrators with `operator` role.
+#[handler]
+async fn revoke_invitation(req: &mut Request, depot: &mut Depot) -> MResult<OK> {
+  let request = req.parse_msgpack::<AdminRequest<RevokeInvitationRequest>>().await?;
+  let kv = extract_db(depot)?;
+  let admin = check_admin(req, &kv, &request, AdminRole::Operator).await?;
+
+  invitations::revoke(&kv, &admin.name, &request.body.id).await?;
+  ok!()
+}
+
+/// Returns all registered apps.
+///
+/// This method is available for C3A administrators with `viewer` role.
+#[handler]
+async fn list_apps(req: &mut Request, depot: &mut Depot) -> MResult<MsgPack<ListAppsResponse>> {
+  let request = req.parse_msgpack::<AdminRequest<ListAppsRequest>>().await?;
+  let kv = extract_db(depot)?;
+  check_admin(req, &kv, &request, AdminRole::Viewer).await?;
+
+  let mut apps = kv
+    .scan_versioned::<AppAuthConfiguration>()
+    .await?
+    .into_iter()
+    .map(|app_conf| AppSummary {
+      app_name: app_conf.app_name,
+      domain: app_conf.domain,
+      author_dpub: app_conf.author_dpub,
+    })
+    .collect::<Vec<_>>();
+  apps.sort_by(|a, b| a.app_name.cmp(&b.app_name));
+
+  msgpack!(ListAppsResponse { apps })
+}
+
+/// Returns active bans of the app or of all apps, see `crate::core::bans`.
+///
+/// This method is available for C3A administrators with `viewer` role.
+#[handler]
+async fn list_bans(req: &mut Request, depot: &mut Depot) -> MResult<MsgPack<ListBansResponse>> {
+  let request = req.parse_msgpack::<AdminRequest<ListBansRequest>>().await?;
+  let kv = extract_db(depot)?;
+  check_admin(req, &kv, &request, AdminRole::Viewer).await?;
+
+  msgpack!(ListBansResponse {
+    bans: bans::list(&kv, request.body.app_name.as_deref()).await?,
+  })
+}
+
+/// Lifts the ban before it expires.
+///
+/// This method is available for C3A administrators with `operator` role.
+#[handler]
+async fn lift_ban(req: &mut Request, depot: &mut Depot) -> MResult<OK> {
+  let request = req.parse_msgpack::<AdminRequest<LiftBanRequest>>().await?;
+  let kv = extract_db(depot)?;
+  let admin = check_admin(req, &kv, &request, AdminRole::Operator).await?;
+
+  bans::lift(&kv, &admin.name, &request.body.app_name, &request.body.subject).await?;
+  ok!()
+}
+
+/// Registers the first admin with `C3A_PRIVATE_ADM_KEY`, see `crate::core::admins`.
+///
+/// You should send `AdminRequest<BootstrapAdminRequest>` signed by the new admin's key. The method is refused
+/// once any admin is registered.
+#[handler]
+async fn bootstrap_admin_handler(req: &mut Request, depot: &mut Depot) -> MResult<OK> {
+  let request = req.parse_msgpack::<AdminRequest<BootstrapAdminRequest>>().await?;
+  let kv = extract_db(depot)?;
+  bootstrap_admin(req, &kv, depot.obtain::<Setup>()?, &request).await?;
+  ok!()
+}
+
+/// Returns registered admins.
+///
+/// This method is available for C3A administrators with `viewer` role.
+#[handler]
+async fn list_admins(req: &mut Request, depot: &mut Depot) -> MResult<MsgPack<ListAdminsResponse>> {
+  let request = req.parse_msgpack::<AdminRequest<ListAdminsRequest>>().await?;
+  let kv = extract_db(depot)?;
+  check_admin(req, &kv, &request, AdminRole::Viewer).await?;
+
+  msgpack!(ListAdminsResponse {
+    admins: Admins::load(&kv).await?.list().to_vec(),
+  })
+}
+
+/// Registers a new admin.
+///
+/// This method is available for C3A administrators with `owner` role.
+#[handler]
+async fn add_admin_handler(req: &mut Request, depot: &mut Depot) -> MResult<OK> {
+  let request = req.parse_msgpack::<AdminRequest<AddAdminRequest>>().await?;
+  let kv = extract_db(depot)?;
+  let admin = check_admin(req, &kv, &request, AdminRole::Owner).await?;
+
+  add_admin(
+    &kv,
+    &admin.name,
+    &request.body.name,
+    request.body.role,
+    &request.body.public,
+  )
+  .await?;
+  ok!()
+}
+
+/// Removes the admin; the last owner can't be removed.
+///
+/// This method is available for C3A administrators with `owner` role.
+#[handler]
+async fn remove_admin_handler(req: &mut Request, depot: &mut Depot) -> MResult<OK> {
+  let request = req.parse_msgpack::<AdminRequest<RemoveAdminRequest>>().await?;
+  let kv = extract_db(depot)?;
+  let admin = check_admin(req, &kv, &request, AdminRole::Owner).await?;
+
+  remove_admin(&kv, &admin.name, &request.body.name).await?;
+  ok!()
+}
+
+/// Router to C3A administrator's API.
+pub(crate) fn admin_api() -> Router {
+  Router::with_path("/admin")
+    .push(Router::with_path("bootstrap").post(bootstrap_admin_handler))
+    .push(Router::with_path("admins").post(list_admins))
+    .push(Router::with_path("admins/add").post(add_admin_handler))
+    .push(Router::with_path("admins/remove").post(remove_admin_handler))
+    .push(Router::with_path("mail-queue").post(mail_queue_status))
+    .push(Router::with_path("mail-queue/requeue").post(requeue_dead_letter))
+    .push(Router::with_path("mail-queue/remove").post(remove_dead_letter))
+    .push(Router::with_path("invitations").post(list_invitations))
+    .push(Router::with_path("invitations/revoke").post(revoke_invitation))
+    .push(Router::with_path("apps").post(list_apps))
+    .push(Router::with_path("bans").post(list_bans))
+    .push(Router::with_path("bans/lift").post(lift_ban))
+    .push(Router::with_path("cache").post(cache_stats))
+    .push(Router::with_path("peppers/rotate").post(rotate_pepper_handler))
+    .push(Router::with_path("signing-keys/rotate").post(rotate_signing_key_handler))
+    .push(Router::with_path("seal").post(seal_handler))
+    .push(Router::with_path("records/migrate").post(migrate_records_handler))
+    .push(Router::with_path("backup").post(backup_handler))
+    .push(Router::with_path("restore").post(restore_handler))
+}
+
+#[cfg(test)]
+mod tests {
+  use c3a_common::{
+    AddAdminRequest, AdminRole, BootstrapAdminRequest, GenerateInvitationRequest, GenerateInvitationResponse, Keypair,
+    ListAdminsRequest, ListAdminsResponse, ListAppsRequest, ListAppsResponse, ListInvitationsRequest,
+    ListInvitationsResponse, RemoveAdminRequest, RevokeInvitationRequest,
+  };
+  use cc_server_kit::test_exts::ResponseExt;
+  use salvo::affix_state;
+  use salvo::core::prelude::*;
+  use salvo::test::TestClient;
+  use serde::Serialize;
+
+  use crate::core::admins::{register_test_admin, sign_test_request};
+  use crate::kv::KvDb;
+
+  fn private_adm_key() -> String {
+    format!("test-key-{}", "X".repeat(119))
+  }
+
+  async fn create_service(kv_db: KvDb) -> Service {
+    let mut setup = crate::Setup::default();
+    setup.private_adm_key = Some(private_adm_key());
+
+    let router = Router::new()
+      .hoop(affix_state::inject(setup).inject(kv_db))
+      .push(super::admin_api())
+      .push(crate::api::applications::application_server_api());
+
+    Service::new(router)
+  }
+
+  async fn post_signed<T: Serialize>(service: &Service, admin: &str, keys: &Keypair, path: &str, body: T) -> Response {
+    let (request, signature) = sign_test_request(admin, path, body, keys);
+    post(service, path, &request, &signature).await
+  }
+
+  async fn post(service: &Service, path: &str, request: &impl Serialize, signature: &str) -> Response {
+    TestClient::post(format!("http://0.0.0.0:5800{path}"))
+      .add_header("Content-Type", "application/msgpack", true)
+      .add_header(c3a_common::SIGN_HEADER, signature, true)
+      .bytes(rmp_serde::to_vec(request).unwrap())
+      .send(service)
+      .await
+  }
+
+  #[tokio::test]
+  async fn test_list_and_revoke_invitations() {
+    let kv_db = KvDb::in_memory_unsealed().await.unwrap();
+    let keys = register_test_admin(&kv_db, "admin", AdminRole::Operator).await;
+    let service = create_service(kv_db).await;
+
+    let mut invites = vec![];
+    for intended_for in [None, Some(String::from("a-app"))] {
+      let path = "/apps/generate-invitation";
+      let request = GenerateInvitationRequest {
+        intended_for,
+        lifetime_days: Some(3),
+      };
+      let mut content = post_signed(&service, "admin", &keys, path, request).await;
+      invites.push(content.take_msgpack::<GenerateInvitationResponse>().await.unwrap());
+    }
+
+    let mut content = post_signed(
+      &service,
+      "admin",
+      &keys,
+      "/admin/invitations",
+      ListInvitationsRequest {},
+    )
+    .await;
+    let listed = content.take_msgpack::<ListInvitationsResponse>().await.unwrap();
+    let ids = listed
+      .invitations
+      .iter()
+      .map(|info| info.id.as_str())
+      .collect::<Vec<_>>();
+    assert_eq!(ids, vec![invites[0].id.as_str(), invites[1].id.as_str()]);
+    assert_eq!(listed.invitations[1].created_by, "admin");
+    assert_eq!(listed.invitations[1].intended_for.as_deref(), Some("a-app"));
+    assert_eq!(listed.invitations[1].expires_at, invites[1].expires_at);
+
+    let revoke = RevokeInvitationRequest {
+      id: invites[0].id.clone(),
+    };
+    let path = "/admin/invitations/revoke";
+    let content = post_signed(&service, "admin", &keys, path, revoke.clone()).await;
+    assert_eq!(content.status_code, Some(StatusCode::OK));
+    let content = post_signed(&service, "admin", &keys, path, revoke).await;
+    assert_eq!(content.status_code, Some(StatusCode::NOT_FOUND));
+
+    let mut content = post_signed(
+      &service,
+      "admin",
+      &keys,
+      "/admin/invitations",
+      ListInvitationsRequest {},
+    )
+    .await;
+    let listed = content.take_msgpack::<ListInvitationsResponse>().await.unwrap();
+    assert_eq!(listed.invitations.len(), 1);
+    assert_eq!(listed.invitations[0].id, invites[1].id);
+  }
+
+  #[tokio::test]
+  async fn test_list_apps() {
+    let kv_db = KvDb::in_memory_unsealed().await.unwrap();
+    let keys = register_test_admin(&kv_db, "viewer", AdminRole::Viewer).await;
+    for (app_name, domain) in [("b-app", "b.example.com"), ("a-app", "a.example.com")] {
+      let app_conf = c3a_common::AppAuthConfiguration {
+        app_name: app_name.to_owned(),
+        domain: domain.to_owned(),
+        allowed_tags: vec![],
+        allow_sign_up: None,
+        client_based_auth_opts: None,
+        author_dpub: vec![1, 2],
+        email_templates: None,
+        hash_params: None,
+      };
+      kv_db.insert_versioned(&KvDb::app(app_name), &app_conf).await.unwrap();
+    }
+    let service = create_service(kv_db).await;
+
+    let mut content = post_signed(&service, "viewer", &keys, "/admin/apps", ListAppsRequest {}).await;
+    let listed = content.take_msgpack::<ListAppsResponse>().await.unwrap();
+    let domains = listed.apps.iter().map(|app| app.domain.as_str()).collect::<Vec<_>>();
+    assert_eq!(domains, vec!["a.example.com", "b.example.com"]);
+  }
+
+  #[tokio::test]
+  async fn test_admin_requests_are_checked() {
+    let kv_db = KvDb::in_memory_unsealed().await.unwrap();
+    let keys = register_test_admin(&kv_db, "viewer", AdminRole::Viewer).await;
+    let service = create_service(kv_db).await;
+    let other_keys = c3a_common::generate_dilithium_keypair();
+
+    // Signed by another key.
+    let content = post_signed(&service, "viewer", &other_keys, "/admin/apps", ListAppsRequest {}).await;
+    assert_eq!(content.status_code, Some(StatusCode::UNAUTHORIZED));
+    // Unknown admin, refused as a wrong signature.
+    let content = post_signed(&service, "nobody", &keys, "/admin/apps", ListAppsRequest {}).await;
+    assert_eq!(content.status_code, Some(StatusCode::UNAUTHORIZED));
+
+    // Replayed.
+    let (request, signature) = sign_test_request("viewer", "/admin/apps", ListAppsRequest {}, &keys);
+    let content = post(&service, "/admin/apps", &request, &signature).await;
+    assert_eq!(content.status_code, Some(StatusCode::OK));
+    let content = post(&service, "/admin/apps", &request, &signature).await;
+    assert_eq!(content.status_code, Some(StatusCode::UNAUTHORIZED));
+
+    // Sent to another method.
+    let (request, signature) = sign_test_request("viewer", "/admin/apps", ListInvitationsRequest {}, &keys);
+    let content = post(&service, "/admin/invitations", &request, &signature).await;
+    assert_eq!(content.status_code, Some(StatusCode::UNAUTHORIZED));
+
+    // Expired.
+    let (mut request, _) = sign_test_request("viewer", "/admin/apps", ListAppsRequest {}, &keys);
+    request.issued_at -= chrono::TimeDelta::hours(1);
+    let signature = c3a_common::base64_encode(&c3a_common::sign(&request, &keys).unwrap());
+    let content = post(&service, "/admin/apps", &request, &signature).await;
+    assert_eq!(content.status_code, Some(StatusCode::UNAUTHORIZED));
+
+    // Not allowed for the role.
+    let revoke = RevokeInvitationRequest { id: String::from("00") };
+    let content = post_signed(&service, "viewer", &keys, "/admin/invitations/revoke", revoke).await;
+    assert_eq!(content.status_code, Some(StatusCode::FORBIDDEN));
+  }
+
+  #[tokio::test]
+  async fn test_bootstrap_and_manage_admins() {
+    let service = create_service(KvDb::in_memory_unsealed().await.unwrap()).await;
+    let owner_keys = c3a_common::generate_dilithium_keypair();
+    let bootstrap = |private_adm_key: &str| {
+      let body = BootstrapAdminRequest {
+        public: owner_keys.public.to_vec(),
+        key_proof: vec![],
+      };
+      let (mut request, _) = sign_test_request("owner", "/admin/bootstrap", body, &owner_keys);
+      request.body.key_proof = c3a_common::bootstrap_key_proof(private_adm_key.as_bytes(), &request).unwrap();
+      let signature = c3a_common::base64_encode(&c3a_common::sign(&request, &owner_keys).unwrap());
+      (request, signature)
+    };
+
+    let (request, signature) = bootstrap("wrong");
+    let content = post(&service, "/admin/bootstrap", &request, &signature).await;
+    assert_eq!(content.status_code, Some(StatusCode::UNAUTHORIZED));
+    let (request, signature) = bootstrap(&private_adm_key());
+    let content = post(&service, "/admin/bootstrap", &request, &signature).await;
+    assert_eq!(content.status_code, Some(StatusCode::OK));
+    // The proof is bound to the request, so it can't be replayed.
+    let content = post(&service, "/admin/bootstrap", &request, &signature).await;
+    assert_eq!(content.status_code, Some(StatusCode::UNAUTHORIZED));
+    let (request, signature) = bootstrap(&private_adm_key());
+    let content = post(&service, "/admin/bootstrap", &request, &signature).await;
+    assert_eq!(content.status_code, Some(StatusCode::FORBIDDEN));
+
+    let operator_keys = c3a_common::generate_dilithium_keypair();
+    let add = AddAdminRequest {
+      name: String::from("operator"),
+      role: AdminRole::Operator,
+      public: operator_keys.public.to_vec(),
+    };
+    let content = post_signed(&service, "owner", &owner_keys, "/admin/admins/add", add.clone()).await;
+    assert_eq!(content.status_code, Some(StatusCode::OK));
+    let content = post_signed(&service, "owner", &owner_keys, "/admin/admins/add", add).await;
+    assert_eq!(content.status_code, Some(StatusCode::BAD_REQUEST));
+
+    let mut content = post_signed(
+      &service,
+      "operator",
+      &operator_keys,
+      "/admin/admins",
+      ListAdminsRequest {},
+    )
+    .await;
+    let listed = content.take_msgpack::<ListAdminsResponse>().await.unwrap();
+    let admins = listed
+      .admins
+      .iter()
+      .map(|admin| (admin.name.as_str(), admin.role, admin.added_by.as_deref()))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      admins,
+      vec![
+        ("owner", AdminRole::Owner, None),
+        ("operator", AdminRole::Operator, Some("owner"))
+      ]
+    );
+
+    let remove = |name: &str| RemoveAdminRequest { name: name.to_owned() };
+    let content = post_signed(
+      &service,
+      "operator",
+      &operator_keys,
+      "/admin/admins/remove",
+      remove("owner"),
+    )
+    .await;
+    assert_eq!(content.status_code, Some(StatusCode::FORBIDDEN));
+    let content = post_signed(&service, "owner", &owner_keys, "/admin/admins/remove", remove("owner")).await;
+    assert_eq!(content.status_code, Some(StatusCode::BAD_REQUEST));
+    let content = post_signed(
+      &service,
+      "owner",
+      &owner_keys,
+      "/admin/admins/remove",
+      remove("operator"),
+    )
+    .await;
+    assert_eq!(content.status_code, Some(StatusCode::OK));
+    let content = post_signed(
+      &service,
+      "operator",
+      &operator_keys,
+      "/admin/admins",
+      ListAdminsRequest {},
+    )
+    .await;
+    assert_eq!(content.status_code, Some(StatusCode::UNAUTHORIZED));
+  }
+}
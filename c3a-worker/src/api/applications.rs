@@ -9,17 +9,19 @@
 //! (it's allowed to get `200` or `400` status codes depending on your app registration existance).
 
 use c3a_common::{
-  EditAppAuthConfigurationRequest, GenerateInvitationRequest, GetAppAuthConfigurationRequest,
-  GetAppAuthConfigurationResponse, RegisterAppAuthConfigurationRequest, RegisterAppAuthConfigurationResponse,
-  RemoveAppRequest, generate,
+  AdminRequest, AdminRole, EditAppAuthConfigurationRequest, GenerateInvitationRequest, GenerateInvitationResponse,
+  GetAppAuthConfigurationRequest, GetAppAuthConfigurationResponse, RegisterAppAuthConfigurationRequest,
+  RegisterAppAuthConfigurationResponse, RemoveAppRequest, seal_token_key,
 };
 use cc_server_kit::prelude::*;
-use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
 
 use crate::Setup;
-use crate::kv::{KvDb, PreConverted, extract_db};
-use crate::utils::{sign_by_header, verify_sign_by_header};
+use crate::core::admins::check_admin;
+use crate::core::invitations;
+use crate::keys::KeyPurpose;
+use crate::kv::{KvDb, extract_db};
+use crate::mailer::templates::validate_sender;
+use crate::utils::{sign_by_header, validate_hash_params, verify_sign_by_header};
 
 /// Service availability check.
 #[endpoint(
@@ -32,35 +34,17 @@ async fn health_check(req: &mut Request) -> MResult<OK> {
   return ok!();
 }
 
-#[derive(Deserialize, Serialize, Default)]
-struct Invitations {
-  invitations: HashSet<Vec<u8>>,
-}
-
 /// Generates invitation for app registration.
 ///
-/// This method is available only for C3A administrator.
-/// You should send `GenerateInvitationRequest` as MessagePack inside request body.
+/// This method is available for C3A administrators with `operator` role.
+/// You should send `AdminRequest<GenerateInvitationRequest>` as MessagePack inside request body.
 #[handler]
-async fn generate_invitation(req: &mut Request, depot: &mut Depot) -> MResult<MsgPack<Vec<u8>>> {
-  let request = req.parse_msgpack::<GenerateInvitationRequest>().await?;
+async fn generate_invitation(req: &mut Request, depot: &mut Depot) -> MResult<MsgPack<GenerateInvitationResponse>> {
+  let request = req.parse_msgpack::<AdminRequest<GenerateInvitationRequest>>().await?;
   let kv = extract_db(depot)?;
-  let c3a_state = depot.obtain::<Setup>()?;
-
-  if c3a_state.private_adm_key.as_ref().unwrap().as_bytes()[..24] != request.private_admin_key_begin {
-    return Err(
-      ErrorResponse::from("Invalid authentication request")
-        .with_401_pub()
-        .build(),
-    );
-  }
-
-  let mut invitations = kv.get::<Invitations>(KvDb::INVITES).await?.unwrap_or_default();
-  let new_invite = generate::<1024>().to_vec();
-  invitations.invitations.insert(new_invite.clone());
-  kv.upsert(KvDb::INVITES, &invitations).await?;
+  let admin = check_admin(req, &kv, &request, AdminRole::Operator).await?;
 
-  msgpack!(new_invite)
+  msgpack!(invitations::generate(&kv, &admin.name, &request.body).await?)
 }
 
 /// Register app.
@@ -95,29 +79,49 @@ async fn app_register(
 ) -> MResult<MsgPack<RegisterAppAuthConfigurationResponse>> {
   let request = req.parse_msgpack::<RegisterAppAuthConfigurationRequest>().await?;
   let kv = extract_db(depot)?;
-  let keypair = kv.get_dilithium_keypair().await?;
+  let signing_key = kv.get_signing_key().await?;
 
   verify_sign_by_header(req, &request, &request.config.author_dpub)?;
 
-  let mut invitations = kv.get::<Invitations>(KvDb::INVITES).await?.unwrap_or_default();
-  if !invitations.invitations.contains(&request.invite) {
-    return Err(
-      ErrorResponse::from("Your invitation code is invalid!")
-        .with_401_pub()
-        .build(),
-    );
+  let app_conf = request.config;
+  let c3a_state = depot.obtain::<Setup>()?;
+  if let Some(hash_params) = &app_conf.hash_params {
+    validate_hash_params(hash_params, &c3a_state.hash_params_bounds)?;
   }
-  invitations.invitations.remove(&request.invite);
-  kv.upsert(KvDb::INVITES, &invitations).await?;
+  validate_sender(
+    app_conf.email_templates.as_ref(),
+    &app_conf.domain,
+    &c3a_state.app_sender_domains,
+  )?;
+
+  // Sealed before the invite is redeemed, so an invalid key can't waste it. The key is derived, so it's the same
+  // regardless of when the app enables token encryption.
+  let sealed_token_key = match &request.key_exchange_public {
+    Some(key_exchange_public) => {
+      let token_key = kv.get_keys().await?.derive(KeyPurpose::TokenEncryption {
+        app_name: &app_conf.app_name,
+      });
+      Some(
+        seal_token_key(token_key.as_slice(), key_exchange_public, &app_conf.app_name)
+          .map_err(|e| ErrorResponse::from(e.to_string()).with_400_pub().build())?,
+      )
+    }
+    None => None,
+  };
 
-  let app_conf = request.config;
-  kv.insert(&KvDb::app(&app_conf.app_name), &app_conf).await?;
+  let invite_id = invitations::redeem(&kv, &request.invite, &app_conf).await?;
+  if let Err(e) = kv.insert_versioned(&KvDb::app(&app_conf.app_name), &app_conf).await {
+    // The app is registered already, so the invite is given back.
+    invitations::give_back(&kv, &invite_id).await?;
+    return Err(e);
+  }
 
   let answer = RegisterAppAuthConfigurationResponse {
     author_dpub: app_conf.author_dpub,
-    c3a_dpub: keypair.public.to_vec(),
+    c3a_dpub: signing_key.public().to_vec(),
+    sealed_token_key,
   };
-  sign_by_header(res, &answer, &keypair)?;
+  sign_by_header(res, &answer, &signing_key)?;
 
   msgpack!(answer)
 }
@@ -154,7 +158,7 @@ async fn app_get_info(
 ) -> MResult<MsgPack<GetAppAuthConfigurationResponse>> {
   let request = req.parse_msgpack::<GetAppAuthConfigurationRequest>().await?;
   let kv = extract_db(depot)?;
-  let keypair = kv.get_dilithium_keypair().await?;
+  let signing_key = kv.get_signing_key().await?;
 
   let app_conf = kv.get_app_conf(&request.app_name).await?;
   if app_conf.author_dpub.as_slice().ne(request.author_dpub.as_slice()) {
@@ -169,9 +173,9 @@ async fn app_get_info(
 
   let answer = GetAppAuthConfigurationResponse {
     config: app_conf,
-    c3a_dpub: keypair.public.to_vec(),
+    c3a_dpub: signing_key.public().to_vec(),
   };
-  sign_by_header(res, &answer, &keypair)?;
+  sign_by_header(res, &answer, &signing_key)?;
 
   msgpack!(answer)
 }
@@ -183,40 +187,59 @@ async fn edit_app_configuration(req: &mut Request, depot: &mut Depot) -> MResult
   let request = req.parse_msgpack::<EditAppAuthConfigurationRequest>().await?;
   let kv = extract_db(depot)?;
 
-  let mut app_conf = kv.get_app_conf(&request.edit_app).await?;
+  let author_dpub = kv.get_app_conf(&request.edit_app).await?.author_dpub;
 
-  verify_sign_by_header(req, &request, &app_conf.author_dpub)?;
+  verify_sign_by_header(req, &request, &author_dpub)?;
 
-  if let Some(new_app_name) = &request.app_name {
-    app_conf.app_name = new_app_name.to_owned();
-  }
-  if let Some(new_domain) = &request.domain {
-    app_conf.domain = new_domain.to_owned();
-  }
-  if let Some(new_tags) = &request.allowed_tags {
-    app_conf.allowed_tags = new_tags.to_owned();
-  }
-  if let Some(new_signup_opts) = &request.allow_sign_up {
-    app_conf.allow_sign_up = Some(new_signup_opts.to_owned());
-  }
-  if let Some(new_client_based_auth_opts) = &request.client_based_auth_opts {
-    app_conf.client_based_auth_opts = Some(new_client_based_auth_opts.to_owned());
+  let c3a_state = depot.obtain::<Setup>()?;
+  if let Some(new_hash_params) = &request.hash_params {
+    validate_hash_params(new_hash_params, &c3a_state.hash_params_bounds)?;
   }
 
-  kv.batch_ops(
-    vec![],
-    vec![(
-      KvDb::app(request.app_name.as_deref().unwrap_or(&request.edit_app)),
-      PreConverted::new(&app_conf)?,
-    )],
-    vec![KvDb::app(&request.edit_app)],
-  )
+  kv.update_app(&request.edit_app, |app_conf| {
+    if app_conf.author_dpub.ne(&author_dpub) {
+      return Err(
+        ErrorResponse::from("The app's author key is changed meanwhile.")
+          .with_403_pub()
+          .build(),
+      );
+    }
+    if let Some(new_app_name) = &request.app_name {
+      app_conf.app_name = new_app_name.to_owned();
+    }
+    if let Some(new_domain) = &request.domain {
+      app_conf.domain = new_domain.to_owned();
+    }
+    if let Some(new_tags) = &request.allowed_tags {
+      app_conf.allowed_tags = new_tags.to_owned();
+    }
+    if let Some(new_signup_opts) = &request.allow_sign_up {
+      app_conf.allow_sign_up = Some(new_signup_opts.to_owned());
+    }
+    if let Some(new_client_based_auth_opts) = &request.client_based_auth_opts {
+      app_conf.client_based_auth_opts = Some(new_client_based_auth_opts.to_owned());
+    }
+    if let Some(new_email_templates) = &request.email_templates {
+      app_conf.email_templates = Some(new_email_templates.to_owned());
+    }
+    if let Some(new_hash_params) = request.hash_params {
+      app_conf.hash_params = Some(new_hash_params);
+    }
+    if request.domain.is_some() || request.email_templates.is_some() {
+      validate_sender(
+        app_conf.email_templates.as_ref(),
+        &app_conf.domain,
+        &c3a_state.app_sender_domains,
+      )?;
+    }
+    Ok(())
+  })
   .await?;
 
   ok!()
 }
 
-/// Removes app configuration from C3A Service.
+/// Removes app configuration and its users from C3A Service.
 #[endpoint(tags("maintenance"))]
 #[instrument(skip_all, fields(http.uri = req.uri().path(), http.method = req.method().as_str()))]
 async fn app_remove(req: &mut Request, depot: &mut Depot) -> MResult<OK> {
@@ -234,7 +257,7 @@ async fn app_remove(req: &mut Request, depot: &mut Depot) -> MResult<OK> {
     );
   }
 
-  kv.remove(&KvDb::app(&request.app_name)).await?;
+  kv.remove_app(&request.app_name).await?;
   ok!()
 }
 
@@ -263,34 +286,38 @@ pub(crate) fn application_server_api() -> Router {
 #[cfg(test)]
 mod tests {
   use c3a_common::{
-    EditAppAuthConfigurationRequest, GenerateInvitationRequest, GetAppAuthConfigurationRequest,
-    GetAppAuthConfigurationResponse, RegisterAppAuthConfigurationRequest, RegisterAppAuthConfigurationResponse,
-    RemoveAppRequest, base64_decode, base64_encode, sign, verify,
+    EditAppAuthConfigurationRequest, GenerateInvitationRequest, GenerateInvitationResponse,
+    GetAppAuthConfigurationRequest, GetAppAuthConfigurationResponse, RegisterAppAuthConfigurationRequest,
+    RegisterAppAuthConfigurationResponse, RemoveAppRequest, base64_decode, base64_encode, sign, verify,
   };
   use cc_server_kit::test_exts::ResponseExt;
   use salvo::affix_state;
   use salvo::core::prelude::*;
   use salvo::test::TestClient;
 
-  async fn create_service(partition_name: &str) -> Service {
+  use crate::core::admins::{register_test_admin, sign_test_request};
+
+  /// Returns the service and keys of its operator `admin`.
+  async fn create_service() -> (Service, c3a_common::Keypair) {
     use crate::Setup;
 
     let mut setup = Setup::default();
     setup.private_adm_key = Some("test-key-XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX".to_string());
 
-    let kv_db = crate::kv::KvDb::load(partition_name).unwrap();
+    let kv_db = crate::kv::KvDb::in_memory_unsealed().await.unwrap();
     kv_db.initial_setup().await.unwrap();
+    let admin_keys = register_test_admin(&kv_db, "admin", c3a_common::AdminRole::Operator).await;
 
     let router = Router::new()
       .hoop(affix_state::inject(setup).inject(kv_db))
       .push(super::application_server_api());
 
-    Service::new(router)
+    (Service::new(router), admin_keys)
   }
 
   #[tokio::test]
   async fn test_health_check() {
-    let service = create_service("tests-1").await;
+    let (service, _) = create_service().await;
 
     let content = TestClient::get("http://0.0.0.0:5800/health-check").send(&service).await;
 
@@ -299,22 +326,26 @@ mod tests {
 
   #[tokio::test]
   async fn test_invite_register_get_and_remove() {
-    let service = create_service("tests-2").await;
+    let (service, admin_keys) = create_service().await;
 
-    let invite_req = GenerateInvitationRequest {
-      private_admin_key_begin: *b"test-key-XXXXXXXXXXXXXXX",
-    };
+    let (invite_req, signature) = sign_test_request(
+      "admin",
+      "/apps/generate-invitation",
+      GenerateInvitationRequest::default(),
+      &admin_keys,
+    );
 
     let mut content = TestClient::post("http://0.0.0.0:5800/apps/generate-invitation")
       .add_header("Content-Type", "application/msgpack", true)
+      .add_header(c3a_common::SIGN_HEADER, signature.as_str(), true)
       .bytes(rmp_serde::to_vec(&invite_req).unwrap())
       .send(&service)
       .await;
 
     assert_eq!(content.status_code, Some(StatusCode::OK));
 
-    let invite = content.take_msgpack::<Vec<u8>>().await.unwrap();
-    assert_eq!(invite.len(), 1024);
+    let invite = content.take_msgpack::<GenerateInvitationResponse>().await.unwrap().invite;
+    assert_eq!(invite.len(), 47);
 
     let keypair = c3a_common::generate_dilithium_keypair();
     let config = c3a_common::AppAuthConfiguration {
@@ -359,11 +390,30 @@ mod tests {
       }),
       client_based_auth_opts: None,
       author_dpub: keypair.public.to_vec(),
+      email_templates: None,
+      hash_params: None,
+    };
+
+    // An invalid key exchange key is refused before the invite is redeemed.
+    let bad_register_req = RegisterAppAuthConfigurationRequest {
+      invite: invite.clone(),
+      config: config.clone(),
+      key_exchange_public: Some(vec![1, 2, 3]),
     };
+    let signature = base64_encode(&sign(&bad_register_req, &keypair).unwrap());
+    let content = TestClient::post("http://0.0.0.0:5800/apps/register")
+      .add_header("Content-Type", "application/msgpack", true)
+      .add_header(c3a_common::SIGN_HEADER, signature.as_str(), true)
+      .bytes(rmp_serde::to_vec(&bad_register_req).unwrap())
+      .send(&service)
+      .await;
+    assert_eq!(content.status_code, Some(StatusCode::BAD_REQUEST));
 
+    let (key_exchange_secret, key_exchange_public) = c3a_common::generate_x25519_keypair();
     let app_register_req = RegisterAppAuthConfigurationRequest {
       invite,
       config: config.clone(),
+      key_exchange_public: Some(key_exchange_public.to_vec()),
     };
 
     let signature = sign(&app_register_req, &keypair).unwrap();
@@ -394,6 +444,10 @@ mod tests {
 
     assert_eq!(app_register_res.author_dpub.as_slice(), &keypair.public);
 
+    let sealed_token_key = app_register_res.sealed_token_key.as_ref().unwrap();
+    let token_key = c3a_common::open_token_key(sealed_token_key, &key_exchange_secret, &config.app_name).unwrap();
+    assert_eq!(token_key.len(), 32);
+
     let app_info_req = GetAppAuthConfigurationRequest {
       app_name: config.app_name.to_owned(),
       author_dpub: keypair.public.to_vec(),
@@ -445,22 +499,26 @@ mod tests {
 
   #[tokio::test]
   async fn test_edit_app_info() {
-    let service = create_service("tests-3").await;
+    let (service, admin_keys) = create_service().await;
 
-    let invite_req = GenerateInvitationRequest {
-      private_admin_key_begin: *b"test-key-XXXXXXXXXXXXXXX",
-    };
+    let (invite_req, signature) = sign_test_request(
+      "admin",
+      "/apps/generate-invitation",
+      GenerateInvitationRequest::default(),
+      &admin_keys,
+    );
 
     let mut content = TestClient::post("http://0.0.0.0:5800/apps/generate-invitation")
       .add_header("Content-Type", "application/msgpack", true)
+      .add_header(c3a_common::SIGN_HEADER, signature.as_str(), true)
       .bytes(rmp_serde::to_vec(&invite_req).unwrap())
       .send(&service)
       .await;
 
     assert_eq!(content.status_code, Some(StatusCode::OK));
 
-    let invite = content.take_msgpack::<Vec<u8>>().await.unwrap();
-    assert_eq!(invite.len(), 1024);
+    let invite = content.take_msgpack::<GenerateInvitationResponse>().await.unwrap().invite;
+    assert_eq!(invite.len(), 47);
 
     let keypair = c3a_common::generate_dilithium_keypair();
     let config = c3a_common::AppAuthConfiguration {
@@ -505,11 +563,14 @@ mod tests {
       }),
       client_based_auth_opts: None,
       author_dpub: keypair.public.to_vec(),
+      email_templates: None,
+      hash_params: None,
     };
 
     let app_register_req = RegisterAppAuthConfigurationRequest {
       invite,
       config: config.clone(),
+      key_exchange_public: None,
     };
 
     let signature = sign(&app_register_req, &keypair).unwrap();
@@ -607,5 +668,30 @@ mod tests {
       .await;
 
     assert_eq!(content.status_code, Some(StatusCode::NOT_FOUND));
+
+    // Senders outside of the app's domain are refused.
+    for sender in ["C3A <no-reply@mail.verbalautomation.tech>", "not a mailbox"] {
+      let edit_info_req = EditAppAuthConfigurationRequest {
+        edit_app: String::from("test-app-02"),
+        email_templates: Some(c3a_common::EmailTemplates {
+          sender: Some(sender.to_owned()),
+          default_locale: String::from("en"),
+          locales: Default::default(),
+        }),
+        ..Default::default()
+      };
+
+      let signature = sign(&edit_info_req, &keypair).unwrap();
+      let signature = base64_encode(&signature);
+
+      let content = TestClient::patch("http://0.0.0.0:5800/apps/info")
+        .add_header("Content-Type", "application/msgpack", true)
+        .add_header(c3a_common::SIGN_HEADER, signature.as_str(), true)
+        .bytes(rmp_serde::to_vec(&edit_info_req).unwrap())
+        .send(&service)
+        .await;
+
+      assert_eq!(content.status_code, Some(StatusCode::BAD_REQUEST));
+    }
   }
 }
@@ -0,0 +1,145 @@
+//! Verification keys of C3A instance.
+//!
+//! C3A rotates its Dilithium5 signing keys (see `crate::core::signing_keys`). Application servers should
+//! verify tokens with every announced key, choosing it by `MPAATHeader::kid`.
+//!
+//! `/.well-known/c3a-keys` publishes the keys with their endorsements. Pin the key you got as `c3a_dpub`
+//! on the app registration, and follow the rotations with `c3a_common::follow_key_chain`.
+//!
+//! Retired keys are pruned with their endorsements on the next rotation, so the chain can be followed only from
+//! a key which is still published. Clients should refresh the key set and re-pin the active key at least once
+//! per `retire_after_hours` (see `SigningKeysOpts`); otherwise the app has to be re-registered to get a new pin.
+
+use c3a_common::{C3AKeySet, SigningKeysAnnouncement};
+use cc_server_kit::prelude::*;
+
+use crate::kv::extract_db;
+use crate::utils::sign_by_header;
+
+/// Announces current, upcoming and retiring verification keys.
+///
+/// The announcement is signed by the active key; check the signature with the key you already trust
+/// before accepting new keys.
+#[endpoint(
+  tags("keys"),
+  responses((
+    status_code = 200,
+    description = "Verification keys announcement",
+    body = SigningKeysAnnouncement,
+    content_type = "application/msgpack",
+    headers(("C3A-Sign" = String, description = "Dilithium5 response signature"))
+  ))
+)]
+#[instrument(skip_all, fields(http.uri = req.uri().path(), http.method = req.method().as_str()))]
+async fn announce_keys(
+  req: &mut Request,
+  res: &mut Response,
+  depot: &mut Depot,
+) -> MResult<MsgPack<SigningKeysAnnouncement>> {
+  let kv = extract_db(depot)?;
+  let now = chrono::Utc::now();
+  let keyring = kv.get_signing_keyring().await?;
+  let signing_key = keyring.active(kv.signer_backend().as_ref(), now)?;
+
+  let announcement = SigningKeysAnnouncement {
+    issued_at: now,
+    active_kid: signing_key.kid.to_owned(),
+    keys: keyring.verification_keys(now),
+  };
+  sign_by_header(res, &announcement, &signing_key)?;
+
+  msgpack!(announcement)
+}
+
+/// Publishes current, upcoming and retiring keys with endorsements by previous keys.
+#[endpoint(
+  tags("keys"),
+  responses((
+    status_code = 200,
+    description = "C3A key set",
+    body = C3AKeySet,
+    content_type = "application/msgpack",
+    headers(("C3A-Sign" = String, description = "Dilithium5 response signature"))
+  ))
+)]
+#[instrument(skip_all, fields(http.uri = req.uri().path(), http.method = req.method().as_str()))]
+async fn well_known_keys(req: &mut Request, res: &mut Response, depot: &mut Depot) -> MResult<MsgPack<C3AKeySet>> {
+  let kv = extract_db(depot)?;
+  let now = chrono::Utc::now();
+  let keyring = kv.get_signing_keyring().await?;
+
+  let key_set = C3AKeySet {
+    keys: keyring.published_keys(now),
+  };
+  sign_by_header(res, &key_set, &keyring.active(kv.signer_backend().as_ref(), now)?)?;
+
+  msgpack!(key_set)
+}
+
+/// Router to verification keys' API.
+pub(crate) fn keys_api() -> Router {
+  Router::new()
+    .push(Router::with_path("/keys/announcement").get(announce_keys))
+    .push(Router::with_path("/.well-known/c3a-keys").get(well_known_keys))
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::kv::KvDb;
+  use c3a_common::{C3AKeySet, SigningKeysAnnouncement, base64_decode, follow_key_chain, verify};
+  use cc_server_kit::test_exts::ResponseExt;
+  use salvo::affix_state;
+  use salvo::core::prelude::*;
+  use salvo::test::TestClient;
+
+  #[tokio::test]
+  async fn test_announcement_is_signed_by_active_key() {
+    let kv_db = KvDb::in_memory_unsealed().await.unwrap();
+    kv_db.initial_setup().await.unwrap();
+    crate::core::signing_keys::rotate_signing_key(&kv_db, chrono::Utc::now(), &Default::default())
+      .await
+      .unwrap();
+
+    let router = Router::new()
+      .hoop(affix_state::inject(kv_db.clone()))
+      .push(super::keys_api());
+    let service = Service::new(router);
+
+    let mut content = TestClient::get("http://0.0.0.0:5800/keys/announcement").send(&service).await;
+    assert_eq!(content.status_code, Some(StatusCode::OK));
+    let sign = base64_decode(content.headers().get(c3a_common::SIGN_HEADER).unwrap().to_str().unwrap()).unwrap();
+    let announcement = content.take_msgpack::<SigningKeysAnnouncement>().await.unwrap();
+
+    let active = kv_db.get_signing_key().await.unwrap();
+    assert_eq!(announcement.active_kid, active.kid);
+    assert!(announcement.keys.len() >= 2);
+    assert!(verify(&announcement, &sign, active.public()).unwrap());
+  }
+
+  #[tokio::test]
+  async fn test_well_known_keys_follow_rotations() {
+    let kv_db = KvDb::in_memory_unsealed().await.unwrap();
+    kv_db.initial_setup().await.unwrap();
+    let pinned = kv_db.get_signing_key().await.unwrap().public().to_vec();
+    let (kid, _) = crate::core::signing_keys::rotate_signing_key(
+      &kv_db,
+      chrono::Utc::now() + chrono::TimeDelta::hours(1),
+      &Default::default(),
+    )
+    .await
+    .unwrap();
+
+    let router = Router::new()
+      .hoop(affix_state::inject(kv_db.clone()))
+      .push(super::keys_api());
+    let service = Service::new(router);
+
+    let mut content = TestClient::get("http://0.0.0.0:5800/.well-known/c3a-keys").send(&service).await;
+    assert_eq!(content.status_code, Some(StatusCode::OK));
+    let key_set = content.take_msgpack::<C3AKeySet>().await.unwrap();
+
+    // The upcoming key is published and trusted through the pinned one.
+    let trusted = follow_key_chain(&pinned, &key_set).unwrap();
+    assert!(trusted.iter().any(|key| key.kid == kid && key.activates_at > chrono::Utc::now()));
+  }
+}
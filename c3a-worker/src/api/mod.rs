@@ -1,2 +1,5 @@
+pub(crate) mod admin;
 pub(crate) mod applications;
+pub(crate) mod keys;
+pub(crate) mod unseal;
 pub(crate) mod users;
@@ -0,0 +1,147 @@
+//! Unsealing API, see `crate::kv::unseal`.
+
+use c3a_common::{UnsealRequest, UnsealStatusResponse};
+use cc_server_kit::prelude::*;
+use salvo::prelude::{FlowCtrl, StatusCode, Text};
+
+use crate::kv::extract_db;
+use crate::kv::unseal::extract_unsealer;
+
+/// Paths served while the storage is sealed.
+const SEALED_PATHS: [&str; 2] = ["/health-check", "/unseal"];
+
+/// Rejects any request but health checks and unsealing while the storage is sealed.
+#[handler]
+pub(crate) async fn sealed_guard(req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+  if let Ok(kv) = extract_db(depot)
+    && kv.is_sealed()
+    && !SEALED_PATHS.contains(&req.uri().path())
+  {
+    res.status_code(StatusCode::SERVICE_UNAVAILABLE);
+    res.render(Text::Plain("C3A instance is sealed."));
+    ctrl.skip_rest();
+  }
+}
+
+/// Returns whether the storage is sealed and how many shares are submitted.
+#[endpoint(
+  tags("maintenance"),
+  responses((
+    status_code = 200,
+    description = "Unseal status",
+    body = UnsealStatusResponse,
+    content_type = "application/msgpack"
+  ))
+)]
+#[instrument(skip_all, fields(http.uri = req.uri().path(), http.method = req.method().as_str()))]
+async fn unseal_status(req: &mut Request, depot: &mut Depot) -> MResult<MsgPack<UnsealStatusResponse>> {
+  let kv = extract_db(depot)?;
+  let unsealer = extract_unsealer(depot)?;
+  msgpack!(unsealer.status(&kv).await)
+}
+
+/// Accepts a share of the master key.
+///
+/// You should send `UnsealRequest` as MessagePack inside request body. Only shares matching the configured
+/// digests are accepted. When the threshold is reached, the storage is unsealed; if the shares don't match
+/// the master key, they are discarded.
+#[endpoint(
+  tags("maintenance"),
+  responses((
+    status_code = 200,
+    description = "Unseal status",
+    body = UnsealStatusResponse,
+    content_type = "application/msgpack"
+  ))
+)]
+#[instrument(skip_all, fields(http.uri = req.uri().path(), http.method = req.method().as_str()))]
+async fn submit_share(req: &mut Request, depot: &mut Depot) -> MResult<MsgPack<UnsealStatusResponse>> {
+  let request = req.parse_msgpack::<UnsealRequest>().await?;
+  let kv = extract_db(depot)?;
+  let unsealer = extract_unsealer(depot)?;
+  msgpack!(unsealer.submit(&kv, &request.share).await?)
+}
+
+/// Discards submitted shares, e.g. when some of them belong to another master key.
+///
+/// You should send `UnsealRequest` with one of the configured shares as MessagePack inside request body.
+#[endpoint(
+  tags("maintenance"),
+  responses((
+    status_code = 200,
+    description = "Unseal status",
+    body = UnsealStatusResponse,
+    content_type = "application/msgpack"
+  ))
+)]
+#[instrument(skip_all, fields(http.uri = req.uri().path(), http.method = req.method().as_str()))]
+async fn reset_shares(req: &mut Request, depot: &mut Depot) -> MResult<MsgPack<UnsealStatusResponse>> {
+  let request = req.parse_msgpack::<UnsealRequest>().await?;
+  let kv = extract_db(depot)?;
+  let unsealer = extract_unsealer(depot)?;
+  msgpack!(unsealer.reset(&kv, &request.share).await?)
+}
+
+/// Router to unsealing API.
+pub(crate) fn unseal_api() -> Router {
+  Router::with_path("/unseal")
+    .get(unseal_status)
+    .post(submit_share)
+    .delete(reset_shares)
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::kv::KvDb;
+  use crate::kv::unseal::{UnsealMode, Unsealer, share_digest, split_master_key};
+  use c3a_common::{UnsealRequest, UnsealStatusResponse};
+  use cc_server_kit::test_exts::ResponseExt;
+  use salvo::affix_state;
+  use salvo::core::prelude::*;
+  use salvo::test::TestClient;
+
+  #[tokio::test]
+  async fn test_sealed_instance_serves_only_unseal() {
+    let kv_db = KvDb::in_memory_unsealed().await.unwrap();
+    kv_db.initial_setup().await.unwrap();
+    kv_db.reseal();
+
+    let shares = split_master_key(b"test-master-key-XXXXXXXXXXXXXXXXXXXXXXXX", 2, 2).unwrap();
+    let unsealer = Unsealer::new(&UnsealMode::Shamir {
+      threshold: 2,
+      share_digests: shares.iter().map(|share| share_digest(share)).collect(),
+    })
+    .unwrap();
+
+    let router = Router::new()
+      .hoop(affix_state::inject(kv_db.clone()).inject(unsealer))
+      .hoop(super::sealed_guard)
+      .push(super::unseal_api())
+      .push(crate::api::keys::keys_api());
+    let service = Service::new(router);
+
+    let content = TestClient::get("http://0.0.0.0:5800/keys/announcement").send(&service).await;
+    assert_eq!(content.status_code, Some(StatusCode::SERVICE_UNAVAILABLE));
+
+    let content = TestClient::post("http://0.0.0.0:5800/unseal")
+      .add_header("Content-Type", "application/msgpack", true)
+      .bytes(rmp_serde::to_vec(&UnsealRequest { share: vec![1, 2, 3] }).unwrap())
+      .send(&service)
+      .await;
+    assert_eq!(content.status_code, Some(StatusCode::FORBIDDEN));
+
+    for share in shares.iter() {
+      let content = TestClient::post("http://0.0.0.0:5800/unseal")
+        .add_header("Content-Type", "application/msgpack", true)
+        .bytes(rmp_serde::to_vec(&UnsealRequest { share: share.to_vec() }).unwrap())
+        .send(&service)
+        .await;
+      assert_eq!(content.status_code, Some(StatusCode::OK));
+    }
+
+    let mut content = TestClient::get("http://0.0.0.0:5800/unseal").send(&service).await;
+    assert!(!content.take_msgpack::<UnsealStatusResponse>().await.unwrap().sealed);
+    let content = TestClient::get("http://0.0.0.0:5800/keys/announcement").send(&service).await;
+    assert_eq!(content.status_code, Some(StatusCode::OK));
+  }
+}
@@ -1,22 +1,34 @@
 use c3a_common::{
-  AuthenticationData, RegisterUserRequest, RegistrationRequirementsResponse, UserData, deploy_lmpaat,
-  lmpaat_extract_payload, validate_identifier,
+  AuthenticationData, PasswordPolicyViolationsResponse, RegisterUserRequest, RegisterUserResponse,
+  RegistrationRequirementsResponse, TokenUsageType, UserAccessClaims, UserData, VerifyUserSecretRequest,
+  validate_identifier,
 };
 use cc_server_kit::prelude::*;
-use lettre::AsyncTransport;
+use salvo::prelude::StatusCode;
 use serde::{Deserialize, Serialize};
 
-use crate::core::user_registration_checks::validate_authentication_flows;
 use crate::Setup;
+use crate::core::auth_states::{StateKind, consume_state_id, open_state, register_state_id, seal_state};
+use crate::core::bans;
 use crate::core::user_preregistration_inspects::{gen_email_requirement, gen_totp_requirement, gen_u2f_requirement};
-use crate::kv::{KvDb, extract_db};
-use crate::mailer::extract_mailer;
-use crate::utils::{sign_by_header, take_exp_from_duration};
+use crate::core::password_policy::extract_breached_passwords;
+use crate::core::peppers::Peppers;
+use crate::core::tokens::issue_token;
+use crate::core::user_authentication_checks::{invalid_credentials, verify_user_secret};
+use crate::core::user_registration_checks::{FlowRejection, FlowValidationContext, validate_authentication_flows};
+use crate::keys::KeyPurpose;
+use crate::kv::extract_db;
+use crate::mailer::queue::extract_mail_queue;
+use crate::mailer::templates::{DEFAULT_SENDER, EmailContext, EmailRenderer};
+use crate::utils::{app_hash_params, client_ip, sign_by_header, take_exp_from_duration, write_msgpack};
+
+const REGISTRATION_STATE_TTL: chrono::TimeDelta = chrono::TimeDelta::minutes(10);
+const ACCESS_TOKEN_TTL: chrono::TimeDelta = chrono::TimeDelta::hours(1);
 
 #[derive(Deserialize, Serialize)]
 pub(crate) struct RegistrationStatePayload {
-  requested_identifier: String,
-  metadata: Vec<AuthenticationData>,
+  pub(crate) requested_identifier: String,
+  pub(crate) metadata: Vec<AuthenticationData>,
 }
 
 /// Application server's method.
@@ -35,15 +47,18 @@ async fn get_authentication_flow_to_register(
   struct AuthFlowQuery {
     app_name: String,
     identifier: String,
+    /// User's locale for emails, e.g. `en` or `pt-BR`.
+    #[serde(default)]
+    locale: Option<String>,
   }
 
   let query = req.parse_msgpack::<AuthFlowQuery>().await?;
   let kv = extract_db(depot)?;
-  let mailer = extract_mailer(depot)?;
-  let keypair = kv.get_dilithium_keypair().await?;
+  let mail_queue = extract_mail_queue(depot)?;
+  let signing_key = kv.get_signing_key().await?;
   let c3a_state = depot.obtain::<Setup>()?;
 
-  if kv.exists(&KvDb::user(&query.identifier)).await? {
+  if kv.user_exists(&query.app_name, &query.identifier).await? {
     return Err(ErrorResponse::from("User already exists.").with_403_pub().build());
   }
 
@@ -60,10 +75,23 @@ async fn get_authentication_flow_to_register(
       .map_err(|e| ErrorResponse::from(e.to_string()).with_500_pub().build())?,
   }
 
+  let peppers = Peppers::load(&kv, c3a_state).await?;
   let mut metadata = vec![];
   let mut inspect_err = Ok(());
   let mut mail_to_send = None;
 
+  let renderer = EmailRenderer {
+    templates: app_conf.email_templates.as_ref(),
+    default_sender: c3a_state.mail_sender.as_deref().unwrap_or(DEFAULT_SENDER),
+    locale: query.locale.as_deref(),
+  };
+  let email_ctx = EmailContext {
+    app_name: &app_conf.app_name,
+    identifier: &query.identifier,
+    code: None,
+    expires_in: REGISTRATION_STATE_TTL,
+  };
+
   let resp = RegistrationRequirementsResponse {
     allowed_authentication_flow: app_conf
       .allow_sign_up
@@ -77,7 +105,10 @@ async fn get_authentication_flow_to_register(
         if let Err(e) = gen_email_requirement(
           method,
           &query.identifier,
-          &c3a_state.private_adm_key.as_ref().unwrap().as_bytes()[24..=48],
+          &peppers,
+          &app_hash_params(c3a_state, &app_conf),
+          &renderer,
+          &email_ctx,
           &mut metadata,
           &mut mail_to_send,
         ) {
@@ -99,10 +130,9 @@ async fn get_authentication_flow_to_register(
 
   inspect_err?;
   if let Some(email) = mail_to_send {
-    mailer
-      .send(email)
-      .await
-      .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+    mail_queue
+      .enqueue(&app_conf.app_name, email, renderer.has_app_sender())
+      .await?;
   }
 
   let registration_state = RegistrationStatePayload {
@@ -110,23 +140,47 @@ async fn get_authentication_flow_to_register(
     requested_identifier: query.identifier.to_owned(),
   };
 
-  let lmpaat = deploy_lmpaat(
+  let sealed = seal_state(
+    StateKind::Registration,
+    &app_conf.app_name,
     registration_state,
-    None::<()>,
-    take_exp_from_duration(chrono::TimeDelta::minutes(10))?,
+    REGISTRATION_STATE_TTL,
+    kv.get_keys().await?.derive(KeyPurpose::RegistrationState).as_slice(),
     &app_conf.author_dpub,
-    &keypair,
-  )
-  .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+    &signing_key,
+  )?;
+  register_state_id(&kv, &sealed).await?;
 
-  sign_by_header(res, &resp, &keypair)?;
-  res.add_header(c3a_common::PREREGISTER_HEADER, lmpaat, true)?;
+  sign_by_header(res, &resp, &signing_key)?;
+  res.add_header(c3a_common::PREREGISTER_HEADER, sealed.token, true)?;
 
   msgpack!(resp)
 }
 
 /// Register a new user.
-#[endpoint(tags("users"))]
+///
+/// Returns the user's access token (see `c3a_common::UserAccessClaims`) in the body or as `C3A-Access` cookie,
+/// as requested by `token_request_type`. The token is signed by C3A and bound to the app's author key, so the
+/// cookie isn't signed separately. If the password violates the app's policy, returns `400` with
+/// `PasswordPolicyViolationsResponse`.
+#[endpoint(
+  tags("users"),
+  responses(
+    (
+      status_code = 200,
+      description = "User is registered",
+      body = RegisterUserResponse,
+      content_type = ["application/msgpack"],
+      headers(("C3A-Sign" = String, description = "Dilithium5 response signature"))
+    ),
+    (
+      status_code = 400,
+      description = "Password violates the app's policy",
+      body = PasswordPolicyViolationsResponse,
+      content_type = ["application/msgpack"]
+    )
+  )
+)]
 #[instrument(skip_all, fields(http.uri = req.uri().path(), http.method = req.method().as_str()))]
 async fn register(depot: &mut Depot, req: &mut Request, res: &mut Response) -> MResult<()> {
   let register_request = req.parse_msgpack::<RegisterUserRequest>().await?;
@@ -135,28 +189,134 @@ async fn register(depot: &mut Depot, req: &mut Request, res: &mut Response) -> M
   )?;
 
   let kv = extract_db(depot)?;
-  let keypair = kv.get_dilithium_keypair().await?;
+  let breached = extract_breached_passwords(depot)?;
+  let verification_keys = kv.get_verification_keys().await?;
+  let c3a_state = depot.obtain::<Setup>()?;
   
   let app_conf = kv.get_app_conf(&register_request.app_name).await?;
   if app_conf.allow_sign_up.is_none() {
     return Err(ErrorResponse::from("Operation is not permitted by application administrator.").with_403_pub().build())
   }
   
-  let registration_state =
-    lmpaat_extract_payload::<RegistrationStatePayload, ()>(&registration_state, &keypair, chrono::Utc::now())
-      .map_err(|e| ErrorResponse::from(e.to_string()).with_400_pub().with_text("No provided registration state!").build())?;
+  let (registration_state, state_id) = open_state::<RegistrationStatePayload>(
+    &registration_state,
+    StateKind::Registration,
+    &app_conf.app_name,
+    kv.get_keys().await?.derive(KeyPurpose::RegistrationState).as_slice(),
+    &verification_keys,
+  )?;
+  let peppers = Peppers::load(&kv, c3a_state).await?;
+
+  let identifier = registration_state.requested_identifier.to_owned();
+  let ban_subjects = bans::subjects(&app_conf, &identifier, client_ip(req, c3a_state));
+  bans::check_bans(&kv, &app_conf.app_name, &ban_subjects).await?;
 
+  let authentication_flows = match validate_authentication_flows(
+    registration_state,
+    &register_request.authentication_flows,
+    app_conf.allow_sign_up.as_ref().unwrap(),
+    &FlowValidationContext {
+      app_name: &app_conf.app_name,
+      peppers: &peppers,
+      hash_params: app_hash_params(c3a_state, &app_conf),
+      breached: &breached,
+    },
+  ) {
+    Ok(authentication_flows) => authentication_flows,
+    Err(FlowRejection::PasswordPolicy(violations)) => {
+      return write_msgpack(
+        res,
+        StatusCode::BAD_REQUEST,
+        &PasswordPolicyViolationsResponse { violations },
+      );
+    }
+    Err(FlowRejection::WrongSecret) => {
+      bans::register_failure(&kv, &app_conf, &ban_subjects).await?;
+      return Err(invalid_credentials());
+    }
+    Err(FlowRejection::Error(e)) => return Err(e),
+  };
   let user_data = UserData {
-    identifier: registration_state.requested_identifier.to_owned(),
-    authentication_flows: validate_authentication_flows(
-      registration_state,
-      &register_request.authentication_flows,
-      app_conf.allow_sign_up.as_ref().unwrap(),
-    )?,
+    identifier,
+    authentication_flows,
   };
-  
-  
-  
 
-  Ok(())
+  // Consumed before the user is written, so concurrent requests with the same state can't both pass.
+  consume_state_id(&kv, &state_id).await?;
+  kv.insert_user(&app_conf.app_name, &user_data).await?;
+
+  let signing_key = kv.get_signing_key().await?;
+  let expires_at = take_exp_from_duration(ACCESS_TOKEN_TTL)?;
+  let access_token = issue_token(
+    &app_conf,
+    &kv.get_keys().await?,
+    UserAccessClaims {
+      app_name: app_conf.app_name.to_owned(),
+      identifier: user_data.identifier,
+      tags: app_conf.allow_sign_up.as_ref().unwrap().auto_assign_tags.to_owned(),
+    },
+    None::<()>,
+    expires_at,
+    &app_conf.author_dpub,
+    &signing_key,
+  )?;
+
+  let resp = match register_request.token_request_type {
+    TokenUsageType::Cookie => {
+      res.add_header(
+        "Set-Cookie",
+        format!(
+          "{}={access_token}; Max-Age={}; Path=/; Secure; HttpOnly; SameSite=Strict",
+          c3a_common::ACCESS_TOKEN,
+          ACCESS_TOKEN_TTL.num_seconds()
+        ),
+        true,
+      )?;
+      RegisterUserResponse {
+        access_token: None,
+        expires_at,
+      }
+    }
+    TokenUsageType::ResponseBody => RegisterUserResponse {
+      access_token: Some(access_token),
+      expires_at,
+    },
+  };
+  sign_by_header(res, &resp, &signing_key)?;
+
+  write_msgpack(res, StatusCode::OK, &resp)
+}
+
+/// Application server's method.
+///
+/// Checks the user's password or answer to a question, e.g. on the application's sign-in form. Secrets hashed
+/// with outdated Argon2 costs or pepper are rehashed with the current ones. Wrong secrets are counted by the app's
+/// fail-to-ban options.
+#[endpoint(tags("users"))]
+#[instrument(skip_all, fields(http.uri = req.uri().path(), http.method = req.method().as_str()))]
+async fn verify_secret(depot: &mut Depot, req: &mut Request) -> MResult<OK> {
+  let verify_request = req.parse_msgpack::<VerifyUserSecretRequest>().await?;
+  let kv = extract_db(depot)?;
+  let c3a_state = depot.obtain::<Setup>()?;
+
+  let app_conf = kv.get_app_conf(&verify_request.app_name).await?;
+  let ban_subjects = bans::subjects(&app_conf, &verify_request.login, client_ip(req, c3a_state));
+  bans::check_bans(&kv, &app_conf.app_name, &ban_subjects).await?;
+
+  let peppers = Peppers::load(&kv, c3a_state).await?;
+  if !verify_user_secret(
+    &kv,
+    &app_conf.app_name,
+    &verify_request.login,
+    &verify_request.secret,
+    &peppers,
+    &app_hash_params(c3a_state, &app_conf),
+  )
+  .await?
+  {
+    bans::register_failure(&kv, &app_conf, &ban_subjects).await?;
+    return Err(invalid_credentials());
+  }
+
+  ok!()
 }
@@ -0,0 +1,254 @@
+//! Administrators of C3A instance.
+//!
+//! Admins are registered with their Dilithium5 public keys and roles. Every admin's request is an `AdminRequest`
+//! signed by the admin's key; it's accepted only by the method it's issued for, only within `REQUEST_LIFETIME_SECS`
+//! after it's issued and only once, so a captured request can't be replayed.
+//!
+//! The first admin is registered by `/admin/bootstrap` with a proof of knowledge of `C3A_PRIVATE_ADM_KEY`; the key
+//! itself is never sent, since a part of it is the legacy pepper. Other admins are added by owners.
+
+use c3a_common::{AdminInfo, AdminRequest, AdminRole};
+use cc_server_kit::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::Setup;
+use crate::core::audit::{self, AuditAction};
+use crate::kv::KvDb;
+use crate::utils::verify_sign_by_header;
+
+/// How long a request is accepted after it's issued; clocks of the admin and the instance may differ as much.
+const REQUEST_LIFETIME_SECS: i64 = 300;
+const NONCE_LENGTH: std::ops::RangeInclusive<usize> = 16..=64;
+/// Requests of unknown admins are verified with this key; no signature is valid for it.
+const UNKNOWN_ADMIN_KEY: [u8; c3a_common::PUBLICKEYBYTES] = [0; c3a_common::PUBLICKEYBYTES];
+
+#[derive(Deserialize, Serialize, Default)]
+pub(crate) struct Admins {
+  admins: Vec<AdminInfo>,
+}
+
+impl Admins {
+  pub(crate) async fn load(kv: &KvDb) -> MResult<Self> {
+    Ok(kv.get_sealed::<Admins>(KvDb::ADMINS).await?.unwrap_or_default())
+  }
+
+  pub(crate) fn list(&self) -> &[AdminInfo] {
+    &self.admins
+  }
+
+  fn find(&self, name: &str) -> Option<&AdminInfo> {
+    self.admins.iter().find(|admin| admin.name.eq(name))
+  }
+}
+
+/// Nonces of accepted requests by the moment they may be forgotten.
+#[derive(Deserialize, Serialize, Default)]
+struct AdminNonces {
+  nonces: HashMap<Vec<u8>, chrono::DateTime<chrono::Utc>>,
+}
+
+fn unauthorized(msg: &str) -> ErrorResponse {
+  ErrorResponse::from(msg).with_401_pub().build()
+}
+
+/// Checks the signature by `public`, the method and the time of the request, then spends its nonce.
+async fn verify_request<T: Serialize>(
+  req: &mut Request,
+  kv: &KvDb,
+  request: &AdminRequest<T>,
+  public: &[u8],
+) -> MResult<()> {
+  if request.path.ne(req.uri().path()) {
+    return Err(unauthorized("The request is issued for another method."));
+  }
+  let now = chrono::Utc::now();
+  let lifetime = chrono::TimeDelta::seconds(REQUEST_LIFETIME_SECS);
+  if request.issued_at < now - lifetime || request.issued_at > now + lifetime {
+    return Err(unauthorized("The request is expired or issued in the future."));
+  }
+  if !NONCE_LENGTH.contains(&request.nonce.len()) {
+    return Err(unauthorized("Invalid request nonce."));
+  }
+  if public.len() != c3a_common::PUBLICKEYBYTES {
+    return Err(unauthorized("Invalid admin's public key."));
+  }
+  verify_sign_by_header(req, request, public)?;
+
+  kv.update::<AdminNonces, _, _>(KvDb::ADMIN_NONCES, |nonces| {
+    let nonces = &mut nonces.get_or_insert_default().nonces;
+    nonces.retain(|_, forget_at| *forget_at > now);
+    if nonces.contains_key(&request.nonce) {
+      return Err(unauthorized("The request is already accepted."));
+    }
+    nonces.insert(request.nonce.clone(), request.issued_at + lifetime);
+    Ok(())
+  })
+  .await
+}
+
+/// Authenticates the admin's request and checks that the admin has at least `role`.
+pub(crate) async fn check_admin<T: Serialize>(
+  req: &mut Request,
+  kv: &KvDb,
+  request: &AdminRequest<T>,
+  role: AdminRole,
+) -> MResult<AdminInfo> {
+  let admins = Admins::load(kv).await?;
+  let admin = admins.find(&request.admin);
+  // Unknown names are refused after the same checks as a wrong signature, so they can't be told apart.
+  let public = admin.map_or(UNKNOWN_ADMIN_KEY.as_slice(), |admin| admin.public.as_slice());
+  verify_request(req, kv, request, public).await?;
+  let admin = admin.ok_or(unauthorized("Signature is invalid."))?;
+
+  if admin.role < role {
+    return Err(
+      ErrorResponse::from(format!("This method requires `{role:?}` role."))
+        .with_403_pub()
+        .build(),
+    );
+  }
+  Ok(admin.clone())
+}
+
+/// Registers the first admin as an owner; the request is signed by the new admin's key and proves knowledge of
+/// `C3A_PRIVATE_ADM_KEY` with `c3a_common::bootstrap_key_proof`.
+pub(crate) async fn bootstrap_admin(
+  req: &mut Request,
+  kv: &KvDb,
+  setup: &Setup,
+  request: &AdminRequest<c3a_common::BootstrapAdminRequest>,
+) -> MResult<()> {
+  let private_adm_key = setup.private_adm_key.as_ref().unwrap().as_bytes();
+  let key_proof = c3a_common::bootstrap_key_proof(private_adm_key, request)
+    .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+  if !constant_time_eq::constant_time_eq(&key_proof, &request.body.key_proof) {
+    return Err(unauthorized("Invalid authentication request"));
+  }
+  verify_request(req, kv, request, &request.body.public).await?;
+
+  kv.update_sealed::<Admins, _, _>(KvDb::ADMINS, |admins| {
+    let admins = admins.get_or_insert_default();
+    if !admins.admins.is_empty() {
+      return Err(
+        ErrorResponse::from("Admins are registered already.")
+          .with_403_pub()
+          .build(),
+      );
+    }
+    admins.admins.push(AdminInfo {
+      name: request.admin.clone(),
+      role: AdminRole::Owner,
+      public: request.body.public.clone(),
+      added_at: chrono::Utc::now(),
+      added_by: None,
+    });
+    Ok(())
+  })
+  .await?;
+
+  tracing::info!("The first admin `{}` is registered.", request.admin);
+  audit::record(kv, Some(request.admin.as_str()), AuditAction::BootstrapAdmin).await
+}
+
+pub(crate) async fn add_admin(kv: &KvDb, added_by: &str, name: &str, role: AdminRole, public: &[u8]) -> MResult<()> {
+  if name.is_empty() || public.len() != c3a_common::PUBLICKEYBYTES {
+    return Err(
+      ErrorResponse::from("Admin should have a name and a Dilithium5 public key.")
+        .with_400_pub()
+        .build(),
+    );
+  }
+
+  kv.update_sealed::<Admins, _, _>(KvDb::ADMINS, |admins| {
+    let admins = admins.get_or_insert_default();
+    if admins.find(name).is_some() {
+      return Err(
+        ErrorResponse::from(format!("Admin `{name}` already exists."))
+          .with_400_pub()
+          .build(),
+      );
+    }
+    admins.admins.push(AdminInfo {
+      name: name.to_owned(),
+      role,
+      public: public.to_vec(),
+      added_at: chrono::Utc::now(),
+      added_by: Some(added_by.to_owned()),
+    });
+    Ok(())
+  })
+  .await?;
+
+  tracing::info!("Admin `{}` is added by `{}` as {:?}.", name, added_by, role);
+  audit::record(
+    kv,
+    Some(added_by),
+    AuditAction::AddAdmin {
+      name: name.to_owned(),
+      role,
+    },
+  )
+  .await
+}
+
+/// Removes the admin; the last owner can't be removed.
+pub(crate) async fn remove_admin(kv: &KvDb, removed_by: &str, name: &str) -> MResult<()> {
+  kv.update_sealed::<Admins, _, _>(KvDb::ADMINS, |admins| {
+    let admins = admins.get_or_insert_default();
+    if admins.find(name).is_none() {
+      return Err(ErrorResponse::from("There is no such admin.").with_404_pub().build());
+    }
+    admins.admins.retain(|admin| admin.name.ne(name));
+    if !admins.admins.iter().any(|admin| admin.role == AdminRole::Owner) {
+      return Err(
+        ErrorResponse::from("The last owner can't be removed.")
+          .with_400_pub()
+          .build(),
+      );
+    }
+    Ok(())
+  })
+  .await?;
+
+  tracing::info!("Admin `{}` is removed by `{}`.", name, removed_by);
+  audit::record(kv, Some(removed_by), AuditAction::RemoveAdmin { name: name.to_owned() }).await
+}
+
+/// Registers the admin and returns its keys.
+#[cfg(test)]
+pub(crate) async fn register_test_admin(kv: &KvDb, name: &str, role: AdminRole) -> c3a_common::Keypair {
+  let keypair = c3a_common::generate_dilithium_keypair();
+  kv.update_sealed::<Admins, _, _>(KvDb::ADMINS, |admins| {
+    admins.get_or_insert_default().admins.push(AdminInfo {
+      name: name.to_owned(),
+      role,
+      public: keypair.public.to_vec(),
+      added_at: chrono::Utc::now(),
+      added_by: None,
+    });
+    Ok(())
+  })
+  .await
+  .unwrap();
+  keypair
+}
+
+/// Returns the request and its `C3A-Sign` header.
+#[cfg(test)]
+pub(crate) fn sign_test_request<T: Serialize>(
+  name: &str,
+  path: &str,
+  body: T,
+  keypair: &c3a_common::Keypair,
+) -> (AdminRequest<T>, String) {
+  let request = AdminRequest {
+    admin: name.to_owned(),
+    path: path.to_owned(),
+    issued_at: chrono::Utc::now(),
+    nonce: c3a_common::generate::<32>().to_vec(),
+    body,
+  };
+  let signature = c3a_common::base64_encode(&c3a_common::sign(&request, keypair).unwrap());
+  (request, signature)
+}
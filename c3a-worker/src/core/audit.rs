@@ -0,0 +1,70 @@
+//! Audit trail of admins' actions.
+//!
+//! Records are written after the action succeeds and are never changed; restores from backups keep the current
+//! trail along with the archived one (see `crate::kv::backup`).
+
+use c3a_common::{AdminRole, BanSubject};
+use cc_server_kit::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::kv::KvDb;
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub(crate) enum AuditAction {
+  BootstrapAdmin,
+  AddAdmin { name: String, role: AdminRole },
+  RemoveAdmin { name: String },
+  GenerateInvitation { id: String },
+  RevokeInvitation { id: String },
+  LiftBan { app_name: String, subject: BanSubject },
+  RotatePepper { version: u32 },
+  RotateSigningKey { kid: String },
+  Seal,
+  Backup,
+  Restore { records: usize },
+  Rekey,
+}
+
+#[derive(Deserialize, Serialize)]
+pub(crate) struct AuditRecord {
+  pub(crate) at: chrono::DateTime<chrono::Utc>,
+  /// Not set for commands of the worker's CLI.
+  pub(crate) admin: Option<String>,
+  pub(crate) action: AuditAction,
+}
+
+pub(crate) async fn record(kv: &KvDb, admin: Option<&str>, action: AuditAction) -> MResult<()> {
+  let at = chrono::Utc::now();
+  kv.insert(
+    &KvDb::audit(at),
+    &AuditRecord {
+      at,
+      admin: admin.map(str::to_owned),
+      action,
+    },
+  )
+  .await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{AuditAction, AuditRecord, record};
+  use crate::kv::KvDb;
+
+  #[tokio::test]
+  async fn test_records_are_ordered_by_time() {
+    let kv = KvDb::in_memory();
+    record(&kv, Some("alice"), AuditAction::RotatePepper { version: 2 })
+      .await
+      .unwrap();
+    record(&kv, None, AuditAction::Backup).await.unwrap();
+
+    let records = kv.scan_prefix::<AuditRecord>(KvDb::AUDIT_PREFIX).await.unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].1.admin.as_deref(), Some("alice"));
+    assert_eq!(records[0].1.action, AuditAction::RotatePepper { version: 2 });
+    assert!(records[1].1.admin.is_none());
+    assert!(records[0].1.at <= records[1].1.at);
+  }
+}
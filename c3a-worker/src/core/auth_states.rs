@@ -0,0 +1,241 @@
+//! Encrypted authentication states.
+//!
+//! Registration and login are two-phase operations: the worker generates some secret data (TOTP secrets,
+//! U2F challenges, email code hashes) on the first call and checks user's answers on the second one.
+//! Between these calls the data lives on the client side as an encrypted MPAAT, so nobody except the worker
+//! can read it.
+//!
+//! Every state is bound to the application and has its own one-time identifier. The identifier is stored
+//! in `KvDb` on issuance and consumed before the operation's result is written, so the state can't be replayed.
+//! Identifiers of states which were never completed are removed by `run_state_sweeper` after they expire.
+
+use c3a_common::{VerificationKey, deploy_mpaat_with_signer, mpaat_extract_payload_with_keys};
+use cc_server_kit::prelude::*;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::core::signing_keys::SigningKey;
+use crate::kv::KvDb;
+use crate::utils::take_exp_from_duration;
+
+const STATE_SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(600);
+
+#[derive(Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum StateKind {
+  Registration,
+  #[allow(dead_code)]
+  Login,
+}
+
+#[derive(Deserialize, Serialize)]
+struct BoundState<T> {
+  kind: StateKind,
+  app_name: String,
+  state_id: Vec<u8>,
+  state: T,
+}
+
+/// Sealed state ready to be sent to the client.
+pub(crate) struct SealedState {
+  pub(crate) token: String,
+  pub(crate) state_id: Vec<u8>,
+  pub(crate) exp: chrono::DateTime<chrono::Utc>,
+}
+
+/// Encrypts the state and binds it to the given application.
+pub(crate) fn seal_state<T: Serialize>(
+  kind: StateKind,
+  app_name: &str,
+  state: T,
+  ttl: chrono::TimeDelta,
+  state_key: &[u8],
+  client_public: &[u8],
+  signing_key: &SigningKey,
+) -> MResult<SealedState> {
+  let state_id = c3a_common::generate::<32>().to_vec();
+  let exp = take_exp_from_duration(ttl)?;
+
+  let bound = BoundState {
+    kind,
+    app_name: app_name.to_owned(),
+    state_id: state_id.clone(),
+    state,
+  };
+
+  let token = deploy_mpaat_with_signer(
+    bound,
+    None::<()>,
+    exp,
+    client_public,
+    Some(state_key),
+    signing_key.public(),
+    Some(&signing_key.kid),
+    |data| signing_key.signer.sign(data),
+  )
+  .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+
+  Ok(SealedState { token, state_id, exp })
+}
+
+/// Decrypts the state and checks that it was issued for the given application.
+///
+/// Returns the state and its one-time identifier.
+pub(crate) fn open_state<T: DeserializeOwned>(
+  token: &str,
+  kind: StateKind,
+  app_name: &str,
+  state_key: &[u8],
+  keys: &[VerificationKey],
+) -> MResult<(T, Vec<u8>)> {
+  let bound = mpaat_extract_payload_with_keys::<BoundState<T>, ()>(token, Some(state_key), keys, chrono::Utc::now())
+    .map_err(|e| {
+      ErrorResponse::from(e.to_string())
+        .with_400_pub()
+        .with_text("Invalid authentication state!")
+        .build()
+    })?;
+
+  if bound.kind != kind || bound.app_name.as_str().ne(app_name) {
+    return Err(
+      ErrorResponse::from("Authentication state is issued for another operation or application.")
+        .with_400_pub()
+        .build(),
+    );
+  }
+
+  Ok((bound.state, bound.state_id))
+}
+
+/// Remembers the one-time identifier of the issued state.
+pub(crate) async fn register_state_id(kv: &KvDb, sealed: &SealedState) -> MResult<()> {
+  kv.insert(&KvDb::state(&sealed.state_id), &sealed.exp).await
+}
+
+/// Consumes the one-time identifier; only one of concurrent requests with the same state succeeds.
+pub(crate) async fn consume_state_id(kv: &KvDb, state_id: &[u8]) -> MResult<()> {
+  kv.pop::<chrono::DateTime<chrono::Utc>>(&KvDb::state(state_id))
+    .await?
+    .ok_or(
+      ErrorResponse::from("Authentication state is already used.")
+        .with_403_pub()
+        .build(),
+    )
+    .map(|_| ())
+}
+
+/// Removes identifiers of expired states; returns their count.
+pub(crate) async fn sweep_expired_states(kv: &KvDb) -> MResult<usize> {
+  let now = chrono::Utc::now();
+  let mut removed = 0;
+  let states = kv
+    .scan_prefix::<chrono::DateTime<chrono::Utc>>(KvDb::STATE_PREFIX)
+    .await?;
+  for (key, exp) in states {
+    if exp <= now {
+      kv.remove(&key).await?;
+      removed += 1;
+    }
+  }
+
+  tracing::debug!("Identifiers of {} expired states are removed.", removed);
+  Ok(removed)
+}
+
+/// Removes identifiers of expired states until the worker stops.
+pub(crate) async fn run_state_sweeper(kv: KvDb) {
+  loop {
+    tokio::time::sleep(STATE_SWEEP_INTERVAL).await;
+    if let Err(e) = sweep_expired_states(&kv).await {
+      tracing::error!("Can't remove expired states: {e:?}");
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{
+    SealedState, StateKind, consume_state_id, open_state, register_state_id, seal_state, sweep_expired_states,
+  };
+  use crate::core::signer::InProcessBackend;
+  use crate::core::signing_keys::SigningKeyring;
+  use crate::kv::KvDb;
+
+  #[test]
+  fn test_state_round_trip_and_binding() {
+    let keyring = SigningKeyring::generate();
+    let signing_key = keyring.active(&InProcessBackend, chrono::Utc::now()).unwrap();
+    let keys = keyring.verification_keys(chrono::Utc::now());
+    let client = c3a_common::generate_dilithium_keypair();
+    let key = c3a_common::generate::<32>();
+
+    let sealed = seal_state(
+      StateKind::Registration,
+      "test-app",
+      String::from("secret data"),
+      chrono::TimeDelta::minutes(1),
+      &key,
+      &client.public,
+      &signing_key,
+    )
+    .unwrap();
+
+    let (state, state_id) =
+      open_state::<String>(&sealed.token, StateKind::Registration, "test-app", &key, &keys).unwrap();
+    assert_eq!(state.as_str(), "secret data");
+    assert_eq!(state_id, sealed.state_id);
+
+    assert!(open_state::<String>(&sealed.token, StateKind::Registration, "another-app", &key, &keys).is_err());
+    assert!(open_state::<String>(&sealed.token, StateKind::Login, "test-app", &key, &keys).is_err());
+
+    let wrong_key = c3a_common::generate::<32>();
+    assert!(open_state::<String>(&sealed.token, StateKind::Registration, "test-app", &wrong_key, &keys).is_err());
+  }
+
+  #[test]
+  fn test_state_is_not_readable_without_key() {
+    let signing_key = SigningKeyring::generate().active(&InProcessBackend, chrono::Utc::now()).unwrap();
+    let key = c3a_common::generate::<32>();
+
+    let sealed = seal_state(
+      StateKind::Registration,
+      "test-app",
+      String::from("totp-secret-to-hide"),
+      chrono::TimeDelta::minutes(1),
+      &key,
+      signing_key.public(),
+      &signing_key,
+    )
+    .unwrap();
+
+    let payload = sealed.token.split('.').next().unwrap();
+    let payload = c3a_common::base64_decode(payload).unwrap();
+    assert!(
+      !payload
+        .windows(b"totp-secret-to-hide".len())
+        .any(|w| w == b"totp-secret-to-hide")
+    );
+  }
+
+  #[tokio::test]
+  async fn test_states_are_consumed_once_and_swept() {
+    let kv = KvDb::in_memory();
+    let now = chrono::Utc::now();
+    let states = [
+      (b"live", now + chrono::TimeDelta::minutes(1)),
+      (b"gone", now - chrono::TimeDelta::minutes(1)),
+    ];
+    for (state_id, exp) in states {
+      let sealed = SealedState {
+        token: String::new(),
+        state_id: state_id.to_vec(),
+        exp,
+      };
+      register_state_id(&kv, &sealed).await.unwrap();
+    }
+
+    assert_eq!(sweep_expired_states(&kv).await.unwrap(), 1);
+    assert!(!kv.exists(&KvDb::state(b"gone")).await.unwrap());
+    consume_state_id(&kv, b"live").await.unwrap();
+    assert!(consume_state_id(&kv, b"live").await.is_err());
+  }
+}
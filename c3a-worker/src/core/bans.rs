@@ -0,0 +1,239 @@
+//! Bans by the app's `Fail2BanOptions`.
+//!
+//! Unsuccessful attempts are counted per app for the user's identifier and, if `ban_ip` is set, for the client's
+//! IP address (see `crate::utils::client_ip`). After `max_allowed_unsuccessful_attempts` the subject is banned for
+//! `ban_login_expiration`, or IP address for `ban_ip_expiration` if it's set, and the counter starts over.
+//!
+//! Only wrong secrets are unsuccessful attempts: email confirmation codes on registration and passwords or
+//! answers checked by `verify_secret`. Requests refused by the app's policy, e.g. weak passwords, aren't counted.
+//!
+//! Admins list active bans and lift them with `/admin/bans`.
+
+use c3a_common::{AppAuthConfiguration, BanInfo, BanSubject, Fail2BanOptions};
+use cc_server_kit::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::core::audit::{self, AuditAction};
+use crate::kv::KvDb;
+
+#[derive(Deserialize, Serialize, Default)]
+struct BanRecord {
+  /// Unsuccessful attempts since the last ban.
+  failures: usize,
+  ban: Option<BanInfo>,
+}
+
+impl BanRecord {
+  fn active_ban(&self, now: chrono::DateTime<chrono::Utc>) -> Option<&BanInfo> {
+    self.ban.as_ref().filter(|ban| ban.banned_until > now)
+  }
+}
+
+fn fail_to_ban(app_conf: &AppAuthConfiguration) -> Option<&Fail2BanOptions> {
+  app_conf.allow_sign_up.as_ref()?.enable_fail_to_ban.as_ref()
+}
+
+/// Subjects whose attempts are counted in the app.
+pub(crate) fn subjects(app_conf: &AppAuthConfiguration, identifier: &str, ip: Option<String>) -> Vec<BanSubject> {
+  let Some(opts) = fail_to_ban(app_conf) else {
+    return vec![];
+  };
+  let mut subjects = vec![BanSubject::Login(identifier.to_owned())];
+  if opts.ban_ip {
+    subjects.extend(ip.map(BanSubject::Ip));
+  }
+  subjects
+}
+
+/// Refuses banned subjects.
+pub(crate) async fn check_bans(kv: &KvDb, app_name: &str, subjects: &[BanSubject]) -> MResult<()> {
+  let now = chrono::Utc::now();
+  for subject in subjects {
+    if let Some(record) = kv.get::<BanRecord>(&KvDb::ban(app_name, subject)).await?
+      && let Some(ban) = record.active_ban(now)
+    {
+      return Err(
+        ErrorResponse::from(format!(
+          "Too many unsuccessful attempts; try again after {}.",
+          ban.banned_until.to_rfc3339()
+        ))
+        .with_403_pub()
+        .build(),
+      );
+    }
+  }
+  Ok(())
+}
+
+/// Counts the unsuccessful attempt of every subject and bans the ones which have run out of attempts.
+pub(crate) async fn register_failure(
+  kv: &KvDb,
+  app_conf: &AppAuthConfiguration,
+  subjects: &[BanSubject],
+) -> MResult<()> {
+  let Some(opts) = fail_to_ban(app_conf) else {
+    return Ok(());
+  };
+  for subject in subjects {
+    kv.update::<BanRecord, _, _>(&KvDb::ban(&app_conf.app_name, subject), |record| {
+      let now = chrono::Utc::now();
+      let record = record.get_or_insert_default();
+      if record.active_ban(now).is_some() {
+        return Ok(());
+      }
+      record.failures += 1;
+      if record.failures >= opts.max_allowed_unsuccessful_attempts {
+        let expiration = match subject {
+          BanSubject::Login(_) => opts.ban_login_expiration,
+          BanSubject::Ip(_) => opts.ban_ip_expiration.unwrap_or(opts.ban_login_expiration),
+        };
+        tracing::info!("bans: {:?} is banned in `{}` app", subject, app_conf.app_name);
+        record.failures = 0;
+        record.ban = Some(BanInfo {
+          app_name: app_conf.app_name.clone(),
+          subject: subject.clone(),
+          banned_at: now,
+          banned_until: now.checked_add_signed(expiration).ok_or(
+            ErrorResponse::from("No way to add ban expiration to current time")
+              .with_500()
+              .build(),
+          )?,
+        });
+      }
+      Ok(())
+    })
+    .await?;
+  }
+  Ok(())
+}
+
+/// Active bans of the app or of all apps, by the time they're imposed.
+pub(crate) async fn list(kv: &KvDb, app_name: Option<&str>) -> MResult<Vec<BanInfo>> {
+  let prefix = match app_name {
+    Some(app_name) => KvDb::bans_of(app_name),
+    None => KvDb::BAN_PREFIX.to_owned(),
+  };
+  let now = chrono::Utc::now();
+  let mut bans = kv
+    .scan_prefix::<BanRecord>(&prefix)
+    .await?
+    .into_iter()
+    .filter_map(|(_, record)| record.active_ban(now).cloned())
+    .collect::<Vec<_>>();
+  bans.sort_by(|a, b| a.banned_at.cmp(&b.banned_at));
+  Ok(bans)
+}
+
+/// Lifts the ban and forgets unsuccessful attempts of the subject.
+pub(crate) async fn lift(kv: &KvDb, admin_name: &str, app_name: &str, subject: &BanSubject) -> MResult<()> {
+  let key = KvDb::ban(app_name, subject);
+  let now = chrono::Utc::now();
+  kv.update::<BanRecord, _, _>(&key, |record| {
+    if record.as_ref().and_then(|record| record.active_ban(now)).is_none() {
+      return Err(ErrorResponse::from("There is no such ban.").with_404_pub().build());
+    }
+    *record = None;
+    Ok(())
+  })
+  .await?;
+  tracing::info!(
+    "bans: {:?} ban in `{}` app is lifted by `{}`",
+    subject,
+    app_name,
+    admin_name
+  );
+  audit::record(
+    kv,
+    Some(admin_name),
+    AuditAction::LiftBan {
+      app_name: app_name.to_owned(),
+      subject: subject.clone(),
+    },
+  )
+  .await
+}
+
+#[cfg(test)]
+mod tests {
+  use c3a_common::{
+    AppAuthConfiguration, BanSubject, Fail2BanOptions, IdenticationRequirement, SignUpOpts, TokenEncryptionType,
+  };
+
+  use crate::kv::KvDb;
+
+  fn app_conf(ban_ip_expiration: Option<chrono::Duration>) -> AppAuthConfiguration {
+    AppAuthConfiguration {
+      app_name: String::from("app"),
+      domain: String::from("example.com"),
+      allowed_tags: vec![],
+      allow_sign_up: Some(SignUpOpts {
+        identify_by: IdenticationRequirement::Email {
+          exclude_email_domains: vec![],
+        },
+        allow_sign_up: true,
+        auto_assign_tags: vec![],
+        allowed_authentication_flow: vec![],
+        required_authentication: vec![],
+        allow_honeypots: false,
+        enable_fail_to_ban: Some(Fail2BanOptions {
+          max_allowed_unsuccessful_attempts: 3,
+          ban_login_expiration: chrono::Duration::minutes(10),
+          ban_ip: true,
+          ban_ip_expiration,
+        }),
+        allow_recovery_key: false,
+        token_encryption_type: TokenEncryptionType::None,
+      }),
+      client_based_auth_opts: None,
+      author_dpub: vec![],
+      email_templates: None,
+      hash_params: None,
+    }
+  }
+
+  #[tokio::test]
+  async fn test_subjects_are_banned_after_failures() {
+    let kv = KvDb::in_memory();
+    let app_conf = app_conf(None);
+    let subjects = super::subjects(&app_conf, "alice@example.com", Some(String::from("192.0.2.1")));
+    assert_eq!(subjects.len(), 2);
+
+    for _ in 0..2 {
+      super::register_failure(&kv, &app_conf, &subjects).await.unwrap();
+      super::check_bans(&kv, "app", &subjects).await.unwrap();
+    }
+    super::register_failure(&kv, &app_conf, &subjects).await.unwrap();
+    assert!(super::check_bans(&kv, "app", &subjects[..1]).await.is_err());
+    assert!(super::check_bans(&kv, "app", &subjects[1..]).await.is_err());
+    // Bans are per app.
+    super::check_bans(&kv, "other", &subjects).await.unwrap();
+
+    let bans = super::list(&kv, Some("app")).await.unwrap();
+    assert_eq!(bans.len(), 2);
+    let ip_ban = bans
+      .iter()
+      .find(|ban| matches!(ban.subject, BanSubject::Ip(_)))
+      .unwrap();
+    // IP addresses are banned for `ban_login_expiration` unless `ban_ip_expiration` is set.
+    assert_eq!(ip_ban.banned_until, ip_ban.banned_at + chrono::Duration::minutes(10));
+    assert!(super::list(&kv, Some("other")).await.unwrap().is_empty());
+
+    super::lift(&kv, "admin", "app", &subjects[0]).await.unwrap();
+    assert!(super::lift(&kv, "admin", "app", &subjects[0]).await.is_err());
+    super::check_bans(&kv, "app", &subjects[..1]).await.unwrap();
+    assert_eq!(super::list(&kv, None).await.unwrap().len(), 1);
+  }
+
+  #[tokio::test]
+  async fn test_expired_bans_are_not_active() {
+    let kv = KvDb::in_memory();
+    let app_conf = app_conf(Some(chrono::Duration::zero()));
+    let subjects = vec![BanSubject::Ip(String::from("192.0.2.1"))];
+    for _ in 0..3 {
+      super::register_failure(&kv, &app_conf, &subjects).await.unwrap();
+    }
+    super::check_bans(&kv, "app", &subjects).await.unwrap();
+    assert!(super::list(&kv, None).await.unwrap().is_empty());
+    assert!(super::lift(&kv, "admin", "app", &subjects[0]).await.is_err());
+  }
+}
@@ -0,0 +1,338 @@
+//! Invitations to register apps.
+//!
+//! An invitation code is `SECRET_LENGTH` random bytes followed by `CHECKSUM_LENGTH` bytes of their hash, written
+//! in Crockford's base32 by groups of `GROUP_LENGTH` characters, e.g. `7ZK3M-1QW8D-...`. Codes are
+//! case-insensitive and a mistyped code is refused before it's looked up.
+//!
+//! Only a hash of the secret is stored together with the creator, the expiry, the app the invite is intended
+//! for and the app registered with it. Used invites are kept for `RETENTION_DAYS` after they expire.
+
+use c3a_common::{
+  AppAuthConfiguration, GenerateInvitationRequest, GenerateInvitationResponse, InvitationInfo, InvitationUsage,
+};
+use cc_server_kit::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+
+use crate::core::audit::{self, AuditAction};
+use crate::kv::KvDb;
+
+const SECRET_LENGTH: usize = 20;
+const CHECKSUM_LENGTH: usize = 5;
+const GROUP_LENGTH: usize = 5;
+const ALPHABET: base32::Alphabet = base32::Alphabet::Crockford;
+
+const DEFAULT_LIFETIME_DAYS: u16 = 7;
+const MAX_LIFETIME_DAYS: u16 = 90;
+/// How long expired invites are listed.
+const RETENTION_DAYS: i64 = 30;
+
+#[derive(Deserialize, Serialize, Clone)]
+struct Invitation {
+  /// SHA3-256 of the secret.
+  hash: Vec<u8>,
+  info: InvitationInfo,
+}
+
+#[derive(Deserialize, Serialize, Default)]
+pub(crate) struct Invitations {
+  invitations: Vec<Invitation>,
+}
+
+impl Invitations {
+  fn find_mut(&mut self, id: &str) -> Option<&mut Invitation> {
+    self.invitations.iter_mut().find(|invitation| invitation.info.id.eq(id))
+  }
+}
+
+fn checksum(secret: &[u8]) -> [u8; CHECKSUM_LENGTH] {
+  let mut hasher = Sha3_256::new();
+  hasher.update(b"c3a-invitation-checksum");
+  hasher.update(secret);
+  hasher.finalize()[..CHECKSUM_LENGTH].try_into().unwrap()
+}
+
+/// Identifier shown to admins; it's a part of the hash, so it doesn't disclose the code.
+fn id(hash: &[u8]) -> String {
+  hex::encode(&hash[..8])
+}
+
+fn encode(secret: &[u8]) -> String {
+  let mut data = secret.to_vec();
+  data.extend_from_slice(&checksum(secret));
+  let encoded = base32::encode(ALPHABET, &data);
+  encoded
+    .as_bytes()
+    .chunks(GROUP_LENGTH)
+    .map(|group| std::str::from_utf8(group).unwrap())
+    .collect::<Vec<_>>()
+    .join("-")
+}
+
+/// Returns the secret of the code, if the code is well-formed.
+fn decode(invite: &str) -> Option<Vec<u8>> {
+  let normalized = invite
+    .chars()
+    .filter(|c| !c.is_whitespace() && *c != '-')
+    .collect::<String>()
+    .to_ascii_uppercase();
+  let mut data = base32::decode(ALPHABET, &normalized)?;
+  if data.len() != SECRET_LENGTH + CHECKSUM_LENGTH {
+    return None;
+  }
+  let secret = data.drain(..SECRET_LENGTH).collect::<Vec<_>>();
+  constant_time_eq::constant_time_eq(&data, &checksum(&secret)).then_some(secret)
+}
+
+fn invalid_invite(msg: &str) -> ErrorResponse {
+  ErrorResponse::from(msg).with_401_pub().build()
+}
+
+pub(crate) async fn generate(
+  kv: &KvDb,
+  created_by: &str,
+  request: &GenerateInvitationRequest,
+) -> MResult<GenerateInvitationResponse> {
+  let lifetime_days = request.lifetime_days.unwrap_or(DEFAULT_LIFETIME_DAYS);
+  if lifetime_days == 0 || lifetime_days > MAX_LIFETIME_DAYS {
+    return Err(
+      ErrorResponse::from(format!(
+        "Invitation lifetime should be from 1 to {MAX_LIFETIME_DAYS} days."
+      ))
+      .with_400_pub()
+      .build(),
+    );
+  }
+
+  let secret = c3a_common::generate::<SECRET_LENGTH>();
+  let hash = Sha3_256::digest(secret).to_vec();
+  let now = chrono::Utc::now();
+  let info = InvitationInfo {
+    id: id(&hash),
+    created_by: created_by.to_owned(),
+    created_at: now,
+    expires_at: now + chrono::TimeDelta::days(i64::from(lifetime_days)),
+    intended_for: request
+      .intended_for
+      .clone()
+      .filter(|intended_for| !intended_for.is_empty()),
+    used: None,
+  };
+  kv.update_versioned::<Invitations, _, _>(KvDb::INVITES, |invitations| {
+    let invitations = &mut invitations.get_or_insert_default().invitations;
+    invitations.retain(|invitation| invitation.info.expires_at + chrono::TimeDelta::days(RETENTION_DAYS) > now);
+    invitations.push(Invitation {
+      hash: hash.clone(),
+      info: info.clone(),
+    });
+    Ok(())
+  })
+  .await?;
+
+  tracing::info!("Invitation `{}` is generated by `{}`.", info.id, created_by);
+  audit::record(
+    kv,
+    Some(created_by),
+    AuditAction::GenerateInvitation { id: info.id.clone() },
+  )
+  .await?;
+  Ok(GenerateInvitationResponse {
+    id: info.id,
+    invite: encode(&secret),
+    expires_at: info.expires_at,
+  })
+}
+
+pub(crate) async fn list(kv: &KvDb) -> MResult<Vec<InvitationInfo>> {
+  let invitations = kv
+    .get_versioned::<Invitations>(KvDb::INVITES)
+    .await?
+    .unwrap_or_default();
+  Ok(
+    invitations
+      .invitations
+      .into_iter()
+      .map(|invitation| invitation.info)
+      .collect(),
+  )
+}
+
+/// Removes the unused invite.
+pub(crate) async fn revoke(kv: &KvDb, revoked_by: &str, id: &str) -> MResult<()> {
+  kv.update_versioned::<Invitations, _, _>(KvDb::INVITES, |invitations| {
+    let invitations = invitations.get_or_insert_default();
+    let invitation = invitations.find_mut(id).ok_or_else(|| {
+      ErrorResponse::from("There is no such invitation.")
+        .with_404_pub()
+        .build()
+    })?;
+    if invitation.info.used.is_some() {
+      return Err(
+        ErrorResponse::from("The invitation is already used.")
+          .with_400_pub()
+          .build(),
+      );
+    }
+    invitations.invitations.retain(|invitation| invitation.info.id.ne(id));
+    Ok(())
+  })
+  .await?;
+
+  tracing::info!("Invitation `{}` is revoked by `{}`.", id, revoked_by);
+  audit::record(
+    kv,
+    Some(revoked_by),
+    AuditAction::RevokeInvitation { id: id.to_owned() },
+  )
+  .await
+}
+
+/// Marks the invite as used by the app; returns its identifier.
+///
+/// The invite is taken atomically, so it can't register two apps.
+pub(crate) async fn redeem(kv: &KvDb, invite: &str, app_conf: &AppAuthConfiguration) -> MResult<String> {
+  let secret = decode(invite).ok_or_else(|| {
+    ErrorResponse::from("Your invitation code is malformed!")
+      .with_400_pub()
+      .build()
+  })?;
+  let hash = Sha3_256::digest(&secret).to_vec();
+  let invite_id = id(&hash);
+
+  kv.update_versioned::<Invitations, _, _>(KvDb::INVITES, |invitations| {
+    let invitation = invitations
+      .as_mut()
+      .and_then(|invitations| invitations.find_mut(&invite_id))
+      .filter(|invitation| constant_time_eq::constant_time_eq(&invitation.hash, &hash))
+      .ok_or_else(|| invalid_invite("Your invitation code is invalid!"))?;
+    if invitation.info.used.is_some() {
+      return Err(invalid_invite("Your invitation code is already used!"));
+    }
+    if invitation.info.expires_at <= chrono::Utc::now() {
+      return Err(invalid_invite("Your invitation code is expired!"));
+    }
+    if let Some(intended_for) = &invitation.info.intended_for
+      && intended_for.ne(&app_conf.app_name)
+      && intended_for.ne(&app_conf.domain)
+    {
+      return Err(invalid_invite("Your invitation code is issued for another app!"));
+    }
+
+    invitation.info.used = Some(InvitationUsage {
+      app_name: app_conf.app_name.clone(),
+      used_at: chrono::Utc::now(),
+    });
+    Ok(())
+  })
+  .await?;
+
+  Ok(invite_id)
+}
+
+/// Makes the invite unused again, e.g. if the app can't be registered.
+pub(crate) async fn give_back(kv: &KvDb, id: &str) -> MResult<()> {
+  kv.update_versioned::<Invitations, _, _>(KvDb::INVITES, |invitations| {
+    if let Some(invitation) = invitations.as_mut().and_then(|invitations| invitations.find_mut(id)) {
+      invitation.info.used = None;
+    }
+    Ok(())
+  })
+  .await
+}
+
+#[cfg(test)]
+mod tests {
+  use c3a_common::{AppAuthConfiguration, GenerateInvitationRequest};
+
+  use crate::kv::KvDb;
+
+  fn app_conf(app_name: &str) -> AppAuthConfiguration {
+    AppAuthConfiguration {
+      app_name: app_name.to_owned(),
+      domain: format!("{app_name}.example.com"),
+      allowed_tags: vec![],
+      allow_sign_up: None,
+      client_based_auth_opts: None,
+      author_dpub: vec![1, 2],
+      email_templates: None,
+      hash_params: None,
+    }
+  }
+
+  #[test]
+  fn test_codes() {
+    let secret = c3a_common::generate::<{ super::SECRET_LENGTH }>();
+    let invite = super::encode(&secret);
+    assert_eq!(invite.len(), 47);
+    assert_eq!(super::decode(&invite).unwrap(), secret);
+    assert_eq!(super::decode(&invite.to_lowercase().replace('-', " ")).unwrap(), secret);
+
+    let first = invite.chars().next().unwrap();
+    let mistyped = format!("{}{}", if first == '0' { '1' } else { '0' }, &invite[1..]);
+    assert!(super::decode(&mistyped).is_none());
+    assert!(super::decode(&invite[..invite.len() - 1]).is_none());
+  }
+
+  #[tokio::test]
+  async fn test_invitation_lifecycle() {
+    let kv = KvDb::in_memory();
+    let request = GenerateInvitationRequest {
+      intended_for: Some(String::from("b.example.com")),
+      lifetime_days: Some(1),
+    };
+    let intended = super::generate(&kv, "alice", &request).await.unwrap();
+    let open = super::generate(&kv, "bob", &GenerateInvitationRequest::default())
+      .await
+      .unwrap();
+
+    let listed = super::list(&kv).await.unwrap();
+    assert_eq!(listed.len(), 2);
+    assert_eq!(listed[0].created_by, "alice");
+    assert_eq!(listed[0].intended_for.as_deref(), Some("b.example.com"));
+    assert_eq!(listed[1].expires_at - listed[1].created_at, chrono::TimeDelta::days(7));
+
+    assert!(super::redeem(&kv, &intended.invite, &app_conf("a")).await.is_err());
+    let invite_id = super::redeem(&kv, &intended.invite, &app_conf("b")).await.unwrap();
+    assert_eq!(invite_id, intended.id);
+    assert!(super::redeem(&kv, &intended.invite, &app_conf("b")).await.is_err());
+    assert!(super::revoke(&kv, "alice", &intended.id).await.is_err());
+    let used = super::list(&kv).await.unwrap().remove(0).used.unwrap();
+    assert_eq!(used.app_name, "b");
+
+    super::give_back(&kv, &intended.id).await.unwrap();
+    assert!(super::list(&kv).await.unwrap()[0].used.is_none());
+
+    super::revoke(&kv, "bob", &open.id).await.unwrap();
+    assert!(super::redeem(&kv, &open.invite, &app_conf("c")).await.is_err());
+    assert!(super::revoke(&kv, "bob", &open.id).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn test_expired_invitations() {
+    let kv = KvDb::in_memory();
+    let response = super::generate(&kv, "alice", &GenerateInvitationRequest::default())
+      .await
+      .unwrap();
+    kv.update_versioned::<super::Invitations, _, _>(KvDb::INVITES, |invitations| {
+      invitations.as_mut().unwrap().invitations[0].info.expires_at -= chrono::TimeDelta::days(8);
+      Ok(())
+    })
+    .await
+    .unwrap();
+    assert!(super::redeem(&kv, &response.invite, &app_conf("a")).await.is_err());
+
+    // Dropped when they are expired for long enough.
+    kv.update_versioned::<super::Invitations, _, _>(KvDb::INVITES, |invitations| {
+      invitations.as_mut().unwrap().invitations[0].info.expires_at -= chrono::TimeDelta::days(super::RETENTION_DAYS);
+      Ok(())
+    })
+    .await
+    .unwrap();
+    super::generate(&kv, "alice", &GenerateInvitationRequest::default())
+      .await
+      .unwrap();
+    let listed = super::list(&kv).await.unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_ne!(listed[0].id, response.id);
+  }
+}
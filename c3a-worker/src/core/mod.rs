@@ -1,3 +1,14 @@
 // pub(crate) mod checks;
+pub(crate) mod admins;
+pub(crate) mod audit;
+pub(crate) mod auth_states;
+pub(crate) mod bans;
+pub(crate) mod invitations;
+pub(crate) mod password_policy;
+pub(crate) mod peppers;
+pub(crate) mod signer;
+pub(crate) mod signing_keys;
+pub(crate) mod tokens;
+pub(crate) mod user_authentication_checks;
 pub(crate) mod user_preregistration_inspects;
 pub(crate) mod user_registration_checks;
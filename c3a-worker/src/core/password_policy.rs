@@ -0,0 +1,375 @@
+//! Password policy enforcement.
+//!
+//! Besides the rules of `c3a_common::PasswordPolicy` which can be checked on the client side, C3A estimates
+//! password strength with zxcvbn and checks the password against the breached-password list.
+//!
+//! The list is a text file with one SHA-1 hash (hex) per line, sorted by hash as HIBP downloads are; HIBP
+//! `HASH:COUNT` format is accepted too. On startup the list is compacted into `<list>.idx` next to it - sorted
+//! 8-byte hash prefixes - which is rebuilt whenever the list is newer. Lookups are binary searches over the index
+//! file, so the worker's memory doesn't grow with the list, and the chance of a false positive stays negligible.
+
+use c3a_common::{PasswordPolicy, PasswordPolicyViolation};
+use cc_server_kit::prelude::*;
+use sha1::{Digest, Sha1};
+use std::io::{BufRead, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const PREFIX_LENGTH: u64 = 8;
+
+#[derive(Clone, Default)]
+pub(crate) struct BreachedPasswords {
+  index: Option<Arc<std::fs::File>>,
+  /// Count of prefixes in the index.
+  len: u64,
+}
+
+fn io_error(path: &Path) -> impl Fn(std::io::Error) -> ErrorResponse {
+  move |e| {
+    ErrorResponse::from(format!("Breached-password list `{}`: {e}", path.display()))
+      .with_500()
+      .build()
+  }
+}
+
+impl BreachedPasswords {
+  /// Opens the index of the list, building it first if it's missing or outdated.
+  pub(crate) fn load(path: &Path) -> MResult<Self> {
+    let index_path = Self::index_path(path);
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|meta| meta.modified()).ok();
+    let list_modified = modified(path).ok_or(
+      ErrorResponse::from(format!("Can't open breached-password list `{}`", path.display()))
+        .with_500()
+        .build(),
+    )?;
+
+    if modified(&index_path).is_none_or(|index_modified| index_modified < list_modified) {
+      tracing::info!("Indexing breached-password list `{}`...", path.display());
+      let list = std::fs::File::open(path).map_err(io_error(path))?;
+      let mut tmp_path = index_path.clone().into_os_string();
+      tmp_path.push(".tmp");
+      let tmp_path = PathBuf::from(tmp_path);
+      let tmp = std::fs::File::create(&tmp_path).map_err(io_error(&tmp_path))?;
+      Self::build_index(std::io::BufReader::new(list), std::io::BufWriter::new(tmp))?;
+      std::fs::rename(&tmp_path, &index_path).map_err(io_error(&index_path))?;
+    }
+
+    let list = Self::open(&index_path)?;
+    tracing::info!("Loaded {} breached password hashes.", list.len);
+    Ok(list)
+  }
+
+  fn index_path(path: &Path) -> PathBuf {
+    let mut index_path = path.as_os_str().to_owned();
+    index_path.push(".idx");
+    PathBuf::from(index_path)
+  }
+
+  /// Writes sorted unique prefixes of the sorted list; returns their count.
+  pub(crate) fn build_index(reader: impl BufRead, mut writer: impl Write) -> MResult<u64> {
+    let mut last = None;
+    let mut count = 0;
+
+    for line in reader.lines() {
+      let line = line.map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+      let line = line.trim();
+      if line.is_empty() {
+        continue;
+      }
+
+      let hash = line.split(':').next().unwrap_or_default();
+      let prefix = hash
+        .get(..16)
+        .and_then(|prefix| u64::from_str_radix(prefix, 16).ok())
+        .ok_or(
+          ErrorResponse::from(format!("Invalid SHA-1 hash in breached-password list: `{line}`"))
+            .with_500()
+            .build(),
+        )?;
+
+      match last {
+        Some(last) if prefix == last => continue,
+        Some(last) if prefix < last => {
+          return Err(
+            ErrorResponse::from(format!("Breached-password list isn't sorted by hash at `{line}`"))
+              .with_500()
+              .build(),
+          );
+        }
+        _ => {}
+      }
+      writer
+        .write_all(&prefix.to_be_bytes())
+        .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+      last = Some(prefix);
+      count += 1;
+    }
+
+    writer
+      .flush()
+      .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+    Ok(count)
+  }
+
+  pub(crate) fn open(index_path: &Path) -> MResult<Self> {
+    let index = std::fs::File::open(index_path).map_err(io_error(index_path))?;
+    let size = index.metadata().map_err(io_error(index_path))?.len();
+    if size % PREFIX_LENGTH != 0 {
+      return Err(
+        ErrorResponse::from(format!(
+          "Breached-password index `{}` is corrupted",
+          index_path.display()
+        ))
+        .with_500()
+        .build(),
+      );
+    }
+
+    Ok(Self {
+      index: Some(Arc::new(index)),
+      len: size / PREFIX_LENGTH,
+    })
+  }
+
+  pub(crate) fn is_loaded(&self) -> bool {
+    self.len > 0
+  }
+
+  fn prefix_at(index: &std::fs::File, position: u64) -> MResult<u64> {
+    let mut prefix = [0u8; PREFIX_LENGTH as usize];
+    index
+      .read_exact_at(&mut prefix, position * PREFIX_LENGTH)
+      .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+    Ok(u64::from_be_bytes(prefix))
+  }
+
+  pub(crate) fn contains(&self, password: &str) -> MResult<bool> {
+    let Some(index) = &self.index else {
+      return Ok(false);
+    };
+    let hash = Sha1::digest(password.as_bytes());
+    let mut prefix = [0u8; PREFIX_LENGTH as usize];
+    prefix.copy_from_slice(&hash[..PREFIX_LENGTH as usize]);
+    let prefix = u64::from_be_bytes(prefix);
+
+    let (mut low, mut high) = (0, self.len);
+    while low < high {
+      let middle = low + (high - low) / 2;
+      match Self::prefix_at(index, middle)?.cmp(&prefix) {
+        std::cmp::Ordering::Equal => return Ok(true),
+        std::cmp::Ordering::Less => low = middle + 1,
+        std::cmp::Ordering::Greater => high = middle,
+      }
+    }
+    Ok(false)
+  }
+}
+
+/// Checks the password against all rules of the policy.
+///
+/// `user_inputs` (identifier, app name, etc.) make passwords built from them weaker. Strength and breached
+/// checks are skipped for passwords of wrong length, so huge inputs never reach zxcvbn.
+pub(crate) fn check_password(
+  policy: &PasswordPolicy,
+  password: &str,
+  user_inputs: &[&str],
+  breached: &BreachedPasswords,
+) -> MResult<Vec<PasswordPolicyViolation>> {
+  let mut violations = policy.check(password);
+  if violations.iter().any(|violation| {
+    matches!(
+      violation,
+      PasswordPolicyViolation::TooShort { .. } | PasswordPolicyViolation::TooLong { .. }
+    )
+  }) {
+    return Ok(violations);
+  }
+
+  if let Some(min_score) = policy.min_strength_score {
+    let entropy = zxcvbn::zxcvbn(password, user_inputs);
+    let score = u8::from(entropy.score());
+    if score < min_score {
+      violations.push(PasswordPolicyViolation::TooWeak {
+        score,
+        min_score,
+        suggestions: entropy
+          .feedback()
+          .map(|feedback| feedback.suggestions().iter().map(|s| s.to_string()).collect())
+          .unwrap_or_default(),
+      });
+    }
+  }
+
+  if policy.reject_breached {
+    if !breached.is_loaded() {
+      tracing::warn!("Application requires breached-password check, but no list is loaded.");
+    } else if breached.contains(password)? {
+      violations.push(PasswordPolicyViolation::Breached);
+    }
+  }
+
+  Ok(violations)
+}
+
+pub(crate) fn extract_breached_passwords(depot: &mut Depot) -> MResult<BreachedPasswords> {
+  Ok(
+    depot
+      .obtain::<BreachedPasswords>()
+      .map_err(|_| {
+        ErrorResponse::from("Can't get `BreachedPasswords` instance")
+          .with_500()
+          .build()
+      })?
+      .clone(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{BreachedPasswords, check_password};
+  use c3a_common::{PasswordPolicy, PasswordPolicyViolation};
+
+  fn policy() -> PasswordPolicy {
+    PasswordPolicy {
+      min_size: 10,
+      max_size: Some(64),
+      should_contain_different_case: true,
+      should_contain_symbols: true,
+      min_strength_score: Some(3),
+      reject_breached: true,
+    }
+  }
+
+  fn sha1_hex(password: &str) -> String {
+    use sha1::{Digest, Sha1};
+    hex::encode_upper(Sha1::digest(password.as_bytes()))
+  }
+
+  /// Writes the sorted list and loads its index.
+  fn breached_in(dir: &tempfile::TempDir) -> BreachedPasswords {
+    // HIBP format, lowercase hashes and duplicates must be accepted too.
+    let mut hashes = ["password", "Tr0ub4dor&3-Horse-Battery", "password"]
+      .into_iter()
+      .map(sha1_hex)
+      .collect::<Vec<_>>();
+    hashes.sort();
+    let list = hashes
+      .into_iter()
+      .enumerate()
+      .map(|(i, hash)| {
+        if i % 2 == 0 {
+          format!("{hash}:{i}")
+        } else {
+          hash.to_lowercase()
+        }
+      })
+      .collect::<Vec<_>>()
+      .join("\n");
+
+    let path = dir.path().join("pwned.txt");
+    std::fs::write(&path, list).unwrap();
+    BreachedPasswords::load(&path).unwrap()
+  }
+
+  fn breached() -> (tempfile::TempDir, BreachedPasswords) {
+    let dir = tempfile::TempDir::new().unwrap();
+    let list = breached_in(&dir);
+    (dir, list)
+  }
+
+  #[test]
+  fn test_basic_rules() {
+    let violations = check_password(&policy(), "short", &[], &BreachedPasswords::default()).unwrap();
+    assert!(violations.contains(&PasswordPolicyViolation::TooShort { min_size: 10 }));
+    assert!(violations.contains(&PasswordPolicyViolation::NoDifferentCase));
+    assert!(violations.contains(&PasswordPolicyViolation::NoSymbols));
+
+    let long = "Aa!".repeat(30);
+    let violations = check_password(&policy(), &long, &[], &BreachedPasswords::default()).unwrap();
+    assert!(violations.contains(&PasswordPolicyViolation::TooLong { max_size: 64 }));
+  }
+
+  #[test]
+  fn test_length_is_always_capped() {
+    let unbounded = PasswordPolicy {
+      max_size: None,
+      ..policy()
+    };
+    let (_dir, breached) = breached();
+    let huge = "correct-Horse-battery-staple-92!".repeat(1024);
+    let violations = check_password(&unbounded, &huge, &[], &breached).unwrap();
+    assert_eq!(
+      violations,
+      vec![PasswordPolicyViolation::TooLong {
+        max_size: PasswordPolicy::MAX_LENGTH
+      }]
+    );
+
+    // Neither strength nor breached list is checked for the password of wrong length.
+    let violations = check_password(&policy(), "password", &[], &breached).unwrap();
+    assert!(violations.contains(&PasswordPolicyViolation::TooShort { min_size: 10 }));
+    assert!(!violations.contains(&PasswordPolicyViolation::Breached));
+    assert!(
+      !violations
+        .iter()
+        .any(|v| matches!(v, PasswordPolicyViolation::TooWeak { .. }))
+    );
+  }
+
+  #[test]
+  fn test_strength_score() {
+    let violations = check_password(&policy(), "Password123!", &[], &BreachedPasswords::default()).unwrap();
+    assert!(
+      violations
+        .iter()
+        .any(|v| matches!(v, PasswordPolicyViolation::TooWeak { min_score: 3, .. }))
+    );
+
+    let violations = check_password(
+      &policy(),
+      "correct-Horse-battery-staple-92!",
+      &[],
+      &BreachedPasswords::default(),
+    )
+    .unwrap();
+    assert!(violations.is_empty());
+  }
+
+  #[test]
+  fn test_breached_list() {
+    let (dir, list) = breached();
+    assert!(list.contains("password").unwrap());
+    assert!(list.contains("Tr0ub4dor&3-Horse-Battery").unwrap());
+    assert!(!list.contains("correct-Horse-battery-staple-92!").unwrap());
+    // Duplicates are written once.
+    assert_eq!(std::fs::metadata(dir.path().join("pwned.txt.idx")).unwrap().len(), 16);
+
+    let violations = check_password(&policy(), "Tr0ub4dor&3-Horse-Battery", &[], &list).unwrap();
+    assert!(violations.contains(&PasswordPolicyViolation::Breached));
+
+    // The index is rebuilt once the list is newer.
+    std::fs::write(
+      dir.path().join("pwned.txt"),
+      sha1_hex("correct-Horse-battery-staple-92!"),
+    )
+    .unwrap();
+    let index = dir.path().join("pwned.txt.idx");
+    let file = std::fs::File::options().write(true).open(&index).unwrap();
+    file
+      .set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(60))
+      .unwrap();
+    let list = BreachedPasswords::load(&dir.path().join("pwned.txt")).unwrap();
+    assert!(!list.contains("password").unwrap());
+    assert!(list.contains("correct-Horse-battery-staple-92!").unwrap());
+  }
+
+  #[test]
+  fn test_unsorted_list_is_refused() {
+    let mut list = [sha1_hex("a"), sha1_hex("b")];
+    list.sort();
+    list.reverse();
+    let unsorted = list.join("\n");
+    assert!(BreachedPasswords::build_index(unsorted.as_bytes(), std::io::sink()).is_err());
+  }
+}
@@ -0,0 +1,179 @@
+//! Versioned peppers of users' secrets.
+//!
+//! Peppers are random, stored by C3A instance and never sent anywhere. Every stored hash records the version
+//! of the pepper it was computed with, and old versions are kept after the rotation, so existing hashes stay
+//! verifiable. New hashes use the current pepper, and a secret hashed with an older one is re-peppered on its
+//! next successful check (see `core::user_authentication_checks`).
+//!
+//! Version `0` is the legacy pepper, sliced from `C3A_PRIVATE_ADM_KEY`; hashes without recorded version
+//! were computed with it. It's only kept to verify such hashes, which move to the current pepper the same way.
+
+use cc_server_kit::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::Setup;
+use crate::kv::KvDb;
+
+const PEPPER_LENGTH: usize = 32;
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub(crate) struct Peppers {
+  current: u32,
+  versions: BTreeMap<u32, Vec<u8>>,
+}
+
+impl Peppers {
+  pub(crate) const LEGACY_VERSION: u32 = 0;
+
+  /// Loads peppers with the legacy one included.
+  pub(crate) async fn load(kv: &KvDb, setup: &Setup) -> MResult<Self> {
+    let mut peppers = kv
+      .get_sealed::<Peppers>(KvDb::PEPPERS)
+      .await?
+      .ok_or(ErrorResponse::from("No peppers available!").with_500().build())?;
+    peppers.versions.insert(Self::LEGACY_VERSION, legacy_pepper(setup).to_vec());
+    Ok(peppers)
+  }
+
+  /// Version and value of the pepper for new hashes.
+  pub(crate) fn current(&self) -> MResult<(u32, &[u8])> {
+    Ok((self.current, self.get(self.current)?))
+  }
+
+  pub(crate) fn get(&self, version: u32) -> MResult<&[u8]> {
+    self.versions.get(&version).map(|pepper| pepper.as_slice()).ok_or(
+      ErrorResponse::from(format!("There is no pepper of version {version}!"))
+        .with_500()
+        .build(),
+    )
+  }
+
+  pub(crate) fn versions(&self) -> Vec<u32> {
+    self.versions.keys().copied().collect()
+  }
+
+  fn add_version(&mut self) -> u32 {
+    let version = self.versions.keys().max().copied().unwrap_or(Self::LEGACY_VERSION) + 1;
+    self.versions.insert(version, c3a_common::generate::<PEPPER_LENGTH>().to_vec());
+    self.current = version;
+    version
+  }
+}
+
+fn legacy_pepper(setup: &Setup) -> &[u8] {
+  &setup.private_adm_key.as_ref().unwrap().as_bytes()[24..=48]
+}
+
+/// Generates the first pepper if there is none.
+pub(crate) async fn init_peppers(kv: &KvDb) -> MResult<()> {
+  if !kv.exists(&KvDb::sealed(KvDb::PEPPERS)).await? {
+    tracing::info!("There are no peppers, generating...");
+    let mut peppers = Peppers::default();
+    peppers.add_version();
+    kv.insert_sealed(KvDb::PEPPERS, &peppers).await?;
+    tracing::info!("Pepper is generated.");
+  }
+  Ok(())
+}
+
+/// Generates a new pepper and makes it current. Previous versions are kept.
+pub(crate) async fn rotate_pepper(kv: &KvDb) -> MResult<u32> {
+  let version = kv
+    .update_sealed::<Peppers, _, _>(KvDb::PEPPERS, |peppers| Ok(peppers.get_or_insert_default().add_version()))
+    .await?;
+  tracing::info!("Pepper is rotated, current version is {}.", version);
+  Ok(version)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Peppers, init_peppers, rotate_pepper};
+  use crate::Setup;
+  use crate::core::user_authentication_checks::verify_user_secret;
+  use crate::kv::KvDb;
+  use crate::utils::hash;
+  use c3a_common::{AuthenticationStep, AuthenticationStepRequest, HashParams, UserData};
+
+  fn setup() -> Setup {
+    Setup {
+      private_adm_key: Some("test-key-".repeat(16)),
+      ..Default::default()
+    }
+  }
+
+  #[tokio::test]
+  async fn test_rotation_keeps_old_versions() {
+    let kv = KvDb::in_memory_unsealed().await.unwrap();
+    let setup = setup();
+
+    init_peppers(&kv).await.unwrap();
+    init_peppers(&kv).await.unwrap();
+    let peppers = Peppers::load(&kv, &setup).await.unwrap();
+    let (first_version, first_pepper) = peppers.current().unwrap();
+    assert_eq!(first_version, 1);
+    assert_eq!(peppers.versions(), vec![0, 1]);
+
+    assert_eq!(rotate_pepper(&kv).await.unwrap(), 2);
+    let peppers = Peppers::load(&kv, &setup).await.unwrap();
+    let (version, pepper) = peppers.current().unwrap();
+    assert_eq!(version, 2);
+    assert_ne!(pepper, first_pepper);
+    assert_eq!(peppers.get(first_version).unwrap(), first_pepper);
+    assert_eq!(
+      peppers.get(Peppers::LEGACY_VERSION).unwrap(),
+      &setup.private_adm_key.as_ref().unwrap().as_bytes()[24..=48]
+    );
+    assert!(peppers.get(3).is_err());
+  }
+
+  #[tokio::test]
+  async fn test_old_peppers_are_replaced_on_check() {
+    let kv = KvDb::in_memory_unsealed().await.unwrap();
+    let setup = setup();
+    let params = HashParams::default();
+    init_peppers(&kv).await.unwrap();
+    let old_peppers = Peppers::load(&kv, &setup).await.unwrap();
+
+    // Users whose passwords are hashed with the legacy pepper and with the current one before the rotation.
+    for (identifier, version) in [("legacy", Peppers::LEGACY_VERSION), ("alice", 1)] {
+      let (salt, hash) = hash("hello world!", old_peppers.get(version).unwrap(), &params).unwrap();
+      let user_data = UserData {
+        identifier: identifier.to_owned(),
+        authentication_flows: vec![vec![AuthenticationStep::Password {
+          salt: salt.into_bytes(),
+          hash,
+          params,
+          pepper_version: version,
+        }]],
+      };
+      kv.insert_versioned(&KvDb::user("app", identifier), &user_data)
+        .await
+        .unwrap();
+    }
+
+    rotate_pepper(&kv).await.unwrap();
+    let peppers = Peppers::load(&kv, &setup).await.unwrap();
+    let password = AuthenticationStepRequest::Password {
+      password: String::from("hello world!"),
+    };
+
+    for identifier in ["legacy", "alice"] {
+      let checked = verify_user_secret(&kv, "app", identifier, &password, &peppers, &params).await;
+      assert!(checked.unwrap());
+      let user_data = kv
+        .get_versioned::<UserData>(&KvDb::user("app", identifier))
+        .await
+        .unwrap()
+        .unwrap();
+      assert!(matches!(
+        &user_data.authentication_flows[0][0],
+        AuthenticationStep::Password { pepper_version: 2, .. }
+      ));
+
+      // The re-peppered hash is verified with the new pepper.
+      let checked = verify_user_secret(&kv, "app", identifier, &password, &peppers, &params).await;
+      assert!(checked.unwrap());
+    }
+  }
+}
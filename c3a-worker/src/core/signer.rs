@@ -0,0 +1,88 @@
+//! Backends which hold the secret parts of signing keys.
+//!
+//! The keys are kept in the sealed keyring and signing happens in the worker's memory. There is no HSM backend:
+//! apps verify tokens with Dilithium5 (`c3a_common::verify`), and signatures of HSMs implementing ML-DSA
+//! (FIPS 204) don't verify with it.
+
+use c3a_common::SignError;
+use cc_server_kit::prelude::*;
+use serde::Deserialize;
+use std::sync::Arc;
+use zeroize::Zeroizing;
+
+/// Signs data with the secret key matching `public`.
+pub(crate) trait Signer: Send + Sync {
+  fn public(&self) -> &[u8];
+  /// Returns Dilithium5 signature of the data.
+  fn sign(&self, data: &[u8]) -> Result<Vec<u8>, SignError>;
+}
+
+/// Newly generated key.
+#[derive(Clone)]
+pub(crate) struct GeneratedKey {
+  pub(crate) public: Vec<u8>,
+  pub(crate) secret: Zeroizing<Vec<u8>>,
+}
+
+impl From<&c3a_common::Keypair> for GeneratedKey {
+  fn from(keypair: &c3a_common::Keypair) -> Self {
+    Self {
+      public: keypair.public.to_vec(),
+      secret: Zeroizing::new(keypair.expose_secret().to_vec()),
+    }
+  }
+}
+
+pub(crate) trait SignerBackend: Send + Sync {
+  fn generate(&self) -> MResult<GeneratedKey>;
+  /// Signer of the stored key.
+  fn signer(&self, kid: &str, public: &[u8], secret: &[u8]) -> MResult<Arc<dyn Signer>>;
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub(crate) enum SignerOpts {
+  #[default]
+  InProcess,
+}
+
+pub(crate) fn init_signer_backend(opts: &SignerOpts) -> MResult<Arc<dyn SignerBackend>> {
+  Ok(match opts {
+    SignerOpts::InProcess => Arc::new(InProcessBackend),
+  })
+}
+
+pub(crate) struct InProcessSigner {
+  keypair: c3a_common::Keypair,
+}
+
+impl Signer for InProcessSigner {
+  fn public(&self) -> &[u8] {
+    &self.keypair.public
+  }
+
+  fn sign(&self, data: &[u8]) -> Result<Vec<u8>, SignError> {
+    Ok(self.keypair.sign(data).to_vec())
+  }
+}
+
+/// Keys are kept in the sealed keyring.
+pub(crate) struct InProcessBackend;
+
+impl InProcessBackend {
+  pub(crate) fn restore(public: &[u8], secret: &[u8]) -> MResult<Arc<dyn Signer>> {
+    let keypair = c3a_common::Keypair::restore(public, secret)
+      .map_err(|_| ErrorResponse::from("Can't restore keypair!").with_500().build())?;
+    Ok(Arc::new(InProcessSigner { keypair }))
+  }
+}
+
+impl SignerBackend for InProcessBackend {
+  fn generate(&self) -> MResult<GeneratedKey> {
+    Ok(GeneratedKey::from(&c3a_common::generate_dilithium_keypair()))
+  }
+
+  fn signer(&self, _kid: &str, public: &[u8], secret: &[u8]) -> MResult<Arc<dyn Signer>> {
+    Self::restore(public, secret)
+  }
+}
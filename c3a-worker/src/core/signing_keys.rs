@@ -0,0 +1,396 @@
+//! Rotated Dilithium5 signing keys of C3A instance.
+//!
+//! The keyring (`KvDb::SIGNING_KEYS`) keeps every key which may still be used for verification. The active key
+//! is the latest one whose `activates_at` has come; it signs new tokens and responses, and its identifier is
+//! written to `MPAATHeader::kid`.
+//!
+//! On rotation the new key is added with its activation moment, and the keys in use get
+//! `expires_at = activates_at + retire_after`, so tokens signed by them stay verifiable until they expire.
+//! Retired keys are removed with their endorsements on the next rotation, so clients have to re-pin a key
+//! before it retires.
+//!
+//! The new key is endorsed by the latest key before it (see `c3a_common::EndorsedKey`); the endorsements are
+//! published with the keys at `/.well-known/c3a-keys`, so clients which have pinned a key can follow rotations.
+//!
+//! The first key of the keyring is the one generated before rotation was introduced (`KvDb::MAIN_DLTH_*`).
+//!
+//! Secret keys are kept in the keyring and restored by the configured `SignerBackend`.
+
+use c3a_common::{EndorsedKey, KeyEndorsement, PublishedKey, SignError, VerificationKey};
+use cc_server_kit::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::sync::Arc;
+use zeroize::Zeroizing;
+
+use crate::core::signer::{GeneratedKey, Signer, SignerBackend, SignerOpts};
+use crate::kv::KvDb;
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct SigningKeysOpts {
+  /// How long the previous key is accepted after the new one is activated. Should be not less than
+  /// the longest token TTL.
+  #[serde(default = "default_retire_after_hours")]
+  pub(crate) retire_after_hours: u32,
+  #[serde(default)]
+  pub(crate) signer: SignerOpts,
+}
+
+fn default_retire_after_hours() -> u32 {
+  30 * 24
+}
+
+impl Default for SigningKeysOpts {
+  fn default() -> Self {
+    Self {
+      retire_after_hours: default_retire_after_hours(),
+      signer: Default::default(),
+    }
+  }
+}
+
+impl SigningKeysOpts {
+  pub(crate) fn retire_after(&self) -> chrono::TimeDelta {
+    chrono::TimeDelta::hours(self.retire_after_hours as i64)
+  }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+struct StoredSigningKey {
+  kid: String,
+  public: Vec<u8>,
+  secret: Vec<u8>,
+  activates_at: chrono::DateTime<chrono::Utc>,
+  expires_at: Option<chrono::DateTime<chrono::Utc>>,
+  #[serde(default)]
+  endorsement: Option<KeyEndorsement>,
+}
+
+impl StoredSigningKey {
+  fn verification_key(&self) -> VerificationKey {
+    VerificationKey {
+      kid: self.kid.to_owned(),
+      public: self.public.to_owned(),
+      activates_at: self.activates_at,
+      expires_at: self.expires_at,
+    }
+  }
+
+  fn signing_key(&self, backend: &dyn SignerBackend) -> MResult<SigningKey> {
+    Ok(SigningKey {
+      kid: self.kid.to_owned(),
+      signer: backend.signer(&self.kid, &self.public, &self.secret)?,
+    })
+  }
+}
+
+/// Key which signs new tokens.
+pub(crate) struct SigningKey {
+  pub(crate) kid: String,
+  pub(crate) signer: Arc<dyn Signer>,
+}
+
+impl SigningKey {
+  pub(crate) fn public(&self) -> &[u8] {
+    self.signer.public()
+  }
+
+  /// Signs MessagePack representation of the value, as `c3a_common::sign` does.
+  pub(crate) fn sign(&self, value: &impl Serialize) -> MResult<Vec<u8>> {
+    rmp_serde::to_vec(value)
+      .map_err(SignError::Serialize)
+      .and_then(|data| self.signer.sign(&data))
+      .map_err(|e| ErrorResponse::from(format!("Sign error: {e:?}")).with_500().build())
+  }
+}
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub(crate) struct SigningKeyring {
+  keys: Vec<StoredSigningKey>,
+}
+
+/// Key identifier: hex of the first 8 bytes of SHA3-256 of the public key.
+pub(crate) fn key_id(public: &[u8]) -> String {
+  let mut hasher = Sha3_256::new();
+  hasher.update(public);
+  hex::encode(&hasher.finalize()[..8])
+}
+
+impl SigningKeyring {
+  fn add(
+    &mut self,
+    backend: &dyn SignerBackend,
+    generated: GeneratedKey,
+    activates_at: chrono::DateTime<chrono::Utc>,
+  ) -> MResult<String> {
+    let mut key = StoredSigningKey {
+      kid: key_id(&generated.public),
+      public: generated.public,
+      secret: generated.secret.to_vec(),
+      activates_at,
+      expires_at: None,
+      endorsement: None,
+    };
+
+    if let Some(previous) = self.keys.iter().max_by_key(|key| key.activates_at) {
+      let endorsed = EndorsedKey::new(&key.verification_key(), &previous.kid);
+      key.endorsement = Some(KeyEndorsement {
+        previous_kid: previous.kid.to_owned(),
+        sig: previous.signing_key(backend)?.sign(&endorsed)?,
+      });
+    }
+
+    let kid = key.kid.to_owned();
+    self.keys.push(key);
+    Ok(kid)
+  }
+
+  /// Keyring with a single active key.
+  #[cfg(test)]
+  pub(crate) fn generate() -> Self {
+    let backend = crate::core::signer::InProcessBackend;
+    let mut keyring = Self::default();
+    keyring
+      .add(&backend, backend.generate().unwrap(), chrono::DateTime::UNIX_EPOCH)
+      .unwrap();
+    keyring
+  }
+
+  /// The latest activated key which isn't retired.
+  pub(crate) fn active(&self, backend: &dyn SignerBackend, now: chrono::DateTime<chrono::Utc>) -> MResult<SigningKey> {
+    self
+      .keys
+      .iter()
+      .filter(|key| key.verification_key().is_valid_at(now))
+      .max_by_key(|key| key.activates_at)
+      .ok_or(ErrorResponse::from("No active signing key available!").with_500().build())?
+      .signing_key(backend)
+  }
+
+  /// Keys accepted for verification at the moment, including upcoming ones.
+  pub(crate) fn verification_keys(&self, now: chrono::DateTime<chrono::Utc>) -> Vec<VerificationKey> {
+    self
+      .keys
+      .iter()
+      .map(StoredSigningKey::verification_key)
+      .filter(|key| !key.is_retired_at(now))
+      .collect()
+  }
+
+  /// Verification keys with their endorsements.
+  pub(crate) fn published_keys(&self, now: chrono::DateTime<chrono::Utc>) -> Vec<PublishedKey> {
+    self
+      .keys
+      .iter()
+      .filter(|key| !key.verification_key().is_retired_at(now))
+      .map(|key| PublishedKey {
+        key: key.verification_key(),
+        endorsement: key.endorsement.clone(),
+      })
+      .collect()
+  }
+
+  /// Adds the generated key and schedules retirement of the keys in use.
+  pub(crate) fn rotate(
+    &mut self,
+    backend: &dyn SignerBackend,
+    generated: GeneratedKey,
+    activates_at: chrono::DateTime<chrono::Utc>,
+    retire_after: chrono::TimeDelta,
+    now: chrono::DateTime<chrono::Utc>,
+  ) -> MResult<String> {
+    self.keys.retain(|key| !key.verification_key().is_retired_at(now));
+    for key in self.keys.iter_mut().filter(|key| key.expires_at.is_none()) {
+      key.expires_at = Some(activates_at + retire_after);
+    }
+    self.add(backend, generated, activates_at)
+  }
+}
+
+/// Creates the keyring if there is none, taking the pre-rotation keypair if it exists.
+pub(crate) async fn init_signing_keys(kv: &KvDb) -> MResult<()> {
+  if !kv.exists(&KvDb::sealed(KvDb::SIGNING_KEYS)).await? {
+    let legacy_public = kv.get::<Vec<u8>>(KvDb::MAIN_DLTH_PUB_KEY).await?;
+    let legacy_secret = kv.get::<Vec<u8>>(KvDb::MAIN_DLTH_PRV_KEY).await?;
+    let backend = kv.signer_backend();
+    let generated = if let Some(public) = legacy_public
+      && let Some(secret) = legacy_secret
+    {
+      tracing::info!("Moving the sign keypair to the signing keyring...");
+      GeneratedKey {
+        public,
+        secret: Zeroizing::new(secret),
+      }
+    } else {
+      tracing::info!("There is no sign keypair, generating...");
+      backend.generate()?
+    };
+
+    let mut keyring = SigningKeyring::default();
+    keyring.add(backend.as_ref(), generated, chrono::DateTime::UNIX_EPOCH)?;
+    kv.insert_sealed(KvDb::SIGNING_KEYS, &keyring).await?;
+    tracing::info!("Signing keyring is ready.");
+  }
+
+  // The keypair is kept sealed in the keyring, so the plain copy is removed.
+  kv.remove(KvDb::MAIN_DLTH_PUB_KEY).await?;
+  kv.remove(KvDb::MAIN_DLTH_PRV_KEY).await?;
+  Ok(())
+}
+
+/// Generates a new signing key which becomes active at `activates_at`.
+pub(crate) async fn rotate_signing_key(
+  kv: &KvDb,
+  activates_at: chrono::DateTime<chrono::Utc>,
+  opts: &SigningKeysOpts,
+) -> MResult<(String, Vec<VerificationKey>)> {
+  let now = chrono::Utc::now();
+  let backend = kv.signer_backend();
+  // The key is generated once: if the keyring is changed concurrently, the rotation is retried with the same key.
+  let generated = backend.generate()?;
+  let (kid, keys) = kv
+    .update_sealed::<SigningKeyring, _, _>(KvDb::SIGNING_KEYS, |keyring| {
+      let keyring = keyring
+        .as_mut()
+        .ok_or(ErrorResponse::from("No signing keys available!").with_500().build())?;
+      let kid = keyring.rotate(
+        backend.as_ref(),
+        generated.clone(),
+        activates_at,
+        opts.retire_after(),
+        now,
+      )?;
+      Ok((kid, keyring.verification_keys(now)))
+    })
+    .await?;
+  tracing::info!("Signing key is rotated, `{}` is active since {}.", kid, activates_at);
+  Ok((kid, keys))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{SigningKeyring, init_signing_keys, key_id, rotate_signing_key};
+  use c3a_common::{C3AKeySet, KeyChainError, follow_key_chain};
+  use crate::core::signer::{InProcessBackend, SignerBackend};
+  use crate::kv::KvDb;
+
+  #[test]
+  fn test_rotation_keeps_old_key_until_retirement() {
+    let now = chrono::Utc::now();
+    let backend = InProcessBackend;
+    let mut keyring = SigningKeyring::default();
+    let first = keyring
+      .add(&backend, backend.generate().unwrap(), chrono::DateTime::UNIX_EPOCH)
+      .unwrap();
+
+    let activates_at = now + chrono::TimeDelta::hours(1);
+    let second = keyring
+      .rotate(
+        &backend,
+        backend.generate().unwrap(),
+        activates_at,
+        chrono::TimeDelta::hours(24),
+        now,
+      )
+      .unwrap();
+    assert_ne!(first, second);
+
+    // The new key is announced, but the old one signs until the activation.
+    assert_eq!(keyring.verification_keys(now).len(), 2);
+    assert_eq!(keyring.active(&backend, now).unwrap().kid, first);
+    assert_eq!(keyring.active(&backend, activates_at).unwrap().kid, second);
+
+    let retired = activates_at + chrono::TimeDelta::hours(24);
+    assert_eq!(keyring.verification_keys(activates_at).len(), 2);
+    assert_eq!(keyring.verification_keys(retired).len(), 1);
+    assert_eq!(keyring.verification_keys(retired)[0].kid, second);
+
+    // Retired keys are dropped on the next rotation.
+    keyring
+      .rotate(
+        &backend,
+        backend.generate().unwrap(),
+        retired,
+        chrono::TimeDelta::hours(24),
+        retired,
+      )
+      .unwrap();
+    assert_eq!(keyring.keys.len(), 2);
+    assert!(keyring.keys.iter().all(|key| key.kid.ne(&first)));
+  }
+
+  #[test]
+  fn test_rotations_are_endorsed() {
+    let now = chrono::Utc::now();
+    let backend = InProcessBackend;
+    let mut keyring = SigningKeyring::generate();
+    let pinned = keyring.active(&backend, now).unwrap().public().to_vec();
+    keyring
+      .rotate(
+        &backend,
+        backend.generate().unwrap(),
+        now,
+        chrono::TimeDelta::hours(24),
+        now,
+      )
+      .unwrap();
+    let upcoming = keyring
+      .rotate(
+        &backend,
+        backend.generate().unwrap(),
+        now + chrono::TimeDelta::hours(1),
+        chrono::TimeDelta::hours(24),
+        now,
+      )
+      .unwrap();
+
+    let key_set = C3AKeySet {
+      keys: keyring.published_keys(now),
+    };
+    assert!(key_set.keys[0].endorsement.is_none());
+    let trusted = follow_key_chain(&pinned, &key_set).unwrap();
+    assert_eq!(trusted.len(), 3);
+    assert!(trusted.iter().any(|key| key.kid == upcoming));
+
+    // Keys with forged endorsements are rejected.
+    let mut forged = key_set.clone();
+    forged.keys[2].key.public = c3a_common::generate_dilithium_keypair().public.to_vec();
+    assert!(matches!(
+      follow_key_chain(&pinned, &forged),
+      Err(KeyChainError::InvalidEndorsement(kid)) if kid == upcoming
+    ));
+
+    // Keys out of the chain are not trusted.
+    let mut detached = key_set.clone();
+    detached.keys[1].endorsement = None;
+    assert_eq!(follow_key_chain(&pinned, &detached).unwrap().len(), 1);
+    assert!(matches!(
+      follow_key_chain(&c3a_common::generate_dilithium_keypair().public, &key_set),
+      Err(KeyChainError::UnknownPinnedKey)
+    ));
+  }
+
+  #[tokio::test]
+  async fn test_legacy_keypair_is_kept() {
+    let kv = KvDb::in_memory_unsealed().await.unwrap();
+    let legacy = c3a_common::generate_dilithium_keypair();
+    kv.upsert(KvDb::MAIN_DLTH_PUB_KEY, &legacy.public.to_vec()).await.unwrap();
+    kv.upsert(KvDb::MAIN_DLTH_PRV_KEY, &legacy.expose_secret().to_vec())
+      .await
+      .unwrap();
+
+    init_signing_keys(&kv).await.unwrap();
+    init_signing_keys(&kv).await.unwrap();
+    assert!(!kv.exists(KvDb::MAIN_DLTH_PRV_KEY).await.unwrap());
+    let active = kv.get_signing_key().await.unwrap();
+    assert_eq!(active.kid, key_id(&legacy.public));
+    assert_eq!(active.public(), legacy.public);
+
+    let (kid, keys) = rotate_signing_key(&kv, chrono::Utc::now(), &Default::default())
+      .await
+      .unwrap();
+    assert_eq!(kv.get_signing_key().await.unwrap().kid, kid);
+    assert_eq!(keys.len(), 2);
+    assert_eq!(kv.get_verification_keys().await.unwrap(), keys);
+  }
+}
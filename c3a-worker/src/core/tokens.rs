@@ -0,0 +1,149 @@
+//! Issuance of applications' tokens.
+//!
+//! Token payloads are encrypted when the application has chosen `TokenEncryptionType::ChaCha20Poly1305`;
+//! the key is the application's token encryption key (see `crate::keys`), delivered to the application server
+//! on its registration.
+
+use c3a_common::{AppAuthConfiguration, TokenEncryptionType, deploy_mpaat_with_signer};
+use cc_server_kit::prelude::*;
+use serde::Serialize;
+use zeroize::Zeroizing;
+
+use crate::core::signing_keys::SigningKey;
+use crate::keys::{KEY_LENGTH, KeyHierarchy, KeyPurpose};
+
+/// Key to encrypt tokens of the application, if it has enabled encryption.
+pub(crate) fn token_encryption_key(
+  keys: &KeyHierarchy,
+  app_conf: &AppAuthConfiguration,
+) -> Option<Zeroizing<[u8; KEY_LENGTH]>> {
+  let encryption = app_conf
+    .allow_sign_up
+    .as_ref()
+    .map(|opts| &opts.token_encryption_type)
+    .unwrap_or(&TokenEncryptionType::None);
+
+  match encryption {
+    TokenEncryptionType::None => None,
+    TokenEncryptionType::ChaCha20Poly1305 => Some(keys.derive(KeyPurpose::TokenEncryption {
+      app_name: &app_conf.app_name,
+    })),
+  }
+}
+
+/// Issues MPAAT for the application according to its token encryption settings.
+pub(crate) fn issue_token<T: Serialize, U: Serialize>(
+  app_conf: &AppAuthConfiguration,
+  keys: &KeyHierarchy,
+  payload: T,
+  common_fields: Option<U>,
+  exp: chrono::DateTime<chrono::Utc>,
+  client_public: &[u8],
+  signing_key: &SigningKey,
+) -> MResult<String> {
+  let key = token_encryption_key(keys, app_conf);
+  deploy_mpaat_with_signer(
+    payload,
+    common_fields,
+    exp,
+    client_public,
+    key.as_ref().map(|key| key.as_slice()),
+    signing_key.public(),
+    Some(&signing_key.kid),
+    |data| signing_key.signer.sign(data),
+  )
+  .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{issue_token, token_encryption_key};
+  use crate::core::signer::InProcessBackend;
+  use crate::core::signing_keys::{SigningKey, SigningKeyring};
+  use crate::keys::{KeyHierarchy, KeyPurpose};
+  use c3a_common::{AppAuthConfiguration, TokenEncryptionType, mpaat_extract_payload_with_keys};
+  use serde::{Deserialize, Serialize};
+
+  #[derive(Deserialize, Serialize, PartialEq, Debug)]
+  struct Claims {
+    user_id: String,
+  }
+
+  #[derive(Deserialize, Serialize)]
+  struct Common {
+    app_name: String,
+  }
+
+  fn app_conf(token_encryption_type: TokenEncryptionType) -> AppAuthConfiguration {
+    AppAuthConfiguration {
+      app_name: String::from("test-app"),
+      domain: String::from("test-app.example.com"),
+      allowed_tags: vec![],
+      allow_sign_up: Some(c3a_common::SignUpOpts {
+        identify_by: c3a_common::IdenticationRequirement::Email {
+          exclude_email_domains: vec![],
+        },
+        allow_sign_up: true,
+        auto_assign_tags: vec![],
+        allowed_authentication_flow: vec![],
+        required_authentication: vec![],
+        allow_honeypots: false,
+        enable_fail_to_ban: None,
+        allow_recovery_key: false,
+        token_encryption_type,
+      }),
+      client_based_auth_opts: None,
+      author_dpub: vec![],
+      email_templates: None,
+      hash_params: None,
+    }
+  }
+
+  fn issue(app_conf: &AppAuthConfiguration, keys: &KeyHierarchy, signing_key: &SigningKey) -> String {
+    issue_token(
+      app_conf,
+      keys,
+      Claims {
+        user_id: String::from("user-1"),
+      },
+      Some(Common {
+        app_name: app_conf.app_name.to_owned(),
+      }),
+      chrono::Utc::now() + chrono::TimeDelta::minutes(5),
+      &c3a_common::generate_dilithium_keypair().public,
+      signing_key,
+    )
+    .unwrap()
+  }
+
+  #[test]
+  fn test_encryption_follows_app_config() {
+    let keys = KeyHierarchy::new(&c3a_common::generate::<256>());
+    let now = chrono::Utc::now();
+    let keyring = SigningKeyring::generate();
+    let signing_key = keyring.active(&InProcessBackend, now).unwrap();
+    let verification_keys = keyring.verification_keys(now);
+
+    let plain = app_conf(TokenEncryptionType::None);
+    assert!(token_encryption_key(&keys, &plain).is_none());
+    let token = issue(&plain, &keys, &signing_key);
+    let claims = mpaat_extract_payload_with_keys::<Claims, Common>(&token, None, &verification_keys, now);
+    assert!(claims.is_ok());
+
+    let encrypted = app_conf(TokenEncryptionType::ChaCha20Poly1305);
+    let token = issue(&encrypted, &keys, &signing_key);
+    let claims = mpaat_extract_payload_with_keys::<Claims, Common>(&token, None, &verification_keys, now);
+    assert!(claims.is_err());
+
+    // The application server decrypts the token with its delivered key.
+    let app_key = keys.derive(KeyPurpose::TokenEncryption { app_name: "test-app" });
+    let claims = mpaat_extract_payload_with_keys::<Claims, Common>(
+      &token,
+      Some(app_key.as_slice()),
+      &verification_keys,
+      now,
+    )
+    .unwrap();
+    assert_eq!(claims.user_id, "user-1");
+  }
+}
@@ -0,0 +1,318 @@
+//! Checks of users' secrets.
+//!
+//! A secret verified against a hash with outdated Argon2 costs or pepper is rehashed with the current ones and
+//! written back, so costs can be raised and peppers rotated without resetting users' secrets.
+
+use c3a_common::{AuthenticationStep, AuthenticationStepRequest, HashParams, UserData};
+use cc_server_kit::prelude::*;
+
+use crate::core::peppers::Peppers;
+use crate::kv::KvDb;
+use crate::kv::versioning::{decode, encode, upgrade};
+use crate::utils::{hash, validate_hash};
+
+pub(crate) fn invalid_credentials() -> ErrorResponse {
+  ErrorResponse::from("Invalid credentials.").with_401_pub().build()
+}
+
+/// Outcome of the check of a stored secret.
+#[derive(PartialEq, Eq, Debug)]
+pub(crate) enum SecretCheck {
+  Wrong,
+  Valid,
+  /// The secret is valid, and its step is rehashed with the current costs and pepper.
+  Rehashed,
+}
+
+/// Verifies the value against the hash computed with the given costs and pepper version.
+///
+/// Returns `false` if the value doesn't match; errors are left for unavailable peppers.
+pub(crate) fn verify_secret(
+  value: &str,
+  salt: &str,
+  hash: &[u8],
+  params: &HashParams,
+  pepper_version: u32,
+  peppers: &Peppers,
+) -> MResult<bool> {
+  Ok(validate_hash(value, salt, hash, peppers.get(pepper_version)?, params).is_ok())
+}
+
+/// Verifies the password or the question's answer against the stored step.
+///
+/// When the step was hashed with outdated costs or pepper, it's rehashed in place with `current` costs
+/// and the current pepper; the caller should save the user data then.
+pub(crate) fn verify_secret_step(
+  step: &mut AuthenticationStep,
+  value: &str,
+  peppers: &Peppers,
+  current: &HashParams,
+) -> MResult<SecretCheck> {
+  let (salt, hash_value, params, pepper_version) = match step {
+    AuthenticationStep::Password {
+      salt,
+      hash,
+      params,
+      pepper_version,
+    } => (salt, hash, params, pepper_version),
+    AuthenticationStep::Question {
+      salt,
+      hash,
+      params,
+      pepper_version,
+      ..
+    } => (salt, hash, params, pepper_version),
+    _ => {
+      return Err(
+        ErrorResponse::from("Authentication step doesn't contain a secret.")
+          .with_500()
+          .build(),
+      );
+    }
+  };
+
+  let stored_salt =
+    String::from_utf8(salt.clone()).map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+  if !verify_secret(value, &stored_salt, hash_value, params, *pepper_version, peppers)? {
+    return Ok(SecretCheck::Wrong);
+  }
+
+  let (current_pepper_version, current_pepper) = peppers.current()?;
+  if *params == *current && *pepper_version == current_pepper_version {
+    return Ok(SecretCheck::Valid);
+  }
+
+  let (new_salt, new_hash) = hash(value, current_pepper, current)?;
+  *salt = new_salt.into_bytes();
+  *hash_value = new_hash;
+  *params = *current;
+  *pepper_version = current_pepper_version;
+
+  Ok(SecretCheck::Rehashed)
+}
+
+/// Verifies the user's password or answer to the question, and rehashes the step if it's outdated.
+///
+/// The rehashed record is written with compare-and-swap; if the user is changed meanwhile, the rehash is
+/// skipped until the next successful check. Returns `false` for wrong secrets and, alike, for unknown users
+/// and users without such step.
+pub(crate) async fn verify_user_secret(
+  kv: &KvDb,
+  app_name: &str,
+  identifier: &str,
+  secret: &AuthenticationStepRequest,
+  peppers: &Peppers,
+  current: &HashParams,
+) -> MResult<bool> {
+  let value = match secret {
+    AuthenticationStepRequest::Password { password } => password,
+    AuthenticationStepRequest::Question { answer, .. } => answer,
+    _ => {
+      return Err(
+        ErrorResponse::from("Only passwords and answers to questions can be verified.")
+          .with_400_pub()
+          .build(),
+      );
+    }
+  };
+
+  let key = KvDb::user(app_name, identifier);
+  let Some(stored) = kv.get_raw(&key).await? else {
+    return Ok(false);
+  };
+  let mut user_data = match upgrade::<UserData>(&stored)? {
+    None => decode::<UserData>(&stored)?,
+    Some(upgraded) => decode::<UserData>(&upgraded)?,
+  };
+
+  let Some(step) = user_data
+    .authentication_flows
+    .iter_mut()
+    .flatten()
+    .find(|step| match (secret, step) {
+      (AuthenticationStepRequest::Password { .. }, AuthenticationStep::Password { .. }) => true,
+      (AuthenticationStepRequest::Question { question, .. }, AuthenticationStep::Question { question: stored, .. }) => {
+        question.eq(stored)
+      }
+      _ => false,
+    })
+  else {
+    return Ok(false);
+  };
+
+  let check = verify_secret_step(step, value, peppers, current)?;
+  if check == SecretCheck::Rehashed {
+    if kv
+      .compare_and_swap_raw(&key, Some(stored), Some(encode(&user_data)?))
+      .await?
+    {
+      tracing::debug!(
+        "users: secret of `{}` user in `{}` app is rehashed",
+        identifier,
+        app_name
+      );
+    } else {
+      tracing::debug!(
+        "users: `{}` user in `{}` app is changed meanwhile, rehash is skipped",
+        identifier,
+        app_name
+      );
+    }
+  }
+
+  Ok(check != SecretCheck::Wrong)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{SecretCheck, verify_secret_step, verify_user_secret};
+  use crate::Setup;
+  use crate::core::peppers::{Peppers, init_peppers, rotate_pepper};
+  use crate::kv::KvDb;
+  use crate::utils::hash;
+  use c3a_common::{AuthenticationStep, AuthenticationStepRequest, HashParams, UserData};
+
+  const OLD_PARAMS: HashParams = HashParams {
+    m_cost: 8 * 1024,
+    t_cost: 1,
+    p_cost: 1,
+  };
+
+  async fn peppers() -> (KvDb, Setup, Peppers) {
+    let kv = KvDb::in_memory_unsealed().await.unwrap();
+    init_peppers(&kv).await.unwrap();
+
+    let setup = Setup {
+      private_adm_key: Some("test-key-".repeat(16)),
+      ..Default::default()
+    };
+    let peppers = Peppers::load(&kv, &setup).await.unwrap();
+    (kv, setup, peppers)
+  }
+
+  fn password_step(password: &str, peppers: &Peppers, version: u32, params: HashParams) -> AuthenticationStep {
+    let (salt, hash) = hash(password, peppers.get(version).unwrap(), &params).unwrap();
+    AuthenticationStep::Password {
+      salt: salt.into_bytes(),
+      hash,
+      params,
+      pepper_version: version,
+    }
+  }
+
+  fn password(password: &str) -> AuthenticationStepRequest {
+    AuthenticationStepRequest::Password {
+      password: password.to_owned(),
+    }
+  }
+
+  async fn stored_step(kv: &KvDb) -> AuthenticationStep {
+    let user_data = kv
+      .get_versioned::<UserData>(&KvDb::user("app", "alice"))
+      .await
+      .unwrap()
+      .unwrap();
+    user_data.authentication_flows[0][0].clone()
+  }
+
+  #[tokio::test]
+  async fn test_rehash_on_outdated_params() {
+    let (_, _, peppers) = peppers().await;
+    let current = HashParams::default();
+
+    let mut step = password_step("hello world!", &peppers, 1, OLD_PARAMS);
+    let check = verify_secret_step(&mut step, "hello world!", &peppers, &current).unwrap();
+    assert_eq!(check, SecretCheck::Rehashed);
+    assert!(matches!(&step, AuthenticationStep::Password { params, .. } if *params == current));
+
+    // Rehashed step is verified with new costs and is not rehashed again.
+    let check = verify_secret_step(&mut step, "hello world!", &peppers, &current).unwrap();
+    assert_eq!(check, SecretCheck::Valid);
+  }
+
+  #[tokio::test]
+  async fn test_invalid_secret_is_not_rehashed() {
+    let (_, _, peppers) = peppers().await;
+
+    let mut step = password_step("hello world!", &peppers, 1, OLD_PARAMS);
+    let before = step.clone();
+    let check = verify_secret_step(&mut step, "hello world?", &peppers, &HashParams::default()).unwrap();
+    assert_eq!(check, SecretCheck::Wrong);
+    assert!(step == before);
+  }
+
+  #[tokio::test]
+  async fn test_pepper_rotation() {
+    let (kv, setup, peppers) = peppers().await;
+    let params = HashParams::default();
+    let mut step = password_step("hello world!", &peppers, 1, params);
+
+    rotate_pepper(&kv).await.unwrap();
+    let peppers = Peppers::load(&kv, &setup).await.unwrap();
+
+    // The old pepper is still accepted, and the secret is moved to the new one.
+    let check = verify_secret_step(&mut step, "hello world!", &peppers, &params).unwrap();
+    assert_eq!(check, SecretCheck::Rehashed);
+    assert!(matches!(&step, AuthenticationStep::Password { pepper_version: 2, .. }));
+    let check = verify_secret_step(&mut step, "hello world!", &peppers, &params).unwrap();
+    assert_eq!(check, SecretCheck::Valid);
+  }
+
+  #[tokio::test]
+  async fn test_legacy_steps() {
+    #[derive(serde::Serialize)]
+    #[serde(rename_all = "snake_case", tag = "type")]
+    enum LegacyStep {
+      Password { salt: Vec<u8>, hash: Vec<u8> },
+    }
+
+    let (_, _, peppers) = peppers().await;
+    let (salt, hash) = hash(
+      "hello world!",
+      peppers.get(Peppers::LEGACY_VERSION).unwrap(),
+      &HashParams::default(),
+    )
+    .unwrap();
+    let legacy = rmp_serde::to_vec(&LegacyStep::Password {
+      salt: salt.into_bytes(),
+      hash,
+    })
+    .unwrap();
+
+    // Steps stored without costs and pepper version use Argon2 defaults and the legacy pepper.
+    let mut step = rmp_serde::from_slice::<AuthenticationStep>(&legacy).unwrap();
+    let check = verify_secret_step(&mut step, "hello world!", &peppers, &HashParams::default()).unwrap();
+    assert_eq!(check, SecretCheck::Rehashed);
+    assert!(matches!(&step, AuthenticationStep::Password { pepper_version: 1, .. }));
+  }
+
+  #[tokio::test]
+  async fn test_stored_secret_is_rehashed() {
+    let (kv, _, peppers) = peppers().await;
+    let current = HashParams::default();
+    let user_data = UserData {
+      identifier: String::from("alice"),
+      authentication_flows: vec![vec![password_step("hello world!", &peppers, 1, OLD_PARAMS)]],
+    };
+    kv.insert_versioned(&KvDb::user("app", "alice"), &user_data)
+      .await
+      .unwrap();
+
+    // Wrong secrets and unknown users are refused alike, and nothing is rewritten.
+    for (identifier, secret) in [("alice", "hello world?"), ("bob", "hello world!")] {
+      let checked = verify_user_secret(&kv, "app", identifier, &password(secret), &peppers, &current).await;
+      assert!(!checked.unwrap());
+    }
+    assert!(matches!(stored_step(&kv).await, AuthenticationStep::Password { params, .. } if params == OLD_PARAMS));
+
+    let checked = verify_user_secret(&kv, "app", "alice", &password("hello world!"), &peppers, &current).await;
+    assert!(checked.unwrap());
+    let step = stored_step(&kv).await;
+    assert!(matches!(&step, AuthenticationStep::Password { params, .. } if *params == current));
+
+    // The rewritten hash is verified by itself and isn't rewritten again.
+    let checked = verify_user_secret(&kv, "app", "alice", &password("hello world!"), &peppers, &current).await;
+    assert!(checked.unwrap());
+    assert!(stored_step(&kv).await == step);
+  }
+}
@@ -1,6 +1,8 @@
-use c3a_common::{AuthenticationData, AuthenticationRequirement, TOTPAlgorithm};
+use c3a_common::{AuthenticationData, AuthenticationRequirement, HashParams, TOTPAlgorithm};
 use cc_server_kit::prelude::*;
 
+use crate::core::peppers::Peppers;
+use crate::mailer::templates::{EmailContext, EmailKind, EmailRenderer};
 use crate::utils::{generate_numeric, hash};
 
 pub(crate) fn gen_u2f_requirement(
@@ -42,29 +44,33 @@ pub(crate) fn gen_totp_requirement(method: &AuthenticationRequirement, metadata:
 pub(crate) fn gen_email_requirement(
   method: &AuthenticationRequirement,
   id: &str,
-  pepper: &[u8],
+  peppers: &Peppers,
+  hash_params: &HashParams,
+  renderer: &EmailRenderer<'_>,
+  ctx: &EmailContext<'_>,
   metadata: &mut Vec<AuthenticationData>,
   mail_to_send: &mut Option<lettre::Message>,
 ) -> MResult<()> {
-  use lettre::{Message, message::header::ContentType};
-
   if matches!(method, AuthenticationRequirement::EmailConfirmation) {
     let approve_code = generate_numeric(8)?;
-    let (salt, hash) = hash(&approve_code, pepper)?;
+    let (pepper_version, pepper) = peppers.current()?;
+    let (salt, hash) = hash(&approve_code, pepper, hash_params)?;
 
-    let email = Message::builder()
-      .from(
-        "Verbal Automation Systems - C3A <no-reply@mail.verbalautomation.tech>"
-          .parse()
-          .unwrap(),
-      )
-      .to(id.parse().unwrap())
-      .subject("Email verification")
-      .header(ContentType::TEXT_PLAIN)
-      .body(format!("Code to confirm the account registration: {}", approve_code))
-      .unwrap();
+    let email = renderer.render(
+      EmailKind::Confirmation,
+      id,
+      &EmailContext {
+        code: Some(&approve_code),
+        ..*ctx
+      },
+    )?;
 
-    metadata.push(AuthenticationData::Email { salt, hash });
+    metadata.push(AuthenticationData::Email {
+      salt,
+      hash,
+      params: *hash_params,
+      pepper_version,
+    });
     *mail_to_send = Some(email);
 
     Ok(())
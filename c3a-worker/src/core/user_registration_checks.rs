@@ -1,27 +1,118 @@
-use c3a_common::{AuthenticationFlow, AuthenticationFlowRequest, AuthenticationStep, AuthenticationStepRequest, SignUpOpts};
+use c3a_common::{
+  AuthenticationData, AuthenticationFlow, AuthenticationFlowRequest, AuthenticationStep, AuthenticationStepRequest,
+  HashParams, PasswordPolicyViolation, SignUpOpts,
+};
 use cc_server_kit::prelude::*;
 
 use crate::api::users::RegistrationStatePayload;
+use crate::core::password_policy::{BreachedPasswords, check_password};
+use crate::core::peppers::Peppers;
+use crate::core::user_authentication_checks::verify_secret;
+use crate::utils::hash;
+
+/// User- and instance-specific data to check and store authentication steps.
+pub(crate) struct FlowValidationContext<'a> {
+  pub(crate) app_name: &'a str,
+  pub(crate) peppers: &'a Peppers,
+  pub(crate) hash_params: HashParams,
+  pub(crate) breached: &'a BreachedPasswords,
+}
+
+/// Why the registration's flows are refused.
+pub(crate) enum FlowRejection {
+  /// The password violates the app's policy; reported as `c3a_common::PasswordPolicyViolationsResponse`.
+  PasswordPolicy(Vec<PasswordPolicyViolation>),
+  /// The email confirmation code is wrong; counted as an unsuccessful attempt, see `crate::core::bans`.
+  WrongSecret,
+  Error(ErrorResponse),
+}
+
+impl From<ErrorResponse> for FlowRejection {
+  fn from(e: ErrorResponse) -> Self {
+    Self::Error(e)
+  }
+}
 
 pub(crate) fn validate_authentication_flows(
   registration_state: RegistrationStatePayload,
   authentication_flows_reqs: &Vec<AuthenticationFlowRequest>,
   sign_up_opts: &SignUpOpts,
-) -> MResult<Vec<AuthenticationFlow>> {
+  ctx: &FlowValidationContext<'_>,
+) -> Result<Vec<AuthenticationFlow>, FlowRejection> {
   let mut flows = vec![];
-  
+
   for flow_req in authentication_flows_reqs {
     let mut flow = vec![];
-    
+
     for step_req in flow_req {
       let step = match step_req {
         AuthenticationStepRequest::Password { password } => {
-          
+          let policy = sign_up_opts
+            .required_authentication
+            .iter()
+            .chain(sign_up_opts.allowed_authentication_flow.iter())
+            .find_map(|requirement| requirement.password_policy())
+            .ok_or(
+              ErrorResponse::from("Password authentication is not allowed by application.")
+                .with_400_pub()
+                .build(),
+            )?;
+          let violations = check_password(
+            &policy,
+            password,
+            &[registration_state.requested_identifier.as_str(), ctx.app_name],
+            ctx.breached,
+          )?;
+          if !violations.is_empty() {
+            return Err(FlowRejection::PasswordPolicy(violations));
+          }
+
+          let (pepper_version, pepper) = ctx.peppers.current()?;
+          let (salt, hash) = hash(password, pepper, &ctx.hash_params)?;
+          AuthenticationStep::Password {
+            salt: salt.into_bytes(),
+            hash,
+            params: ctx.hash_params,
+            pepper_version,
+          }
+        }
+        AuthenticationStepRequest::EmailConfirmation { code } => {
+          let (salt, hash, params, pepper_version) = registration_state
+            .metadata
+            .iter()
+            .find_map(|data| match data {
+              AuthenticationData::Email {
+                salt,
+                hash,
+                params,
+                pepper_version,
+              } => Some((salt, hash, params, *pepper_version)),
+              _ => None,
+            })
+            .ok_or(
+              ErrorResponse::from("Email confirmation wasn't requested on preregistration.")
+                .with_400_pub()
+                .build(),
+            )?;
+          if !verify_secret(code, salt, hash, params, pepper_version, ctx.peppers)? {
+            return Err(FlowRejection::WrongSecret);
+          }
+          AuthenticationStep::EmailConfirmation
+        }
+        _ => {
+          return Err(
+            ErrorResponse::from("This authentication step is not supported yet.")
+              .with_400_pub()
+              .build()
+              .into(),
+          );
         }
       };
       flow.push(step);
     }
+
+    flows.push(flow);
   }
-  
+
   Ok(flows)
 }
@@ -0,0 +1,124 @@
+//! Key hierarchy of C3A instance.
+//!
+//! Every symmetric key used by the worker is derived from the main secret (`KvDb::MAIN_SECRET_KEY`, 256 random
+//! bytes) with HKDF-SHA256 (RFC 5869):
+//!
+//! ```text
+//! PRK = HKDF-Extract(salt = "c3a-worker::hkdf::v1", IKM = main secret)
+//! key = HKDF-Expand(PRK, info = label || 0x00 || context, L = 32)
+//! ```
+//!
+//! | Purpose                  | `label`                          | `context` |
+//! |--------------------------|----------------------------------|-----------|
+//! | Token encryption         | `c3a-worker::token-encryption`   | app name  |
+//! | Registration/login state | `c3a-worker::registration-state` | empty     |
+//!
+//! Stored records aren't encrypted with these keys: the main secret is itself a sealed record, so sealing uses
+//! the KEK derived from the master key (see `crate::kv::sealing`).
+//!
+//! The main secret never leaves the worker. An application server only gets its own token encryption key,
+//! which is a 32-byte ChaCha20-Poly1305 key for `TokenEncryptionType::ChaCha20Poly1305` tokens; knowing it
+//! reveals nothing about keys of other applications or other purposes.
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+const HKDF_SALT: &[u8] = b"c3a-worker::hkdf::v1";
+
+pub(crate) const KEY_LENGTH: usize = 32;
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum KeyPurpose<'a> {
+  /// Encryption of the application's tokens.
+  TokenEncryption { app_name: &'a str },
+  /// Encryption of registration and login states (see `crate::core::auth_states`).
+  RegistrationState,
+}
+
+impl KeyPurpose<'_> {
+  fn label(&self) -> &'static [u8] {
+    match self {
+      Self::TokenEncryption { .. } => b"c3a-worker::token-encryption",
+      Self::RegistrationState => b"c3a-worker::registration-state",
+    }
+  }
+
+  fn context(&self) -> &[u8] {
+    match self {
+      Self::TokenEncryption { app_name } => app_name.as_bytes(),
+      _ => &[],
+    }
+  }
+}
+
+/// Derives subkeys from the main secret.
+pub(crate) struct KeyHierarchy {
+  hkdf: Hkdf<Sha256>,
+}
+
+impl KeyHierarchy {
+  pub(crate) fn new(main_secret: &[u8]) -> Self {
+    Self {
+      hkdf: Hkdf::<Sha256>::new(Some(HKDF_SALT), main_secret),
+    }
+  }
+
+  pub(crate) fn derive(&self, purpose: KeyPurpose<'_>) -> Zeroizing<[u8; KEY_LENGTH]> {
+    let label = purpose.label();
+    let context = purpose.context();
+
+    let mut key = Zeroizing::new([0u8; KEY_LENGTH]);
+    self
+      .hkdf
+      .expand_multi_info(&[label, &[0], context], key.as_mut_slice())
+      .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{KeyHierarchy, KeyPurpose};
+  use hkdf::Hkdf;
+  use sha2::Sha256;
+
+  #[test]
+  fn test_derivation_is_documented() {
+    let secret = c3a_common::generate::<256>();
+    let keys = KeyHierarchy::new(&secret);
+
+    let mut expected = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&b"c3a-worker::hkdf::v1"[..]), &secret)
+      .expand(b"c3a-worker::token-encryption\x00test-app", &mut expected)
+      .unwrap();
+
+    assert_eq!(
+      *keys.derive(KeyPurpose::TokenEncryption { app_name: "test-app" }),
+      expected
+    );
+  }
+
+  #[test]
+  fn test_subkeys_are_separated() {
+    let secret = c3a_common::generate::<256>();
+    let keys = KeyHierarchy::new(&secret);
+
+    let derived = [
+      keys.derive(KeyPurpose::TokenEncryption { app_name: "app-1" }),
+      keys.derive(KeyPurpose::TokenEncryption { app_name: "app-2" }),
+      keys.derive(KeyPurpose::RegistrationState),
+    ];
+    for (i, a) in derived.iter().enumerate() {
+      for b in derived.iter().skip(i + 1) {
+        assert_ne!(**a, **b);
+      }
+    }
+
+    // Same secret gives the same keys.
+    let again = KeyHierarchy::new(&secret);
+    assert_eq!(*again.derive(KeyPurpose::RegistrationState), *derived[2]);
+    let other = KeyHierarchy::new(&c3a_common::generate::<256>());
+    assert_ne!(*other.derive(KeyPurpose::RegistrationState), *derived[2]);
+  }
+}
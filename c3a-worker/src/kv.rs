@@ -1,13 +1,34 @@
-use c3a_common::AppAuthConfiguration;
+use c3a_common::BanSubject;
 use cc_server_kit::prelude::*;
-use fjall::{Keyspace, PartitionHandle, PersistMode, Slice};
 use serde::{Serialize, de::DeserializeOwned};
 use sha3::{Digest, Sha3_256};
+use std::sync::{Arc, RwLock};
+use zeroize::Zeroizing;
+
+use crate::core::peppers::init_peppers;
+use crate::core::signer::{InProcessBackend, SignerBackend};
+use crate::core::signing_keys::{SigningKey, init_signing_keys};
+use crate::keys::KeyHierarchy;
+use crate::kv::cache::{Cache, CachedSignerBackend};
+use crate::kv::storage::{Expect, FjallStorage, Storage, StorageOpts};
+
+pub(crate) mod backup;
+pub(crate) mod cache;
+pub(crate) mod sealing;
+pub(crate) mod storage;
+pub(crate) mod transactions;
+pub(crate) mod unseal;
+pub(crate) mod users;
+pub(crate) mod versioning;
 
 #[derive(Clone)]
 pub(crate) struct KvDb {
-  keyspace: Keyspace,
-  db: PartitionHandle,
+  storage: Arc<dyn Storage>,
+  /// Key-encryption key of sealed records, shared by all clones; `None` while the storage is sealed.
+  kek: Arc<RwLock<Option<Arc<sealing::Kek>>>>,
+  /// Holder of signing keys' secrets, see `crate::core::signer`; wrapped into `CachedSignerBackend`.
+  signer_backend: Arc<dyn SignerBackend>,
+  cache: Arc<Cache>,
 }
 
 pub(crate) struct PreConverted {
@@ -21,12 +42,8 @@ impl PreConverted {
     })
   }
 
-  pub(crate) fn as_ref(&self) -> &Vec<u8> {
-    &self.val
-  }
-
-  pub(crate) fn from_raw(slice: Slice) -> Self {
-    Self { val: slice.to_vec() }
+  pub(crate) fn from_raw(val: Vec<u8>) -> Self {
+    Self { val }
   }
 
   #[allow(dead_code)]
@@ -37,252 +54,370 @@ impl PreConverted {
 
 #[allow(dead_code)]
 impl KvDb {
+  /// 256 random bytes; all symmetric keys are derived from it (see `crate::keys`).
   pub(crate) const MAIN_SECRET_KEY: &str = "main_secret";
+  /// Sign keypair generated before rotation was introduced; moved to `SIGNING_KEYS` on startup.
   pub(crate) const MAIN_DLTH_PUB_KEY: &str = "main_sign_pub";
   pub(crate) const MAIN_DLTH_PRV_KEY: &str = "main_sign_prv";
+  /// Rotated sign keys, see `crate::core::signing_keys`.
+  pub(crate) const SIGNING_KEYS: &str = "signing_keys";
 
   pub(crate) const INVITES: &str = "invites";
+  /// Registered admins, see `crate::core::admins`.
+  pub(crate) const ADMINS: &str = "admins";
+  /// Nonces of recently accepted admins' requests.
+  pub(crate) const ADMIN_NONCES: &str = "admin_nonces";
+  pub(crate) const PEPPERS: &str = "peppers";
 
   pub(crate) const APPLICATION_PREFIX: &str = "app::";
+  /// Users of an application: `user::<app-hash>::<identifier-hash>`; see `crate::kv::users`.
   pub(crate) const USER_PREFIX: &str = "user::";
+  /// Identifiers of an application's users: `user_index::<app-hash>::<identifier-hash>`.
+  pub(crate) const USER_INDEX_PREFIX: &str = "user_index::";
+  pub(crate) const STATE_PREFIX: &str = "state::";
+  pub(crate) const OUTBOX_PREFIX: &str = "outbox::";
+  pub(crate) const OUTBOX_DEAD_PREFIX: &str = "outbox_dead::";
+  pub(crate) const MAIL_RATE_PREFIX: &str = "mail_rate::";
+  /// Admins' actions: `audit::<timestamp-nanos>::<random>`; see `crate::core::audit`.
+  pub(crate) const AUDIT_PREFIX: &str = "audit::";
+  /// Unsuccessful attempts and bans: `ban::<app-hash>::<subject-hash>`; see `crate::core::bans`.
+  pub(crate) const BAN_PREFIX: &str = "ban::";
+
+  /// Opens fjall keyspace.
+  pub(crate) fn load(opts: &StorageOpts) -> MResult<Self> {
+    Ok(Self::with_storage(Arc::new(FjallStorage::open(opts)?)))
+  }
+
+  pub(crate) fn with_storage(storage: Arc<dyn Storage>) -> Self {
+    let cache = Arc::<Cache>::default();
+    Self {
+      storage,
+      kek: Default::default(),
+      signer_backend: Arc::new(CachedSignerBackend {
+        backend: Arc::new(InProcessBackend),
+        cache: cache.clone(),
+      }),
+      cache,
+    }
+  }
 
-  pub(crate) fn load(partition_name: &str) -> MResult<Self> {
-    let keyspace = fjall::Config::default()
-      .open()
-      .map_err(|e| ErrorResponse::from(e.to_string()).with_500_pub().build())?;
-    let db = keyspace
-      .open_partition(partition_name, Default::default())
-      .map_err(|e| ErrorResponse::from(e.to_string()).with_500_pub().build())?;
+  /// Sealed storage in memory.
+  #[cfg(test)]
+  pub(crate) fn in_memory() -> Self {
+    Self::with_storage(Arc::new(crate::kv::storage::MemoryStorage::default()))
+  }
+
+  /// Another instance over the same records, sealed.
+  #[cfg(test)]
+  pub(crate) fn reopen(&self) -> Self {
+    Self::with_storage(self.storage.clone())
+  }
 
-    Ok(Self { keyspace, db })
+  pub(crate) fn set_signer_backend(&mut self, backend: Arc<dyn SignerBackend>) {
+    self.signer_backend = Arc::new(CachedSignerBackend {
+      backend,
+      cache: self.cache.clone(),
+    });
   }
 
+  pub(crate) fn signer_backend(&self) -> Arc<dyn SignerBackend> {
+    self.signer_backend.clone()
+  }
+
+  /// Storage in memory unsealed with a test master key.
+  #[cfg(test)]
+  pub(crate) async fn in_memory_unsealed() -> MResult<Self> {
+    let kv = Self::in_memory();
+    kv.unseal(b"test-master-key-XXXXXXXXXXXXXXXXXXXXXXXX").await?;
+    Ok(kv)
+  }
+
+  /// Generates missing secrets; should be called after `KvDb::unseal`.
   pub(crate) async fn initial_setup(&self) -> MResult<()> {
-    if self.get::<Vec<u8>>(KvDb::MAIN_SECRET_KEY).await?.is_none() {
+    self.seal_plain_records().await?;
+
+    if !self.exists(&KvDb::sealed(KvDb::MAIN_SECRET_KEY)).await? {
       tracing::info!("There is no main secret key, generating...");
-      let new_secret = c3a_common::generate_chacha20poly1305_key();
-      self.insert(KvDb::MAIN_SECRET_KEY, &new_secret.to_vec()).await?;
+      let new_secret = Zeroizing::new(c3a_common::generate_chacha20poly1305_key().to_vec());
+      self.insert_sealed(KvDb::MAIN_SECRET_KEY, &*new_secret).await?;
       tracing::info!("Main secret key is generated.");
     }
 
-    if self.get::<Vec<u8>>(KvDb::MAIN_DLTH_PUB_KEY).await?.is_none()
-      || self.get::<Vec<u8>>(KvDb::MAIN_DLTH_PRV_KEY).await?.is_none()
-    {
-      tracing::info!("There is no sign keypair, generating...");
-      let keypair = c3a_common::generate_dilithium_keypair();
-      self.insert(KvDb::MAIN_DLTH_PUB_KEY, &keypair.public.to_vec()).await?;
-      self
-        .insert(KvDb::MAIN_DLTH_PRV_KEY, &keypair.expose_secret().to_vec())
-        .await?;
-      tracing::info!("Sign keypair generated.");
-    }
+    init_signing_keys(self).await?;
+    init_peppers(self).await?;
 
     Ok(())
   }
 
-  pub(crate) fn app(app_name: &str) -> String {
+  fn name_hash(name: &str) -> String {
     let mut hasher = Sha3_256::new();
-    hasher.update(app_name.as_bytes());
-    let hash = hex::encode(hasher.finalize());
+    hasher.update(name.as_bytes());
+    hex::encode(hasher.finalize())
+  }
 
-    format!("{}{}", Self::APPLICATION_PREFIX, hash)
+  pub(crate) fn app(app_name: &str) -> String {
+    format!("{}{}", Self::APPLICATION_PREFIX, Self::name_hash(app_name))
   }
 
-  pub(crate) fn user(user_name: &str) -> String {
-    let mut hasher = Sha3_256::new();
-    hasher.update(user_name.as_bytes());
-    let hash = hex::encode(hasher.finalize());
+  pub(crate) fn user(app_name: &str, user_name: &str) -> String {
+    format!("{}{}", Self::users_of(app_name), Self::name_hash(user_name))
+  }
 
-    format!("{}{}", Self::USER_PREFIX, hash)
+  /// Prefix of all users of the application.
+  pub(crate) fn users_of(app_name: &str) -> String {
+    format!("{}{}::", Self::USER_PREFIX, Self::name_hash(app_name))
   }
 
-  pub(crate) async fn exists(&self, key: &str) -> MResult<bool> {
-    let state = self.clone();
-    let _key = key.to_string();
+  pub(crate) fn user_index(app_name: &str, user_name: &str) -> String {
+    format!("{}{}", Self::user_index_of(app_name), Self::name_hash(user_name))
+  }
 
-    let exists = tokio::task::spawn_blocking(move || state.db.contains_key(_key))
-      .await
-      .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?
-      .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+  /// Prefix of the application's user index.
+  pub(crate) fn user_index_of(app_name: &str) -> String {
+    format!("{}{}::", Self::USER_INDEX_PREFIX, Self::name_hash(app_name))
+  }
 
-    Ok(exists)
+  pub(crate) fn ban(app_name: &str, subject: &BanSubject) -> String {
+    let subject = match subject {
+      BanSubject::Login(identifier) => format!("login:{identifier}"),
+      BanSubject::Ip(ip) => format!("ip:{ip}"),
+    };
+    format!("{}{}", Self::bans_of(app_name), Self::name_hash(&subject))
   }
 
-  pub(crate) async fn get_dilithium_keypair(&self) -> MResult<c3a_common::Keypair> {
-    let pub_key = self
-      .get::<Vec<u8>>(KvDb::MAIN_DLTH_PUB_KEY)
-      .await?
-      .ok_or(ErrorResponse::from("No public key available!").with_500().build())?;
-    let prv_key = self
-      .get::<Vec<u8>>(KvDb::MAIN_DLTH_PRV_KEY)
-      .await?
-      .ok_or(ErrorResponse::from("No public key available!").with_500().build())?;
-    c3a_common::Keypair::restore(&pub_key, &prv_key)
-      .map_err(|_| ErrorResponse::from("Can't restore keypair!").with_500().build())
+  /// Prefix of the application's bans.
+  pub(crate) fn bans_of(app_name: &str) -> String {
+    format!("{}{}::", Self::BAN_PREFIX, Self::name_hash(app_name))
   }
 
-  pub(crate) async fn get_secret_key(&self) -> MResult<[u8; 256]> {
-    use std::mem::MaybeUninit;
+  /// Key of the audit record; records are ordered by the time of actions.
+  pub(crate) fn audit(at: chrono::DateTime<chrono::Utc>) -> String {
+    format!(
+      "{}{:020}::{}",
+      Self::AUDIT_PREFIX,
+      at.timestamp_nanos_opt().unwrap_or_default(),
+      hex::encode(c3a_common::generate::<8>())
+    )
+  }
 
-    let secret = self
-      .get::<Vec<u8>>(KvDb::MAIN_SECRET_KEY)
-      .await?
-      .ok_or(ErrorResponse::from("No public key available!").with_500().build())?;
-    let buffer: [MaybeUninit<u8>; 256] = unsafe { MaybeUninit::uninit().assume_init() };
-    let mut buffer = unsafe { std::mem::transmute::<[MaybeUninit<u8>; 256], [u8; 256]>(buffer) };
-    buffer.copy_from_slice(&secret);
-    Ok(buffer)
+  pub(crate) fn state(state_id: &[u8]) -> String {
+    format!("{}{}", Self::STATE_PREFIX, hex::encode(state_id))
+  }
+
+  /// Runs the blocking storage call off the async runtime.
+  async fn blocking<R, F>(&self, f: F) -> MResult<R>
+  where
+    R: Send + 'static,
+    F: FnOnce(&dyn Storage) -> MResult<R> + Send + 'static,
+  {
+    let storage = self.storage.clone();
+    tokio::task::spawn_blocking(move || f(storage.as_ref()))
+      .await
+      .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?
   }
 
-  pub(crate) async fn get_app_conf(&self, app_name: &str) -> MResult<AppAuthConfiguration> {
+  pub(crate) async fn exists(&self, key: &str) -> MResult<bool> {
+    let _key = key.to_string();
+    self.blocking(move |storage| storage.contains(&_key)).await
+  }
+
+  /// Key which signs new tokens and responses.
+  pub(crate) async fn get_signing_key(&self) -> MResult<SigningKey> {
     self
-      .get::<AppAuthConfiguration>(&KvDb::app(app_name))
+      .get_signing_keyring()
       .await?
-      .ok_or(ErrorResponse::from("There is no such app.").with_404_pub().build())
+      .active(self.signer_backend.as_ref(), chrono::Utc::now())
   }
 
-  pub(crate) async fn get<T: DeserializeOwned>(&self, key: &str) -> MResult<Option<T>> {
-    let state = self.clone();
-    let _key = key.to_string();
+  /// Keys accepted for verification of tokens issued by this instance.
+  pub(crate) async fn get_verification_keys(&self) -> MResult<Vec<c3a_common::VerificationKey>> {
+    Ok(self.get_signing_keyring().await?.verification_keys(chrono::Utc::now()))
+  }
 
-    let item = tokio::task::spawn_blocking(move || state.db.get(&_key))
-      .await
-      .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?
-      .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+  /// Subkeys derived from the main secret, see `crate::keys`.
+  pub(crate) async fn get_keys(&self) -> MResult<KeyHierarchy> {
+    let secret = self.get_secret_key().await?;
+    Ok(KeyHierarchy::new(secret.as_slice()))
+  }
 
-    let slice = if let Some(item) = item { item } else { return Ok(None) };
+  pub(crate) async fn get<T: DeserializeOwned>(&self, key: &str) -> MResult<Option<T>> {
+    let slice = if let Some(item) = self.get_raw(key).await? { item } else { return Ok(None) };
     let value =
       rmp_serde::from_slice::<T>(&slice).map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
 
-    tracing::trace!("fjall: got value by path `{}`", key);
+    tracing::trace!("kv: got value by path `{}`", key);
 
     Ok(Some(value))
   }
 
+  /// Returns the stored bytes without deserialization.
+  pub(crate) async fn get_raw(&self, key: &str) -> MResult<Option<Vec<u8>>> {
+    let _key = key.to_string();
+    self.blocking(move |storage| storage.get(&_key)).await
+  }
+
   pub(crate) async fn insert<T: Serialize>(&self, key: &str, value: &T) -> MResult<()> {
     let vec = rmp_serde::to_vec(value).map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
-    let state = self.clone();
-    let _key = key.to_string();
+    self.insert_raw(key, vec).await
+  }
 
-    tokio::task::spawn_blocking(move || {
-      if state
-        .db
-        .contains_key(&_key)
-        .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?
-      {
-        return Err(ErrorResponse::from("Key already exists!").with_400().build());
-      }
-      state
-        .db
-        .insert(&_key, vec)
-        .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
-      state
-        .keyspace
-        .persist(PersistMode::SyncAll)
-        .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())
-    })
-    .await
-    .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())??;
+  /// Saves the bytes as is; fails if the key already exists.
+  pub(crate) async fn insert_raw(&self, key: &str, vec: Vec<u8>) -> MResult<()> {
+    let _key = key.to_string();
+    self.blocking(move |storage| storage.insert(&_key, vec)).await?;
+    self.cache.invalidate(key);
 
-    tracing::trace!("fjall: inserted value by path `{}`", key);
+    tracing::trace!("kv: inserted value by path `{}`", key);
 
     Ok(())
   }
 
   pub(crate) async fn upsert<T: Serialize>(&self, key: &str, value: &T) -> MResult<()> {
     let vec = rmp_serde::to_vec(value).map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
-    let state = self.clone();
-    let _key = key.to_string();
+    self.upsert_raw(key, vec).await
+  }
 
-    tokio::task::spawn_blocking(move || {
-      state.db.insert(&_key, vec)?;
-      state.keyspace.persist(PersistMode::SyncAll)
-    })
-    .await
-    .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?
-    .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+  pub(crate) async fn upsert_raw(&self, key: &str, vec: Vec<u8>) -> MResult<()> {
+    let _key = key.to_string();
+    self.blocking(move |storage| storage.upsert(&_key, vec)).await?;
+    self.cache.invalidate(key);
 
-    tracing::trace!("fjall: upserted value by path `{}`", key);
+    tracing::trace!("kv: upserted value by path `{}`", key);
 
     Ok(())
   }
 
   pub(crate) async fn remove(&self, key: &str) -> MResult<()> {
-    let state = self.clone();
     let _key = key.to_string();
+    self.blocking(move |storage| storage.remove(&_key)).await?;
+    self.cache.invalidate(key);
 
-    tokio::task::spawn_blocking(move || {
-      state.db.remove(&_key)?;
-      state.keyspace.persist(PersistMode::SyncAll)
-    })
-    .await
-    .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?
-    .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
-
-    tracing::trace!("fjall: removed value by path `{}`", key);
+    tracing::trace!("kv: removed value by path `{}`", key);
 
     Ok(())
   }
 
   pub(crate) async fn pop<T: DeserializeOwned>(&self, key: &str) -> MResult<Option<T>> {
-    let state = self.clone();
     let _key = key.to_string();
-
-    let item = tokio::task::spawn_blocking(move || {
-      let item = state.db.get(&_key).map(|o| o.map(|s| s.to_vec()))?;
-      if item.is_some() {
-        state.db.remove(&_key)?;
-        state.keyspace.persist(PersistMode::SyncAll)?;
-      }
-      fjall::Result::Ok(item)
-    })
-    .await
-    .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?
-    .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+    let item = self.blocking(move |storage| storage.pop(&_key)).await?;
+    self.cache.invalidate(key);
 
     let slice = if let Some(item) = item { item } else { return Ok(None) };
     let value =
       rmp_serde::from_slice::<T>(&slice).map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
 
-    tracing::trace!("fjall: popped value by path `{}`", key);
+    tracing::trace!("kv: popped value by path `{}`", key);
 
     Ok(Some(value))
   }
 
+  /// Returns all records with keys starting with given prefix.
+  pub(crate) async fn scan_prefix<T: DeserializeOwned>(&self, prefix: &str) -> MResult<Vec<(String, T)>> {
+    let mut values = vec![];
+    for (key, slice) in self.scan_prefix_raw(prefix).await? {
+      let value =
+        rmp_serde::from_slice::<T>(&slice).map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+      values.push((key, value));
+    }
+
+    tracing::trace!("kv: scanned {} values by prefix `{}`", values.len(), prefix);
+
+    Ok(values)
+  }
+
+  /// Returns stored bytes of all records with keys starting with given prefix.
+  pub(crate) async fn scan_prefix_raw(&self, prefix: &str) -> MResult<Vec<(String, Vec<u8>)>> {
+    let _prefix = prefix.to_string();
+    self.blocking(move |storage| storage.scan_prefix(&_prefix)).await
+  }
+
   pub(crate) async fn batch_ops(
     &self,
     get: Vec<String>,
     upsert: Vec<(String, PreConverted)>,
     remove: Vec<String>,
   ) -> MResult<Vec<(String, Option<PreConverted>)>> {
-    let state = self.clone();
+    let upsert = upsert.into_iter().map(|(key, value)| (key, value.val)).collect::<Vec<_>>();
+    let written = upsert
+      .iter()
+      .map(|(key, _)| key)
+      .chain(remove.iter())
+      .cloned()
+      .collect::<Vec<_>>();
+    let values = self
+      .blocking(move |storage| storage.batch(get, upsert, remove))
+      .await?;
+    for key in written {
+      self.cache.invalidate(&key);
+    }
 
-    let values = tokio::task::spawn_blocking(move || {
-      let mut values = vec![];
+    Ok(
+      values
+        .into_iter()
+        .map(|(key, value)| (key, value.map(PreConverted::from_raw)))
+        .collect(),
+    )
+  }
 
-      for key in get {
-        values.push((key.to_owned(), state.db.get(&key)?.map(PreConverted::from_raw)));
+  /// Same as `KvDb::batch_ops`, but writes only if all `expect` conditions hold; returns whether it has written.
+  pub(crate) async fn batch_if(
+    &self,
+    expect: Vec<Expect>,
+    upsert: Vec<(String, PreConverted)>,
+    remove: Vec<String>,
+  ) -> MResult<bool> {
+    let upsert = upsert.into_iter().map(|(key, value)| (key, value.val)).collect::<Vec<_>>();
+    let written = upsert
+      .iter()
+      .map(|(key, _)| key)
+      .chain(remove.iter())
+      .cloned()
+      .collect::<Vec<_>>();
+    let done = self
+      .blocking(move |storage| storage.batch_if(expect, upsert, remove))
+      .await?;
+    if done {
+      for key in written {
+        self.cache.invalidate(&key);
       }
+    }
 
-      let mut batch = state.keyspace.batch();
+    tracing::trace!("kv: conditional batch is written: {}", done);
 
-      for key in remove {
-        batch.remove(&state.db, key.clone());
-      }
-      for (key, value) in upsert {
-        batch.insert(&state.db, key.clone(), value.as_ref());
-      }
+    Ok(done)
+  }
 
-      batch.commit()?;
-      state.keyspace.persist(PersistMode::SyncAll)?;
+  /// Replaces the raw record only if it's still equal to `expected` (`None` means absence).
+  pub(crate) async fn compare_and_swap<T: Serialize>(
+    &self,
+    key: &str,
+    expected: Option<Vec<u8>>,
+    new: Option<&T>,
+  ) -> MResult<bool> {
+    let new = new
+      .map(rmp_serde::to_vec)
+      .transpose()
+      .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+    self.compare_and_swap_raw(key, expected, new).await
+  }
 
-      fjall::Result::<Vec<(String, Option<PreConverted>)>>::Ok(values)
-    })
-    .await
-    .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?
-    .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+  pub(crate) async fn compare_and_swap_raw(
+    &self,
+    key: &str,
+    expected: Option<Vec<u8>>,
+    new: Option<Vec<u8>>,
+  ) -> MResult<bool> {
+    let _key = key.to_string();
+    let swapped = self
+      .blocking(move |storage| storage.compare_and_swap(&_key, expected.as_deref(), new))
+      .await?;
+    if swapped {
+      self.cache.invalidate(key);
+    }
 
-    Ok(values)
+    tracing::trace!("kv: compare-and-swap by path `{}`: {}", key, swapped);
+
+    Ok(swapped)
   }
 }
 
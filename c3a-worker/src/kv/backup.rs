@@ -0,0 +1,429 @@
+//! Backups of the whole store.
+//!
+//! An archive is a consistent snapshot of all records, encrypted with the operator's backup key:
+//!
+//! ```text
+//! archive = "C3AB" || format version (u16, big-endian) || salt (32 bytes) || nonce (12 bytes) || ciphertext
+//! key = HKDF-SHA256(salt, IKM = backup key, info = "c3a-worker::backup")
+//! ciphertext = ChaCha20-Poly1305(key, nonce, MessagePack of `BackupContents`, AD = the header before it)
+//! ```
+//!
+//! The records are also checksummed with SHA3-256, and the archive is fully checked before anything is
+//! replaced. Sealed records stay encrypted by the KEK, so the restored store is unsealed with the master key
+//! (or its shares) of the store it was taken from; if the master key is known, it's checked before the restore.
+//!
+//! Admins and nonces of their accepted requests aren't restored: the instance keeps its own ones, so an older
+//! archive doesn't bring back removed admins and doesn't make captured requests acceptable again. Only an
+//! instance without admins takes the archived ones. If the archive is sealed with another KEK, the current
+//! admins are re-sealed with it, which needs the master key and the unsealed instance. The audit trail isn't
+//! rolled back either: the current records are kept along with the archived ones.
+//!
+//! The backup key is read from `C3A_BACKUP_KEY` env variable or `backup_key_file`. The stopped worker is
+//! backed up and restored with `c3a-worker backup FILE` and `c3a-worker restore FILE`, the running one with
+//! `/admin/backup` and `/admin/restore`.
+
+use chacha20poly1305::{
+  ChaCha20Poly1305,
+  aead::{Aead, AeadCore, KeyInit, OsRng, Payload, generic_array::GenericArray},
+  consts::U12,
+};
+use cc_server_kit::prelude::*;
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sha3::{Digest, Sha3_256};
+use std::collections::HashSet;
+use std::sync::Arc;
+use zeroize::Zeroizing;
+
+use crate::Setup;
+use crate::core::admins::Admins;
+use crate::core::audit::{self, AuditAction};
+use crate::kv::KvDb;
+use crate::kv::sealing::{MIN_MASTER_KEY_LENGTH, load_master_key, read_key_file};
+use crate::kv::storage::MemoryStorage;
+use crate::kv::unseal::UnsealMode;
+
+const ARCHIVE_MAGIC: &[u8; 4] = b"C3AB";
+const ARCHIVE_VERSION: u16 = 1;
+const SALT_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+const HEADER_LENGTH: usize = 4 + 2 + SALT_LENGTH + NONCE_LENGTH;
+const BACKUP_KEY_INFO: &[u8] = b"c3a-worker::backup";
+
+pub(crate) const BACKUP_KEY_ENV: &str = "C3A_BACKUP_KEY";
+
+#[derive(Deserialize, Serialize)]
+struct BackupContents {
+  created_at: chrono::DateTime<chrono::Utc>,
+  records: Vec<(String, Vec<u8>)>,
+  /// SHA3-256 of MessagePack of `records`.
+  checksum: Vec<u8>,
+}
+
+fn backup_error(e: impl std::fmt::Display) -> ErrorResponse {
+  ErrorResponse::from(format!("Invalid backup: {e}")).with_400_pub().build()
+}
+
+fn checksum(records: &[(String, Vec<u8>)]) -> MResult<Vec<u8>> {
+  let data = rmp_serde::to_vec(records).map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+  Ok(Sha3_256::digest(&data).to_vec())
+}
+
+/// Reads the backup key from `C3A_BACKUP_KEY` env variable or from `backup_key_file`.
+pub(crate) fn load_backup_key(setup: &Setup) -> MResult<Zeroizing<Vec<u8>>> {
+  let key = if let Ok(key) = std::env::var(BACKUP_KEY_ENV) {
+    Zeroizing::new(key.into_bytes())
+  } else if let Some(path) = &setup.backup_key_file {
+    read_key_file(path)?
+  } else {
+    return Err(ErrorResponse::from(format!(
+      "There is no backup key! Set `{BACKUP_KEY_ENV}` env variable or `backup_key_file` option."
+    )));
+  };
+  if key.len() < MIN_MASTER_KEY_LENGTH {
+    return Err(ErrorResponse::from(format!(
+      "Backup key should be at least {MIN_MASTER_KEY_LENGTH} bytes long!"
+    )));
+  }
+  Ok(key)
+}
+
+fn cipher(backup_key: &[u8], salt: &[u8]) -> ChaCha20Poly1305 {
+  let mut key = Zeroizing::new([0u8; 32]);
+  Hkdf::<Sha256>::new(Some(salt), backup_key)
+    .expand(BACKUP_KEY_INFO, key.as_mut_slice())
+    .expect("32 bytes is a valid HKDF-SHA256 output length");
+  ChaCha20Poly1305::new(GenericArray::from_slice(key.as_slice()))
+}
+
+pub(crate) fn seal_archive(backup_key: &[u8], records: Vec<(String, Vec<u8>)>) -> MResult<Vec<u8>> {
+  let contents = BackupContents {
+    created_at: chrono::Utc::now(),
+    checksum: checksum(&records)?,
+    records,
+  };
+  let plaintext = Zeroizing::new(
+    rmp_serde::to_vec(&contents).map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?,
+  );
+
+  let salt = c3a_common::generate::<SALT_LENGTH>();
+  let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+  let mut archive = Vec::with_capacity(HEADER_LENGTH + plaintext.len() + 16);
+  archive.extend_from_slice(ARCHIVE_MAGIC);
+  archive.extend_from_slice(&ARCHIVE_VERSION.to_be_bytes());
+  archive.extend_from_slice(&salt);
+  archive.extend_from_slice(&nonce);
+  let ciphertext = cipher(backup_key, &salt)
+    .encrypt(
+      &nonce,
+      Payload {
+        msg: &plaintext,
+        aad: &archive,
+      },
+    )
+    .map_err(|_| ErrorResponse::from("Can't encrypt the backup!").with_500().build())?;
+  archive.extend_from_slice(&ciphertext);
+  Ok(archive)
+}
+
+/// Decrypts the archive and checks its integrity; returns the records.
+pub(crate) fn open_archive(backup_key: &[u8], archive: &[u8]) -> MResult<Vec<(String, Vec<u8>)>> {
+  if archive.len() < HEADER_LENGTH || !archive.starts_with(ARCHIVE_MAGIC) {
+    return Err(backup_error("it's not a C3A backup"));
+  }
+  let (header, ciphertext) = archive.split_at(HEADER_LENGTH);
+  let version = u16::from_be_bytes([header[4], header[5]]);
+  if version != ARCHIVE_VERSION {
+    return Err(backup_error(format!("unsupported format version {version}")));
+  }
+  let salt = &header[6..6 + SALT_LENGTH];
+  let nonce = GenericArray::<u8, U12>::from_slice(&header[6 + SALT_LENGTH..]);
+
+  let plaintext = Zeroizing::new(
+    cipher(backup_key, salt)
+      .decrypt(
+        nonce,
+        Payload {
+          msg: ciphertext,
+          aad: header,
+        },
+      )
+      .map_err(|_| backup_error("wrong backup key or corrupted archive"))?,
+  );
+  let contents = rmp_serde::from_slice::<BackupContents>(&plaintext).map_err(backup_error)?;
+  if checksum(&contents.records)?.ne(&contents.checksum) {
+    return Err(backup_error("checksum mismatch"));
+  }
+
+  tracing::info!("Backup of {} is opened: {} records.", contents.created_at, contents.records.len());
+  Ok(contents.records)
+}
+
+impl KvDb {
+  /// Archive of all records.
+  pub(crate) async fn export_backup(&self, backup_key: &[u8]) -> MResult<Vec<u8>> {
+    let records = self.blocking(|storage| storage.snapshot()).await?;
+    let count = records.len();
+    let archive = seal_archive(backup_key, records)?;
+    tracing::info!("Backup of {} records is made.", count);
+    Ok(archive)
+  }
+
+  /// Replaces all records with the archived ones; returns the number of records.
+  ///
+  /// If the master key is given, it should unseal the archived records. The storage is sealed after
+  /// the restore, since the KEK may differ.
+  pub(crate) async fn restore_backup(
+    &self,
+    backup_key: &[u8],
+    archive: &[u8],
+    master_key: Option<&[u8]>,
+  ) -> MResult<usize> {
+    let mut records = open_archive(backup_key, archive)?;
+    let restored = match master_key {
+      Some(master_key) => {
+        let restored = KvDb::with_storage(Arc::new(MemoryStorage::default()));
+        restored
+          .blocking({
+            let records = records.clone();
+            move |storage| storage.restore(records)
+          })
+          .await?;
+        restored.unseal(master_key).await.map_err(|_| {
+          ErrorResponse::from("The backup is sealed with another master key!")
+            .with_400_pub()
+            .build()
+        })?;
+        Some(restored)
+      }
+      None => None,
+    };
+    self.keep_admin_records(&mut records, restored.as_ref()).await?;
+    self.keep_audit_records(&mut records).await?;
+
+    let count = records.len();
+    self.blocking(move |storage| storage.restore(records)).await?;
+    self.reseal();
+    tracing::info!("{} records are restored from the backup.", count);
+    Ok(count)
+  }
+
+  /// Adds the current audit trail to the archived one.
+  async fn keep_audit_records(&self, records: &mut Vec<(String, Vec<u8>)>) -> MResult<()> {
+    let current = self.scan_prefix_raw(KvDb::AUDIT_PREFIX).await?;
+    let keys = current.iter().map(|(key, _)| key.as_str()).collect::<HashSet<_>>();
+    records.retain(|(key, _)| !keys.contains(key.as_str()));
+    records.extend(current);
+    Ok(())
+  }
+
+  /// Replaces admins and their nonces of the archive with the current ones.
+  async fn keep_admin_records(&self, records: &mut Vec<(String, Vec<u8>)>, restored: Option<&KvDb>) -> MResult<()> {
+    let admins_key = KvDb::sealed(KvDb::ADMINS);
+    let archived_admins = records
+      .iter()
+      .position(|(key, _)| key.eq(&admins_key))
+      .map(|i| records.remove(i));
+    records.retain(|(key, _)| key.ne(KvDb::ADMIN_NONCES));
+    if let Some(nonces) = self.get_raw(KvDb::ADMIN_NONCES).await? {
+      records.push((KvDb::ADMIN_NONCES.to_owned(), nonces));
+    }
+
+    let Some(admins) = self.get_raw(&admins_key).await? else {
+      records.extend(archived_admins);
+      return Ok(());
+    };
+    let archived_salt = records
+      .iter()
+      .find(|(key, _)| key.eq(KvDb::KEK_SALT))
+      .map(|(_, salt)| salt.clone());
+    if archived_salt.is_some() && archived_salt.eq(&self.get_raw(KvDb::KEK_SALT).await?) {
+      records.push((admins_key, admins));
+      return Ok(());
+    }
+
+    let (Some(restored), false) = (restored, self.is_sealed()) else {
+      return Err(
+        ErrorResponse::from(
+          "The backup is sealed with another master key, so the current admins can't be kept without it!",
+        )
+        .with_400_pub()
+        .build(),
+      );
+    };
+    restored.remove(&admins_key).await?;
+    restored.insert_sealed(KvDb::ADMINS, &Admins::load(self).await?).await?;
+    let admins = restored
+      .get_raw(&admins_key)
+      .await?
+      .ok_or(ErrorResponse::from("Can't re-seal admins!").with_500().build())?;
+    records.push((admins_key, admins));
+    Ok(())
+  }
+}
+
+/// Offline backup: `c3a-worker backup FILE`. The worker must be stopped.
+pub(crate) async fn run_backup(kv: &KvDb, setup: &Setup, args: &[String]) -> MResult<()> {
+  let [path] = args else {
+    return Err(ErrorResponse::from("Usage: c3a-worker backup FILE"));
+  };
+  let archive = kv.export_backup(&load_backup_key(setup)?).await?;
+  std::fs::write(path, archive).map_err(|e| ErrorResponse::from(format!("Can't write backup file `{path}`: {e}")))?;
+  audit::record(kv, None, AuditAction::Backup).await
+}
+
+/// Offline restore: `c3a-worker restore FILE`. The worker must be stopped.
+///
+/// With `unseal: { type: master_key }` the archive is checked against the master key first.
+pub(crate) async fn run_restore(kv: &KvDb, setup: &Setup, args: &[String]) -> MResult<()> {
+  let [path] = args else {
+    return Err(ErrorResponse::from("Usage: c3a-worker restore FILE"));
+  };
+  let archive =
+    std::fs::read(path).map_err(|e| ErrorResponse::from(format!("Can't read backup file `{path}`: {e}")))?;
+  let master_key = match setup.unseal {
+    UnsealMode::MasterKey => Some(load_master_key(setup)?),
+    UnsealMode::Shamir { .. } => None,
+  };
+  let records = kv
+    .restore_backup(
+      &load_backup_key(setup)?,
+      &archive,
+      master_key.as_ref().map(|key| key.as_slice()),
+    )
+    .await?;
+  audit::record(kv, None, AuditAction::Restore { records }).await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{open_archive, seal_archive};
+  use crate::core::audit::{AuditAction, AuditRecord, record};
+  use crate::kv::KvDb;
+
+  const BACKUP_KEY: &[u8] = b"test-backup-key-XXXXXXXXXXXXXXXXXXXXXXXX";
+  const MASTER_KEY: &[u8] = b"test-master-key-XXXXXXXXXXXXXXXXXXXXXXXX";
+
+  #[test]
+  fn test_archive_is_checked() {
+    let records = vec![(String::from("a"), b"1".to_vec()), (String::from("b"), b"2".to_vec())];
+    let archive = seal_archive(BACKUP_KEY, records.clone()).unwrap();
+    assert_eq!(open_archive(BACKUP_KEY, &archive).unwrap(), records);
+
+    assert!(open_archive(b"test-backup-key-YYYYYYYYYYYYYYYYYYYYYYYY", &archive).is_err());
+    for i in [0, 5, 10, archive.len() - 1] {
+      let mut corrupted = archive.clone();
+      corrupted[i] ^= 1;
+      assert!(open_archive(BACKUP_KEY, &corrupted).is_err());
+    }
+    assert!(open_archive(BACKUP_KEY, &archive[..40]).is_err());
+  }
+
+  #[tokio::test]
+  async fn test_backup_and_restore() {
+    let kv = KvDb::in_memory_unsealed().await.unwrap();
+    kv.initial_setup().await.unwrap();
+    kv.insert(&KvDb::app("app"), &String::from("app")).await.unwrap();
+    let secret = kv.get_secret_key().await.unwrap();
+    let archive = kv.export_backup(BACKUP_KEY).await.unwrap();
+
+    let other = KvDb::in_memory();
+    other.unseal(b"test-master-key-YYYYYYYYYYYYYYYYYYYYYYYY").await.unwrap();
+    other.insert(&KvDb::app("other"), &String::from("other")).await.unwrap();
+    // The archive can't be unsealed with this instance's master key.
+    assert!(
+      other
+        .restore_backup(BACKUP_KEY, &archive, Some(b"test-master-key-YYYYYYYYYYYYYYYYYYYYYYYY"))
+        .await
+        .is_err()
+    );
+    assert!(other.exists(&KvDb::app("other")).await.unwrap());
+
+    other.restore_backup(BACKUP_KEY, &archive, Some(MASTER_KEY)).await.unwrap();
+    assert!(other.is_sealed());
+    assert!(!other.exists(&KvDb::app("other")).await.unwrap());
+    assert_eq!(other.get::<String>(&KvDb::app("app")).await.unwrap().unwrap(), "app");
+    other.unseal(MASTER_KEY).await.unwrap();
+    assert_eq!(other.get_secret_key().await.unwrap(), secret);
+  }
+
+  #[tokio::test]
+  async fn test_audit_trail_is_kept() {
+    let kv = KvDb::in_memory_unsealed().await.unwrap();
+    kv.initial_setup().await.unwrap();
+    record(&kv, Some("alice"), AuditAction::Backup).await.unwrap();
+    let archive = kv.export_backup(BACKUP_KEY).await.unwrap();
+
+    record(&kv, Some("alice"), AuditAction::Seal).await.unwrap();
+    kv.restore_backup(BACKUP_KEY, &archive, None).await.unwrap();
+    let actions = kv
+      .scan_prefix::<AuditRecord>(KvDb::AUDIT_PREFIX)
+      .await
+      .unwrap()
+      .into_iter()
+      .map(|(_, record)| record.action)
+      .collect::<Vec<_>>();
+    assert_eq!(actions, vec![AuditAction::Backup, AuditAction::Seal]);
+  }
+
+  #[tokio::test]
+  async fn test_admins_are_not_restored() {
+    let kv = KvDb::in_memory_unsealed().await.unwrap();
+    kv.initial_setup().await.unwrap();
+    kv.insert_sealed(KvDb::ADMINS, &String::from("removed admin"))
+      .await
+      .unwrap();
+    kv.insert(KvDb::ADMIN_NONCES, &String::from("old nonces"))
+      .await
+      .unwrap();
+    let archive = kv.export_backup(BACKUP_KEY).await.unwrap();
+
+    kv.remove(&KvDb::sealed(KvDb::ADMINS)).await.unwrap();
+    kv.insert_sealed(KvDb::ADMINS, &String::from("current admin"))
+      .await
+      .unwrap();
+    kv.remove(KvDb::ADMIN_NONCES).await.unwrap();
+    kv.insert(KvDb::ADMIN_NONCES, &String::from("current nonces"))
+      .await
+      .unwrap();
+    kv.restore_backup(BACKUP_KEY, &archive, None).await.unwrap();
+    kv.unseal(MASTER_KEY).await.unwrap();
+    assert_eq!(
+      kv.get_sealed::<String>(KvDb::ADMINS).await.unwrap().unwrap(),
+      "current admin"
+    );
+    assert_eq!(
+      kv.get::<String>(KvDb::ADMIN_NONCES).await.unwrap().unwrap(),
+      "current nonces"
+    );
+
+    // Admins of an instance with another master key are re-sealed with the archived KEK.
+    let other = KvDb::in_memory();
+    other.unseal(b"test-master-key-YYYYYYYYYYYYYYYYYYYYYYYY").await.unwrap();
+    other
+      .insert_sealed(KvDb::ADMINS, &String::from("other admin"))
+      .await
+      .unwrap();
+    assert!(other.restore_backup(BACKUP_KEY, &archive, None).await.is_err());
+    other
+      .restore_backup(BACKUP_KEY, &archive, Some(MASTER_KEY))
+      .await
+      .unwrap();
+    other.unseal(MASTER_KEY).await.unwrap();
+    assert_eq!(
+      other.get_sealed::<String>(KvDb::ADMINS).await.unwrap().unwrap(),
+      "other admin"
+    );
+    assert!(!other.exists(KvDb::ADMIN_NONCES).await.unwrap());
+
+    // An instance without admins takes the archived ones.
+    let fresh = KvDb::in_memory();
+    fresh.restore_backup(BACKUP_KEY, &archive, None).await.unwrap();
+    fresh.unseal(MASTER_KEY).await.unwrap();
+    assert_eq!(
+      fresh.get_sealed::<String>(KvDb::ADMINS).await.unwrap().unwrap(),
+      "removed admin"
+    );
+  }
+}
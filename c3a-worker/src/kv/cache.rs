@@ -0,0 +1,309 @@
+//! In-memory cache of records read on hot paths.
+//!
+//! The signing keyring, the main secret key and apps' configurations are read on every token operation and
+//! registration; each read is a blocking storage call, and sealed records are also decrypted. Signers of the
+//! keyring's keys are cached too, so keypairs are restored once. The cache is shared by clones of `KvDb`.
+//!
+//! Every write through `KvDb` invalidates the entry of its key, and the whole cache is dropped when the storage
+//! is sealed. A value which was read before a concurrent invalidation isn't cached (see `Cache::generation`).
+
+use c3a_common::{AppAuthConfiguration, CacheCounters, CacheStatsResponse};
+use cc_server_kit::prelude::*;
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use zeroize::Zeroizing;
+
+use crate::core::signer::{GeneratedKey, Signer, SignerBackend};
+use crate::core::signing_keys::SigningKeyring;
+use crate::kv::KvDb;
+
+#[derive(Clone, Copy)]
+pub(crate) enum CacheKind {
+  SigningKeys,
+  Signers,
+  SecretKey,
+  Apps,
+}
+
+#[derive(Default)]
+struct Counters {
+  hits: AtomicU64,
+  misses: AtomicU64,
+}
+
+impl Counters {
+  fn count<T>(&self, value: Option<T>) -> Option<T> {
+    let counter = if value.is_some() { &self.hits } else { &self.misses };
+    counter.fetch_add(1, Ordering::Relaxed);
+    value
+  }
+
+  fn snapshot(&self) -> CacheCounters {
+    CacheCounters {
+      hits: self.hits.load(Ordering::Relaxed),
+      misses: self.misses.load(Ordering::Relaxed),
+    }
+  }
+}
+
+type Entry = Arc<dyn Any + Send + Sync>;
+
+#[derive(Default)]
+pub(crate) struct Cache {
+  /// Records by storage keys.
+  entries: RwLock<HashMap<String, Entry>>,
+  /// Signers by key identifiers; a key identifier is a hash of the public key, so they're never stale.
+  signers: RwLock<HashMap<String, Arc<dyn Signer>>>,
+  /// Incremented by every invalidation, under the write lock of `entries`.
+  generation: AtomicU64,
+  counters: [Counters; 4],
+}
+
+impl Cache {
+  /// Should be taken before reading the value to be cached and passed to `Cache::insert`.
+  pub(crate) fn generation(&self) -> u64 {
+    self.generation.load(Ordering::Acquire)
+  }
+
+  pub(crate) fn get<T: Send + Sync + 'static>(&self, kind: CacheKind, key: &str) -> Option<Arc<T>> {
+    let entry = self
+      .entries
+      .read()
+      .ok()
+      .and_then(|entries| entries.get(key).cloned())
+      .and_then(|entry| entry.downcast::<T>().ok());
+    self.counters[kind as usize].count(entry)
+  }
+
+  /// Caches the value unless anything is invalidated since `generation`.
+  pub(crate) fn insert<T: Send + Sync + 'static>(&self, generation: u64, key: &str, value: Arc<T>) {
+    if let Ok(mut entries) = self.entries.write()
+      && self.generation() == generation
+    {
+      entries.insert(key.to_owned(), value);
+    }
+  }
+
+  pub(crate) fn invalidate(&self, key: &str) {
+    let mut entries = self.entries.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    self.generation.fetch_add(1, Ordering::AcqRel);
+    entries.remove(key);
+    // Retired keys' signers are dropped with the keyring.
+    if key.eq(&KvDb::sealed(KvDb::SIGNING_KEYS)) {
+      self.clear_signers();
+    }
+  }
+
+  pub(crate) fn clear(&self) {
+    let mut entries = self.entries.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    self.generation.fetch_add(1, Ordering::AcqRel);
+    entries.clear();
+    self.clear_signers();
+  }
+
+  fn signer(&self, kid: &str) -> Option<Arc<dyn Signer>> {
+    let signer = self
+      .signers
+      .read()
+      .ok()
+      .and_then(|signers| signers.get(kid).cloned());
+    self.counters[CacheKind::Signers as usize].count(signer)
+  }
+
+  fn insert_signer(&self, generation: u64, kid: &str, signer: Arc<dyn Signer>) {
+    // Taken first, as in `Cache::invalidate`.
+    if let Ok(_entries) = self.entries.write()
+      && let Ok(mut signers) = self.signers.write()
+      && self.generation() == generation
+    {
+      signers.insert(kid.to_owned(), signer);
+    }
+  }
+
+  fn clear_signers(&self) {
+    self
+      .signers
+      .write()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+      .clear();
+  }
+
+  pub(crate) fn stats(&self) -> CacheStatsResponse {
+    CacheStatsResponse {
+      signing_keys: self.counters[CacheKind::SigningKeys as usize].snapshot(),
+      signers: self.counters[CacheKind::Signers as usize].snapshot(),
+      secret_key: self.counters[CacheKind::SecretKey as usize].snapshot(),
+      apps: self.counters[CacheKind::Apps as usize].snapshot(),
+    }
+  }
+}
+
+/// `SignerBackend` which restores each key once.
+pub(crate) struct CachedSignerBackend {
+  pub(crate) backend: Arc<dyn SignerBackend>,
+  pub(crate) cache: Arc<Cache>,
+}
+
+impl SignerBackend for CachedSignerBackend {
+  fn generate(&self) -> MResult<GeneratedKey> {
+    self.backend.generate()
+  }
+
+  fn signer(&self, kid: &str, public: &[u8], secret: &[u8]) -> MResult<Arc<dyn Signer>> {
+    if let Some(signer) = self.cache.signer(kid) {
+      return Ok(signer);
+    }
+    let generation = self.cache.generation();
+    let signer = self.backend.signer(kid, public, secret)?;
+    self.cache.insert_signer(generation, kid, signer.clone());
+    Ok(signer)
+  }
+}
+
+impl KvDb {
+  pub(crate) async fn get_signing_keyring(&self) -> MResult<Arc<SigningKeyring>> {
+    let key = KvDb::sealed(KvDb::SIGNING_KEYS);
+    if let Some(keyring) = self.cache.get::<SigningKeyring>(CacheKind::SigningKeys, &key) {
+      return Ok(keyring);
+    }
+
+    let generation = self.cache.generation();
+    let keyring = self
+      .get_sealed::<SigningKeyring>(KvDb::SIGNING_KEYS)
+      .await?
+      .map(Arc::new)
+      .ok_or(ErrorResponse::from("No signing keys available!").with_500().build())?;
+    self.cache.insert(generation, &key, keyring.clone());
+    Ok(keyring)
+  }
+
+  pub(crate) async fn get_secret_key(&self) -> MResult<Arc<Zeroizing<Vec<u8>>>> {
+    let key = KvDb::sealed(KvDb::MAIN_SECRET_KEY);
+    if let Some(secret) = self.cache.get::<Zeroizing<Vec<u8>>>(CacheKind::SecretKey, &key) {
+      return Ok(secret);
+    }
+
+    let generation = self.cache.generation();
+    let secret = self
+      .get_sealed::<Vec<u8>>(KvDb::MAIN_SECRET_KEY)
+      .await?
+      .map(|secret| Arc::new(Zeroizing::new(secret)))
+      .ok_or(ErrorResponse::from("No main secret key available!").with_500().build())?;
+    self.cache.insert(generation, &key, secret.clone());
+    Ok(secret)
+  }
+
+  pub(crate) async fn get_app_conf(&self, app_name: &str) -> MResult<AppAuthConfiguration> {
+    let key = KvDb::app(app_name);
+    if let Some(app_conf) = self.cache.get::<AppAuthConfiguration>(CacheKind::Apps, &key) {
+      return Ok(app_conf.as_ref().clone());
+    }
+
+    let generation = self.cache.generation();
+    let app_conf = self
+      .get_versioned::<AppAuthConfiguration>(&key)
+      .await?
+      .ok_or(ErrorResponse::from("There is no such app.").with_404_pub().build())?;
+    self.cache.insert(generation, &key, Arc::new(app_conf.clone()));
+    Ok(app_conf)
+  }
+
+  pub(crate) fn cache_stats(&self) -> CacheStatsResponse {
+    self.cache.stats()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use c3a_common::AppAuthConfiguration;
+  use cc_server_kit::prelude::*;
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  use crate::core::signer::{GeneratedKey, InProcessBackend, Signer, SignerBackend};
+  use crate::kv::{KvDb, PreConverted};
+
+  fn app_conf(domain: &str) -> AppAuthConfiguration {
+    AppAuthConfiguration {
+      app_name: String::from("app"),
+      domain: domain.to_owned(),
+      allowed_tags: vec![],
+      allow_sign_up: None,
+      client_based_auth_opts: None,
+      author_dpub: vec![],
+      email_templates: None,
+      hash_params: None,
+    }
+  }
+
+  #[tokio::test]
+  async fn test_app_conf_is_invalidated_on_writes() {
+    let kv = KvDb::in_memory();
+    kv.insert_versioned(&KvDb::app("app"), &app_conf("a.example.com")).await.unwrap();
+
+    assert_eq!(kv.get_app_conf("app").await.unwrap().domain, "a.example.com");
+    assert_eq!(kv.get_app_conf("app").await.unwrap().domain, "a.example.com");
+    let stats = kv.cache_stats();
+    assert_eq!((stats.apps.hits, stats.apps.misses), (1, 1));
+
+    kv.batch_ops(
+      vec![],
+      vec![(KvDb::app("app"), PreConverted::versioned(&app_conf("b.example.com")).unwrap())],
+      vec![],
+    )
+    .await
+    .unwrap();
+    assert_eq!(kv.get_app_conf("app").await.unwrap().domain, "b.example.com");
+
+    kv.remove(&KvDb::app("app")).await.unwrap();
+    assert!(kv.get_app_conf("app").await.is_err());
+    assert_eq!(kv.cache_stats().apps.misses, 3);
+  }
+
+  #[tokio::test]
+  async fn test_secrets_are_dropped_on_seal() {
+    let kv = KvDb::in_memory_unsealed().await.unwrap();
+    kv.initial_setup().await.unwrap();
+    let secret = kv.get_secret_key().await.unwrap();
+    assert_eq!(kv.get_secret_key().await.unwrap(), secret);
+    kv.get_signing_key().await.unwrap();
+    kv.get_signing_key().await.unwrap();
+    let stats = kv.cache_stats();
+    assert_eq!((stats.secret_key.hits, stats.signing_keys.hits, stats.signers.hits), (1, 1, 1));
+
+    kv.reseal();
+    assert!(kv.get_secret_key().await.is_err());
+    assert!(kv.get_signing_key().await.is_err());
+  }
+
+  /// Counts restored keys.
+  struct CountingBackend(AtomicUsize);
+
+  impl SignerBackend for CountingBackend {
+    fn generate(&self) -> MResult<GeneratedKey> {
+      InProcessBackend.generate()
+    }
+
+    fn signer(&self, kid: &str, public: &[u8], secret: &[u8]) -> MResult<Arc<dyn Signer>> {
+      self.0.fetch_add(1, Ordering::Relaxed);
+      InProcessBackend.signer(kid, public, secret)
+    }
+  }
+
+  #[tokio::test]
+  async fn test_signers_are_restored_once() {
+    let mut kv = KvDb::in_memory_unsealed().await.unwrap();
+    let backend = Arc::new(CountingBackend(AtomicUsize::new(0)));
+    kv.set_signer_backend(backend.clone());
+    kv.initial_setup().await.unwrap();
+
+    for _ in 0..3 {
+      let signing_key = kv.get_signing_key().await.unwrap();
+      let sig = signing_key.sign(&"data").unwrap();
+      assert!(c3a_common::verify(&"data", &sig, signing_key.public()).unwrap());
+    }
+    assert_eq!(backend.0.load(Ordering::Relaxed), 1);
+  }
+}
@@ -0,0 +1,422 @@
+//! Encryption of secret records at rest.
+//!
+//! Records with secret material (the main secret, signing keys, peppers) are stored under
+//! `KvDb::SEALED_PREFIX`, encrypted with ChaCha20-Poly1305 by the key-encryption key (KEK). The record name
+//! is the associated data, so a ciphertext can't be moved to another record.
+//!
+//! ```text
+//! KEK = HKDF-SHA256(salt = KvDb::KEK_SALT record, IKM = master key, info = "c3a-worker::kek")
+//! ```
+//!
+//! The master key is supplied by the operator with `C3A_MASTER_KEY` env variable or `master_key_file`;
+//! it's never stored. The sealed `KvDb::KEK_CHECK` record makes the worker refuse to start with a wrong key.
+//!
+//! Outgoing mails hold confirmation codes, so they're sealed in place: the records keep their keys (and
+//! partitions), only the values are encrypted, with the whole key as the associated data.
+//! To change the master key, stop the worker and run `c3a-worker rekey` (see `run_rekey`).
+
+use chacha20poly1305::{
+  ChaCha20Poly1305,
+  aead::{Aead, AeadCore, KeyInit, OsRng, Payload, generic_array::GenericArray},
+  consts::U12,
+};
+use cc_server_kit::prelude::*;
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::Arc;
+use zeroize::Zeroizing;
+
+use crate::Setup;
+use crate::core::audit::{self, AuditAction};
+use crate::kv::{KvDb, PreConverted};
+
+const KEK_INFO: &[u8] = b"c3a-worker::kek";
+const KEK_CHECK_VALUE: &[u8] = b"c3a-worker::kek-check";
+const KEK_SALT_LENGTH: usize = 32;
+
+pub(crate) const MASTER_KEY_ENV: &str = "C3A_MASTER_KEY";
+pub(crate) const NEW_MASTER_KEY_ENV: &str = "C3A_NEW_MASTER_KEY";
+pub(crate) const MIN_MASTER_KEY_LENGTH: usize = 32;
+
+#[derive(Deserialize, Serialize)]
+pub(crate) struct SealedRecord {
+  nonce: Vec<u8>,
+  ciphertext: Vec<u8>,
+}
+
+/// Key-encryption key.
+pub(crate) struct Kek {
+  key: Zeroizing<[u8; 32]>,
+}
+
+impl Kek {
+  pub(crate) fn derive(master_key: &[u8], salt: &[u8]) -> MResult<Self> {
+    if master_key.len() < MIN_MASTER_KEY_LENGTH {
+      return Err(
+        ErrorResponse::from(format!(
+          "Master key should be at least {MIN_MASTER_KEY_LENGTH} bytes long!"
+        ))
+        .with_500()
+        .build(),
+      );
+    }
+
+    let mut key = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(Some(salt), master_key)
+      .expand(KEK_INFO, key.as_mut_slice())
+      .expect("32 bytes is a valid HKDF-SHA256 output length");
+    Ok(Self { key })
+  }
+
+  pub(crate) fn seal(&self, name: &str, plaintext: &[u8]) -> MResult<SealedRecord> {
+    let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(self.key.as_slice()));
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+      .encrypt(
+        &nonce,
+        Payload {
+          msg: plaintext,
+          aad: name.as_bytes(),
+        },
+      )
+      .map_err(|_| ErrorResponse::from(format!("Can't seal `{name}` record!")).with_500().build())?;
+
+    Ok(SealedRecord {
+      nonce: nonce.to_vec(),
+      ciphertext,
+    })
+  }
+
+  pub(crate) fn open(&self, name: &str, record: &SealedRecord) -> MResult<Zeroizing<Vec<u8>>> {
+    if record.nonce.len() != 12 {
+      return Err(ErrorResponse::from(format!("Sealed `{name}` record is corrupted!")).with_500().build());
+    }
+
+    let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(self.key.as_slice()));
+    cipher
+      .decrypt(
+        GenericArray::<u8, U12>::from_slice(&record.nonce),
+        Payload {
+          msg: &record.ciphertext,
+          aad: name.as_bytes(),
+        },
+      )
+      .map(Zeroizing::new)
+      .map_err(|_| ErrorResponse::from(format!("Can't open `{name}` record!")).with_500().build())
+  }
+}
+
+/// Reads the master key from `C3A_MASTER_KEY` env variable or from `master_key_file`.
+pub(crate) fn load_master_key(setup: &Setup) -> MResult<Zeroizing<Vec<u8>>> {
+  if let Ok(key) = std::env::var(MASTER_KEY_ENV) {
+    return Ok(Zeroizing::new(key.into_bytes()));
+  }
+  if let Some(path) = &setup.master_key_file {
+    return read_key_file(path);
+  }
+  Err(ErrorResponse::from(format!(
+    "There is no master key! Set `{MASTER_KEY_ENV}` env variable or `master_key_file` option."
+  )))
+}
+
+pub(crate) fn read_key_file(path: &std::path::Path) -> MResult<Zeroizing<Vec<u8>>> {
+  let content = Zeroizing::new(std::fs::read(path).map_err(|e| {
+    ErrorResponse::from(format!("Can't read key file `{}`: {e}", path.display()))
+      .with_500()
+      .build()
+  })?);
+  let end = content.iter().rposition(|b| !b.is_ascii_whitespace()).map_or(0, |i| i + 1);
+  Ok(Zeroizing::new(content[..end].to_vec()))
+}
+
+impl KvDb {
+  /// Prefix of encrypted records; the rest of the key is the record name.
+  pub(crate) const SEALED_PREFIX: &str = "sealed::";
+  pub(crate) const KEK_SALT: &str = "kek_salt";
+  /// Sealed known value to check the master key.
+  pub(crate) const KEK_CHECK: &str = "kek_check";
+  /// Records which were stored in plain before sealing was introduced.
+  const SECRET_RECORDS: [&str; 3] = [KvDb::MAIN_SECRET_KEY, KvDb::SIGNING_KEYS, KvDb::PEPPERS];
+  /// Prefixes of records sealed in place.
+  const SEALED_IN_PLACE_PREFIXES: [&str; 2] = [KvDb::OUTBOX_PREFIX, KvDb::OUTBOX_DEAD_PREFIX];
+
+  pub(crate) fn sealed(name: &str) -> String {
+    format!("{}{}", Self::SEALED_PREFIX, name)
+  }
+
+  /// Derives the KEK from the master key; refuses a key which doesn't match the stored records.
+  pub(crate) async fn unseal(&self, master_key: &[u8]) -> MResult<()> {
+    let salt = self
+      .update::<Vec<u8>, _, _>(KvDb::KEK_SALT, |salt| {
+        Ok(salt.get_or_insert_with(|| c3a_common::generate::<KEK_SALT_LENGTH>().to_vec()).clone())
+      })
+      .await?;
+    let kek = Kek::derive(master_key, &salt)?;
+
+    match self.get::<SealedRecord>(&KvDb::sealed(KvDb::KEK_CHECK)).await? {
+      Some(check) => {
+        let value = kek.open(KvDb::KEK_CHECK, &check).ok();
+        if value.is_none_or(|value| value.as_slice().ne(KEK_CHECK_VALUE)) {
+          return Err(ErrorResponse::from("Wrong master key!"));
+        }
+      }
+      None => {
+        self
+          .insert(&KvDb::sealed(KvDb::KEK_CHECK), &kek.seal(KvDb::KEK_CHECK, KEK_CHECK_VALUE)?)
+          .await?
+      }
+    }
+
+    self.set_kek(Some(kek));
+    Ok(())
+  }
+
+  /// Checks the master key against the stored `KvDb::KEK_CHECK` without unsealing. Unlike `KvDb::unseal`,
+  /// never accepts a key for a storage which has no check record yet.
+  pub(crate) async fn check_master_key(&self, master_key: &[u8]) -> MResult<bool> {
+    let (Some(salt), Some(check)) = (
+      self.get::<Vec<u8>>(KvDb::KEK_SALT).await?,
+      self.get::<SealedRecord>(&KvDb::sealed(KvDb::KEK_CHECK)).await?,
+    ) else {
+      return Err(
+        ErrorResponse::from("The storage has never been unsealed with the master key, shares can't be checked.")
+          .with_500_pub()
+          .build(),
+      );
+    };
+    let Ok(kek) = Kek::derive(master_key, &salt) else {
+      return Ok(false);
+    };
+    Ok(kek.open(KvDb::KEK_CHECK, &check).is_ok_and(|value| value.as_slice().eq(KEK_CHECK_VALUE)))
+  }
+
+  /// Wipes the KEK from memory; sealed records are unavailable until the next `KvDb::unseal`.
+  pub(crate) fn reseal(&self) {
+    self.set_kek(None);
+  }
+
+  pub(crate) fn is_sealed(&self) -> bool {
+    self.kek.read().map(|kek| kek.is_none()).unwrap_or(true)
+  }
+
+  fn set_kek(&self, kek: Option<Kek>) {
+    let mut guard = self.kek.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    *guard = kek.map(Arc::new);
+    self.cache.clear();
+  }
+
+  fn kek(&self) -> MResult<Arc<Kek>> {
+    self
+      .kek
+      .read()
+      .ok()
+      .and_then(|kek| kek.clone())
+      .ok_or(ErrorResponse::from("Storage is sealed!").with_500().build())
+  }
+
+  pub(crate) async fn get_sealed<T: serde::de::DeserializeOwned>(&self, name: &str) -> MResult<Option<T>> {
+    let Some(record) = self.get::<SealedRecord>(&KvDb::sealed(name)).await? else {
+      return Ok(None);
+    };
+    let plaintext = self.kek()?.open(name, &record)?;
+    let value =
+      rmp_serde::from_slice::<T>(&plaintext).map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+    Ok(Some(value))
+  }
+
+  pub(crate) async fn insert_sealed<T: Serialize>(&self, name: &str, value: &T) -> MResult<()> {
+    let record = self.seal_value(name, value)?;
+    self.insert(&KvDb::sealed(name), &record).await
+  }
+
+  /// `KvDb::update` of the sealed record.
+  pub(crate) async fn update_sealed<T, R, F>(&self, name: &str, mut f: F) -> MResult<R>
+  where
+    T: Serialize + serde::de::DeserializeOwned,
+    F: FnMut(&mut Option<T>) -> MResult<R>,
+  {
+    let kek = self.kek()?;
+    self
+      .update_raw(&KvDb::sealed(name), |stored| {
+        let mut value = match stored {
+          Some(stored) => {
+            let record = rmp_serde::from_slice::<SealedRecord>(stored)
+              .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+            let plaintext = kek.open(name, &record)?;
+            let value = rmp_serde::from_slice::<T>(&plaintext)
+              .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+            Some(value)
+          }
+          None => None,
+        };
+        let result = f(&mut value)?;
+        let new = match value {
+          Some(value) => {
+            let plaintext = Zeroizing::new(
+              rmp_serde::to_vec(&value).map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?,
+            );
+            Some(
+              rmp_serde::to_vec(&kek.seal(name, &plaintext)?)
+                .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?,
+            )
+          }
+          None => None,
+        };
+        Ok((new, result))
+      })
+      .await
+  }
+
+  /// Seals the value under `name`; in-place sealed records use their keys as names.
+  pub(crate) fn seal_value<T: Serialize>(&self, name: &str, value: &T) -> MResult<SealedRecord> {
+    let plaintext =
+      Zeroizing::new(rmp_serde::to_vec(value).map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?);
+    self.kek()?.seal(name, &plaintext)
+  }
+
+  pub(crate) fn open_value<T: serde::de::DeserializeOwned>(&self, name: &str, record: &SealedRecord) -> MResult<T> {
+    let plaintext = self.kek()?.open(name, record)?;
+    rmp_serde::from_slice::<T>(&plaintext).map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())
+  }
+
+  /// `KvDb::scan_prefix` of records sealed in place.
+  pub(crate) async fn scan_sealed_in_place<T: serde::de::DeserializeOwned>(
+    &self,
+    prefix: &str,
+  ) -> MResult<Vec<(String, T)>> {
+    self
+      .scan_prefix::<SealedRecord>(prefix)
+      .await?
+      .into_iter()
+      .map(|(key, record)| {
+        let value = self.open_value(&key, &record)?;
+        Ok((key, value))
+      })
+      .collect()
+  }
+
+  /// Encrypts secret records left in plain by previous versions.
+  pub(crate) async fn seal_plain_records(&self) -> MResult<()> {
+    for name in Self::SECRET_RECORDS {
+      let Some(plaintext) = self.get_raw(name).await? else {
+        continue;
+      };
+      let plaintext = Zeroizing::new(plaintext);
+      let record = self.kek()?.seal(name, &plaintext)?;
+
+      tracing::info!("Sealing `{}` record...", name);
+      self
+        .batch_ops(
+          vec![],
+          vec![(KvDb::sealed(name), PreConverted::new(&record)?)],
+          vec![name.to_owned()],
+        )
+        .await?;
+    }
+    Ok(())
+  }
+
+  /// Re-encrypts all sealed records with the KEK derived from the new master key.
+  pub(crate) async fn rekey(&self, new_master_key: &[u8]) -> MResult<()> {
+    let salt = c3a_common::generate::<KEK_SALT_LENGTH>().to_vec();
+    let new_kek = Kek::derive(new_master_key, &salt)?;
+
+    let kek = self.kek()?;
+    let mut upsert = vec![(KvDb::KEK_SALT.to_owned(), PreConverted::new(&salt)?)];
+    for (key, record) in self.scan_prefix::<SealedRecord>(KvDb::SEALED_PREFIX).await? {
+      let name = key.trim_start_matches(KvDb::SEALED_PREFIX);
+      let plaintext = kek.open(name, &record)?;
+      upsert.push((key.to_owned(), PreConverted::new(&new_kek.seal(name, &plaintext)?)?));
+    }
+    for prefix in Self::SEALED_IN_PLACE_PREFIXES {
+      for (key, record) in self.scan_prefix::<SealedRecord>(prefix).await? {
+        let plaintext = kek.open(&key, &record)?;
+        upsert.push((key.to_owned(), PreConverted::new(&new_kek.seal(&key, &plaintext)?)?));
+      }
+    }
+    let count = upsert.len() - 1;
+
+    self.batch_ops(vec![], upsert, vec![]).await?;
+    self.set_kek(Some(new_kek));
+    tracing::info!("{} sealed records are re-encrypted with the new master key.", count);
+    Ok(())
+  }
+}
+
+/// Offline change of the master key: `c3a-worker rekey [--new-key-file PATH]`.
+///
+/// The new key is read from the file or from `C3A_NEW_MASTER_KEY` env variable. The worker must be stopped.
+pub(crate) async fn run_rekey(kv: &KvDb, args: &[String]) -> MResult<()> {
+  let new_master_key = match args {
+    [flag, path] if flag.as_str().eq("--new-key-file") => read_key_file(std::path::Path::new(path))?,
+    [] => Zeroizing::new(
+      std::env::var(NEW_MASTER_KEY_ENV)
+        .map_err(|_| ErrorResponse::from(format!("There is no `{NEW_MASTER_KEY_ENV}` env variable!")))?
+        .into_bytes(),
+    ),
+    _ => return Err(ErrorResponse::from("Usage: c3a-worker rekey [--new-key-file PATH]")),
+  };
+  kv.rekey(&new_master_key).await?;
+  audit::record(kv, None, AuditAction::Rekey).await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Kek, SealedRecord};
+  use crate::kv::KvDb;
+
+  const MASTER_KEY: &[u8] = b"test-master-key-XXXXXXXXXXXXXXXXXXXXXXXX";
+  const NEW_MASTER_KEY: &[u8] = b"test-master-key-YYYYYYYYYYYYYYYYYYYYYYYY";
+
+  #[test]
+  fn test_records_are_bound_to_names() {
+    let kek = Kek::derive(MASTER_KEY, b"salt").unwrap();
+    let record = kek.seal("main_secret", b"secret").unwrap();
+    assert_eq!(kek.open("main_secret", &record).unwrap().as_slice(), b"secret");
+    assert!(kek.open("peppers", &record).is_err());
+    assert!(Kek::derive(MASTER_KEY, b"another salt").unwrap().open("main_secret", &record).is_err());
+    assert!(Kek::derive(b"short", b"salt").is_err());
+  }
+
+  #[tokio::test]
+  async fn test_wrong_master_key_and_rekey() {
+    let kv = KvDb::in_memory();
+
+    kv.unseal(MASTER_KEY).await.unwrap();
+    kv.insert_sealed("record", &String::from("secret")).await.unwrap();
+    let mail_key = format!("{}mail", KvDb::OUTBOX_PREFIX);
+    kv.insert(&mail_key, &kv.seal_value(&mail_key, &String::from("code")).unwrap())
+      .await
+      .unwrap();
+    let record = kv.get::<SealedRecord>(&KvDb::sealed("record")).await.unwrap().unwrap();
+    assert!(!record.ciphertext.windows(b"secret".len()).any(|w| w == b"secret"));
+
+    let other = kv.reopen();
+    assert!(other.unseal(NEW_MASTER_KEY).await.is_err());
+    assert!(other.get_sealed::<String>("record").await.is_err());
+
+    kv.rekey(NEW_MASTER_KEY).await.unwrap();
+    let reopened = kv.reopen();
+    assert!(reopened.unseal(MASTER_KEY).await.is_err());
+    reopened.unseal(NEW_MASTER_KEY).await.unwrap();
+    assert_eq!(reopened.get_sealed::<String>("record").await.unwrap().unwrap(), "secret");
+    assert_eq!(
+      reopened.scan_sealed_in_place::<String>(KvDb::OUTBOX_PREFIX).await.unwrap(),
+      vec![(mail_key, String::from("code"))]
+    );
+  }
+
+  #[tokio::test]
+  async fn test_plain_records_are_sealed() {
+    let kv = KvDb::in_memory_unsealed().await.unwrap();
+    kv.upsert(KvDb::MAIN_SECRET_KEY, &vec![7u8; 256]).await.unwrap();
+
+    kv.seal_plain_records().await.unwrap();
+    assert!(!kv.exists(KvDb::MAIN_SECRET_KEY).await.unwrap());
+    assert_eq!(
+      kv.get_sealed::<Vec<u8>>(KvDb::MAIN_SECRET_KEY).await.unwrap().unwrap(),
+      vec![7u8; 256]
+    );
+  }
+}
@@ -0,0 +1,397 @@
+//! Storage backends of `KvDb`.
+//!
+//! `KvDb` (de)serializes records, seals secrets and runs blocking calls off the async runtime; a backend only
+//! stores raw bytes by string keys. `FjallStorage` is used in production, `MemoryStorage` keeps tests hermetic.
+
+use cc_server_kit::prelude::*;
+use serde::Deserialize;
+
+use crate::kv::KvDb;
+
+mod disk;
+mod memory;
+
+pub(crate) use disk::FjallStorage;
+pub(crate) use memory::MemoryStorage;
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct StorageOpts {
+  /// Directory of fjall keyspace.
+  #[serde(default = "default_path")]
+  pub(crate) path: std::path::PathBuf,
+  #[serde(default)]
+  pub(crate) fsync: FsyncMode,
+}
+
+fn default_path() -> std::path::PathBuf {
+  std::path::PathBuf::from(".fjall_data")
+}
+
+impl Default for StorageOpts {
+  fn default() -> Self {
+    Self {
+      path: default_path(),
+      fsync: Default::default(),
+    }
+  }
+}
+
+impl StorageOpts {
+  /// Fresh temporary directory; it's removed when the returned guard is dropped.
+  #[cfg(test)]
+  pub(crate) fn temp() -> (tempfile::TempDir, Self) {
+    let dir = tempfile::TempDir::new().unwrap();
+    let opts = Self {
+      path: dir.path().to_owned(),
+      fsync: Default::default(),
+    };
+    (dir, opts)
+  }
+}
+
+/// How writes are persisted before they're acknowledged.
+#[derive(Deserialize, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum FsyncMode {
+  /// Data and metadata are synced to disk.
+  #[default]
+  SyncAll,
+  /// Only data is synced to disk.
+  SyncData,
+  /// Writes are flushed to OS buffers; the last writes may be lost on power failure.
+  Buffer,
+}
+
+/// Records are kept in partitions by entity type, so each can be scanned and retained on its own.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Partition {
+  Keys,
+  /// Admins, sealed, and nonces of their requests.
+  Admins,
+  Apps,
+  Users,
+  Invites,
+  Sessions,
+  Bans,
+  Mail,
+  /// Admins' actions, see `crate::core::audit`.
+  Audit,
+  /// Anything else.
+  Misc,
+}
+
+impl Partition {
+  pub(crate) const ALL: [Partition; 10] = [
+    Self::Keys,
+    Self::Admins,
+    Self::Apps,
+    Self::Users,
+    Self::Invites,
+    Self::Sessions,
+    Self::Bans,
+    Self::Mail,
+    Self::Audit,
+    Self::Misc,
+  ];
+
+  pub(crate) fn name(&self) -> &'static str {
+    match self {
+      Self::Keys => "keys",
+      Self::Admins => "admins",
+      Self::Apps => "apps",
+      Self::Users => "users",
+      Self::Invites => "invites",
+      Self::Sessions => "sessions",
+      Self::Bans => "bans",
+      Self::Mail => "mail",
+      Self::Audit => "audit",
+      Self::Misc => "misc",
+    }
+  }
+
+  /// Partition of the record, or of all records with the prefix; prefixes shorter than the entity ones
+  /// fall into `Misc`.
+  ///
+  /// Sealed admins are kept with admins' nonces rather than with keys, see `Partition::of_prefix`.
+  pub(crate) fn of(key: &str) -> Self {
+    let starts_with = |prefixes: &[&str]| prefixes.iter().any(|prefix| key.starts_with(prefix));
+
+    if key.eq(KvDb::ADMIN_NONCES) || key.strip_prefix(KvDb::SEALED_PREFIX) == Some(KvDb::ADMINS) {
+      Self::Admins
+    } else if starts_with(&[KvDb::SEALED_PREFIX])
+      || [
+        KvDb::MAIN_SECRET_KEY,
+        KvDb::MAIN_DLTH_PUB_KEY,
+        KvDb::MAIN_DLTH_PRV_KEY,
+        KvDb::SIGNING_KEYS,
+        KvDb::PEPPERS,
+        KvDb::KEK_SALT,
+        KvDb::KEK_CHECK,
+      ]
+      .contains(&key)
+    {
+      Self::Keys
+    } else if starts_with(&[KvDb::APPLICATION_PREFIX]) {
+      Self::Apps
+    } else if starts_with(&[KvDb::USER_PREFIX, KvDb::USER_INDEX_PREFIX]) {
+      Self::Users
+    } else if key.eq(KvDb::INVITES) {
+      Self::Invites
+    } else if starts_with(&[KvDb::STATE_PREFIX]) {
+      Self::Sessions
+    } else if starts_with(&[KvDb::BAN_PREFIX]) {
+      Self::Bans
+    } else if starts_with(&[KvDb::OUTBOX_PREFIX, KvDb::OUTBOX_DEAD_PREFIX, KvDb::MAIL_RATE_PREFIX]) {
+      Self::Mail
+    } else if starts_with(&[KvDb::AUDIT_PREFIX]) {
+      Self::Audit
+    } else {
+      Self::Misc
+    }
+  }
+
+  /// Partitions which may hold records with the prefix: sealed records are scanned along with sealed admins.
+  pub(crate) fn of_prefix(prefix: &str) -> Vec<Self> {
+    let partition = Self::of(prefix);
+    if partition == Self::Keys && KvDb::sealed(KvDb::ADMINS).starts_with(prefix) {
+      vec![Self::Keys, Self::Admins]
+    } else {
+      vec![partition]
+    }
+  }
+}
+
+/// Condition of `Storage::batch_if`.
+pub(crate) enum Expect {
+  /// The record is equal to the value; `None` means absence.
+  Record(String, Option<Vec<u8>>),
+  /// The record exists, whatever its value is.
+  Exists(String),
+  /// Records with keys starting with the prefix are exactly these ones, ordered by key.
+  Prefix(String, Vec<(String, Vec<u8>)>),
+}
+
+impl Expect {
+  fn holds(&self, storage: &dyn Storage) -> MResult<bool> {
+    Ok(match self {
+      Self::Record(key, value) => storage.get(key)?.eq(value),
+      Self::Exists(key) => storage.contains(key)?,
+      Self::Prefix(prefix, records) => storage.scan_prefix(prefix)?.eq(records),
+    })
+  }
+}
+
+/// Key-value storage; every method is blocking.
+pub(crate) trait Storage: Send + Sync {
+  fn contains(&self, key: &str) -> MResult<bool>;
+  fn get(&self, key: &str) -> MResult<Option<Vec<u8>>>;
+  /// Fails if the key already exists.
+  fn insert(&self, key: &str, value: Vec<u8>) -> MResult<()>;
+  fn upsert(&self, key: &str, value: Vec<u8>) -> MResult<()>;
+  fn remove(&self, key: &str) -> MResult<()>;
+  /// Removes the record and returns it.
+  fn pop(&self, key: &str) -> MResult<Option<Vec<u8>>>;
+  /// Reads `get` records, then atomically removes `remove` and writes `upsert` ones.
+  fn batch(
+    &self,
+    get: Vec<String>,
+    upsert: Vec<(String, Vec<u8>)>,
+    remove: Vec<String>,
+  ) -> MResult<Vec<(String, Option<Vec<u8>>)>>;
+  /// Atomically removes `remove` and writes `upsert` records only if all `expect` conditions hold. Returns
+  /// whether the records were written.
+  fn batch_if(&self, expect: Vec<Expect>, upsert: Vec<(String, Vec<u8>)>, remove: Vec<String>) -> MResult<bool>;
+  /// Records with keys starting with the prefix, ordered by key.
+  fn scan_prefix(&self, prefix: &str) -> MResult<Vec<(String, Vec<u8>)>>;
+  /// Replaces the record (or removes it if `new` is `None`) only if it's equal to `expected`, where `None`
+  /// means absence. Returns whether the record was replaced.
+  fn compare_and_swap(&self, key: &str, expected: Option<&[u8]>, new: Option<Vec<u8>>) -> MResult<bool>;
+  /// All records at one moment, ordered by key.
+  fn snapshot(&self) -> MResult<Vec<(String, Vec<u8>)>>;
+  /// Atomically replaces all records.
+  fn restore(&self, records: Vec<(String, Vec<u8>)>) -> MResult<()>;
+}
+
+pub(crate) fn storage_error(e: impl std::fmt::Display) -> ErrorResponse {
+  ErrorResponse::from(e.to_string()).with_500().build()
+}
+
+fn key_exists_error() -> ErrorResponse {
+  ErrorResponse::from("Key already exists!").with_400().build()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Expect, FjallStorage, MemoryStorage, Partition, Storage, StorageOpts};
+  use crate::kv::KvDb;
+
+  fn check_storage(storage: &dyn Storage) {
+    for (key, _) in storage.scan_prefix("storage-test::").unwrap() {
+      storage.remove(&key).unwrap();
+    }
+
+    storage.insert("storage-test::a", b"1".to_vec()).unwrap();
+    assert!(storage.insert("storage-test::a", b"2".to_vec()).is_err());
+    storage.upsert("storage-test::b", b"2".to_vec()).unwrap();
+    assert!(storage.contains("storage-test::b").unwrap());
+    assert_eq!(storage.get("storage-test::a").unwrap().unwrap(), b"1");
+
+    let values = storage
+      .batch(
+        vec![String::from("storage-test::a")],
+        vec![(String::from("storage-test::c"), b"3".to_vec())],
+        vec![String::from("storage-test::a")],
+      )
+      .unwrap();
+    assert_eq!(values[0].1.as_deref(), Some(b"1".as_slice()));
+    assert!(storage.get("storage-test::a").unwrap().is_none());
+    let keys = storage
+      .scan_prefix("storage-test::")
+      .unwrap()
+      .into_iter()
+      .map(|(key, _)| key)
+      .collect::<Vec<_>>();
+    assert_eq!(keys, vec!["storage-test::b", "storage-test::c"]);
+
+    assert!(!storage.compare_and_swap("storage-test::b", Some(b"1"), Some(b"4".to_vec())).unwrap());
+    assert!(storage.compare_and_swap("storage-test::b", Some(b"2"), Some(b"4".to_vec())).unwrap());
+    assert!(!storage.compare_and_swap("storage-test::d", Some(b"4"), None).unwrap());
+    assert!(storage.compare_and_swap("storage-test::d", None, Some(b"5".to_vec())).unwrap());
+    assert!(storage.compare_and_swap("storage-test::d", Some(b"5"), None).unwrap());
+    assert!(!storage.contains("storage-test::d").unwrap());
+
+    let upsert = vec![(String::from("storage-test::e"), b"6".to_vec())];
+    assert!(
+      !storage
+        .batch_if(
+          vec![
+            Expect::Record(String::from("storage-test::d"), None),
+            Expect::Prefix(String::from("storage-test::c"), vec![]),
+          ],
+          upsert.clone(),
+          vec![]
+        )
+        .unwrap()
+    );
+    assert!(!storage.contains("storage-test::e").unwrap());
+    assert!(
+      storage
+        .batch_if(
+          vec![
+            Expect::Record(String::from("storage-test::b"), Some(b"4".to_vec())),
+            Expect::Exists(String::from("storage-test::c")),
+            Expect::Prefix(
+              String::from("storage-test::c"),
+              vec![(String::from("storage-test::c"), b"3".to_vec())]
+            ),
+          ],
+          upsert,
+          vec![String::from("storage-test::e")]
+        )
+        .unwrap()
+    );
+    assert_eq!(storage.pop("storage-test::e").unwrap().unwrap(), b"6");
+
+    assert_eq!(storage.pop("storage-test::b").unwrap().unwrap(), b"4");
+    assert!(storage.pop("storage-test::b").unwrap().is_none());
+
+    let snapshot = storage.snapshot().unwrap();
+    assert_eq!(snapshot, vec![(String::from("storage-test::c"), b"3".to_vec())]);
+    storage
+      .restore(vec![(KvDb::app("app"), b"app".to_vec()), (String::from("storage-test::e"), b"5".to_vec())])
+      .unwrap();
+    assert!(!storage.contains("storage-test::c").unwrap());
+    assert_eq!(storage.get(&KvDb::app("app")).unwrap().unwrap(), b"app");
+    assert_eq!(storage.snapshot().unwrap().len(), 2);
+    storage.restore(snapshot.clone()).unwrap();
+    assert_eq!(storage.snapshot().unwrap(), snapshot);
+  }
+
+  #[test]
+  fn test_memory_storage() {
+    check_storage(&MemoryStorage::default());
+  }
+
+  #[test]
+  fn test_fjall_storage() {
+    let (_dir, opts) = StorageOpts::temp();
+    check_storage(&FjallStorage::open(&opts).unwrap());
+  }
+
+  #[test]
+  fn test_partitions() {
+    assert_eq!(Partition::of(&KvDb::sealed(KvDb::SIGNING_KEYS)), Partition::Keys);
+    assert_eq!(Partition::of(KvDb::KEK_SALT), Partition::Keys);
+    assert_eq!(Partition::of(&KvDb::app("app")), Partition::Apps);
+    assert_eq!(Partition::of(&KvDb::user("app", "user")), Partition::Users);
+    assert_eq!(Partition::of(&KvDb::user_index_of("app")), Partition::Users);
+    assert_eq!(Partition::of(KvDb::INVITES), Partition::Invites);
+    assert_eq!(Partition::of(&KvDb::state(b"id")), Partition::Sessions);
+    assert_eq!(Partition::of(KvDb::OUTBOX_DEAD_PREFIX), Partition::Mail);
+    assert_eq!(Partition::of(&KvDb::sealed(KvDb::ADMINS)), Partition::Admins);
+    assert_eq!(Partition::of(KvDb::ADMIN_NONCES), Partition::Admins);
+    assert_eq!(Partition::of(&KvDb::bans_of("app")), Partition::Bans);
+    assert_eq!(Partition::of(&KvDb::audit(chrono::Utc::now())), Partition::Audit);
+    assert_eq!(Partition::of("storage-test::a"), Partition::Misc);
+
+    assert_eq!(
+      Partition::of_prefix(KvDb::SEALED_PREFIX),
+      vec![Partition::Keys, Partition::Admins]
+    );
+    assert_eq!(
+      Partition::of_prefix(&KvDb::sealed(KvDb::PEPPERS)),
+      vec![Partition::Keys]
+    );
+    assert_eq!(Partition::of_prefix(KvDb::BAN_PREFIX), vec![Partition::Bans]);
+  }
+
+  #[test]
+  fn test_single_partition_store_is_migrated() {
+    let (_dir, opts) = StorageOpts::temp();
+    let keyspace = fjall::Config::new(&opts.path).open().unwrap();
+    let legacy = keyspace
+      .open_partition(FjallStorage::LEGACY_PARTITION, Default::default())
+      .unwrap();
+    legacy.insert(KvDb::app("app"), b"app").unwrap();
+    legacy.insert(KvDb::user("app", "user"), b"user").unwrap();
+    legacy.insert(KvDb::INVITES, b"invites").unwrap();
+
+    let storage = FjallStorage::from_keyspace(keyspace.clone(), opts.fsync).unwrap();
+    assert!(!keyspace.partition_exists(FjallStorage::LEGACY_PARTITION));
+    assert_eq!(storage.get(&KvDb::app("app")).unwrap().unwrap(), b"app");
+    assert_eq!(storage.get(&KvDb::user("app", "user")).unwrap().unwrap(), b"user");
+    assert_eq!(storage.get(KvDb::INVITES).unwrap().unwrap(), b"invites");
+    assert_eq!(storage.scan_prefix(KvDb::USER_PREFIX).unwrap().len(), 1);
+  }
+
+  #[test]
+  fn test_misplaced_records_are_moved() {
+    let (_dir, opts) = StorageOpts::temp();
+    let keyspace = fjall::Config::new(&opts.path).open().unwrap();
+    let keys = keyspace
+      .open_partition(Partition::Keys.name(), Default::default())
+      .unwrap();
+    let misc = keyspace
+      .open_partition(Partition::Misc.name(), Default::default())
+      .unwrap();
+    keys.insert(KvDb::sealed(KvDb::ADMINS), b"admins").unwrap();
+    keys.insert(KvDb::sealed(KvDb::PEPPERS), b"peppers").unwrap();
+    misc.insert(KvDb::ADMIN_NONCES, b"nonces").unwrap();
+    misc.insert(KvDb::bans_of("app"), b"ban").unwrap();
+
+    let storage = FjallStorage::from_keyspace(keyspace, opts.fsync).unwrap();
+    assert!(!keys.contains_key(KvDb::sealed(KvDb::ADMINS)).unwrap());
+    assert!(misc.iter().next().is_none());
+    assert_eq!(storage.get(KvDb::ADMIN_NONCES).unwrap().unwrap(), b"nonces");
+    assert_eq!(storage.scan_prefix(KvDb::BAN_PREFIX).unwrap().len(), 1);
+
+    // Sealed admins are scanned along with other sealed records, e.g. on rekey.
+    let sealed = storage
+      .scan_prefix(KvDb::SEALED_PREFIX)
+      .unwrap()
+      .into_iter()
+      .map(|(key, _)| key)
+      .collect::<Vec<_>>();
+    assert_eq!(sealed, vec![KvDb::sealed(KvDb::ADMINS), KvDb::sealed(KvDb::PEPPERS)]);
+  }
+}
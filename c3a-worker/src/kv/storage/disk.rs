@@ -0,0 +1,287 @@
+use cc_server_kit::prelude::*;
+use fjall::{Keyspace, PartitionHandle, PersistMode};
+use std::collections::HashSet;
+use std::sync::{Mutex, MutexGuard};
+
+use super::{Expect, FsyncMode, Partition, Storage, StorageOpts, key_exists_error, storage_error};
+
+/// fjall keyspace with a partition per entity type (see `Partition`); writes are serialized, so
+/// checks-then-writes (`insert`, `pop`, `compare_and_swap`) are atomic.
+pub(crate) struct FjallStorage {
+  keyspace: Keyspace,
+  partitions: Vec<(Partition, PartitionHandle)>,
+  persist_mode: PersistMode,
+  write_lock: Mutex<()>,
+}
+
+impl From<FsyncMode> for PersistMode {
+  fn from(mode: FsyncMode) -> Self {
+    match mode {
+      FsyncMode::SyncAll => PersistMode::SyncAll,
+      FsyncMode::SyncData => PersistMode::SyncData,
+      FsyncMode::Buffer => PersistMode::Buffer,
+    }
+  }
+}
+
+impl FjallStorage {
+  /// The only partition of stores created before the split.
+  pub(crate) const LEGACY_PARTITION: &str = "data";
+  const MIGRATION_BATCH: usize = 1024;
+
+  pub(crate) fn open(opts: &StorageOpts) -> MResult<Self> {
+    let keyspace = fjall::Config::new(&opts.path)
+      .open()
+      .map_err(|e| ErrorResponse::from(e.to_string()).with_500_pub().build())?;
+    Self::from_keyspace(keyspace, opts.fsync)
+  }
+
+  pub(crate) fn from_keyspace(keyspace: Keyspace, fsync: FsyncMode) -> MResult<Self> {
+    let partitions = Partition::ALL
+      .iter()
+      .map(|partition| {
+        keyspace
+          .open_partition(partition.name(), Default::default())
+          .map(|handle| (*partition, handle))
+      })
+      .collect::<fjall::Result<Vec<_>>>()
+      .map_err(|e| ErrorResponse::from(e.to_string()).with_500_pub().build())?;
+
+    let storage = Self {
+      keyspace,
+      partitions,
+      persist_mode: fsync.into(),
+      write_lock: Default::default(),
+    };
+    storage.migrate_legacy_partition()?;
+    storage.migrate_misplaced_records()?;
+    Ok(storage)
+  }
+
+  /// Moves records of the single-partition store to their partitions and drops the old partition.
+  fn migrate_legacy_partition(&self) -> MResult<()> {
+    if !self.keyspace.partition_exists(Self::LEGACY_PARTITION) {
+      return Ok(());
+    }
+    let legacy = self
+      .keyspace
+      .open_partition(Self::LEGACY_PARTITION, Default::default())
+      .map_err(storage_error)?;
+
+    tracing::info!("Moving records to partitions by entity type...");
+    let mut moved = 0;
+    let mut records = legacy.iter().peekable();
+    while records.peek().is_some() {
+      let mut batch = self.keyspace.batch();
+      for record in records.by_ref().take(Self::MIGRATION_BATCH) {
+        let (key, value) = record.map_err(storage_error)?;
+        batch.insert(self.partition(&String::from_utf8_lossy(&key)), key, value);
+        moved += 1;
+      }
+      batch.commit().map_err(storage_error)?;
+    }
+    self.persist()?;
+
+    self.keyspace.delete_partition(legacy).map_err(storage_error)?;
+    tracing::info!("{} records are moved, `{}` partition is removed.", moved, Self::LEGACY_PARTITION);
+    Ok(())
+  }
+
+  /// Moves records written before they got partitions of their own (admins, bans) out of `keys` and `misc`.
+  fn migrate_misplaced_records(&self) -> MResult<()> {
+    for partition in [Partition::Keys, Partition::Misc] {
+      let handle = self.handle(partition);
+      let mut batch = self.keyspace.batch();
+      let mut moved = 0;
+      for record in handle.iter() {
+        let (key, value) = record.map_err(storage_error)?;
+        let target = Partition::of(&String::from_utf8_lossy(&key));
+        if target.ne(&partition) {
+          batch.remove(handle, key.clone());
+          batch.insert(self.handle(target), key, value);
+          moved += 1;
+        }
+      }
+      if moved > 0 {
+        batch.commit().map_err(storage_error)?;
+        self.persist()?;
+        tracing::info!("{} records are moved out of `{}` partition.", moved, partition.name());
+      }
+    }
+    Ok(())
+  }
+
+  fn partition(&self, key: &str) -> &PartitionHandle {
+    self.handle(Partition::of(key))
+  }
+
+  fn handle(&self, partition: Partition) -> &PartitionHandle {
+    self
+      .partitions
+      .iter()
+      .find_map(|(p, handle)| p.eq(&partition).then_some(handle))
+      .expect("all partitions are opened")
+  }
+
+  fn persist(&self) -> MResult<()> {
+    self.keyspace.persist(self.persist_mode).map_err(storage_error)
+  }
+
+  fn lock(&self) -> MResult<MutexGuard<'_, ()>> {
+    self.write_lock.lock().map_err(storage_error)
+  }
+}
+
+impl Storage for FjallStorage {
+  fn contains(&self, key: &str) -> MResult<bool> {
+    self.partition(key).contains_key(key).map_err(storage_error)
+  }
+
+  fn get(&self, key: &str) -> MResult<Option<Vec<u8>>> {
+    Ok(
+      self
+        .partition(key)
+        .get(key)
+        .map_err(storage_error)?
+        .map(|slice| slice.to_vec()),
+    )
+  }
+
+  fn insert(&self, key: &str, value: Vec<u8>) -> MResult<()> {
+    let _guard = self.lock()?;
+    if self.contains(key)? {
+      return Err(key_exists_error());
+    }
+    self.partition(key).insert(key, value).map_err(storage_error)?;
+    self.persist()
+  }
+
+  fn upsert(&self, key: &str, value: Vec<u8>) -> MResult<()> {
+    let _guard = self.lock()?;
+    self.partition(key).insert(key, value).map_err(storage_error)?;
+    self.persist()
+  }
+
+  fn remove(&self, key: &str) -> MResult<()> {
+    let _guard = self.lock()?;
+    self.partition(key).remove(key).map_err(storage_error)?;
+    self.persist()
+  }
+
+  fn pop(&self, key: &str) -> MResult<Option<Vec<u8>>> {
+    let _guard = self.lock()?;
+    let item = self.get(key)?;
+    if item.is_some() {
+      self.partition(key).remove(key).map_err(storage_error)?;
+      self.persist()?;
+    }
+    Ok(item)
+  }
+
+  fn batch(
+    &self,
+    get: Vec<String>,
+    upsert: Vec<(String, Vec<u8>)>,
+    remove: Vec<String>,
+  ) -> MResult<Vec<(String, Option<Vec<u8>>)>> {
+    let _guard = self.lock()?;
+    let mut values = vec![];
+    for key in get {
+      let value = self.get(&key)?;
+      values.push((key, value));
+    }
+
+    let mut batch = self.keyspace.batch();
+    for key in remove {
+      batch.remove(self.partition(&key), key);
+    }
+    for (key, value) in upsert {
+      batch.insert(self.partition(&key), key, value);
+    }
+    batch.commit().map_err(storage_error)?;
+    self.persist()?;
+
+    Ok(values)
+  }
+
+  fn batch_if(&self, expect: Vec<Expect>, upsert: Vec<(String, Vec<u8>)>, remove: Vec<String>) -> MResult<bool> {
+    let _guard = self.lock()?;
+    for expect in &expect {
+      if !expect.holds(self)? {
+        return Ok(false);
+      }
+    }
+
+    let mut batch = self.keyspace.batch();
+    for key in remove {
+      batch.remove(self.partition(&key), key);
+    }
+    for (key, value) in upsert {
+      batch.insert(self.partition(&key), key, value);
+    }
+    batch.commit().map_err(storage_error)?;
+    self.persist()?;
+    Ok(true)
+  }
+
+  fn scan_prefix(&self, prefix: &str) -> MResult<Vec<(String, Vec<u8>)>> {
+    let partitions = Partition::of_prefix(prefix);
+    let mut records = partitions
+      .iter()
+      .flat_map(|partition| self.handle(*partition).prefix(prefix))
+      .map(|item| item.map(|(key, value)| (String::from_utf8_lossy(&key).to_string(), value.to_vec())))
+      .collect::<fjall::Result<Vec<_>>>()
+      .map_err(storage_error)?;
+    if partitions.len() > 1 {
+      records.sort_by(|(a, _), (b, _)| a.cmp(b));
+    }
+    Ok(records)
+  }
+
+  fn compare_and_swap(&self, key: &str, expected: Option<&[u8]>, new: Option<Vec<u8>>) -> MResult<bool> {
+    let _guard = self.lock()?;
+    if self.get(key)?.as_deref() != expected {
+      return Ok(false);
+    }
+    match new {
+      Some(value) => self.partition(key).insert(key, value).map_err(storage_error)?,
+      None => self.partition(key).remove(key).map_err(storage_error)?,
+    }
+    self.persist()?;
+    Ok(true)
+  }
+
+  fn snapshot(&self) -> MResult<Vec<(String, Vec<u8>)>> {
+    // Writes are blocked, so all partitions are read at the same moment.
+    let _guard = self.lock()?;
+    let mut records = self
+      .partitions
+      .iter()
+      .flat_map(|(_, handle)| handle.iter())
+      .map(|item| item.map(|(key, value)| (String::from_utf8_lossy(&key).to_string(), value.to_vec())))
+      .collect::<fjall::Result<Vec<_>>>()
+      .map_err(storage_error)?;
+    records.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(records)
+  }
+
+  fn restore(&self, records: Vec<(String, Vec<u8>)>) -> MResult<()> {
+    let _guard = self.lock()?;
+    let mut batch = self.keyspace.batch();
+    // A key shouldn't be both removed and inserted by one batch.
+    let restored = records.iter().map(|(key, _)| key.as_bytes()).collect::<HashSet<_>>();
+    for (_, handle) in &self.partitions {
+      for key in handle.keys() {
+        let key = key.map_err(storage_error)?;
+        if !restored.contains(&*key) {
+          batch.remove(handle, key);
+        }
+      }
+    }
+    for (key, value) in records {
+      batch.insert(self.partition(&key), key, value);
+    }
+    batch.commit().map_err(storage_error)?;
+    self.persist()
+  }
+}
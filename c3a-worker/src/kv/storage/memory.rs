@@ -0,0 +1,123 @@
+use cc_server_kit::prelude::*;
+use std::collections::BTreeMap;
+use std::sync::{Mutex, MutexGuard};
+
+use super::{Expect, Storage, key_exists_error, storage_error};
+
+/// Records kept in process memory; nothing survives the process.
+#[derive(Default)]
+pub(crate) struct MemoryStorage {
+  records: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+  fn records(&self) -> MResult<MutexGuard<'_, BTreeMap<String, Vec<u8>>>> {
+    self.records.lock().map_err(storage_error)
+  }
+}
+
+impl Storage for MemoryStorage {
+  fn contains(&self, key: &str) -> MResult<bool> {
+    Ok(self.records()?.contains_key(key))
+  }
+
+  fn get(&self, key: &str) -> MResult<Option<Vec<u8>>> {
+    Ok(self.records()?.get(key).cloned())
+  }
+
+  fn insert(&self, key: &str, value: Vec<u8>) -> MResult<()> {
+    let mut records = self.records()?;
+    if records.contains_key(key) {
+      return Err(key_exists_error());
+    }
+    records.insert(key.to_owned(), value);
+    Ok(())
+  }
+
+  fn upsert(&self, key: &str, value: Vec<u8>) -> MResult<()> {
+    self.records()?.insert(key.to_owned(), value);
+    Ok(())
+  }
+
+  fn remove(&self, key: &str) -> MResult<()> {
+    self.records()?.remove(key);
+    Ok(())
+  }
+
+  fn pop(&self, key: &str) -> MResult<Option<Vec<u8>>> {
+    Ok(self.records()?.remove(key))
+  }
+
+  fn batch(
+    &self,
+    get: Vec<String>,
+    upsert: Vec<(String, Vec<u8>)>,
+    remove: Vec<String>,
+  ) -> MResult<Vec<(String, Option<Vec<u8>>)>> {
+    let mut records = self.records()?;
+    let values = get
+      .into_iter()
+      .map(|key| {
+        let value = records.get(&key).cloned();
+        (key, value)
+      })
+      .collect();
+    for key in remove {
+      records.remove(&key);
+    }
+    records.extend(upsert);
+    Ok(values)
+  }
+
+  fn batch_if(&self, expect: Vec<Expect>, upsert: Vec<(String, Vec<u8>)>, remove: Vec<String>) -> MResult<bool> {
+    let mut records = self.records()?;
+    let holds = expect.iter().all(|expect| match expect {
+      Expect::Record(key, value) => records.get(key).eq(&value.as_ref()),
+      Expect::Exists(key) => records.contains_key(key),
+      Expect::Prefix(prefix, expected) => records
+        .range(prefix.to_owned()..)
+        .take_while(|(key, _)| key.starts_with(prefix.as_str()))
+        .eq(expected.iter().map(|(key, value)| (key, value))),
+    });
+    if !holds {
+      return Ok(false);
+    }
+    for key in remove {
+      records.remove(&key);
+    }
+    records.extend(upsert);
+    Ok(true)
+  }
+
+  fn scan_prefix(&self, prefix: &str) -> MResult<Vec<(String, Vec<u8>)>> {
+    Ok(
+      self
+        .records()?
+        .range(prefix.to_owned()..)
+        .take_while(|(key, _)| key.starts_with(prefix))
+        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        .collect(),
+    )
+  }
+
+  fn compare_and_swap(&self, key: &str, expected: Option<&[u8]>, new: Option<Vec<u8>>) -> MResult<bool> {
+    let mut records = self.records()?;
+    if records.get(key).map(Vec::as_slice) != expected {
+      return Ok(false);
+    }
+    match new {
+      Some(value) => records.insert(key.to_owned(), value),
+      None => records.remove(key),
+    };
+    Ok(true)
+  }
+
+  fn snapshot(&self) -> MResult<Vec<(String, Vec<u8>)>> {
+    Ok(self.records()?.clone().into_iter().collect())
+  }
+
+  fn restore(&self, records: Vec<(String, Vec<u8>)>) -> MResult<()> {
+    *self.records()? = records.into_iter().collect();
+    Ok(())
+  }
+}
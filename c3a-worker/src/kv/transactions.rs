@@ -0,0 +1,217 @@
+//! Read-modify-write of records.
+//!
+//! The record is read, changed by the closure and written back by `compare_and_swap` only if nobody has
+//! changed it meanwhile; otherwise the closure is called again with the new value. So the closure may be called
+//! several times and shouldn't have effects besides the value and its result.
+//!
+//! Single-record writes of `Storage` (`insert`, `pop`, `compare_and_swap`) are atomic by themselves.
+
+use cc_server_kit::prelude::*;
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::kv::KvDb;
+
+pub(super) const MAX_UPDATE_ATTEMPTS: usize = 64;
+
+impl KvDb {
+  /// Changes the record with `f` atomically; the record is removed if `f` leaves `None`. Returns the result of
+  /// `f`; if `f` fails, nothing is written.
+  pub(crate) async fn update<T, R, F>(&self, key: &str, mut f: F) -> MResult<R>
+  where
+    T: Serialize + DeserializeOwned,
+    F: FnMut(&mut Option<T>) -> MResult<R>,
+  {
+    self
+      .update_raw(key, |stored| {
+        let mut value = stored
+          .map(rmp_serde::from_slice::<T>)
+          .transpose()
+          .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+        let result = f(&mut value)?;
+        let new = value
+          .as_ref()
+          .map(rmp_serde::to_vec)
+          .transpose()
+          .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+        Ok((new, result))
+      })
+      .await
+  }
+
+  /// Same as `KvDb::update`, but `f` gets the stored bytes and returns new ones.
+  pub(crate) async fn update_raw<R, F>(&self, key: &str, mut f: F) -> MResult<R>
+  where
+    F: FnMut(Option<&[u8]>) -> MResult<(Option<Vec<u8>>, R)>,
+  {
+    for _ in 0..MAX_UPDATE_ATTEMPTS {
+      let stored = self.get_raw(key).await?;
+      let (new, result) = f(stored.as_deref())?;
+      if new == stored || self.compare_and_swap_raw(key, stored, new).await? {
+        return Ok(result);
+      }
+      tracing::trace!("kv: value by path `{}` is changed concurrently, retrying", key);
+    }
+
+    Err(
+      ErrorResponse::from(format!("Too many concurrent updates of `{key}`!"))
+        .with_500()
+        .build(),
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use c3a_common::{AppAuthConfiguration, UserData};
+  use cc_server_kit::prelude::*;
+  use std::collections::HashSet;
+  use std::sync::Arc;
+
+  use crate::kv::KvDb;
+  use crate::kv::storage::{FjallStorage, StorageOpts};
+
+  const KEY: &str = "transactions-test";
+
+  fn on_disk() -> (tempfile::TempDir, KvDb) {
+    let (dir, opts) = StorageOpts::temp();
+    (dir, KvDb::with_storage(Arc::new(FjallStorage::open(&opts).unwrap())))
+  }
+
+  async fn check_concurrent_updates_are_not_lost(kv: KvDb) {
+    let tasks = (0..32u32)
+      .map(|i| {
+        let kv = kv.clone();
+        tokio::spawn(async move {
+          kv.update::<HashSet<u32>, _, _>(KEY, |set| {
+            set.get_or_insert_default().insert(i);
+            Ok(())
+          })
+          .await
+        })
+      })
+      .collect::<Vec<_>>();
+    for task in tasks {
+      task.await.unwrap().unwrap();
+    }
+    assert_eq!(kv.get::<HashSet<u32>>(KEY).await.unwrap().unwrap().len(), 32);
+
+    // Only one of concurrent takers gets the item.
+    let tasks = (0..16)
+      .map(|_| {
+        let kv = kv.clone();
+        tokio::spawn(async move {
+          kv.update::<HashSet<u32>, _, _>(KEY, |set| {
+            if set.as_mut().is_some_and(|set| set.remove(&7)) {
+              Ok(())
+            } else {
+              Err(ErrorResponse::from("taken").with_400().build())
+            }
+          })
+          .await
+        })
+      })
+      .collect::<Vec<_>>();
+    let mut taken = 0;
+    for task in tasks {
+      taken += task.await.unwrap().is_ok() as usize;
+    }
+    assert_eq!(taken, 1);
+    assert_eq!(kv.get::<HashSet<u32>>(KEY).await.unwrap().unwrap().len(), 31);
+
+    kv.update::<HashSet<u32>, _, _>(KEY, |set| {
+      *set = None;
+      Ok(())
+    })
+    .await
+    .unwrap();
+    assert!(!kv.exists(KEY).await.unwrap());
+  }
+
+  async fn check_concurrent_inserts(kv: KvDb) {
+    let tasks = (0..16)
+      .map(|i| {
+        let kv = kv.clone();
+        tokio::spawn(async move { kv.insert(KEY, &i).await })
+      })
+      .collect::<Vec<_>>();
+    let mut inserted = 0;
+    for task in tasks {
+      inserted += task.await.unwrap().is_ok() as usize;
+    }
+    assert_eq!(inserted, 1);
+  }
+
+  #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+  async fn test_concurrent_updates_are_not_lost() {
+    check_concurrent_updates_are_not_lost(KvDb::in_memory()).await;
+    let (_dir, kv) = on_disk();
+    check_concurrent_updates_are_not_lost(kv).await;
+  }
+
+  #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+  async fn test_concurrent_inserts() {
+    check_concurrent_inserts(KvDb::in_memory()).await;
+    let (_dir, kv) = on_disk();
+    check_concurrent_inserts(kv).await;
+  }
+
+  #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+  async fn test_users_registered_during_rename_are_moved() {
+    let (_dir, kv) = on_disk();
+    let app_conf = AppAuthConfiguration {
+      app_name: String::from("old"),
+      domain: String::from("example.com"),
+      allowed_tags: vec![],
+      allow_sign_up: None,
+      client_based_auth_opts: None,
+      author_dpub: vec![],
+      email_templates: None,
+      hash_params: None,
+    };
+    kv.insert_versioned(&KvDb::app("old"), &app_conf).await.unwrap();
+
+    let registrations = (0..32)
+      .map(|i| {
+        let kv = kv.clone();
+        tokio::spawn(async move {
+          let user_data = UserData {
+            identifier: format!("user-{i}@example.com"),
+            authentication_flows: vec![],
+          };
+          // Registers under the name the app has now.
+          for app_name in ["old", "new"] {
+            if kv.insert_user(app_name, &user_data).await.is_ok() {
+              return Some(user_data.identifier);
+            }
+          }
+          None
+        })
+      })
+      .collect::<Vec<_>>();
+    kv.update_app("old", |app_conf| {
+      app_conf.app_name = String::from("new");
+      Ok(())
+    })
+    .await
+    .unwrap();
+
+    let mut registered = vec![];
+    for task in registrations {
+      registered.extend(task.await.unwrap());
+    }
+    assert!(kv.scan_prefix_raw(&KvDb::users_of("old")).await.unwrap().is_empty());
+    assert!(
+      kv.scan_prefix_raw(&KvDb::user_index_of("old"))
+        .await
+        .unwrap()
+        .is_empty()
+    );
+    for identifier in &registered {
+      assert!(kv.user_exists("new", identifier).await.unwrap());
+    }
+    assert_eq!(
+      kv.scan_prefix_raw(&KvDb::users_of("new")).await.unwrap().len(),
+      registered.len()
+    );
+  }
+}
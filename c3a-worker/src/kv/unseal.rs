@@ -0,0 +1,387 @@
+//! Unsealing the storage with Shamir shares of the master key.
+//!
+//! With `unseal: { type: shamir, threshold: K }` the worker doesn't read the master key on startup. It starts
+//! sealed, serving only health checks and `/unseal`, until K shares are submitted; then the master key is
+//! recovered, checked against the `KvDb::KEK_CHECK` record and the storage is unsealed and prepared with
+//! `KvDb::initial_setup`. The storage should have been unsealed with the master key once before, so the check
+//! record exists.
+//!
+//! Shares are made offline from the current master key (`C3A_MASTER_KEY` or `master_key_file`) with
+//! `c3a-worker split-master-key --shares N --threshold K`, see `run_split`. It also prints SHA-256 digests of
+//! the shares for `share_digests`; `/unseal` is open to anyone, so only shares matching these digests are
+//! accepted, and nobody but share holders can take the slots or reset them (`DELETE /unseal`).
+//!
+//! C3A administrator can seal the instance back (`/admin/seal`); the KEK and submitted shares are wiped
+//! from memory.
+
+use c3a_common::UnsealStatusResponse;
+use cc_server_kit::prelude::*;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sharks::{Share, Sharks};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use zeroize::Zeroizing;
+
+use crate::kv::KvDb;
+
+#[derive(Deserialize, Clone, Default, Debug)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub(crate) enum UnsealMode {
+  /// The master key is read from `C3A_MASTER_KEY` env variable or `master_key_file` on startup.
+  #[default]
+  MasterKey,
+  /// The worker starts sealed and waits for `threshold` shares of the master key.
+  Shamir {
+    threshold: u8,
+    /// Hex-encoded SHA-256 digests of the shares, printed by `split-master-key`.
+    share_digests: Vec<String>,
+  },
+}
+
+/// Shares submitted to `/unseal`.
+#[derive(Clone)]
+pub(crate) struct Unsealer {
+  threshold: Option<u8>,
+  share_digests: Arc<Vec<String>>,
+  shares: Arc<Mutex<Vec<Zeroizing<Vec<u8>>>>>,
+}
+
+impl Unsealer {
+  pub(crate) fn new(mode: &UnsealMode) -> MResult<Self> {
+    let (threshold, share_digests) = match mode {
+      UnsealMode::MasterKey => (None, vec![]),
+      UnsealMode::Shamir {
+        threshold,
+        share_digests,
+      } => {
+        if share_digests.len() < *threshold as usize {
+          return Err(ErrorResponse::from(
+            "There should be at least `threshold` share digests to unseal the storage!",
+          ));
+        }
+        (
+          Some(*threshold),
+          share_digests.iter().map(|digest| digest.to_lowercase()).collect(),
+        )
+      }
+    };
+    Ok(Self {
+      threshold,
+      share_digests: Arc::new(share_digests),
+      shares: Default::default(),
+    })
+  }
+
+  pub(crate) async fn status(&self, kv: &KvDb) -> UnsealStatusResponse {
+    UnsealStatusResponse {
+      sealed: kv.is_sealed(),
+      submitted: self.shares.lock().await.len(),
+      threshold: self.threshold.unwrap_or_default(),
+    }
+  }
+
+  /// Accepts a share; when there are enough of them, recovers the master key and unseals the storage.
+  ///
+  /// Only shares matching the configured digests are accepted, and they are told apart by their index (the first
+  /// byte). If the recovered key doesn't match `KvDb::KEK_CHECK`, the shares are of another master key; they
+  /// are discarded, so shares of the current key can be submitted.
+  pub(crate) async fn submit(&self, kv: &KvDb, share: &[u8]) -> MResult<UnsealStatusResponse> {
+    let threshold = self.check_share(kv, share)?;
+
+    let mut shares = self.shares.lock().await;
+    let status = |submitted: usize| UnsealStatusResponse {
+      sealed: true,
+      submitted,
+      threshold,
+    };
+    if let Some(submitted) = shares.iter().find(|submitted| submitted[0] == share[0]) {
+      if submitted.as_slice().ne(share) {
+        return Err(
+          ErrorResponse::from(format!("Another share #{} is already submitted.", share[0]))
+            .with_400_pub()
+            .build(),
+        );
+      }
+      return Ok(status(shares.len()));
+    }
+    shares.push(Zeroizing::new(share.to_vec()));
+    if shares.len() < threshold as usize {
+      return Ok(status(shares.len()));
+    }
+
+    let master_key = recover_master_key(threshold, &shares);
+    let master_key = match master_key {
+      Ok(master_key) if kv.check_master_key(&master_key).await? => master_key,
+      _ => {
+        shares.clear();
+        tracing::warn!("Submitted shares don't match the master key and are discarded.");
+        return Err(
+          ErrorResponse::from("Shares don't match the master key and are discarded; submit shares of the current key.")
+            .with_403_pub()
+            .build(),
+        );
+      }
+    };
+
+    kv.unseal(&master_key).await?;
+    kv.initial_setup().await?;
+    shares.clear();
+    tracing::info!("The storage is unsealed with {} shares.", threshold);
+    Ok(UnsealStatusResponse {
+      sealed: false,
+      submitted: threshold as usize,
+      threshold,
+    })
+  }
+
+  /// Forgets submitted shares without sealing the storage; any share holder can do it.
+  pub(crate) async fn reset(&self, kv: &KvDb, share: &[u8]) -> MResult<UnsealStatusResponse> {
+    let threshold = self.check_share(kv, share)?;
+    self.shares.lock().await.clear();
+    tracing::info!("Submitted shares are discarded by the holder of share #{}.", share[0]);
+    Ok(UnsealStatusResponse {
+      sealed: true,
+      submitted: 0,
+      threshold,
+    })
+  }
+
+  /// Checks that the storage waits for shares and the share is one of the configured ones; returns the threshold.
+  fn check_share(&self, kv: &KvDb, share: &[u8]) -> MResult<u8> {
+    let threshold = self.threshold.ok_or(
+      ErrorResponse::from("The instance is unsealed with the master key; restart it to unseal.")
+        .with_400_pub()
+        .build(),
+    )?;
+    if !kv.is_sealed() {
+      return Err(ErrorResponse::from("The instance is already unsealed.").with_400_pub().build());
+    }
+    Share::try_from(share).map_err(|e| ErrorResponse::from(format!("Invalid share: {e}")).with_400_pub().build())?;
+    if !self.share_digests.contains(&share_digest(share)) {
+      return Err(
+        ErrorResponse::from("The share doesn't match any configured share digest.")
+          .with_403_pub()
+          .build(),
+      );
+    }
+    Ok(threshold)
+  }
+
+  /// Seals the storage and forgets submitted shares.
+  pub(crate) async fn seal(&self, kv: &KvDb) {
+    self.shares.lock().await.clear();
+    kv.reseal();
+    tracing::info!("The storage is sealed.");
+  }
+}
+
+/// Splits the master key into `shares` shares, any `threshold` of which recover it.
+pub(crate) fn split_master_key(master_key: &[u8], shares: u8, threshold: u8) -> MResult<Vec<Zeroizing<Vec<u8>>>> {
+  if threshold < 2 || shares < threshold {
+    return Err(ErrorResponse::from(
+      "Threshold should be at least 2 and not greater than the number of shares!",
+    ));
+  }
+  Ok(
+    Sharks(threshold)
+      .dealer(master_key)
+      .take(shares as usize)
+      .map(|share| Zeroizing::new(Vec::from(&share)))
+      .collect(),
+  )
+}
+
+/// Hex-encoded SHA-256 digest of the share, as configured in `share_digests`.
+pub(crate) fn share_digest(share: &[u8]) -> String {
+  hex::encode(Sha256::digest(share))
+}
+
+fn recover_master_key(threshold: u8, shares: &[Zeroizing<Vec<u8>>]) -> MResult<Zeroizing<Vec<u8>>> {
+  let shares = shares
+    .iter()
+    .map(|share| Share::try_from(share.as_slice()))
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|e| ErrorResponse::from(format!("Invalid share: {e}")).with_400_pub().build())?;
+  Sharks(threshold)
+    .recover(shares.as_slice())
+    .map(Zeroizing::new)
+    .map_err(|e| ErrorResponse::from(format!("Can't recover the master key: {e}")).with_403_pub().build())
+}
+
+/// Prints shares of the master key: `c3a-worker split-master-key --shares N --threshold K`.
+pub(crate) fn run_split(master_key: &[u8], args: &[String]) -> MResult<()> {
+  let usage = || ErrorResponse::from("Usage: c3a-worker split-master-key --shares N --threshold K");
+  let value = |flag: &str| -> MResult<u8> {
+    args
+      .iter()
+      .position(|arg| arg.as_str().eq(flag))
+      .and_then(|i| args.get(i + 1))
+      .and_then(|value| value.parse::<u8>().ok())
+      .ok_or_else(usage)
+  };
+
+  let threshold = value("--threshold")?;
+  let shares = split_master_key(master_key, value("--shares")?, threshold)?;
+  for (i, share) in shares.iter().enumerate() {
+    println!("Share {}: {}", i + 1, hex::encode(share.as_slice()));
+  }
+
+  println!("\nWorker configuration:\nunseal:\n  type: shamir\n  threshold: {threshold}\n  share_digests:");
+  for share in shares.iter() {
+    println!("    - {}", share_digest(share));
+  }
+  Ok(())
+}
+
+pub(crate) fn extract_unsealer(depot: &mut Depot) -> MResult<Unsealer> {
+  Ok(
+    depot
+      .obtain::<Unsealer>()
+      .map_err(|_| ErrorResponse::from("Can't get `Unsealer` instance").with_500().build())?
+      .clone(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{UnsealMode, Unsealer, share_digest, split_master_key};
+  use crate::kv::KvDb;
+  use zeroize::Zeroizing;
+
+  const MASTER_KEY: &[u8] = b"test-master-key-XXXXXXXXXXXXXXXXXXXXXXXX";
+
+  fn unsealer(threshold: u8, shares: &[Zeroizing<Vec<u8>>]) -> Unsealer {
+    Unsealer::new(&UnsealMode::Shamir {
+      threshold,
+      share_digests: shares.iter().map(|share| share_digest(share)).collect(),
+    })
+    .unwrap()
+  }
+
+  async fn sealed_kv() -> KvDb {
+    let kv = KvDb::in_memory_unsealed().await.unwrap();
+    kv.initial_setup().await.unwrap();
+    kv.reseal();
+    kv
+  }
+
+  #[test]
+  fn test_split_and_recover() {
+    let shares = split_master_key(MASTER_KEY, 5, 3).unwrap();
+    assert_eq!(shares.len(), 5);
+    assert_eq!(super::recover_master_key(3, &shares[1..4]).unwrap().as_slice(), MASTER_KEY);
+    assert!(super::recover_master_key(3, &shares[..2]).is_err());
+    assert!(split_master_key(MASTER_KEY, 2, 3).is_err());
+    assert!(split_master_key(MASTER_KEY, 3, 1).is_err());
+  }
+
+  #[tokio::test]
+  async fn test_unseal_with_shares_and_reseal() {
+    let kv = KvDb::in_memory_unsealed().await.unwrap();
+    kv.initial_setup().await.unwrap();
+    let secret = kv.get_secret_key().await.unwrap();
+    kv.reseal();
+    assert!(kv.is_sealed());
+    assert!(kv.get_secret_key().await.is_err());
+
+    let shares = split_master_key(MASTER_KEY, 3, 2).unwrap();
+    let unsealer = unsealer(2, &shares);
+
+    let status = unsealer.submit(&kv, &shares[2]).await.unwrap();
+    assert!(status.sealed);
+    // Repeated share isn't counted.
+    assert_eq!(unsealer.submit(&kv, &shares[2]).await.unwrap().submitted, 1);
+
+    let status = unsealer.submit(&kv, &shares[0]).await.unwrap();
+    assert!(!status.sealed);
+    assert_eq!(kv.get_secret_key().await.unwrap(), secret);
+
+    unsealer.seal(&kv).await;
+    assert!(kv.is_sealed());
+    assert_eq!(unsealer.status(&kv).await.submitted, 0);
+  }
+
+  #[tokio::test]
+  async fn test_hostile_submitter() {
+    let kv = sealed_kv().await;
+    let shares = split_master_key(MASTER_KEY, 3, 2).unwrap();
+    let unsealer = unsealer(2, &shares);
+
+    // Shares forged with the real indices, shares of another key and garbage take no slots.
+    let other = split_master_key(b"test-master-key-YYYYYYYYYYYYYYYYYYYYYYYY", 3, 2).unwrap();
+    for share in shares.iter() {
+      let mut forged = share.to_vec();
+      *forged.last_mut().unwrap() ^= 1;
+      assert!(unsealer.submit(&kv, &forged).await.is_err());
+    }
+    for share in other.iter() {
+      assert!(unsealer.submit(&kv, share).await.is_err());
+    }
+    assert!(unsealer.submit(&kv, &[1, 2, 3]).await.is_err());
+    assert_eq!(unsealer.status(&kv).await.submitted, 0);
+
+    // Nor can they discard the holders' shares.
+    unsealer.submit(&kv, &shares[0]).await.unwrap();
+    assert!(unsealer.reset(&kv, &other[1]).await.is_err());
+    assert_eq!(unsealer.status(&kv).await.submitted, 1);
+
+    let status = unsealer.submit(&kv, &shares[1]).await.unwrap();
+    assert!(!status.sealed);
+  }
+
+  #[tokio::test]
+  async fn test_shares_of_another_key_are_discarded() {
+    let kv = sealed_kv().await;
+    let shares = split_master_key(MASTER_KEY, 3, 2).unwrap();
+    let old = split_master_key(b"test-master-key-YYYYYYYYYYYYYYYYYYYYYYYY", 3, 2).unwrap();
+    // Digests of an old split are configured along with the current ones by mistake.
+    let unsealer = unsealer(2, &[shares.as_slice(), &old[1..]].concat());
+
+    unsealer.submit(&kv, &old[1]).await.unwrap();
+    unsealer.submit(&kv, &old[2]).await.unwrap_err();
+    assert!(kv.is_sealed());
+    assert_eq!(unsealer.status(&kv).await.submitted, 0);
+
+    unsealer.submit(&kv, &shares[1]).await.unwrap();
+    assert!(!unsealer.submit(&kv, &shares[2]).await.unwrap().sealed);
+  }
+
+  #[tokio::test]
+  async fn test_reset_by_share_holder() {
+    let kv = sealed_kv().await;
+    let shares = split_master_key(MASTER_KEY, 3, 2).unwrap();
+    let unsealer = unsealer(2, &shares);
+
+    unsealer.submit(&kv, &shares[0]).await.unwrap();
+    assert_eq!(unsealer.reset(&kv, &shares[2]).await.unwrap().submitted, 0);
+    assert_eq!(unsealer.status(&kv).await.submitted, 0);
+    assert!(kv.is_sealed());
+
+    unsealer.submit(&kv, &shares[2]).await.unwrap();
+    assert!(!unsealer.submit(&kv, &shares[1]).await.unwrap().sealed);
+  }
+
+  #[tokio::test]
+  async fn test_shares_are_not_accepted_without_kek_check() {
+    let kv = KvDb::in_memory();
+    let shares = split_master_key(MASTER_KEY, 2, 2).unwrap();
+    let unsealer = unsealer(2, &shares);
+
+    unsealer.submit(&kv, &shares[0]).await.unwrap();
+    assert!(unsealer.submit(&kv, &shares[1]).await.is_err());
+    assert!(kv.is_sealed());
+  }
+
+  #[test]
+  fn test_share_digests_are_required() {
+    let shares = split_master_key(MASTER_KEY, 3, 2).unwrap();
+    assert!(
+      Unsealer::new(&UnsealMode::Shamir {
+        threshold: 2,
+        share_digests: vec![share_digest(&shares[0])],
+      })
+      .is_err()
+    );
+  }
+}
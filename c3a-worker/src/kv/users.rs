@@ -0,0 +1,241 @@
+//! Users' records, namespaced by application.
+//!
+//! The same identifier may be registered in several applications independently, each registration having
+//! its own authentication flows; there is no cross-app identity unless the user links the accounts explicitly.
+//!
+//! The index keeps identifiers of the application's users, so they can be listed without reading the users'
+//! records. Both are moved on the application's rename and removed with it.
+//!
+//! Records written before the namespacing (`user::<identifier-hash>`) aren't bound to any application and
+//! are ignored.
+
+use c3a_common::{AppAuthConfiguration, UserData};
+use cc_server_kit::prelude::*;
+
+use crate::kv::storage::Expect;
+use crate::kv::transactions::MAX_UPDATE_ATTEMPTS;
+use crate::kv::versioning::{decode, encode, upgrade};
+use crate::kv::{KvDb, PreConverted};
+
+impl KvDb {
+  pub(crate) async fn user_exists(&self, app_name: &str, identifier: &str) -> MResult<bool> {
+    self.exists(&KvDb::user(app_name, identifier)).await
+  }
+
+  /// Saves a new user of the application with its index entry in one write; fails if the identifier is
+  /// already taken in it or the application doesn't exist anymore (e.g. it's renamed meanwhile).
+  pub(crate) async fn insert_user(&self, app_name: &str, user_data: &UserData) -> MResult<()> {
+    let key = KvDb::user(app_name, &user_data.identifier);
+    let inserted = self
+      .batch_if(
+        vec![Expect::Exists(KvDb::app(app_name)), Expect::Record(key.clone(), None)],
+        vec![
+          (key.clone(), PreConverted::versioned(user_data)?),
+          (
+            KvDb::user_index(app_name, &user_data.identifier),
+            PreConverted::new(&user_data.identifier)?,
+          ),
+        ],
+        vec![],
+      )
+      .await?;
+    if inserted {
+      Ok(())
+    } else if self.exists(&key).await? {
+      Err(ErrorResponse::from("User already exists.").with_403_pub().build())
+    } else {
+      Err(ErrorResponse::from("There is no such app.").with_404_pub().build())
+    }
+  }
+
+  /// Changes the application's configuration with `f` atomically, as `KvDb::update_versioned` does. If `f`
+  /// renames the application, its users are moved in the same write; the name taken by another application or
+  /// its users is refused.
+  pub(crate) async fn update_app<F>(&self, app_name: &str, mut f: F) -> MResult<AppAuthConfiguration>
+  where
+    F: FnMut(&mut AppAuthConfiguration) -> MResult<()>,
+  {
+    let key = KvDb::app(app_name);
+    for _ in 0..MAX_UPDATE_ATTEMPTS {
+      let stored = self
+        .get_raw(&key)
+        .await?
+        .ok_or(ErrorResponse::from("There is no such app.").with_404_pub().build())?;
+      let mut app_conf = match upgrade::<AppAuthConfiguration>(&stored)? {
+        None => decode::<AppAuthConfiguration>(&stored)?,
+        Some(upgraded) => decode::<AppAuthConfiguration>(&upgraded)?,
+      };
+      f(&mut app_conf)?;
+
+      let new_app_name = app_conf.app_name.as_str();
+      if new_app_name.eq(app_name) {
+        if self
+          .compare_and_swap_raw(&key, Some(stored), Some(encode(&app_conf)?))
+          .await?
+        {
+          return Ok(app_conf);
+        }
+        continue;
+      }
+
+      let mut expect = vec![
+        Expect::Record(key.clone(), Some(stored)),
+        Expect::Record(KvDb::app(new_app_name), None),
+        Expect::Prefix(KvDb::users_of(new_app_name), vec![]),
+        Expect::Prefix(KvDb::user_index_of(new_app_name), vec![]),
+      ];
+      let mut upsert = vec![(KvDb::app(new_app_name), PreConverted::versioned(&app_conf)?)];
+      let mut remove = vec![key.clone()];
+      for (from, to) in [
+        (KvDb::users_of(app_name), KvDb::users_of(new_app_name)),
+        (KvDb::user_index_of(app_name), KvDb::user_index_of(new_app_name)),
+      ] {
+        let records = self.scan_prefix_raw(&from).await?;
+        for (key, record) in &records {
+          // Moved as is, so records are upgraded to the current version only on read.
+          upsert.push((
+            format!("{}{}", to, &key[from.len()..]),
+            PreConverted::from_raw(record.clone()),
+          ));
+          remove.push(key.clone());
+        }
+        // Users registered meanwhile would be left behind.
+        expect.push(Expect::Prefix(from, records));
+      }
+      if self.batch_if(expect, upsert, remove).await? {
+        return Ok(app_conf);
+      }
+
+      if self.exists(&KvDb::app(new_app_name)).await?
+        || !self.scan_prefix_raw(&KvDb::users_of(new_app_name)).await?.is_empty()
+        || !self
+          .scan_prefix_raw(&KvDb::user_index_of(new_app_name))
+          .await?
+          .is_empty()
+      {
+        return Err(
+          ErrorResponse::from("The app name is already taken.")
+            .with_400_pub()
+            .build(),
+        );
+      }
+      tracing::trace!("kv: app `{}` is changed concurrently, retrying", app_name);
+    }
+
+    Err(
+      ErrorResponse::from(format!("Too many concurrent updates of `{app_name}` app!"))
+        .with_500()
+        .build(),
+    )
+  }
+
+  /// Removes the application's configuration and users in one write.
+  pub(crate) async fn remove_app(&self, app_name: &str) -> MResult<()> {
+    let mut remove = vec![KvDb::app(app_name)];
+    for prefix in [KvDb::users_of(app_name), KvDb::user_index_of(app_name)] {
+      remove.extend(self.scan_prefix_raw(&prefix).await?.into_iter().map(|(key, _)| key));
+    }
+    self.batch_ops(vec![], vec![], remove).await?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::kv::KvDb;
+  use c3a_common::{AppAuthConfiguration, UserData};
+  use cc_server_kit::prelude::*;
+
+  fn user(identifier: &str) -> UserData {
+    UserData {
+      identifier: identifier.to_owned(),
+      authentication_flows: vec![],
+    }
+  }
+
+  fn app(app_name: &str) -> AppAuthConfiguration {
+    AppAuthConfiguration {
+      app_name: app_name.to_owned(),
+      domain: String::from("example.com"),
+      allowed_tags: vec![],
+      allow_sign_up: None,
+      client_based_auth_opts: None,
+      author_dpub: vec![],
+      email_templates: None,
+      hash_params: None,
+    }
+  }
+
+  fn rename(app_name: &str) -> impl FnMut(&mut AppAuthConfiguration) -> MResult<()> {
+    move |app_conf| {
+      app_conf.app_name = app_name.to_owned();
+      Ok(())
+    }
+  }
+
+  async fn list_users(kv: &KvDb, app_name: &str) -> Vec<String> {
+    let mut users = kv
+      .scan_prefix::<String>(&KvDb::user_index_of(app_name))
+      .await
+      .unwrap()
+      .into_iter()
+      .map(|(_, identifier)| identifier)
+      .collect::<Vec<_>>();
+    users.sort();
+    users
+  }
+
+  #[tokio::test]
+  async fn test_users_are_namespaced_by_app() {
+    let kv = KvDb::in_memory_unsealed().await.unwrap();
+    for app_name in ["app-a", "app-b"] {
+      kv.insert_versioned(&KvDb::app(app_name), &app(app_name)).await.unwrap();
+    }
+    kv.insert_user("app-a", &user("alice@example.com")).await.unwrap();
+    // The same identifier is free in another app.
+    assert!(!kv.user_exists("app-b", "alice@example.com").await.unwrap());
+    kv.insert_user("app-b", &user("alice@example.com")).await.unwrap();
+    kv.insert_user("app-b", &user("bob@example.com")).await.unwrap();
+    assert!(kv.insert_user("app-a", &user("alice@example.com")).await.is_err());
+
+    assert_eq!(list_users(&kv, "app-a").await, vec![String::from("alice@example.com")]);
+    assert_eq!(
+      list_users(&kv, "app-b").await,
+      vec![String::from("alice@example.com"), String::from("bob@example.com")]
+    );
+
+    kv.update_app("app-b", rename("app-c")).await.unwrap();
+    // Users can't be registered in the app under its old name.
+    assert!(kv.insert_user("app-b", &user("carol@example.com")).await.is_err());
+    assert!(!kv.exists(&KvDb::app("app-b")).await.unwrap());
+    assert_eq!(kv.get_app_conf("app-c").await.unwrap().app_name, "app-c");
+    assert!(list_users(&kv, "app-b").await.is_empty());
+    assert!(kv.user_exists("app-c", "bob@example.com").await.unwrap());
+    assert!(!kv.user_exists("app-b", "bob@example.com").await.unwrap());
+
+    kv.remove_app("app-c").await.unwrap();
+    assert!(list_users(&kv, "app-c").await.is_empty());
+    assert!(!kv.user_exists("app-c", "alice@example.com").await.unwrap());
+    assert!(kv.user_exists("app-a", "alice@example.com").await.unwrap());
+  }
+
+  #[tokio::test]
+  async fn test_app_cannot_be_renamed_onto_another() {
+    let kv = KvDb::in_memory_unsealed().await.unwrap();
+    kv.insert_versioned(&KvDb::app("victim"), &app("victim")).await.unwrap();
+    kv.insert_user("victim", &user("alice@example.com")).await.unwrap();
+    kv.insert_versioned(&KvDb::app("attacker"), &app("attacker"))
+      .await
+      .unwrap();
+    kv.insert_user("attacker", &user("alice@example.com")).await.unwrap();
+
+    assert!(kv.update_app("attacker", rename("victim")).await.is_err());
+    assert_eq!(kv.get_app_conf("attacker").await.unwrap().app_name, "attacker");
+    assert!(kv.user_exists("attacker", "alice@example.com").await.unwrap());
+
+    // Users left without the app's configuration occupy the name too.
+    kv.remove(&KvDb::app("victim")).await.unwrap();
+    assert!(kv.update_app("attacker", rename("victim")).await.is_err());
+    assert_eq!(list_users(&kv, "victim").await, vec![String::from("alice@example.com")]);
+  }
+}
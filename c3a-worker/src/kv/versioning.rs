@@ -0,0 +1,547 @@
+//! Schema versions of stored records.
+//!
+//! MessagePack of `rmp_serde` is positional, so a record written before a field was added (even in a nested
+//! struct like `SignUpOpts`) can't be read by the new code. Records of `Versioned` types are stored in an
+//! envelope:
+//!
+//! ```text
+//! 0xC1 || version (u16, big-endian) || MessagePack of the record
+//! ```
+//!
+//! `0xC1` is never used by MessagePack, so records written before the envelope are recognized as version 0.
+//!
+//! When a stored format changes, bump `Versioned::VERSION` and register a migration from the previous version
+//! in `MIGRATIONS`. Old records are upgraded step by step on read and written back; all of them can be
+//! upgraded at once with `/admin/records/migrate`. The worker refuses to read records of versions newer than
+//! it knows, so it can't be rolled back past a migration.
+
+use c3a_common::{AppAuthConfiguration, UserData};
+use cc_server_kit::prelude::*;
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::core::invitations::Invitations;
+use crate::kv::{KvDb, PreConverted};
+
+const ENVELOPE_MAGIC: u8 = 0xC1;
+const ENVELOPE_HEADER_LENGTH: usize = 3;
+
+pub(crate) trait Versioned: Serialize + DeserializeOwned {
+  /// Kind of records in `MIGRATIONS`.
+  const KIND: &'static str;
+  /// Version of the format written by this code.
+  const VERSION: u16;
+  /// Prefix of keys of all records of this kind.
+  const PREFIX: &'static str;
+}
+
+impl Versioned for AppAuthConfiguration {
+  const KIND: &'static str = "app";
+  const VERSION: u16 = 1;
+  const PREFIX: &'static str = KvDb::APPLICATION_PREFIX;
+}
+
+impl Versioned for UserData {
+  const KIND: &'static str = "user";
+  const VERSION: u16 = 1;
+  const PREFIX: &'static str = KvDb::USER_PREFIX;
+}
+
+impl Versioned for Invitations {
+  const KIND: &'static str = "invitations";
+  const VERSION: u16 = 1;
+  const PREFIX: &'static str = KvDb::INVITES;
+}
+
+/// Converts MessagePack of a record from `version` to `version + 1`.
+pub(crate) type Migration = fn(Vec<u8>) -> MResult<Vec<u8>>;
+
+/// Migrations by kind and the version they upgrade from.
+pub(crate) const MIGRATIONS: &[(&str, u16, Migration)] = &[
+  (AppAuthConfiguration::KIND, 0, unversioned),
+  (UserData::KIND, 0, unversioned),
+  (Invitations::KIND, 0, legacy_invitations),
+];
+
+/// Records written before the envelope have the same format as the first versioned ones: the fields added to
+/// them since (password policy, hash params, pepper versions) are trailing and have defaults.
+fn unversioned(record: Vec<u8>) -> MResult<Vec<u8>> {
+  Ok(record)
+}
+
+/// Invites were bare 1 KiB blobs without an expiry or a creator; they can't be typed as codes, so unused ones
+/// are dropped and should be generated again.
+fn legacy_invitations(record: Vec<u8>) -> MResult<Vec<u8>> {
+  #[derive(serde::Deserialize)]
+  struct LegacyInvitations {
+    invitations: Vec<Vec<u8>>,
+  }
+
+  let legacy = rmp_serde::from_slice::<LegacyInvitations>(&record).map_err(versioning_error)?;
+  if !legacy.invitations.is_empty() {
+    tracing::warn!("{} legacy invitations are dropped; generate new ones.", legacy.invitations.len());
+  }
+  rmp_serde::to_vec(&Invitations::default()).map_err(versioning_error)
+}
+
+fn versioning_error(e: impl std::fmt::Display) -> ErrorResponse {
+  ErrorResponse::from(e.to_string()).with_500().build()
+}
+
+/// Wraps MessagePack of the record into the envelope.
+pub(crate) fn seal_envelope(version: u16, record: &[u8]) -> Vec<u8> {
+  let mut envelope = Vec::with_capacity(ENVELOPE_HEADER_LENGTH + record.len());
+  envelope.push(ENVELOPE_MAGIC);
+  envelope.extend_from_slice(&version.to_be_bytes());
+  envelope.extend_from_slice(record);
+  envelope
+}
+
+/// Returns the version and MessagePack of the stored record.
+pub(crate) fn open_envelope(stored: &[u8]) -> MResult<(u16, &[u8])> {
+  match stored.first() {
+    Some(&ENVELOPE_MAGIC) if stored.len() >= ENVELOPE_HEADER_LENGTH => {
+      Ok((u16::from_be_bytes([stored[1], stored[2]]), &stored[ENVELOPE_HEADER_LENGTH..]))
+    }
+    Some(&ENVELOPE_MAGIC) => Err(versioning_error("Record envelope is truncated!")),
+    _ => Ok((0, stored)),
+  }
+}
+
+pub(crate) fn encode<T: Versioned>(value: &T) -> MResult<Vec<u8>> {
+  let record = rmp_serde::to_vec(value).map_err(versioning_error)?;
+  Ok(seal_envelope(T::VERSION, &record))
+}
+
+/// Upgrades the stored record to `T::VERSION`; returns `None` if it's up to date.
+pub(crate) fn upgrade<T: Versioned>(stored: &[u8]) -> MResult<Option<Vec<u8>>> {
+  upgrade_with(MIGRATIONS, T::KIND, T::VERSION, stored)
+}
+
+fn upgrade_with(
+  migrations: &[(&str, u16, Migration)],
+  kind: &str,
+  to: u16,
+  stored: &[u8],
+) -> MResult<Option<Vec<u8>>> {
+  let (mut version, record) = open_envelope(stored)?;
+  if version > to {
+    return Err(versioning_error(format!(
+      "`{kind}` record has version {version}, but only versions up to {to} are known!"
+    )));
+  }
+  if version == to {
+    return Ok(None);
+  }
+
+  let mut record = record.to_vec();
+  while version < to {
+    let migration = migrations
+      .iter()
+      .find_map(|(k, from, migration)| (k.eq(&kind) && *from == version).then_some(migration))
+      .ok_or_else(|| {
+        versioning_error(format!("There is no migration of `{kind}` records from version {version}!"))
+      })?;
+    record = migration(record)?;
+    version += 1;
+  }
+  Ok(Some(seal_envelope(to, &record)))
+}
+
+pub(crate) fn decode<T: Versioned>(stored: &[u8]) -> MResult<T> {
+  let (version, record) = open_envelope(stored)?;
+  if version != T::VERSION {
+    return Err(versioning_error(format!(
+      "`{}` record has version {version} instead of {}!",
+      T::KIND,
+      T::VERSION
+    )));
+  }
+  rmp_serde::from_slice::<T>(record).map_err(versioning_error)
+}
+
+impl PreConverted {
+  pub(crate) fn versioned<T: Versioned>(value: &T) -> MResult<Self> {
+    Ok(Self::from_raw(encode(value)?))
+  }
+}
+
+impl KvDb {
+  /// Reads the record, upgrading it to the current version if needed.
+  pub(crate) async fn get_versioned<T: Versioned>(&self, key: &str) -> MResult<Option<T>> {
+    let stored = if let Some(stored) = self.get_raw(key).await? { stored } else { return Ok(None) };
+    match upgrade::<T>(&stored)? {
+      None => Ok(Some(decode(&stored)?)),
+      Some(upgraded) => {
+        let value = decode(&upgraded)?;
+        // If the record was changed meanwhile, it's already written by the new code.
+        if self.compare_and_swap_raw(key, Some(stored), Some(upgraded)).await? {
+          tracing::debug!("kv: upgraded `{}` record by path `{}`", T::KIND, key);
+        }
+        Ok(Some(value))
+      }
+    }
+  }
+
+  pub(crate) async fn insert_versioned<T: Versioned>(&self, key: &str, value: &T) -> MResult<()> {
+    self.insert_raw(key, encode(value)?).await
+  }
+
+  /// Same as `KvDb::update`, but the record is upgraded to the current version before `f` gets it.
+  pub(crate) async fn update_versioned<T, R, F>(&self, key: &str, mut f: F) -> MResult<R>
+  where
+    T: Versioned,
+    F: FnMut(&mut Option<T>) -> MResult<R>,
+  {
+    self
+      .update_raw(key, |stored| {
+        let mut value = match stored {
+          Some(stored) => Some(match upgrade::<T>(stored)? {
+            None => decode::<T>(stored)?,
+            Some(upgraded) => decode::<T>(&upgraded)?,
+          }),
+          None => None,
+        };
+        let result = f(&mut value)?;
+        Ok((value.as_ref().map(encode).transpose()?, result))
+      })
+      .await
+  }
+
+  /// Reads all records of the kind, upgrading them in memory only; see `KvDb::migrate_records`.
+  pub(crate) async fn scan_versioned<T: Versioned>(&self) -> MResult<Vec<T>> {
+    self
+      .scan_prefix_raw(T::PREFIX)
+      .await?
+      .iter()
+      .map(|(_, stored)| match upgrade::<T>(stored)? {
+        None => decode::<T>(stored),
+        Some(upgraded) => decode::<T>(&upgraded),
+      })
+      .collect()
+  }
+
+  /// Upgrades all records of the kind; returns the number of upgraded records.
+  pub(crate) async fn migrate_records<T: Versioned>(&self) -> MResult<usize> {
+    let mut upgraded_count = 0;
+    for (key, stored) in self.scan_prefix_raw(T::PREFIX).await? {
+      let upgraded = if let Some(upgraded) = upgrade::<T>(&stored)? { upgraded } else { continue };
+      if self.compare_and_swap_raw(&key, Some(stored), Some(upgraded)).await? {
+        upgraded_count += 1;
+      }
+    }
+
+    tracing::info!("kv: {} `{}` records are upgraded to version {}", upgraded_count, T::KIND, T::VERSION);
+    Ok(upgraded_count)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use c3a_common::{
+    AppAuthConfiguration, AppTag, AuthenticationRequirement, AuthenticationStep, ClientBasedAuthorizationOpts,
+    Fail2BanOptions, HashParams, IdenticationRequirement, TokenEncryptionType, UserData,
+  };
+  use cc_server_kit::prelude::*;
+  use serde::{Deserialize, Serialize};
+
+  use super::{Migration, Versioned, decode, encode, open_envelope, seal_envelope, upgrade_with};
+  use crate::core::invitations::Invitations;
+  use crate::kv::KvDb;
+
+  /// `AppAuthConfiguration` before `email_templates` and `hash_params`, without the envelope:
+  /// `["a", "a.example.com", [], nil, nil, [1, 2]]`.
+  const APP_V0: &[u8] = &[
+    0x96, 0xa1, 0x61, 0xad, 0x61, 0x2e, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e, 0x63, 0x6f, 0x6d, 0x90, 0xc0,
+    0xc0, 0x92, 0x01, 0x02,
+  ];
+  /// `UserData` without the envelope: `["alice", []]`.
+  const USER_V0: &[u8] = &[0x92, 0xa5, 0x61, 0x6c, 0x69, 0x63, 0x65, 0x90];
+  /// Bare invites without the envelope: `[[[1, 2, 3]]]`.
+  const INVITES_V0: &[u8] = &[0x91, 0x91, 0x93, 0x01, 0x02, 0x03];
+
+  #[test]
+  fn test_envelope() {
+    assert_eq!(open_envelope(APP_V0).unwrap(), (0, APP_V0));
+    let envelope = seal_envelope(258, b"record");
+    assert_eq!(&envelope[..3], &[0xc1, 0x01, 0x02]);
+    assert_eq!(open_envelope(&envelope).unwrap(), (258, b"record".as_slice()));
+    assert!(open_envelope(&[0xc1, 0x01]).is_err());
+  }
+
+  #[tokio::test]
+  async fn test_old_records_are_upgraded_on_read() {
+    let kv = KvDb::in_memory();
+    kv.insert_raw(&KvDb::app("a"), APP_V0.to_vec()).await.unwrap();
+    kv.insert_raw(&KvDb::user("a", "alice"), USER_V0.to_vec()).await.unwrap();
+
+    let app_conf = kv.get_versioned::<AppAuthConfiguration>(&KvDb::app("a")).await.unwrap().unwrap();
+    assert_eq!(app_conf.app_name, "a");
+    assert_eq!(app_conf.domain, "a.example.com");
+    assert_eq!(app_conf.author_dpub, vec![1, 2]);
+    assert!(app_conf.email_templates.is_none() && app_conf.hash_params.is_none());
+    // Written back in the envelope.
+    let stored = kv.get_raw(&KvDb::app("a")).await.unwrap().unwrap();
+    assert_eq!(open_envelope(&stored).unwrap().0, AppAuthConfiguration::VERSION);
+    assert_eq!(decode::<AppAuthConfiguration>(&stored).unwrap(), app_conf);
+
+    assert_eq!(kv.migrate_records::<AppAuthConfiguration>().await.unwrap(), 0);
+    assert_eq!(kv.migrate_records::<UserData>().await.unwrap(), 1);
+    let user_data = kv.get_versioned::<UserData>(&KvDb::user("a", "alice")).await.unwrap().unwrap();
+    assert_eq!(user_data.identifier, "alice");
+  }
+
+  #[tokio::test]
+  async fn test_newer_records_are_refused() {
+    let kv = KvDb::in_memory();
+    let newer = seal_envelope(AppAuthConfiguration::VERSION + 1, &APP_V0[..]);
+    kv.insert_raw(&KvDb::app("a"), newer).await.unwrap();
+    assert!(kv.get_versioned::<AppAuthConfiguration>(&KvDb::app("a")).await.is_err());
+    assert!(kv.migrate_records::<AppAuthConfiguration>().await.is_err());
+  }
+
+  #[tokio::test]
+  async fn test_legacy_invitations_are_dropped() {
+    let kv = KvDb::in_memory();
+    kv.insert_raw(KvDb::INVITES, INVITES_V0.to_vec()).await.unwrap();
+    assert_eq!(kv.migrate_records::<Invitations>().await.unwrap(), 1);
+    assert!(crate::core::invitations::list(&kv).await.unwrap().is_empty());
+  }
+
+  /// `AuthenticationRequirement` before the password policy fields.
+  #[derive(Serialize)]
+  #[serde(rename_all = "snake_case", tag = "type")]
+  enum AuthenticationRequirementV0 {
+    Password {
+      min_size: usize,
+      should_contain_different_case: bool,
+      should_contain_symbols: bool,
+    },
+    EmailConfirmation,
+  }
+
+  #[derive(Serialize)]
+  struct SignUpOptsV0 {
+    identify_by: IdenticationRequirement,
+    allow_sign_up: bool,
+    auto_assign_tags: Vec<AppTag>,
+    allowed_authentication_flow: Vec<AuthenticationRequirementV0>,
+    required_authentication: Vec<AuthenticationRequirementV0>,
+    allow_honeypots: bool,
+    enable_fail_to_ban: Option<Fail2BanOptions>,
+    allow_recovery_key: bool,
+    token_encryption_type: TokenEncryptionType,
+  }
+
+  /// `AppAuthConfiguration` before `email_templates` and `hash_params`.
+  #[derive(Serialize)]
+  struct AppV0 {
+    app_name: String,
+    domain: String,
+    allowed_tags: Vec<AppTag>,
+    allow_sign_up: Option<SignUpOptsV0>,
+    client_based_auth_opts: Option<ClientBasedAuthorizationOpts>,
+    author_dpub: Vec<u8>,
+  }
+
+  /// `AuthenticationStep` before hash params and pepper versions.
+  #[derive(Serialize)]
+  #[serde(rename_all = "snake_case", tag = "type")]
+  enum AuthenticationStepV0 {
+    Password {
+      salt: Vec<u8>,
+      hash: Vec<u8>,
+    },
+    Question {
+      question: String,
+      salt: Vec<u8>,
+      hash: Vec<u8>,
+    },
+  }
+
+  #[derive(Serialize)]
+  struct UserDataV0 {
+    identifier: String,
+    authentication_flows: Vec<Vec<AuthenticationStepV0>>,
+  }
+
+  #[tokio::test]
+  async fn test_unversioned_records_with_nested_fields_are_read() {
+    let tag = AppTag {
+      role: String::from("user"),
+      scope: String::from("read"),
+    };
+    let fail_to_ban = Fail2BanOptions {
+      max_allowed_unsuccessful_attempts: 5,
+      ban_login_expiration: c3a_common::chrono::TimeDelta::hours(6),
+      ban_ip: false,
+      ban_ip_expiration: None,
+    };
+    let app_v0 = AppV0 {
+      app_name: String::from("a"),
+      domain: String::from("a.example.com"),
+      allowed_tags: vec![tag.clone()],
+      allow_sign_up: Some(SignUpOptsV0 {
+        identify_by: IdenticationRequirement::Email {
+          exclude_email_domains: vec![String::from("temp.example.com")],
+        },
+        allow_sign_up: true,
+        auto_assign_tags: vec![tag.clone()],
+        allowed_authentication_flow: vec![AuthenticationRequirementV0::EmailConfirmation],
+        required_authentication: vec![AuthenticationRequirementV0::Password {
+          min_size: 8,
+          should_contain_different_case: true,
+          should_contain_symbols: false,
+        }],
+        allow_honeypots: false,
+        enable_fail_to_ban: Some(fail_to_ban.clone()),
+        allow_recovery_key: true,
+        token_encryption_type: TokenEncryptionType::ChaCha20Poly1305,
+      }),
+      client_based_auth_opts: None,
+      author_dpub: vec![1, 2],
+    };
+    let user_v0 = UserDataV0 {
+      identifier: String::from("alice@example.com"),
+      authentication_flows: vec![vec![
+        AuthenticationStepV0::Password {
+          salt: vec![1],
+          hash: vec![2],
+        },
+        AuthenticationStepV0::Question {
+          question: String::from("q"),
+          salt: vec![3],
+          hash: vec![4],
+        },
+      ]],
+    };
+
+    let kv = KvDb::in_memory();
+    kv.insert_raw(&KvDb::app("a"), rmp_serde::to_vec(&app_v0).unwrap()).await.unwrap();
+    kv.insert_raw(
+      &KvDb::user("a", "alice@example.com"),
+      rmp_serde::to_vec(&user_v0).unwrap(),
+    )
+    .await
+    .unwrap();
+
+    // Fields added since are filled with their defaults.
+    let app_conf = kv.get_versioned::<AppAuthConfiguration>(&KvDb::app("a")).await.unwrap().unwrap();
+    let sign_up = app_conf.allow_sign_up.as_ref().unwrap();
+    assert_eq!(
+      sign_up.required_authentication,
+      vec![AuthenticationRequirement::Password {
+        min_size: 8,
+        should_contain_different_case: true,
+        should_contain_symbols: false,
+        max_size: None,
+        min_strength_score: None,
+        reject_breached: false,
+      }]
+    );
+    assert_eq!(sign_up.allowed_authentication_flow, vec![AuthenticationRequirement::EmailConfirmation]);
+    assert_eq!(sign_up.enable_fail_to_ban, Some(fail_to_ban));
+    assert_eq!(sign_up.token_encryption_type, TokenEncryptionType::ChaCha20Poly1305);
+    assert_eq!(app_conf.author_dpub, vec![1, 2]);
+    assert!(app_conf.email_templates.is_none() && app_conf.hash_params.is_none());
+
+    let user_data = kv
+      .get_versioned::<UserData>(&KvDb::user("a", "alice@example.com"))
+      .await
+      .unwrap()
+      .unwrap();
+    assert!(matches!(
+      user_data.authentication_flows[0].as_slice(),
+      [
+        AuthenticationStep::Password { salt, hash, params, pepper_version: 0 },
+        AuthenticationStep::Question { question, params: question_params, pepper_version: 0, .. },
+      ] if salt == &[1] && hash == &[2] && question == "q"
+        && *params == HashParams::default() && *question_params == HashParams::default()
+    ));
+
+    // The records are written back as version 1 without changes.
+    let stored = kv.get_raw(&KvDb::app("a")).await.unwrap().unwrap();
+    assert_eq!(open_envelope(&stored).unwrap().0, 1);
+    assert_eq!(decode::<AppAuthConfiguration>(&stored).unwrap(), app_conf);
+    let stored = kv.get_raw(&KvDb::user("a", "alice@example.com")).await.unwrap().unwrap();
+    assert!(decode::<UserData>(&stored).unwrap() == user_data);
+  }
+
+  #[derive(Serialize)]
+  struct RecordV1 {
+    name: String,
+    tags: Vec<String>,
+  }
+
+  #[derive(Serialize, Deserialize, PartialEq, Debug)]
+  struct RecordV3 {
+    name: String,
+    enabled: bool,
+    tags: Vec<String>,
+    limit: u32,
+  }
+
+  impl Versioned for RecordV3 {
+    const KIND: &'static str = "test";
+    const VERSION: u16 = 3;
+    const PREFIX: &'static str = "versioning-test::";
+  }
+
+  fn as_fields(record: Vec<u8>) -> MResult<Vec<serde_json::Value>> {
+    rmp_serde::from_slice(&record).map_err(super::versioning_error)
+  }
+
+  fn from_fields(fields: Vec<serde_json::Value>) -> MResult<Vec<u8>> {
+    rmp_serde::to_vec(&fields).map_err(super::versioning_error)
+  }
+
+  /// `enabled` is inserted between `name` and `tags`.
+  fn test_v1_to_v2(record: Vec<u8>) -> MResult<Vec<u8>> {
+    let mut fields = as_fields(record)?;
+    fields.insert(1, serde_json::Value::Bool(true));
+    from_fields(fields)
+  }
+
+  /// `limit` is appended.
+  fn test_v2_to_v3(record: Vec<u8>) -> MResult<Vec<u8>> {
+    let mut fields = as_fields(record)?;
+    fields.push(serde_json::Value::from(10));
+    from_fields(fields)
+  }
+
+  const TEST_MIGRATIONS: &[(&str, u16, Migration)] = &[
+    (RecordV3::KIND, 2, test_v2_to_v3),
+    (AppAuthConfiguration::KIND, 1, test_v1_to_v2),
+    (RecordV3::KIND, 1, test_v1_to_v2),
+  ];
+
+  #[test]
+  fn test_migrations_are_chained() {
+    let v1 = seal_envelope(
+      1,
+      &rmp_serde::to_vec(&RecordV1 {
+        name: String::from("record"),
+        tags: vec![String::from("tag")],
+      })
+      .unwrap(),
+    );
+    let v3 = upgrade_with(TEST_MIGRATIONS, RecordV3::KIND, RecordV3::VERSION, &v1)
+      .unwrap()
+      .unwrap();
+    assert_eq!(
+      decode::<RecordV3>(&v3).unwrap(),
+      RecordV3 {
+        name: String::from("record"),
+        enabled: true,
+        tags: vec![String::from("tag")],
+        limit: 10,
+      }
+    );
+    assert!(upgrade_with(TEST_MIGRATIONS, RecordV3::KIND, RecordV3::VERSION, &v3).unwrap().is_none());
+    // There is no migration from version 0.
+    assert!(upgrade_with(TEST_MIGRATIONS, RecordV3::KIND, RecordV3::VERSION, &v1[3..]).is_err());
+    // Records written by the current code can't be decoded as another version.
+    let current = encode(&decode::<RecordV3>(&v3).unwrap()).unwrap();
+    assert_eq!(current, v3);
+    assert!(decode::<RecordV3>(&v1).is_err());
+  }
+}
@@ -1,26 +1,214 @@
 use cc_server_kit::prelude::*;
-use lettre::{AsyncSmtpTransport, Tokio1Executor, transport::smtp::authentication::Credentials};
-
-pub(crate) fn extract_mailer(depot: &mut Depot) -> MResult<AsyncSmtpTransport<Tokio1Executor>> {
-  Ok(
-    depot
-      .obtain::<AsyncSmtpTransport<Tokio1Executor>>()
-      .map_err(|_| {
-        ErrorResponse::from("Can't get `AsyncSmtpTransport<Tokio1Executor>` instance")
-          .with_500()
-          .build()
-      })?
-      .clone(),
-  )
-}
-
-pub(crate) fn init_mailer() -> MResult<AsyncSmtpTransport<Tokio1Executor>> {
-  let creds = Credentials::new(std::env::var("SMTP_USERNAME")?, std::env::var("SMTP_PASSWORD")?);
-  let mailer: AsyncSmtpTransport<Tokio1Executor> =
-    AsyncSmtpTransport::<Tokio1Executor>::relay(std::env::var("SMTP_ADDR")?.as_str())
+use lettre::{
+  AsyncFileTransport, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor, address::Envelope,
+  transport::smtp::authentication::Credentials,
+};
+use serde::Deserialize;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+pub(crate) mod dkim;
+pub(crate) mod queue;
+pub(crate) mod templates;
+
+/// Mail transport selection in `c3a-worker.yaml`.
+///
+/// SMTP credentials are never read from the config file; set `SMTP_USERNAME`, `SMTP_PASSWORD`
+/// and `SMTP_ADDR` env variables instead.
+#[derive(Deserialize, Clone, Default, Debug)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub(crate) enum MailerConfig {
+  #[default]
+  Smtp,
+  /// Writes every message as `.eml` file into the given directory. Useful for local development.
+  FileDrop { dir: PathBuf },
+  /// Keeps the last `MEMORY_MAIL_CAPACITY` messages in memory and only logs them.
+  Memory,
+}
+
+/// Messages kept by `MemoryMailSender`; older ones are dropped.
+const MEMORY_MAIL_CAPACITY: usize = 1024;
+
+pub(crate) type SendFuture<'a> = Pin<Box<dyn Future<Output = MResult<()>> + Send + 'a>>;
+
+/// Outgoing mail transport.
+pub(crate) trait MailSender: Send + Sync {
+  /// Sends already formatted message.
+  fn send_raw(&self, envelope: Envelope, raw: Vec<u8>) -> SendFuture<'_>;
+}
+
+/// Outgoing mail transport of the mail queue's worker, see `queue::run_mail_worker`.
+#[derive(Clone)]
+pub(crate) struct Mailer(Arc<dyn MailSender>);
+
+impl Mailer {
+  pub(crate) fn new(sender: impl MailSender + 'static) -> Self {
+    Self(Arc::new(sender))
+  }
+
+  #[allow(dead_code)]
+  pub(crate) async fn send(&self, message: Message) -> MResult<()> {
+    self.0.send_raw(message.envelope().clone(), message.formatted()).await
+  }
+
+  pub(crate) async fn send_raw(&self, envelope: Envelope, raw: Vec<u8>) -> MResult<()> {
+    self.0.send_raw(envelope, raw).await
+  }
+}
+
+pub(crate) struct SmtpMailSender {
+  transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpMailSender {
+  pub(crate) fn from_env() -> MResult<Self> {
+    let creds = Credentials::new(std::env::var("SMTP_USERNAME")?, std::env::var("SMTP_PASSWORD")?);
+    let transport = AsyncSmtpTransport::<Tokio1Executor>::relay(std::env::var("SMTP_ADDR")?.as_str())
       .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?
       .credentials(creds)
       .build();
 
-  Ok(mailer)
+    Ok(Self { transport })
+  }
+}
+
+impl MailSender for SmtpMailSender {
+  fn send_raw(&self, envelope: Envelope, raw: Vec<u8>) -> SendFuture<'_> {
+    Box::pin(async move {
+      self
+        .transport
+        .send_raw(&envelope, &raw)
+        .await
+        .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+      Ok(())
+    })
+  }
+}
+
+pub(crate) struct FileMailSender {
+  transport: AsyncFileTransport<Tokio1Executor>,
+}
+
+impl FileMailSender {
+  pub(crate) fn new(dir: PathBuf) -> MResult<Self> {
+    std::fs::create_dir_all(&dir).map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+    Ok(Self {
+      transport: AsyncFileTransport::<Tokio1Executor>::new(dir),
+    })
+  }
+}
+
+impl MailSender for FileMailSender {
+  fn send_raw(&self, envelope: Envelope, raw: Vec<u8>) -> SendFuture<'_> {
+    Box::pin(async move {
+      let id = self
+        .transport
+        .send_raw(&envelope, &raw)
+        .await
+        .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+      tracing::debug!("mailer: message is dropped into file with id `{}`", id);
+      Ok(())
+    })
+  }
+}
+
+/// Message captured by `MemoryMailSender`.
+#[derive(Clone)]
+pub(crate) struct CapturedMail {
+  pub(crate) envelope: Envelope,
+  pub(crate) raw: Vec<u8>,
+}
+
+/// Captures the last `MEMORY_MAIL_CAPACITY` sent messages.
+#[derive(Clone, Default)]
+pub(crate) struct MemoryMailSender {
+  messages: Arc<Mutex<VecDeque<CapturedMail>>>,
+}
+
+#[allow(dead_code)]
+impl MemoryMailSender {
+  pub(crate) fn messages(&self) -> Vec<CapturedMail> {
+    self.messages.lock().unwrap().iter().cloned().collect()
+  }
+
+  pub(crate) fn take_messages(&self) -> Vec<CapturedMail> {
+    self.messages.lock().unwrap().drain(..).collect()
+  }
+}
+
+impl MailSender for MemoryMailSender {
+  fn send_raw(&self, envelope: Envelope, raw: Vec<u8>) -> SendFuture<'_> {
+    Box::pin(async move {
+      tracing::info!("mailer: captured message to {:?}", envelope.to());
+      let mut messages = self.messages.lock().unwrap();
+      if messages.len() == MEMORY_MAIL_CAPACITY {
+        messages.pop_front();
+      }
+      messages.push_back(CapturedMail { envelope, raw });
+      Ok(())
+    })
+  }
+}
+
+pub(crate) fn init_mailer(config: &MailerConfig) -> MResult<Mailer> {
+  Ok(match config {
+    MailerConfig::Smtp => Mailer::new(SmtpMailSender::from_env()?),
+    MailerConfig::FileDrop { dir } => Mailer::new(FileMailSender::new(dir.to_owned())?),
+    MailerConfig::Memory => Mailer::new(MemoryMailSender::default()),
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{FileMailSender, Mailer, MemoryMailSender};
+  use lettre::Message;
+
+  fn test_message() -> Message {
+    Message::builder()
+      .from("C3A <no-reply@example.com>".parse().unwrap())
+      .to("user@example.com".parse().unwrap())
+      .subject("Test")
+      .body(String::from("Hello!"))
+      .unwrap()
+  }
+
+  #[tokio::test]
+  async fn test_memory_sender_captures_messages() {
+    let sender = MemoryMailSender::default();
+    let mailer = Mailer::new(sender.clone());
+
+    mailer.send(test_message()).await.unwrap();
+
+    let messages = sender.take_messages();
+    assert_eq!(messages.len(), 1);
+    assert!(String::from_utf8_lossy(&messages[0].raw).contains("Hello!"));
+    assert!(sender.messages().is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_memory_sender_is_bounded() {
+    let sender = MemoryMailSender::default();
+    let mailer = Mailer::new(sender.clone());
+
+    for _ in 0..super::MEMORY_MAIL_CAPACITY + 5 {
+      mailer.send(test_message()).await.unwrap();
+    }
+
+    assert_eq!(sender.messages().len(), super::MEMORY_MAIL_CAPACITY);
+  }
+
+  #[tokio::test]
+  async fn test_file_sender_drops_eml() {
+    let dir = std::env::temp_dir().join(format!("c3a-mails-{}", hex::encode(c3a_common::generate::<8>())));
+    let mailer = Mailer::new(FileMailSender::new(dir.clone()).unwrap());
+
+    mailer.send(test_message()).await.unwrap();
+
+    let files = std::fs::read_dir(&dir).unwrap().collect::<Vec<_>>();
+    assert_eq!(files.len(), 1);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
 }
@@ -0,0 +1,399 @@
+//! DKIM signing of outgoing mail.
+//!
+//! Keys are configured per sending domain in `c3a-worker.yaml`:
+//!
+//! ```yaml
+//! dkim:
+//!   - domain: mail.example.com
+//!     selector: c3a
+//!     algorithm: ed25519_sha256
+//!     private_key_file: /etc/c3a/dkim-ed25519.key
+//! ```
+//!
+//! RSA keys are read as PKCS#1 PEM, Ed25519 keys - as base64-encoded raw 32-byte seeds (RFC 8463).
+//! The message is signed by the key whose domain is the longest suffix of the `From` domain, so both
+//! `mail.example.com` and `example.com` keys may sign mails from `no-reply@mail.example.com`.
+//!
+//! Apps' own senders are never signed by the key which signs the instance's default sender, even if the app
+//! claims the same domain; configure a separate key for the app's domain instead.
+
+use cc_server_kit::prelude::*;
+use lettre::Message;
+use lettre::message::Mailbox;
+use lettre::message::dkim::{
+  DkimCanonicalization, DkimCanonicalizationType, DkimConfig, DkimSigningAlgorithm, DkimSigningKey,
+};
+use lettre::message::header::HeaderName;
+use serde::Deserialize;
+use std::path::PathBuf;
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DkimAlgorithm {
+  RsaSha256,
+  Ed25519Sha256,
+}
+
+impl DkimAlgorithm {
+  fn into_lettre(self) -> DkimSigningAlgorithm {
+    match self {
+      Self::RsaSha256 => DkimSigningAlgorithm::Rsa,
+      Self::Ed25519Sha256 => DkimSigningAlgorithm::Ed25519,
+    }
+  }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub(crate) struct DkimDomainOpts {
+  pub(crate) domain: String,
+  pub(crate) selector: String,
+  pub(crate) algorithm: DkimAlgorithm,
+  pub(crate) private_key_file: PathBuf,
+}
+
+/// Whether `domain` is `parent` or its subdomain; case-insensitive.
+pub(crate) fn is_same_or_subdomain(domain: &str, parent: &str) -> bool {
+  let (domain, parent) = (domain.to_lowercase(), parent.to_lowercase());
+  domain == parent
+    || domain
+      .strip_suffix(parent.as_str())
+      .is_some_and(|sub| sub.ends_with('.'))
+}
+
+/// Signs messages by the key of the sender's domain.
+#[derive(Default)]
+pub(crate) struct DkimSigner {
+  configs: Vec<(String, DkimConfig)>,
+  /// Domain of the instance's default sender.
+  instance_domain: Option<String>,
+}
+
+impl DkimSigner {
+  pub(crate) fn load(opts: &[DkimDomainOpts], default_sender: &str) -> MResult<Self> {
+    let mut signer = Self::default();
+    signer.set_instance_sender(default_sender)?;
+    for opt in opts {
+      let private_key = std::fs::read_to_string(&opt.private_key_file).map_err(|e| {
+        ErrorResponse::from(format!(
+          "Can't read DKIM key for `{}` from `{}`: {e}",
+          opt.domain,
+          opt.private_key_file.display()
+        ))
+        .with_500()
+        .build()
+      })?;
+      signer.add_domain(&opt.domain, &opt.selector, opt.algorithm, private_key.trim())?;
+    }
+    Ok(signer)
+  }
+
+  pub(crate) fn add_domain(
+    &mut self,
+    domain: &str,
+    selector: &str,
+    algorithm: DkimAlgorithm,
+    private_key: &str,
+  ) -> MResult<()> {
+    let key = DkimSigningKey::new(private_key, algorithm.into_lettre()).map_err(|e| {
+      ErrorResponse::from(format!("Invalid DKIM key for `{domain}`: {e}"))
+        .with_500()
+        .build()
+    })?;
+
+    let headers = ["From", "To", "Subject", "Date", "Message-ID", "MIME-Version", "Content-Type"]
+      .into_iter()
+      .map(HeaderName::new_from_ascii_str)
+      .collect();
+    let canonicalization = DkimCanonicalization {
+      header: DkimCanonicalizationType::Relaxed,
+      body: DkimCanonicalizationType::Relaxed,
+    };
+
+    let domain = domain.to_lowercase();
+    self.configs.push((
+      domain.clone(),
+      DkimConfig::new(selector.to_owned(), domain, key, headers, canonicalization),
+    ));
+    Ok(())
+  }
+
+  pub(crate) fn set_instance_sender(&mut self, default_sender: &str) -> MResult<()> {
+    let sender = default_sender.parse::<Mailbox>().map_err(|e| {
+      ErrorResponse::from(format!("Invalid default sender `{default_sender}`: {e}"))
+        .with_500()
+        .build()
+    })?;
+    self.instance_domain = Some(sender.email.domain().to_lowercase());
+    Ok(())
+  }
+
+  /// Index of the key with the longest domain matching the sender's one.
+  fn config_for(&self, sender_domain: &str) -> Option<usize> {
+    self
+      .configs
+      .iter()
+      .enumerate()
+      .filter(|(_, (domain, _))| is_same_or_subdomain(sender_domain, domain))
+      .max_by_key(|(_, (domain, _))| domain.len())
+      .map(|(idx, _)| idx)
+  }
+
+  /// Adds `DKIM-Signature` header if there is a key for the sender's domain.
+  ///
+  /// `app_sender` marks the sender set by the app; such mails aren't signed by the instance's key.
+  pub(crate) fn sign(&self, message: &mut Message, app_sender: bool) {
+    let Some(sender_domain) = message.envelope().from().map(|from| from.domain().to_owned()) else {
+      return;
+    };
+
+    let config = self.config_for(&sender_domain);
+    if app_sender
+      && config.is_some()
+      && self
+        .instance_domain
+        .as_ref()
+        .is_some_and(|domain| self.config_for(domain) == config)
+    {
+      tracing::warn!(
+        "mailer: app's sender `{}` shares the instance's DKIM key, sending unsigned",
+        sender_domain
+      );
+      return;
+    }
+
+    match config.map(|idx| &self.configs[idx].1) {
+      Some(config) => message.sign(config),
+      None if !self.configs.is_empty() => {
+        tracing::warn!("mailer: there is no DKIM key for `{}`, sending unsigned", sender_domain)
+      }
+      None => {}
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{DkimAlgorithm, DkimSigner};
+  use base64::{Engine as _, engine::general_purpose::STANDARD};
+  use lettre::Message;
+  use sha2::{Digest, Sha256};
+
+  fn message(from: &str) -> Message {
+    Message::builder()
+      .from(from.parse().unwrap())
+      .to("user@example.org".parse().unwrap())
+      .subject("Email   verification")
+      .body(String::from("Code to confirm:\t12345678  \r\n\r\n\r\n"))
+      .unwrap()
+  }
+
+  /// Splits formatted message into unfolded headers and body.
+  fn parse(raw: &[u8]) -> (Vec<(String, String)>, Vec<u8>) {
+    let raw = String::from_utf8(raw.to_vec()).unwrap();
+    let (headers, body) = raw.split_once("\r\n\r\n").unwrap();
+
+    let mut parsed: Vec<(String, String)> = vec![];
+    for line in headers.split("\r\n") {
+      if line.starts_with([' ', '\t']) {
+        let last = parsed.last_mut().unwrap();
+        last.1.push_str(line);
+      } else {
+        let (name, value) = line.split_once(':').unwrap();
+        parsed.push((name.to_owned(), value.to_owned()));
+      }
+    }
+
+    (parsed, body.as_bytes().to_vec())
+  }
+
+  /// RFC 6376, 3.4.2.
+  fn relaxed_header(name: &str, value: &str) -> String {
+    let value = value.split([' ', '\t']).filter(|s| !s.is_empty()).collect::<Vec<_>>().join(" ");
+    format!("{}:{}", name.to_lowercase(), value)
+  }
+
+  /// RFC 6376, 3.4.4.
+  fn relaxed_body(body: &[u8]) -> Vec<u8> {
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    let mut lines = body
+      .split("\r\n")
+      .map(|line| line.split([' ', '\t']).filter(|s| !s.is_empty()).collect::<Vec<_>>().join(" "))
+      .collect::<Vec<_>>();
+    while lines.last().is_some_and(|line| line.is_empty()) {
+      lines.pop();
+    }
+
+    let mut out = String::new();
+    for line in lines {
+      out.push_str(&line);
+      out.push_str("\r\n");
+    }
+    out.into_bytes()
+  }
+
+  fn tags(value: &str) -> Vec<(String, String)> {
+    value
+      .split(';')
+      .filter_map(|tag| tag.split_once('='))
+      .map(|(k, v)| (k.trim().to_owned(), v.split_whitespace().collect::<String>()))
+      .collect()
+  }
+
+  fn tag<'a>(tags: &'a [(String, String)], name: &str) -> &'a str {
+    tags.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str()).unwrap()
+  }
+
+  /// Verifies `DKIM-Signature` and returns its tags and the signed headers' hash with the signature.
+  fn check_dkim(raw: &[u8]) -> (Vec<(String, String)>, Vec<u8>, Vec<u8>) {
+    let (headers, body) = parse(raw);
+    let (dkim_name, dkim_value) = headers
+      .iter()
+      .find(|(name, _)| name.eq_ignore_ascii_case("DKIM-Signature"))
+      .expect("message is not signed");
+    let tags = tags(dkim_value);
+
+    assert_eq!(tag(&tags, "c"), "relaxed/relaxed");
+    assert_eq!(
+      tag(&tags, "bh"),
+      STANDARD.encode(Sha256::digest(relaxed_body(&body))),
+      "body hash mismatch"
+    );
+
+    let mut canonicalized = String::new();
+    for name in tag(&tags, "h").split(':') {
+      if let Some((name, value)) = headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+        canonicalized.push_str(&relaxed_header(name, value));
+        canonicalized.push_str("\r\n");
+      }
+    }
+    let unsigned_dkim = dkim_value
+      .split(';')
+      .map(|t| match t.split_once('=') {
+        Some((k, _)) if k.trim() == "b" => format!("{k}="),
+        _ => t.to_owned(),
+      })
+      .collect::<Vec<_>>()
+      .join(";");
+    canonicalized.push_str(&relaxed_header(dkim_name, &unsigned_dkim));
+
+    let hash = Sha256::digest(canonicalized.as_bytes()).to_vec();
+    let signature = STANDARD.decode(tag(&tags, "b")).unwrap();
+    (tags, hash, signature)
+  }
+
+  #[test]
+  fn test_ed25519_signature() {
+    use ed25519_dalek::{Signature, SigningKey, Verifier};
+
+    let seed = c3a_common::generate::<32>();
+    let mut signer = DkimSigner::default();
+    signer
+      .add_domain("example.com", "c3a", DkimAlgorithm::Ed25519Sha256, &STANDARD.encode(seed))
+      .unwrap();
+
+    let mut message = message("C3A <no-reply@mail.example.com>");
+    signer.sign(&mut message, false);
+
+    let (tags, hash, signature) = check_dkim(&message.formatted());
+    assert_eq!(tag(&tags, "a"), "ed25519-sha256");
+    assert_eq!(tag(&tags, "d"), "example.com");
+    assert_eq!(tag(&tags, "s"), "c3a");
+
+    let verifying_key = SigningKey::from_bytes(&seed).verifying_key();
+    let signature = Signature::from_slice(&signature).unwrap();
+    assert!(verifying_key.verify(&hash, &signature).is_ok());
+  }
+
+  #[test]
+  fn test_rsa_signature() {
+    use rsa::pkcs1::{EncodeRsaPrivateKey, LineEnding};
+    use rsa::{Pkcs1v15Sign, RsaPrivateKey};
+
+    // Small key keeps the test fast; production keys should be at least 2048 bits long.
+    let private_key = RsaPrivateKey::new(&mut rsa::rand_core::OsRng, 1024).unwrap();
+    let pem = private_key.to_pkcs1_pem(LineEnding::LF).unwrap();
+
+    let mut signer = DkimSigner::default();
+    signer
+      .add_domain("example.com", "rsa", DkimAlgorithm::RsaSha256, &pem)
+      .unwrap();
+
+    let mut message = message("C3A <no-reply@example.com>");
+    signer.sign(&mut message, false);
+
+    let (tags, hash, signature) = check_dkim(&message.formatted());
+    assert_eq!(tag(&tags, "a"), "rsa-sha256");
+
+    let public_key = private_key.to_public_key();
+    assert!(
+      public_key
+        .verify(Pkcs1v15Sign::new::<Sha256>(), &hash, &signature)
+        .is_ok()
+    );
+  }
+
+  #[test]
+  fn test_unknown_domain_is_not_signed() {
+    let mut signer = DkimSigner::default();
+    signer
+      .add_domain(
+        "example.com",
+        "c3a",
+        DkimAlgorithm::Ed25519Sha256,
+        &STANDARD.encode(c3a_common::generate::<32>()),
+      )
+      .unwrap();
+
+    let mut message = message("C3A <no-reply@notexample.com>");
+    signer.sign(&mut message, false);
+
+    let (headers, _) = parse(&message.formatted());
+    assert!(
+      !headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("DKIM-Signature"))
+    );
+  }
+
+  #[test]
+  fn test_app_sender_is_not_signed_by_instance_key() {
+    let mut signer = DkimSigner::default();
+    signer.set_instance_sender("C3A <no-reply@mail.example.com>").unwrap();
+    signer
+      .add_domain(
+        "example.com",
+        "c3a",
+        DkimAlgorithm::Ed25519Sha256,
+        &STANDARD.encode(c3a_common::generate::<32>()),
+      )
+      .unwrap();
+    signer
+      .add_domain(
+        "app.example.com",
+        "app",
+        DkimAlgorithm::Ed25519Sha256,
+        &STANDARD.encode(c3a_common::generate::<32>()),
+      )
+      .unwrap();
+
+    let is_signed = |message: &Message| {
+      let (headers, _) = parse(&message.formatted());
+      headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("DKIM-Signature"))
+    };
+
+    let mut mail = message("Phishing <no-reply@mail.example.com>");
+    signer.sign(&mut mail, true);
+    assert!(!is_signed(&mail));
+
+    let mut mail = message("App <no-reply@app.example.com>");
+    signer.sign(&mut mail, true);
+    let (tags, _, _) = check_dkim(&mail.formatted());
+    assert_eq!(tag(&tags, "d"), "app.example.com");
+
+    let mut mail = message("C3A <no-reply@mail.example.com>");
+    signer.sign(&mut mail, false);
+    assert!(is_signed(&mail));
+  }
+}
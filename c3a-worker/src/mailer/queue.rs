@@ -0,0 +1,639 @@
+//! Persistent outgoing mail queue.
+//!
+//! Request handlers never talk to the mail relay directly: they put the formatted message into the outbox
+//! inside `KvDb`, and the background worker sends it later. Failed messages are retried with exponential
+//! backoff and moved to the dead-letter list after `max_attempts` tries.
+//!
+//! Messages contain confirmation codes, so outbox and dead-letter records are sealed in place with the KEK (see
+//! `kv::sealing`); the worker waits while the storage is sealed.
+//!
+//! Enqueueing also applies per-recipient and per-application send rate limits (fixed one hour windows). Stale
+//! windows and old dead letters are purged by the worker; admins can requeue or remove dead letters.
+
+use c3a_common::{DeadLetterInfo, MailQueueStatusResponse};
+use cc_server_kit::prelude::*;
+use lettre::{Address, Message, address::Envelope};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+use crate::kv::sealing::SealedRecord;
+use crate::kv::{KvDb, PreConverted};
+use crate::mailer::Mailer;
+use crate::mailer::dkim::DkimSigner;
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub(crate) struct MailQueueOpts {
+  /// How often the outbox is checked for due messages.
+  pub(crate) poll_interval_secs: u64,
+  /// Tries before the message is moved to dead letters.
+  pub(crate) max_attempts: u32,
+  /// Delay after the first failure; doubles with every next one.
+  pub(crate) base_backoff_secs: i64,
+  pub(crate) max_backoff_secs: i64,
+  pub(crate) per_recipient_per_hour: u32,
+  pub(crate) per_app_per_hour: u32,
+  /// Days before dead letters are removed.
+  pub(crate) dead_letter_retention_days: i64,
+}
+
+impl Default for MailQueueOpts {
+  fn default() -> Self {
+    Self {
+      poll_interval_secs: 5,
+      max_attempts: 8,
+      base_backoff_secs: 10,
+      max_backoff_secs: 3600,
+      per_recipient_per_hour: 5,
+      per_app_per_hour: 1000,
+      dead_letter_retention_days: 30,
+    }
+  }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub(crate) struct OutgoingMail {
+  id: String,
+  app_name: String,
+  from: Option<String>,
+  to: Vec<String>,
+  raw: Vec<u8>,
+  attempts: u32,
+  created_at: chrono::DateTime<chrono::Utc>,
+  next_attempt_at: chrono::DateTime<chrono::Utc>,
+  last_error: Option<String>,
+}
+
+impl OutgoingMail {
+  fn envelope(&self) -> MResult<Envelope> {
+    let parse = |address: &String| {
+      address
+        .parse::<Address>()
+        .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())
+    };
+
+    let from = self.from.as_ref().map(parse).transpose()?;
+    let to = self.to.iter().map(parse).collect::<MResult<Vec<_>>>()?;
+    Envelope::new(from, to).map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())
+  }
+}
+
+#[derive(Deserialize, Serialize)]
+struct RateWindow {
+  started_at: chrono::DateTime<chrono::Utc>,
+  count: u32,
+}
+
+impl RateWindow {
+  const LENGTH: chrono::TimeDelta = chrono::TimeDelta::hours(1);
+
+  fn is_over(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+    now - self.started_at >= Self::LENGTH
+  }
+}
+
+/// How often stale rate windows and old dead letters are purged.
+const PURGE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+#[derive(Clone)]
+pub(crate) struct MailQueue {
+  kv: KvDb,
+  opts: MailQueueOpts,
+  dkim: Arc<DkimSigner>,
+  notify: Arc<Notify>,
+}
+
+impl MailQueue {
+  pub(crate) fn new(kv: KvDb, opts: MailQueueOpts, dkim: DkimSigner) -> Self {
+    Self {
+      kv,
+      opts,
+      dkim: Arc::new(dkim),
+      notify: Arc::new(Notify::new()),
+    }
+  }
+
+  fn outbox_key(id: &str) -> String {
+    format!("{}{}", KvDb::OUTBOX_PREFIX, id)
+  }
+
+  fn dead_key(id: &str) -> String {
+    format!("{}{}", KvDb::OUTBOX_DEAD_PREFIX, id)
+  }
+
+  fn rate_key(scope: &str, subject: &str) -> String {
+    let mut hasher = Sha3_256::new();
+    hasher.update(subject.to_lowercase().as_bytes());
+    format!("{}{}::{}", KvDb::MAIL_RATE_PREFIX, scope, hex::encode(hasher.finalize()))
+  }
+
+  /// Counts the mail in the window of the key; returns the window's start.
+  async fn hit_rate_limit(&self, key: &str, limit: u32) -> MResult<chrono::DateTime<chrono::Utc>> {
+    let now = chrono::Utc::now();
+    self
+      .kv
+      .update::<RateWindow, _, _>(key, |window| {
+        if window.as_ref().is_some_and(|window| window.is_over(now)) {
+          *window = None;
+        }
+        let window = window.get_or_insert(RateWindow { started_at: now, count: 0 });
+
+        if window.count >= limit {
+          return Err(
+            ErrorResponse::from("Too many mails are requested. Try again later.")
+              .with_403_pub()
+              .build(),
+          );
+        }
+
+        window.count += 1;
+        Ok(window.started_at)
+      })
+      .await
+  }
+
+  /// Takes back the mail counted by `hit_rate_limit`, if the window hasn't been restarted since.
+  async fn release_rate_limit(&self, key: &str, started_at: chrono::DateTime<chrono::Utc>) -> MResult<()> {
+    self
+      .kv
+      .update::<RateWindow, _, _>(key, |window| {
+        if let Some(window) = window
+          && window.started_at == started_at
+        {
+          window.count = window.count.saturating_sub(1);
+        }
+        Ok(())
+      })
+      .await
+  }
+
+  /// Counts the mail against every limit; if any of them is exceeded, the mail isn't counted anywhere.
+  async fn hit_rate_limits(&self, limits: Vec<(String, u32)>) -> MResult<()> {
+    let mut hit = vec![];
+    for (key, limit) in limits {
+      match self.hit_rate_limit(&key, limit).await {
+        Ok(started_at) => hit.push((key, started_at)),
+        Err(e) => {
+          for (key, started_at) in hit {
+            self.release_rate_limit(&key, started_at).await?;
+          }
+          return Err(e);
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Puts the message into the outbox, applying rate limits.
+  ///
+  /// The message is DKIM-signed here, so the outbox always holds it exactly as it will be sent. `app_sender` marks
+  /// messages from the app's own sender rather than the instance's default one.
+  pub(crate) async fn enqueue(&self, app_name: &str, mut message: Message, app_sender: bool) -> MResult<()> {
+    let envelope = message.envelope().clone();
+
+    let mut limits = envelope
+      .to()
+      .iter()
+      .map(|recipient| {
+        (
+          Self::rate_key("rcpt", recipient.as_ref()),
+          self.opts.per_recipient_per_hour,
+        )
+      })
+      .collect::<Vec<_>>();
+    limits.push((Self::rate_key("app", app_name), self.opts.per_app_per_hour));
+    self.hit_rate_limits(limits).await?;
+
+    self.dkim.sign(&mut message, app_sender);
+
+    let now = chrono::Utc::now();
+    let id = format!(
+      "{:020}-{}",
+      now.timestamp_micros(),
+      hex::encode(c3a_common::generate::<8>())
+    );
+    let mail = OutgoingMail {
+      id: id.clone(),
+      app_name: app_name.to_owned(),
+      from: envelope.from().map(|a| a.to_string()),
+      to: envelope.to().iter().map(|a| a.to_string()).collect(),
+      raw: message.formatted(),
+      attempts: 0,
+      created_at: now,
+      next_attempt_at: now,
+      last_error: None,
+    };
+
+    let key = Self::outbox_key(&id);
+    self.kv.insert(&key, &self.kv.seal_value(&key, &mail)?).await?;
+    self.notify.notify_one();
+
+    tracing::debug!("mailer: message `{}` is enqueued", id);
+
+    Ok(())
+  }
+
+  fn backoff(&self, attempts: u32) -> chrono::TimeDelta {
+    let factor = 2i64.saturating_pow(attempts.saturating_sub(1));
+    chrono::TimeDelta::seconds(
+      self
+        .opts
+        .base_backoff_secs
+        .saturating_mul(factor)
+        .min(self.opts.max_backoff_secs),
+    )
+  }
+
+  /// Tries to send every due message once. Returns the count of successfully sent messages.
+  pub(crate) async fn process_due(&self, mailer: &Mailer) -> MResult<usize> {
+    let now = chrono::Utc::now();
+    let due = self
+      .kv
+      .scan_sealed_in_place::<OutgoingMail>(KvDb::OUTBOX_PREFIX)
+      .await?
+      .into_iter()
+      .filter(|(_, mail)| mail.next_attempt_at <= now);
+
+    let mut sent = 0;
+    for (key, mut mail) in due {
+      let result = match mail.envelope() {
+        Ok(envelope) => mailer.send_raw(envelope, mail.raw.clone()).await,
+        Err(e) => Err(e),
+      };
+
+      match result {
+        Ok(()) => {
+          self.kv.remove(&key).await?;
+          sent += 1;
+          tracing::debug!("mailer: message `{}` is sent", mail.id);
+        }
+        Err(e) => {
+          mail.attempts += 1;
+          mail.last_error = Some(format!("{e:?}"));
+
+          if mail.attempts >= self.opts.max_attempts {
+            tracing::error!(
+              "mailer: message `{}` is moved to dead letters after {} attempts",
+              mail.id,
+              mail.attempts
+            );
+            let dead_key = Self::dead_key(&mail.id);
+            let sealed = self.kv.seal_value(&dead_key, &mail)?;
+            self
+              .kv
+              .batch_ops(vec![], vec![(dead_key, PreConverted::new(&sealed)?)], vec![key])
+              .await?;
+          } else {
+            mail.next_attempt_at = chrono::Utc::now() + self.backoff(mail.attempts);
+            tracing::warn!(
+              "mailer: message `{}` failed (attempt {}), next try at {}",
+              mail.id,
+              mail.attempts,
+              mail.next_attempt_at
+            );
+            self.kv.upsert(&key, &self.kv.seal_value(&key, &mail)?).await?;
+          }
+        }
+      }
+    }
+
+    Ok(sent)
+  }
+
+  /// Moves the dead letter back to the outbox, with attempts counted from zero.
+  pub(crate) async fn requeue_dead_letter(&self, id: &str) -> MResult<()> {
+    let dead_key = Self::dead_key(id);
+    let sealed = self
+      .kv
+      .pop::<SealedRecord>(&dead_key)
+      .await?
+      .ok_or_else(dead_letter_not_found)?;
+    let mut mail = self.kv.open_value::<OutgoingMail>(&dead_key, &sealed)?;
+    mail.attempts = 0;
+    mail.next_attempt_at = chrono::Utc::now();
+    mail.last_error = None;
+
+    let key = Self::outbox_key(id);
+    self.kv.upsert(&key, &self.kv.seal_value(&key, &mail)?).await?;
+    self.notify.notify_one();
+
+    tracing::info!("mailer: dead letter `{}` is requeued", id);
+    Ok(())
+  }
+
+  pub(crate) async fn remove_dead_letter(&self, id: &str) -> MResult<()> {
+    self
+      .kv
+      .pop::<SealedRecord>(&Self::dead_key(id))
+      .await?
+      .ok_or_else(dead_letter_not_found)?;
+
+    tracing::info!("mailer: dead letter `{}` is removed", id);
+    Ok(())
+  }
+
+  /// Removes finished rate windows and dead letters older than `dead_letter_retention_days`. Returns the count
+  /// of removed records.
+  pub(crate) async fn purge(&self) -> MResult<usize> {
+    let now = chrono::Utc::now();
+    let mut purged = 0;
+
+    for (key, raw) in self.kv.scan_prefix_raw(KvDb::MAIL_RATE_PREFIX).await? {
+      let window =
+        rmp_serde::from_slice::<RateWindow>(&raw).map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+      // The window is removed only if no mail has restarted it meanwhile.
+      if window.is_over(now) && self.kv.compare_and_swap_raw(&key, Some(raw), None).await? {
+        purged += 1;
+      }
+    }
+
+    let retention = chrono::TimeDelta::days(self.opts.dead_letter_retention_days);
+    for (key, mail) in self
+      .kv
+      .scan_sealed_in_place::<OutgoingMail>(KvDb::OUTBOX_DEAD_PREFIX)
+      .await?
+    {
+      if now - mail.created_at >= retention {
+        self.kv.remove(&key).await?;
+        purged += 1;
+      }
+    }
+
+    Ok(purged)
+  }
+
+  pub(crate) async fn status(&self) -> MResult<MailQueueStatusResponse> {
+    let now = chrono::Utc::now();
+    let pending = self
+      .kv
+      .scan_sealed_in_place::<OutgoingMail>(KvDb::OUTBOX_PREFIX)
+      .await?;
+    let dead = self
+      .kv
+      .scan_sealed_in_place::<OutgoingMail>(KvDb::OUTBOX_DEAD_PREFIX)
+      .await?;
+
+    Ok(MailQueueStatusResponse {
+      pending: pending.len(),
+      due: pending.iter().filter(|(_, mail)| mail.next_attempt_at <= now).count(),
+      retrying: pending.iter().filter(|(_, mail)| mail.attempts > 0).count(),
+      oldest_pending_created_at: pending.iter().map(|(_, mail)| mail.created_at).min(),
+      dead_letters: dead
+        .into_iter()
+        .map(|(_, mail)| DeadLetterInfo {
+          id: mail.id,
+          app_name: mail.app_name,
+          recipients: mail.to,
+          attempts: mail.attempts,
+          last_error: mail.last_error,
+          created_at: mail.created_at,
+        })
+        .collect(),
+    })
+  }
+}
+
+fn dead_letter_not_found() -> ErrorResponse {
+  ErrorResponse::from("Dead letter is not found.").with_404_pub().build()
+}
+
+/// Sends queued mails until the worker stops.
+pub(crate) async fn run_mail_worker(queue: MailQueue, mailer: Mailer) {
+  let interval = std::time::Duration::from_secs(queue.opts.poll_interval_secs);
+  let mut purged_at = tokio::time::Instant::now();
+
+  loop {
+    if queue.kv.is_sealed() {
+      tokio::time::sleep(interval).await;
+      continue;
+    }
+
+    if let Err(e) = queue.process_due(&mailer).await {
+      tracing::error!("mailer: can't process the outbox: {e:?}");
+    }
+
+    if purged_at.elapsed() >= PURGE_INTERVAL {
+      purged_at = tokio::time::Instant::now();
+      match queue.purge().await {
+        Ok(purged) => tracing::debug!("mailer: {} stale records are purged", purged),
+        Err(e) => tracing::error!("mailer: can't purge stale records: {e:?}"),
+      }
+    }
+
+    tokio::select! {
+      _ = queue.notify.notified() => {}
+      _ = tokio::time::sleep(interval) => {}
+    }
+  }
+}
+
+pub(crate) fn extract_mail_queue(depot: &mut Depot) -> MResult<MailQueue> {
+  Ok(
+    depot
+      .obtain::<MailQueue>()
+      .map_err(|_| ErrorResponse::from("Can't get `MailQueue` instance").with_500().build())?
+      .clone(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{MailQueue, MailQueueOpts, RateWindow};
+  use crate::kv::KvDb;
+  use crate::mailer::dkim::DkimSigner;
+  use crate::mailer::{Mailer, MailSender, MemoryMailSender, SendFuture};
+  use cc_server_kit::prelude::*;
+  use lettre::{Message, address::Envelope};
+
+  struct FailingSender;
+
+  impl MailSender for FailingSender {
+    fn send_raw(&self, _envelope: Envelope, _raw: Vec<u8>) -> SendFuture<'_> {
+      Box::pin(async move { Err(ErrorResponse::from("Relay is down").with_500().build()) })
+    }
+  }
+
+  fn message(to: &str) -> Message {
+    Message::builder()
+      .from("C3A <no-reply@example.com>".parse().unwrap())
+      .to(to.parse().unwrap())
+      .subject("Test")
+      .body(String::from("Hello!"))
+      .unwrap()
+  }
+
+  #[tokio::test]
+  async fn test_queue_sends_and_retries() {
+    let kv = KvDb::in_memory_unsealed().await.unwrap();
+    let queue = MailQueue::new(
+      kv,
+      MailQueueOpts {
+        max_attempts: 2,
+        base_backoff_secs: 0,
+        ..Default::default()
+      },
+      DkimSigner::default(),
+    );
+
+    queue.enqueue("app", message("alice@example.com"), false).await.unwrap();
+
+    let failing = Mailer::new(FailingSender);
+    assert_eq!(queue.process_due(&failing).await.unwrap(), 0);
+    let status = queue.status().await.unwrap();
+    assert_eq!(status.pending, 1);
+    assert_eq!(status.retrying, 1);
+
+    let memory = MemoryMailSender::default();
+    assert_eq!(queue.process_due(&Mailer::new(memory.clone())).await.unwrap(), 1);
+    let sent = memory.take_messages();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].envelope.to()[0].to_string(), "alice@example.com");
+
+    queue.enqueue("app", message("bob@example.com"), false).await.unwrap();
+    queue.process_due(&failing).await.unwrap();
+    queue.process_due(&failing).await.unwrap();
+
+    let status = queue.status().await.unwrap();
+    assert_eq!(status.pending, 0);
+    assert_eq!(status.dead_letters.len(), 1);
+    assert_eq!(status.dead_letters[0].recipients, vec![String::from("bob@example.com")]);
+    assert_eq!(status.dead_letters[0].attempts, 2);
+    assert!(status.dead_letters[0].last_error.is_some());
+  }
+
+  #[tokio::test]
+  async fn test_mails_are_sealed() {
+    let kv = KvDb::in_memory_unsealed().await.unwrap();
+    let queue = MailQueue::new(
+      kv.clone(),
+      MailQueueOpts {
+        max_attempts: 1,
+        ..Default::default()
+      },
+      DkimSigner::default(),
+    );
+    let contains_body = |records: Vec<(String, Vec<u8>)>| {
+      records
+        .iter()
+        .any(|(_, raw)| raw.windows(b"Hello!".len()).any(|w| w == b"Hello!"))
+    };
+
+    queue.enqueue("app", message("alice@example.com"), false).await.unwrap();
+    let outbox = kv.scan_prefix_raw(KvDb::OUTBOX_PREFIX).await.unwrap();
+    assert_eq!(outbox.len(), 1);
+    assert!(!contains_body(outbox));
+
+    queue.process_due(&Mailer::new(FailingSender)).await.unwrap();
+    let dead = kv.scan_prefix_raw(KvDb::OUTBOX_DEAD_PREFIX).await.unwrap();
+    assert_eq!(dead.len(), 1);
+    assert!(!contains_body(dead));
+
+    // Sealed mails are unavailable without the master key.
+    assert!(
+      kv.reopen()
+        .scan_sealed_in_place::<super::OutgoingMail>(KvDb::OUTBOX_DEAD_PREFIX)
+        .await
+        .is_err()
+    );
+  }
+
+  #[tokio::test]
+  async fn test_queue_rate_limits() {
+    let kv = KvDb::in_memory_unsealed().await.unwrap();
+    let queue = MailQueue::new(
+      kv,
+      MailQueueOpts {
+        per_recipient_per_hour: 2,
+        per_app_per_hour: 3,
+        ..Default::default()
+      },
+      DkimSigner::default(),
+    );
+
+    queue.enqueue("app", message("alice@example.com"), false).await.unwrap();
+    queue.enqueue("app", message("alice@example.com"), false).await.unwrap();
+    assert!(queue.enqueue("app", message("alice@example.com"), false).await.is_err());
+
+    queue.enqueue("app", message("bob@example.com"), false).await.unwrap();
+    assert!(queue.enqueue("app", message("carol@example.com"), false).await.is_err());
+    assert_eq!(queue.status().await.unwrap().pending, 3);
+  }
+
+  #[tokio::test]
+  async fn test_exceeded_limit_is_not_counted_elsewhere() {
+    let queue = MailQueue::new(
+      KvDb::in_memory_unsealed().await.unwrap(),
+      MailQueueOpts {
+        per_recipient_per_hour: 2,
+        per_app_per_hour: 1,
+        ..Default::default()
+      },
+      DkimSigner::default(),
+    );
+
+    queue.enqueue("app", message("bob@example.com"), false).await.unwrap();
+    assert!(queue.enqueue("app", message("alice@example.com"), false).await.is_err());
+
+    // The refused mail isn't counted against the recipient.
+    queue
+      .enqueue("app-2", message("alice@example.com"), false)
+      .await
+      .unwrap();
+    queue
+      .enqueue("app-3", message("alice@example.com"), false)
+      .await
+      .unwrap();
+    assert!(
+      queue
+        .enqueue("app-4", message("alice@example.com"), false)
+        .await
+        .is_err()
+    );
+    assert_eq!(queue.status().await.unwrap().pending, 3);
+  }
+
+  #[tokio::test]
+  async fn test_dead_letters_management_and_purge() {
+    let kv = KvDb::in_memory_unsealed().await.unwrap();
+    let queue = MailQueue::new(
+      kv.clone(),
+      MailQueueOpts {
+        max_attempts: 1,
+        dead_letter_retention_days: 0,
+        ..Default::default()
+      },
+      DkimSigner::default(),
+    );
+    let failing = Mailer::new(FailingSender);
+
+    queue.enqueue("app", message("alice@example.com"), false).await.unwrap();
+    queue.enqueue("app", message("bob@example.com"), false).await.unwrap();
+    queue.process_due(&failing).await.unwrap();
+    let dead = queue.status().await.unwrap().dead_letters;
+    assert_eq!(dead.len(), 2);
+
+    queue.requeue_dead_letter(&dead[0].id).await.unwrap();
+    assert!(queue.requeue_dead_letter(&dead[0].id).await.is_err());
+    let status = queue.status().await.unwrap();
+    assert_eq!(status.pending, 1);
+    assert_eq!(status.retrying, 0);
+    assert_eq!(status.dead_letters.len(), 1);
+    let memory = MemoryMailSender::default();
+    assert_eq!(queue.process_due(&Mailer::new(memory.clone())).await.unwrap(), 1);
+
+    let stale = MailQueue::rate_key("app", "stale-app");
+    let window = RateWindow {
+      started_at: chrono::Utc::now() - chrono::TimeDelta::hours(2),
+      count: 3,
+    };
+    kv.upsert(&stale, &window).await.unwrap();
+
+    // Only the stale window and the dead letter are purged; the recipients' and the app's windows are still running.
+    assert_eq!(queue.purge().await.unwrap(), 2);
+    assert!(!kv.exists(&stale).await.unwrap());
+    assert!(queue.status().await.unwrap().dead_letters.is_empty());
+    assert!(queue.remove_dead_letter(&dead[1].id).await.is_err());
+  }
+}
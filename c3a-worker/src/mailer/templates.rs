@@ -0,0 +1,307 @@
+//! Email rendering.
+//!
+//! Applications can define their own sender, subjects and bodies per locale (see `c3a_common::EmailTemplates`);
+//! C3A built-in English templates are used for everything that is not defined.
+//!
+//! The app's sender must be in the app's domain (or its subdomain) or in a domain allowed by `app_sender_domains`
+//! of `c3a-worker.yaml`; it's checked whenever the app is registered or edited.
+
+use c3a_common::{EmailTemplate, EmailTemplates, LocalizedEmailTemplates};
+use cc_server_kit::prelude::*;
+use lettre::Message;
+use lettre::message::{Mailbox, MultiPart, header::ContentType};
+
+use crate::mailer::dkim::is_same_or_subdomain;
+
+pub(crate) const DEFAULT_SENDER: &str = "Verbal Automation Systems - C3A <no-reply@mail.verbalautomation.tech>";
+
+/// Mails sent by the worker; only sign-up confirmation exists for now, other kinds are added with their flows.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum EmailKind {
+  Confirmation,
+}
+
+impl EmailKind {
+  fn select(self, templates: &LocalizedEmailTemplates) -> Option<&EmailTemplate> {
+    match self {
+      Self::Confirmation => templates.confirmation.as_ref(),
+    }
+  }
+
+  fn builtin(self) -> EmailTemplate {
+    let (subject, text) = match self {
+      Self::Confirmation => (
+        "Email verification",
+        "Code to confirm the account registration in {{app_name}}: {{code}}\n\nThe code expires in {{expires_in_minutes}} minutes.",
+      ),
+    };
+
+    EmailTemplate {
+      subject: subject.to_owned(),
+      text: text.to_owned(),
+      html: None,
+    }
+  }
+}
+
+/// Values for template placeholders.
+pub(crate) struct EmailContext<'a> {
+  pub(crate) app_name: &'a str,
+  pub(crate) identifier: &'a str,
+  pub(crate) code: Option<&'a str>,
+  pub(crate) expires_in: chrono::TimeDelta,
+}
+
+/// Everything needed to render mails for the application.
+pub(crate) struct EmailRenderer<'a> {
+  pub(crate) templates: Option<&'a EmailTemplates>,
+  pub(crate) default_sender: &'a str,
+  pub(crate) locale: Option<&'a str>,
+}
+
+/// Refuses the app's sender unless it's in the app's domain or in one of `allowed_domains`.
+pub(crate) fn validate_sender(
+  templates: Option<&EmailTemplates>,
+  app_domain: &str,
+  allowed_domains: &[String],
+) -> MResult<()> {
+  let Some(sender) = templates.and_then(|t| t.sender.as_deref()) else {
+    return Ok(());
+  };
+  let mailbox = sender.parse::<Mailbox>().map_err(|e| {
+    ErrorResponse::from(format!("Invalid sender `{sender}`: {e}"))
+      .with_400_pub()
+      .build()
+  })?;
+  let domain = mailbox.email.domain();
+
+  if is_same_or_subdomain(domain, app_domain)
+    || allowed_domains
+      .iter()
+      .any(|allowed| is_same_or_subdomain(domain, allowed))
+  {
+    Ok(())
+  } else {
+    Err(
+      ErrorResponse::from(format!(
+        "Sender's domain `{domain}` is neither the app's domain nor allowed by C3A instance."
+      ))
+      .with_400_pub()
+      .build(),
+    )
+  }
+}
+
+impl EmailRenderer<'_> {
+  /// Whether the mails are sent from the app's own sender.
+  pub(crate) fn has_app_sender(&self) -> bool {
+    self.templates.is_some_and(|t| t.sender.is_some())
+  }
+
+  fn localized(&self, kind: EmailKind) -> Option<&EmailTemplate> {
+    let templates = self.templates?;
+
+    let mut candidates = vec![];
+    if let Some(locale) = self.locale {
+      candidates.push(locale);
+      if let Some((language, _)) = locale.split_once(['-', '_']) {
+        candidates.push(language);
+      }
+    }
+    candidates.push(templates.default_locale.as_str());
+
+    candidates
+      .into_iter()
+      .filter_map(|locale| templates.locales.get(locale))
+      .find_map(|localized| kind.select(localized))
+  }
+
+  pub(crate) fn render(&self, kind: EmailKind, to: &str, ctx: &EmailContext<'_>) -> MResult<Message> {
+    let builtin;
+    let template = match self.localized(kind) {
+      Some(template) => template,
+      None => {
+        builtin = kind.builtin();
+        &builtin
+      }
+    };
+
+    let sender = self
+      .templates
+      .and_then(|t| t.sender.as_deref())
+      .unwrap_or(self.default_sender);
+
+    let builder = Message::builder()
+      .from(
+        sender
+          .parse()
+          .map_err(|e| ErrorResponse::from(format!("Invalid sender `{sender}`: {e}")).with_500().build())?,
+      )
+      .to(
+        to.parse()
+          .map_err(|e| ErrorResponse::from(format!("Invalid recipient: {e}")).with_400_pub().build())?,
+      )
+      .subject(substitute(&template.subject, ctx, false));
+
+    let text = substitute(&template.text, ctx, false);
+    let message = match &template.html {
+      Some(html) => builder.multipart(MultiPart::alternative_plain_html(text, substitute(html, ctx, true))),
+      None => builder.header(ContentType::TEXT_PLAIN).body(text),
+    };
+
+    message.map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())
+  }
+}
+
+fn substitute(template: &str, ctx: &EmailContext<'_>, html: bool) -> String {
+  let escape = |value: &str| {
+    if html {
+      value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+    } else {
+      value.to_owned()
+    }
+  };
+
+  template
+    .replace("{{code}}", &escape(ctx.code.unwrap_or_default()))
+    .replace("{{app_name}}", &escape(ctx.app_name))
+    .replace("{{identifier}}", &escape(ctx.identifier))
+    .replace("{{expires_in_minutes}}", &ctx.expires_in.num_minutes().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{DEFAULT_SENDER, EmailContext, EmailKind, EmailRenderer, substitute, validate_sender};
+  use c3a_common::{EmailTemplate, EmailTemplates, LocalizedEmailTemplates};
+
+  fn ctx() -> EmailContext<'static> {
+    EmailContext {
+      app_name: "<Test App>",
+      identifier: "user@example.com",
+      code: Some("12345678"),
+      expires_in: chrono::TimeDelta::minutes(10),
+    }
+  }
+
+  fn templates() -> EmailTemplates {
+    let mut locales = std::collections::BTreeMap::new();
+    locales.insert(
+      String::from("ru"),
+      LocalizedEmailTemplates {
+        confirmation: Some(EmailTemplate {
+          subject: String::from("Подтверждение почты в {{app_name}}"),
+          text: String::from("Код: {{code}}, действует {{expires_in_minutes}} минут."),
+          html: Some(String::from("<p>Код для {{app_name}}: <b>{{code}}</b></p>")),
+        }),
+        ..Default::default()
+      },
+    );
+    locales.insert(
+      String::from("en"),
+      LocalizedEmailTemplates {
+        confirmation: Some(EmailTemplate {
+          subject: String::from("Welcome to {{app_name}}"),
+          text: String::from("Your code is {{code}}."),
+          html: None,
+        }),
+        ..Default::default()
+      },
+    );
+
+    EmailTemplates {
+      sender: Some(String::from("Test App <no-reply@test-app.example.com>")),
+      default_locale: String::from("en"),
+      locales,
+    }
+  }
+
+  fn render(renderer: &EmailRenderer<'_>, kind: EmailKind) -> String {
+    let message = renderer.render(kind, "user@example.com", &ctx()).unwrap();
+    String::from_utf8(message.formatted()).unwrap()
+  }
+
+  #[test]
+  fn test_builtin_templates() {
+    let renderer = EmailRenderer {
+      templates: None,
+      default_sender: DEFAULT_SENDER,
+      locale: Some("ru"),
+    };
+
+    let mail = render(&renderer, EmailKind::Confirmation);
+    assert!(mail.contains("no-reply@mail.verbalautomation.tech"));
+    assert!(mail.contains("Subject: Email verification"));
+    assert!(mail.contains("12345678"));
+    assert!(mail.contains("10 minutes"));
+  }
+
+  #[test]
+  fn test_locale_fallbacks() {
+    let templates = templates();
+
+    let renderer = EmailRenderer {
+      templates: Some(&templates),
+      default_sender: DEFAULT_SENDER,
+      locale: Some("ru-RU"),
+    };
+    let mail = render(&renderer, EmailKind::Confirmation);
+    assert!(mail.contains("no-reply@test-app.example.com"));
+    assert!(mail.contains("text/html"));
+
+    let renderer = EmailRenderer {
+      templates: Some(&templates),
+      default_sender: DEFAULT_SENDER,
+      locale: Some("de"),
+    };
+    let mail = render(&renderer, EmailKind::Confirmation);
+    assert!(mail.contains("Subject: Welcome to <Test App>"));
+    assert!(mail.contains("Your code is 12345678."));
+
+    // Neither the requested nor the default locale has the template, so the built-in one is used.
+    let mut templates = templates;
+    templates.default_locale = String::from("fr");
+    let renderer = EmailRenderer {
+      templates: Some(&templates),
+      default_sender: DEFAULT_SENDER,
+      locale: Some("de"),
+    };
+    let mail = render(&renderer, EmailKind::Confirmation);
+    assert!(mail.contains("Subject: Email verification"));
+    assert!(mail.contains("no-reply@test-app.example.com"));
+  }
+
+  #[test]
+  fn test_html_placeholders_are_escaped() {
+    let template = "<p>{{app_name}}: {{code}}</p>";
+    assert_eq!(substitute(template, &ctx(), true), "<p>&lt;Test App&gt;: 12345678</p>");
+    assert_eq!(substitute(template, &ctx(), false), "<p><Test App>: 12345678</p>");
+  }
+
+  #[test]
+  fn test_sender_validation() {
+    let mut templates = templates();
+    let allowed = vec![String::from("relay.example.org")];
+
+    validate_sender(Some(&templates), "test-app.example.com", &[]).unwrap();
+    validate_sender(Some(&templates), "example.com", &[]).unwrap();
+    validate_sender(None, "example.com", &[]).unwrap();
+    assert!(validate_sender(Some(&templates), "other.example.com", &[]).is_err());
+    assert!(validate_sender(Some(&templates), "app.test-app.example.com", &[]).is_err());
+
+    templates.sender = Some(String::from("Test App <no-reply@mail.relay.example.org>"));
+    validate_sender(Some(&templates), "test-app.example.com", &allowed).unwrap();
+
+    // The instance's default sender is not allowed for apps.
+    templates.sender = Some(DEFAULT_SENDER.to_owned());
+    assert!(validate_sender(Some(&templates), "test-app.example.com", &allowed).is_err());
+    templates.sender = Some(String::from("no-reply@notrelay.example.org"));
+    assert!(validate_sender(Some(&templates), "test-app.example.com", &allowed).is_err());
+    templates.sender = Some(String::from("not a mailbox"));
+    assert!(validate_sender(Some(&templates), "test-app.example.com", &allowed).is_err());
+  }
+}
@@ -10,17 +10,56 @@ mod api;
 mod services;
 
 pub(crate) mod core;
+pub(crate) mod keys;
 pub(crate) mod kv;
 pub(crate) mod mailer;
 pub(crate) mod utils;
 
+use crate::api::admin::admin_api;
 use crate::api::applications::application_server_api;
+use crate::api::keys::keys_api;
+use crate::api::unseal::{sealed_guard, unseal_api};
 
 #[derive(Deserialize, Default, Clone)]
 pub(crate) struct Setup {
   #[serde(flatten)]
   generic_values: GenericValues,
   private_adm_key: Option<String>,
+  #[serde(default)]
+  mailer: crate::mailer::MailerConfig,
+  /// Default sender of C3A mails, e.g. `C3A <no-reply@example.com>`.
+  mail_sender: Option<String>,
+  /// Domains which apps may send mails from besides their own domains.
+  #[serde(default)]
+  app_sender_domains: Vec<String>,
+  #[serde(default)]
+  mail_queue: crate::mailer::queue::MailQueueOpts,
+  /// DKIM keys by sending domain.
+  #[serde(default)]
+  dkim: Vec<crate::mailer::dkim::DkimDomainOpts>,
+  /// File with SHA-1 hashes of breached passwords, one per line, sorted by hash (HIBP `HASH:COUNT` format is
+  /// accepted). Its index is written next to it, to `<file>.idx`.
+  breached_passwords_file: Option<std::path::PathBuf>,
+  /// Default Argon2 costs of users' secrets; applications may override them.
+  #[serde(default)]
+  hash_params: c3a_common::HashParams,
+  /// Argon2 costs which applications may choose; costs outside of the range are refused.
+  #[serde(default)]
+  hash_params_bounds: crate::utils::HashParamsBounds,
+  #[serde(default)]
+  signing_keys: crate::core::signing_keys::SigningKeysOpts,
+  /// File with the master key of sealed records; `C3A_MASTER_KEY` env variable takes precedence.
+  master_key_file: Option<std::path::PathBuf>,
+  /// File with the key of backup archives; `C3A_BACKUP_KEY` env variable takes precedence.
+  backup_key_file: Option<std::path::PathBuf>,
+  #[serde(default)]
+  storage: crate::kv::storage::StorageOpts,
+  /// How the storage is unsealed on startup.
+  #[serde(default)]
+  unseal: crate::kv::unseal::UnsealMode,
+  /// Header with clients' IP addresses set by the trusted reverse proxy, e.g. `X-Forwarded-For`; the last
+  /// address is taken. Peers' addresses are used if not set.
+  client_ip_header: Option<String>,
 }
 
 impl GenericSetup for Setup {
@@ -52,22 +91,78 @@ async fn main() -> MResult<()> {
     },
   );
 
+  crate::utils::validate_hash_params(&setup.hash_params, &setup.hash_params_bounds)?;
+
+  let args = std::env::args().skip(1).collect::<Vec<_>>();
+  if args.first().is_some_and(|arg| arg.as_str().eq("split-master-key")) {
+    return crate::kv::unseal::run_split(&crate::kv::sealing::load_master_key(&setup)?, &args[1..]);
+  }
+
+  let mut kv_db = crate::kv::KvDb::load(&setup.storage)?;
+  kv_db.set_signer_backend(crate::core::signer::init_signer_backend(&setup.signing_keys.signer)?);
+  if args.first().is_some_and(|arg| arg.as_str().eq("rekey")) {
+    kv_db
+      .unseal(&crate::kv::sealing::load_master_key(&setup)?)
+      .await?;
+    return crate::kv::sealing::run_rekey(&kv_db, &args[1..]).await;
+  }
+  if args.first().is_some_and(|arg| arg.as_str().eq("backup")) {
+    return crate::kv::backup::run_backup(&kv_db, &setup, &args[1..]).await;
+  }
+  if args.first().is_some_and(|arg| arg.as_str().eq("restore")) {
+    return crate::kv::backup::run_restore(&kv_db, &setup, &args[1..]).await;
+  }
+
   let state = load_generic_state(&setup).await?;
 
-  let kv_db = crate::kv::KvDb::load("data")?;
-  kv_db.initial_setup().await?;
+  let unsealer = crate::kv::unseal::Unsealer::new(&setup.unseal)?;
+  match &setup.unseal {
+    crate::kv::unseal::UnsealMode::MasterKey => {
+      kv_db
+        .unseal(&crate::kv::sealing::load_master_key(&setup)?)
+        .await?;
+      kv_db.initial_setup().await?;
+    }
+    crate::kv::unseal::UnsealMode::Shamir { threshold, .. } => {
+      tracing::warn!("The storage is sealed; submit {threshold} shares of the master key to `/unseal`.");
+    }
+  }
 
-  let mailer = crate::mailer::init_mailer()?;
+  let breached_passwords = match &setup.breached_passwords_file {
+    Some(path) => crate::core::password_policy::BreachedPasswords::load(path)?,
+    None => Default::default(),
+  };
+
+  let mailer = crate::mailer::init_mailer(&setup.mailer)?;
+  let mail_queue = crate::mailer::queue::MailQueue::new(
+    kv_db.clone(),
+    setup.mail_queue.clone(),
+    crate::mailer::dkim::DkimSigner::load(
+      &setup.dkim,
+      setup
+        .mail_sender
+        .as_deref()
+        .unwrap_or(crate::mailer::templates::DEFAULT_SENDER),
+    )?,
+  );
+  tokio::spawn(crate::mailer::queue::run_mail_worker(mail_queue.clone(), mailer));
+  tokio::spawn(crate::core::auth_states::run_state_sweeper(kv_db.clone()));
 
   let router = get_root_router(&state)
     .hoop(
       affix_state::inject(state.clone())
         .inject(setup.clone())
         .inject(kv_db)
-        .inject(mailer),
+        .inject(mail_queue)
+        .inject(breached_passwords)
+        .inject(unsealer),
     )
+    .hoop(sealed_guard)
+    .push(unseal_api())
     .push(frontend_router())
-    .push(application_server_api());
+    .push(application_server_api())
+    .push(keys_api())
+    .push(admin_api());
   let (server, _) = start(state, &setup, router).await?;
 
   server.await;
@@ -1,6 +1,9 @@
-use c3a_common::{base64_decode, base64_encode, sign, verify};
+use c3a_common::{base64_decode, base64_encode, verify};
 use cc_server_kit::prelude::*;
 use cc_server_kit::salvo::{Request, Response};
+use salvo::prelude::StatusCode;
+
+use crate::core::signing_keys::SigningKey;
 
 pub(crate) mod generators;
 pub(crate) mod hashes;
@@ -32,10 +35,9 @@ pub(crate) fn verify_sign_by_header(req: &mut Request, value: &impl serde::Seria
 pub(crate) fn sign_by_header(
   res: &mut Response,
   value: &impl serde::Serialize,
-  keypair: &c3a_common::Keypair,
+  signing_key: &SigningKey,
 ) -> MResult<()> {
-  let sign = sign(&value, keypair).map_err(|e| ErrorResponse::from(format!("Sign error: {e:?}")).with_500().build())?;
-  let sign = base64_encode(&sign);
+  let sign = base64_encode(&signing_key.sign(value)?);
   res.add_header(c3a_common::SIGN_HEADER, sign, true).map_err(|e| {
     ErrorResponse::from(format!("Add header error: {e:?}"))
       .with_500()
@@ -45,6 +47,37 @@ pub(crate) fn sign_by_header(
   Ok(())
 }
 
+/// Renders the value as msgpack body with the status; for responses whose body type depends on the outcome.
+pub(crate) fn write_msgpack(res: &mut Response, status: StatusCode, value: &impl serde::Serialize) -> MResult<()> {
+  let body = rmp_serde::to_vec(value).map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+  res.status_code(status);
+  res.add_header("Content-Type", "application/msgpack", true)?;
+  res.write_body(body)?;
+  Ok(())
+}
+
+/// Argon2 costs for secrets of the application's users.
+pub(crate) fn app_hash_params(
+  setup: &crate::Setup,
+  app_conf: &c3a_common::AppAuthConfiguration,
+) -> c3a_common::HashParams {
+  app_conf.hash_params.unwrap_or(setup.hash_params)
+}
+
+/// Client's IP address, from `client_ip_header` if it's configured.
+///
+/// Only the last address of the header is trusted, since it's appended by the proxy itself; there is no fallback
+/// to the peer's address, which is the proxy's then.
+pub(crate) fn client_ip(req: &Request, setup: &crate::Setup) -> Option<String> {
+  match &setup.client_ip_header {
+    Some(header) => req
+      .header::<String>(header.as_str())
+      .and_then(|value| value.rsplit(',').next()?.trim().parse::<std::net::IpAddr>().ok())
+      .map(|ip| ip.to_string()),
+    None => req.remote_addr().clone().into_std().map(|addr| addr.ip().to_string()),
+  }
+}
+
 pub(crate) fn take_exp_from_duration(duration: chrono::TimeDelta) -> MResult<chrono::DateTime<chrono::Utc>> {
   let curr_time = chrono::Utc::now();
   curr_time.checked_add_signed(duration).ok_or(
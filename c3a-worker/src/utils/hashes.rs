@@ -1,31 +1,87 @@
 use argon2::PasswordVerifier;
+use c3a_common::HashParams;
 use cc_server_kit::prelude::{ErrorResponse, MResult};
+use serde::Deserialize;
 
-pub(crate) fn hash(value: &str, pepper: &[u8]) -> MResult<(String, Vec<u8>)> {
-  use argon2::{
-    Argon2,
-    password_hash::{PasswordHasher, SaltString, rand_core::OsRng},
-  };
+/// Range of Argon2 costs which the worker and applications may use.
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub(crate) struct HashParamsBounds {
+  pub(crate) min: HashParams,
+  pub(crate) max: HashParams,
+}
+
+impl Default for HashParamsBounds {
+  fn default() -> Self {
+    Self {
+      min: HashParams::default(),
+      max: HashParams {
+        m_cost: 256 * 1024,
+        t_cost: 10,
+        p_cost: 8,
+      },
+    }
+  }
+}
+
+impl HashParamsBounds {
+  fn contains(&self, params: &HashParams) -> bool {
+    (self.min.m_cost..=self.max.m_cost).contains(&params.m_cost)
+      && (self.min.t_cost..=self.max.t_cost).contains(&params.t_cost)
+      && (self.min.p_cost..=self.max.p_cost).contains(&params.p_cost)
+  }
+}
+
+fn argon2_instance(params: &HashParams) -> MResult<argon2::Argon2<'static>> {
+  let params = argon2::Params::new(params.m_cost, params.t_cost, params.p_cost, None)
+    .map_err(|e| ErrorResponse::from(format!("Invalid Argon2 parameters: {e}")).with_500().build())?;
+  Ok(argon2::Argon2::new(
+    argon2::Algorithm::Argon2id,
+    argon2::Version::V0x13,
+    params,
+  ))
+}
+
+/// Checks that Argon2 accepts the costs and that they are within `bounds`.
+pub(crate) fn validate_hash_params(params: &HashParams, bounds: &HashParamsBounds) -> MResult<()> {
+  argon2::Params::new(params.m_cost, params.t_cost, params.p_cost, None)
+    .map_err(|e| ErrorResponse::from(format!("Invalid Argon2 parameters: {e}")).with_400_pub().build())?;
+  if !bounds.contains(params) {
+    return Err(
+      ErrorResponse::from(format!(
+        "Argon2 costs should be between {:?} and {:?}.",
+        bounds.min, bounds.max
+      ))
+      .with_400_pub()
+      .build(),
+    );
+  }
+  Ok(())
+}
+
+pub(crate) fn hash(value: &str, pepper: &[u8], params: &HashParams) -> MResult<(String, Vec<u8>)> {
+  use argon2::password_hash::{PasswordHasher, SaltString, rand_core::OsRng};
 
   let mut peppered = value.as_bytes().to_vec();
   peppered.extend_from_slice(pepper);
 
   let salt = SaltString::generate(&mut OsRng);
-  let argon2 = Argon2::default();
+  let argon2 = argon2_instance(params)?;
   let phash = argon2
     .hash_password(&peppered, &salt)
     .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
-  let (salt, hash) = Argon2::export(&phash).map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
+  let (salt, hash) =
+    argon2::Argon2::export(&phash).map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
 
   Ok((salt, hash))
 }
 
-#[allow(dead_code)]
-pub(crate) fn validate_hash(value: &str, salt: &str, hash: &[u8], pepper: &[u8]) -> MResult<()> {
+/// Validates the value against the hash computed with given `params`.
+pub(crate) fn validate_hash(value: &str, salt: &str, hash: &[u8], pepper: &[u8], params: &HashParams) -> MResult<()> {
   let mut peppered = value.as_bytes().to_vec();
   peppered.extend_from_slice(pepper);
 
-  let argon2 = argon2::Argon2::default();
+  let argon2 = argon2_instance(params)?;
   let phash = argon2
     .import(salt, hash)
     .map_err(|e| ErrorResponse::from(e.to_string()).with_500().build())?;
@@ -36,14 +92,77 @@ pub(crate) fn validate_hash(value: &str, salt: &str, hash: &[u8], pepper: &[u8])
 
 #[cfg(test)]
 mod tests {
-  use super::{hash, validate_hash};
+  use super::{HashParamsBounds, hash, validate_hash, validate_hash_params};
+  use c3a_common::HashParams;
 
   #[test]
   fn test_hash_and_verify() {
     let password = "hello world!";
     let pepper = b"some pepper";
 
-    let (salt, hash) = hash(password, pepper).unwrap();
-    assert!(validate_hash(password, &salt, &hash, pepper).is_ok())
+    let (salt, hash) = hash(password, pepper, &HashParams::default()).unwrap();
+    assert!(validate_hash(password, &salt, &hash, pepper, &HashParams::default()).is_ok())
+  }
+
+  #[test]
+  fn test_params_are_applied() {
+    let password = "hello world!";
+    let pepper = b"some pepper";
+    let params = HashParams {
+      m_cost: 8 * 1024,
+      t_cost: 3,
+      p_cost: 2,
+    };
+
+    let (salt, hash) = hash(password, pepper, &params).unwrap();
+    assert!(validate_hash(password, &salt, &hash, pepper, &params).is_ok());
+    assert!(validate_hash(password, &salt, &hash, pepper, &HashParams::default()).is_err());
+  }
+
+  #[test]
+  fn test_invalid_params() {
+    let bounds = HashParamsBounds::default();
+    assert!(validate_hash_params(&HashParams::default(), &bounds).is_ok());
+    assert!(validate_hash_params(&bounds.max, &bounds).is_ok());
+    assert!(
+      validate_hash_params(
+        &HashParams {
+          m_cost: 1,
+          t_cost: 0,
+          p_cost: 1,
+        },
+        &HashParamsBounds {
+          min: HashParams {
+            m_cost: 1,
+            t_cost: 0,
+            p_cost: 1,
+          },
+          ..bounds
+        }
+      )
+      .is_err()
+    );
+
+    // Costs accepted by Argon2, but below the floor or above the ceiling.
+    for params in [
+      HashParams {
+        m_cost: 8 * 1024,
+        ..Default::default()
+      },
+      HashParams {
+        t_cost: 1,
+        ..Default::default()
+      },
+      HashParams {
+        m_cost: 4 * 1024 * 1024,
+        ..Default::default()
+      },
+      HashParams {
+        p_cost: 64,
+        ..Default::default()
+      },
+    ] {
+      assert!(validate_hash_params(&params, &bounds).is_err());
+    }
   }
 }